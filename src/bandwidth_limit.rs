@@ -0,0 +1,104 @@
+use std::{sync::Mutex, time::{Duration, Instant}};
+
+/// Limits the rate that file contents are sent at (see --bwlimit), using a token bucket.
+/// One of these is shared by all the syncs in a run (e.g. with --parallel), so that together they stay within the limit.
+pub struct BandwidthLimiter {
+    /// Bytes per second.
+    rate: u64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// The number of bytes that can be sent straight away. This goes negative when something larger
+    /// than this is sent, which is then paid back by waiting.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl BandwidthLimiter {
+    pub fn new(rate: u64) -> BandwidthLimiter {
+        BandwidthLimiter {
+            rate,
+            bucket: Mutex::new(Bucket { tokens: 0.0, last_refill: Instant::now() }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Waits until the given number of bytes can be sent without going over the limit, and returns how long that was.
+    /// At most one second's worth of bytes can build up while nothing is being sent, so that there is only a short burst
+    /// after being idle.
+    pub fn take(&self, bytes: u64) -> Duration {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("Failed to lock mutex");
+            let now = Instant::now();
+            let refill = (now - bucket.last_refill).as_secs_f64() * self.rate as f64;
+            bucket.tokens = (bucket.tokens + refill).min(self.rate as f64) - bytes as f64;
+            bucket.last_refill = now;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.rate as f64)
+            } else {
+                Duration::ZERO
+            }
+        };
+        // Sleep without holding the lock, so that other syncs can take their share in the meantime.
+        // They will wait for any debt that we've left behind, so the total rate stays within the limit.
+        std::thread::sleep(wait);
+        wait
+    }
+}
+
+/// Parses a rate in bytes per second (see --bwlimit), with an optional K, M or G suffix
+/// for multiples of 1024 (e.g. "500K" or "1.5M").
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024.0),
+        Some('M') => (&s[..s.len() - 1], 1024.0 * 1024.0),
+        Some('G') => (&s[..s.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (s, 1.0),
+    };
+    let rate = number.parse::<f64>().ok().filter(|n| n.is_finite() && *n >= 0.0).map(|n| (n * multiplier).round() as u64);
+    match rate {
+        Some(r) if r > 0 => Ok(r),
+        Some(_) => Err("Rate must be at least one byte per second".to_string()),
+        None => Err(format!("Invalid rate '{s}'. Expected a number of bytes per second, optionally with a K, M or G suffix (e.g. 500K)")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1000"), Ok(1000));
+        assert_eq!(parse_rate("500K"), Ok(500 * 1024));
+        assert_eq!(parse_rate("1.5m"), Ok(1536 * 1024));
+        assert_eq!(parse_rate("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("").is_err());
+        assert!(parse_rate("K").is_err());
+        assert!(parse_rate("-5M").is_err());
+        assert!(parse_rate("ten").is_err());
+    }
+
+    /// Sending more than the rate takes about as long as it should, including when it's shared between threads.
+    #[test]
+    fn test_limiter() {
+        let limiter = BandwidthLimiter::new(1000 * 1000);
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| for _ in 0..5 {
+                    limiter.take(100 * 1000);
+                });
+            }
+        });
+        // 1MB at 1MB/s, less any time spent before the first byte
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(900), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(1500), "{elapsed:?}");
+    }
+}
@@ -44,7 +44,7 @@ pub struct Filters {
     /// and won't report the same error twice (from both doers).
     #[serde(serialize_with = "serialize_regex_set_as_strings", deserialize_with="deserialize_regex_set_from_strings")]
     pub regex_set: RegexSet,
-    /// For each regex in the RegexSet above, is it an include filter or an exclude filter.
+    /// For each regex in the RegexSet above, is it an include, exclude or protect filter.
     pub kinds: Vec<FilterKind>,
 }
 
@@ -66,6 +66,9 @@ fn deserialize_regex_set_from_strings<'de, D: Deserializer<'de>>(d: D) -> Result
 pub enum FilterKind {
     Include,
     Exclude,
+    /// Doesn't affect whether an entry is included or excluded, but prevents matching entries
+    /// from being deleted on the dest. This is evaluated on the boss only - doers ignore these.
+    Protect,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -89,6 +92,11 @@ pub enum ProgressPhase {
         /// which can increase even though num_entries_copied remains the same.
         num_bytes_copied: u64,
     },
+    /// Checking that copied files have the same contents on the dest as on the source (see --verify).
+    Verifying {
+        /// The number of files already verified.
+        num_files_verified: u32,
+    },
     Done
 }
 
@@ -102,11 +110,36 @@ pub enum Command {
     },
     GetEntries {
         filters: Filters,
+        /// If set, entries which are excluded by the filters (or are inside an excluded folder) are still
+        /// reported, as ExcludedEntry, rather than being skipped. Used for --delete-excluded.
+        report_excluded: bool,
+        /// If set, only these entries (and everything inside them, for folders) are reported rather than
+        /// walking the whole root. Entries which don't exist are ignored. Used to sync just what changed (see --watch).
+        paths: Option<Vec<RootRelativePath>>,
     },
     CreateRootAncestors,
+    /// Gets the details of a single entry (None if it doesn't exist), without walking anything else.
+    /// Used to check that entries haven't changed since a plan was made (see --apply-plan).
+    GetEntryDetails {
+        path: RootRelativePath,
+    },
+    /// Sends back the contents of a file, as one or more FileContent responses (see CreateOrUpdateFile::more_to_follow).
+    /// The boss may send several of these before receiving the contents (see request_file_contents), so the
+    /// contents of each file are all sent before starting on the next.
     GetFileContent {
         path: RootRelativePath,
     },
+    /// Gets the contents of several small files at once, which are all sent back in a single FileContents response.
+    /// This saves sending a message (and the response) for each file, which adds up when there are lots of them.
+    /// At most SMALL_FILE_MAX_SIZE (+1, so that the boss can see that the file has grown) is read from each file.
+    GetFileContents {
+        paths: Vec<RootRelativePath>,
+    },
+    /// Calculates a hash of the contents of a file, so that the boss can check that the copy on the
+    /// dest matches the source (see --verify).
+    GetFileHash {
+        path: RootRelativePath,
+    },
     CreateOrUpdateFile {
         path: RootRelativePath,
         #[serde(with = "serde_bytes")] // Make serde fast
@@ -118,6 +151,43 @@ pub enum Command {
         /// This is used to split up large files so that we don't send them all in one huge message.
         /// See GetFileContent for more details.
         more_to_follow: bool,
+        /// The hash of the whole file from the source doer, set on the final chunk only.
+        /// The dest doer checks this against what it has written, and rejects the file if they differ.
+        hash: Option<[u8; 32]>,
+    },
+    /// Creates or updates several small files at once, each of which is the same as a CreateOrUpdateFile
+    /// with no more_to_follow. Errors are reported separately for each file.
+    CreateOrUpdateFiles {
+        files: Vec<SmallFile>,
+    },
+    /// Copies a file from the source root of another session with this same doer (see Comms::shares_doer_with),
+    /// so that the contents don't need to be sent via the boss.
+    CopyLocalFile {
+        src_root: String,
+        src_path: RootRelativePath,
+        path: RootRelativePath,
+        /// The size that the boss expects, to check that the file hasn't changed since it was queried.
+        size: u64,
+        set_modified_time: SystemTime,
+    },
+    /// Connects to the source doer, so that file contents can be fetched from it directly rather than
+    /// being sent via the boss (see --direct-transfer and CopyFromSource). The source doer must accept
+    /// more than one session. The key is only good for this one connection (see make_transfer_key), so that
+    /// the dest doer is never given the key that the source doer was launched with.
+    ConnectToSource {
+        hostname: String,
+        port: u16,
+        transfer_id: [u8; 16],
+        key: [u8; 16],
+    },
+    /// Fetches a file from the source doer (see ConnectToSource) and writes it, the same as CreateOrUpdateFile would.
+    CopyFromSource {
+        src_root: String,
+        src_path: RootRelativePath,
+        path: RootRelativePath,
+        /// The size that the boss expects, to check that the file hasn't changed since it was queried.
+        size: u64,
+        set_modified_time: SystemTime,
     },
     CreateSymlink {
         path: RootRelativePath,
@@ -138,6 +208,18 @@ pub enum Command {
         kind: SymlinkKind,
     },
 
+    /// Starts watching the given roots (of each sync) for changes, so that they can be fetched with WaitForChanges
+    /// (see --watch). Unlike SetRoot, this is for all the syncs at once, as they all need watching at the same time.
+    Watch {
+        roots: Vec<String>,
+    },
+    /// Blocks until something inside one of the watched roots changes, then waits until nothing has changed
+    /// for the given debounce time (so that e.g. saving lots of files at once gives one set of changes), and
+    /// responds with the changes.
+    WaitForChanges {
+        debounce: std::time::Duration,
+    },
+
     ProfilingTimeSync,
 
     /// Used to mark a position in the sequence of commands, which the doer will echo back
@@ -157,6 +239,75 @@ impl encrypted_comms::IsFinalMessage for Command {
         }
     }
 }
+impl encrypted_comms::WorthCompressing for Command {
+    fn worth_compressing(&self) -> bool {
+        match self {
+            Self::CreateOrUpdateFile { path, .. } => !is_already_compressed(path),
+            _ => true
+        }
+    }
+}
+
+/// File extensions for formats which are already compressed, so won't get any smaller (see --compress).
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "lz4", "m4a", "mkv", "mov", "mp3",
+    "mp4", "ogg", "png", "pptx", "rar", "tgz", "webm", "webp", "whl", "woff2", "xlsx", "xz", "zip", "zst",
+];
+
+/// Whether the file's extension says that its contents are already compressed (see COMPRESSED_EXTENSIONS).
+fn is_already_compressed(path: &RootRelativePath) -> bool {
+    match path.file_name().rsplit_once('.') {
+        Some((_, ext)) => COMPRESSED_EXTENSIONS.iter().any(|c| c.eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
+/// Files no bigger than this are fetched and written several at a time (see GetFileContents and CreateOrUpdateFiles).
+/// Along with MAX_FILES_PER_BATCH, this limits the size of those messages to roughly the same as the biggest
+/// chunk of a large file (see encrypted_comms.rs).
+pub const SMALL_FILE_MAX_SIZE: u64 = 16 * 1024;
+/// The most files that are fetched or written at a time (see SMALL_FILE_MAX_SIZE).
+pub const MAX_FILES_PER_BATCH: usize = 256;
+
+/// A whole file to be written by the dest doer (see CreateOrUpdateFiles).
+#[derive(Serialize, Deserialize)]
+pub struct SmallFile {
+    pub path: RootRelativePath,
+    #[serde(with = "serde_bytes")] // Make serde fast
+    pub data: Vec<u8>,
+    pub set_modified_time: SystemTime,
+    /// See CreateOrUpdateFile::hash.
+    pub hash: Option<[u8; 32]>,
+}
+impl std::fmt::Debug for SmallFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SmallFile").field("path", &self.path).field("data", &format!("... ({})", HumanBytes(self.data.len() as u64))).field("set_modified_time", &self.set_modified_time).field("hash", &self.hash).finish()
+    }
+}
+
+/// The contents of one of the files requested by GetFileContents.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum SmallFileContent {
+    Content {
+        #[serde(with = "serde_bytes")] // Make serde fast
+        data: Vec<u8>,
+        /// See FileContent::hash.
+        hash: [u8; 32],
+    },
+    /// The same as an EntryError response, for this file.
+    Error {
+        message: String,
+        not_found: bool,
+    },
+}
+impl std::fmt::Debug for SmallFileContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Content { data, hash } => f.debug_struct("Content").field("data", &format!("... ({})", HumanBytes(data.len() as u64))).field("hash", hash).finish(),
+            Self::Error { message, not_found } => f.debug_struct("Error").field("message", message).field("not_found", not_found).finish(),
+        }
+    }
+}
+
 // The default Debug implementation prints all the file data, which is way too much, so we have to override this :(
 impl std::fmt::Debug for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -164,15 +315,24 @@ impl std::fmt::Debug for Command {
         // then we can make the tweaks that we need.
         match self {
             Self::SetRoot { root } => f.debug_struct("SetRoot").field("root", root).finish(),
-            Self::GetEntries { filters } => f.debug_struct("GetEntries").field("filters", filters).finish(),
+            Self::GetEntries { filters, report_excluded, paths } => f.debug_struct("GetEntries").field("filters", filters).field("report_excluded", report_excluded).field("paths", paths).finish(),
             Self::CreateRootAncestors => write!(f, "CreateRootAncestors"),
+            Self::GetEntryDetails { path } => f.debug_struct("GetEntryDetails").field("path", path).finish(),
             Self::GetFileContent { path } => f.debug_struct("GetFileContent").field("path", path).finish(),
-            Self::CreateOrUpdateFile { path, data, set_modified_time, more_to_follow } => f.debug_struct("CreateOrUpdateFile").field("path", path).field("data", &format!("... ({})", HumanBytes(data.len() as u64))).field("set_modified_time", set_modified_time).field("more_to_follow", more_to_follow).finish(),
+            Self::GetFileContents { paths } => f.debug_struct("GetFileContents").field("paths", paths).finish(),
+            Self::GetFileHash { path } => f.debug_struct("GetFileHash").field("path", path).finish(),
+            Self::CreateOrUpdateFile { path, data, set_modified_time, more_to_follow, hash } => f.debug_struct("CreateOrUpdateFile").field("path", path).field("data", &format!("... ({})", HumanBytes(data.len() as u64))).field("set_modified_time", set_modified_time).field("more_to_follow", more_to_follow).field("hash", hash).finish(),
+            Self::CreateOrUpdateFiles { files } => f.debug_struct("CreateOrUpdateFiles").field("files", files).finish(),
+            Self::CopyLocalFile { src_root, src_path, path, size, set_modified_time } => f.debug_struct("CopyLocalFile").field("src_root", src_root).field("src_path", src_path).field("path", path).field("size", size).field("set_modified_time", set_modified_time).finish(),
+            Self::ConnectToSource { hostname, port, transfer_id: _, key: _ } => f.debug_struct("ConnectToSource").field("hostname", hostname).field("port", port).field("key", &"...").finish(),
+            Self::CopyFromSource { src_root, src_path, path, size, set_modified_time } => f.debug_struct("CopyFromSource").field("src_root", src_root).field("src_path", src_path).field("path", path).field("size", size).field("set_modified_time", set_modified_time).finish(),
             Self::CreateSymlink { path, kind, target } => f.debug_struct("CreateSymlink").field("path", path).field("kind", kind).field("target", target).finish(),
             Self::CreateFolder { path } => f.debug_struct("CreateFolder").field("path", path).finish(),
             Self::DeleteFile { path } => f.debug_struct("DeleteFile").field("path", path).finish(),
             Self::DeleteFolder { path } => f.debug_struct("DeleteFolder").field("path", path).finish(),
             Self::DeleteSymlink { path, kind } => f.debug_struct("DeleteSymlink").field("path", path).field("kind", kind).finish(),
+            Self::Watch { roots } => f.debug_struct("Watch").field("roots", roots).finish(),
+            Self::WaitForChanges { debounce } => f.debug_struct("WaitForChanges").field("debounce", debounce).finish(),
             Self::ProfilingTimeSync => write!(f, "ProfilingTimeSync"),
             Self::Marker(arg0) => f.debug_tuple("Marker").field(arg0).finish(),
             Self::Shutdown => write!(f, "Shutdown"),
@@ -202,7 +362,7 @@ pub enum SymlinkTarget {
 /// Details of a file or folder.
 /// Note that this representation is consistent with the approach described in the README,
 /// and so doesn't consider the name of the node to be part of the node itself.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum EntryDetails {
     File {
         // Note that SystemTime is safe to serialize across platforms, because Serde serializes this
@@ -228,12 +388,19 @@ pub enum Response {
         platform_differentiates_symlinks: bool,
         /// Forward vs backwards slash.
         platform_dir_separator: char,
+        /// Whether the filesystem that the root is on treats names that differ only in case as different entries
+        /// (e.g. most Linux filesystems), vs. treating them as the same entry (e.g. Windows, macOS).
+        case_sensitive: bool,
     },
 
     // The result of GetEntries is split into lots of individual messages (rather than one big list)
     // so that the boss can start doing stuff before receiving the full list.
     Entry((RootRelativePath, EntryDetails)),
+    /// An entry which was excluded by the filters, only sent if requested (see GetEntries::report_excluded).
+    ExcludedEntry((RootRelativePath, EntryDetails)),
     EndOfEntries,
+    /// The result of GetEntryDetails.
+    EntryDetails(Option<EntryDetails>),
 
     FileContent {
         #[serde(with = "serde_bytes")] // Make serde fast
@@ -244,7 +411,28 @@ pub enum Response {
         ///   - doesn't crash for really large files
         ///   - more opportunities for pipelining
         more_to_follow: bool,
+        /// A SHA-256 of the whole file, set on the final chunk only, so that the dest doer can check that
+        /// the file it writes is the same as what was read here.
+        hash: Option<[u8; 32]>,
     },
+    /// The result of GetFileContents, in the same order as the paths that were asked for.
+    FileContents(Vec<SmallFileContent>),
+    /// The result of GetFileHash (a SHA-256 of the file contents). Errors are reported here rather than as an
+    /// EntryError, so that the boss can tell them apart from errors for earlier commands on the same entry.
+    FileHash(Result<[u8; 32], String>),
+
+    /// The doer has connected to the source doer given in the ConnectToSource command.
+    ConnectedToSource,
+    /// The doer has written part of a file that it's fetching from the source doer (see CopyFromSource), so that
+    /// the boss can show progress during large files. This isn't sent for the final chunk, which the next
+    /// progress marker covers.
+    ChunkFetched { chunk_start: u64, chunk_size: u64, file_size: u64 },
+    /// The doer has started watching the roots given in the Watch command.
+    Watching,
+    /// The result of WaitForChanges. Each change is the index of the root (from the Watch command) and the
+    /// entry inside it that changed. Entries inside another changed folder are left out, as the whole folder
+    /// needs syncing anyway. The root itself is given if everything needs syncing (e.g. if some changes were missed).
+    Changes(Vec<(usize, RootRelativePath)>),
 
     ProfilingTimeSync(std::time::Duration),
     ProfilingData(ProcessProfilingData),
@@ -253,6 +441,13 @@ pub enum Response {
     Marker(ProgressMarker),
 
     Error(String),
+    /// An error relating to a single entry, which the boss may be able to carry on past (see --keep-going).
+    EntryError {
+        path: RootRelativePath,
+        message: String,
+        /// The entry doesn't exist (any more), e.g. a source file that was deleted after we queried it.
+        not_found: bool,
+    },
 }
 impl encrypted_comms::IsFinalMessage for Response {
     fn is_final_message(&self) -> bool {
@@ -262,20 +457,35 @@ impl encrypted_comms::IsFinalMessage for Response {
         }
     }
 }
+impl encrypted_comms::WorthCompressing for Response {
+    fn worth_compressing(&self) -> bool {
+        // File contents don't say which file they're from, so we rely on compress checking them instead
+        true
+    }
+}
 // The default Debug implementation prints all the file data, which is way too much, so we have to override this :(
 impl std::fmt::Debug for Response {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Note that rust-analyzer can auto-generate the complete version of this for us (delete the function, then Ctrl+Space),
         // then we can make the tweaks that we need.
         match self {
-            Self::RootDetails { root_details, platform_differentiates_symlinks, platform_dir_separator } => f.debug_struct("RootDetails").field("root_details", root_details).field("platform_differentiates_symlinks", platform_differentiates_symlinks).field("platform_dir_separator", platform_dir_separator).finish(),
+            Self::RootDetails { root_details, platform_differentiates_symlinks, platform_dir_separator, case_sensitive } => f.debug_struct("RootDetails").field("root_details", root_details).field("platform_differentiates_symlinks", platform_differentiates_symlinks).field("platform_dir_separator", platform_dir_separator).field("case_sensitive", case_sensitive).finish(),
             Self::Entry(arg0) => f.debug_tuple("Entry").field(arg0).finish(),
+            Self::ExcludedEntry(arg0) => f.debug_tuple("ExcludedEntry").field(arg0).finish(),
             Self::EndOfEntries => write!(f, "EndOfEntries"),
-            Self::FileContent { data, more_to_follow } => f.debug_struct("FileContent").field("data", &format!("... ({})", HumanBytes(data.len() as u64))).field("more_to_follow", more_to_follow).finish(),
+            Self::EntryDetails(arg0) => f.debug_tuple("EntryDetails").field(arg0).finish(),
+            Self::FileContent { data, more_to_follow, hash } => f.debug_struct("FileContent").field("data", &format!("... ({})", HumanBytes(data.len() as u64))).field("more_to_follow", more_to_follow).field("hash", hash).finish(),
+            Self::FileContents(arg0) => f.debug_tuple("FileContents").field(arg0).finish(),
+            Self::FileHash(arg0) => f.debug_tuple("FileHash").field(arg0).finish(),
+            Self::ConnectedToSource => write!(f, "ConnectedToSource"),
+            Self::ChunkFetched { chunk_start, chunk_size, file_size } => f.debug_struct("ChunkFetched").field("chunk_start", chunk_start).field("chunk_size", chunk_size).field("file_size", file_size).finish(),
+            Self::Watching => write!(f, "Watching"),
+            Self::Changes(arg0) => f.debug_tuple("Changes").field(arg0).finish(),
             Self::ProfilingTimeSync(arg0) => f.debug_tuple("ProfilingTimeSync").field(arg0).finish(),
             Self::ProfilingData(_) => f.debug_tuple("ProfilingData").finish(),
             Self::Marker(arg0) => f.debug_tuple("Marker").field(arg0).finish(),
             Self::Error(arg0) => f.debug_tuple("Error").field(arg0).finish(),
+            Self::EntryError { path, message, not_found } => f.debug_struct("EntryError").field("path", path).field("message", message).field("not_found", not_found).finish(),
         }
     }
 }
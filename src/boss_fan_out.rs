@@ -0,0 +1,284 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::thread;
+
+use log::{debug, trace};
+
+use crate::boss_doer_interface::{Command, EntryDetails, Response, SmallFileContent, SMALL_FILE_MAX_SIZE};
+use crate::boss_launch::{Comms, BOSS_DOER_CHANNEL_MEMORY_CAPACITY};
+use crate::memory_bound_channel;
+use crate::profile_this;
+use crate::root_relative_path::RootRelativePath;
+
+/// Lets the syncs to several dests share a single source (see multiple DESTs), so that the source is only
+/// walked once and each file is only read once, no matter how many of the dests need it.
+///
+/// Each sync gets its own Comms to use as its source (see `new`), which behave like a regular local doer,
+/// and which are run on their own threads so that each dest can be diffed and updated separately.
+/// The commands from these are all forwarded to the real source by `run`. Whenever each sync is waiting for the
+/// source, the commands that are the same are only done once, and the responses are sent to all of them.
+/// Files are fetched in the order that the source reported them, which is the order that each sync copies them in,
+/// so a file is fetched once all the syncs that need it have asked for it (or moved on past it).
+/// Each sync fetches small files in batches of its own (see GetFileContents), which won't line up with those of the
+/// other syncs, so small files are fetched and kept one at a time instead (see small_file_contents).
+pub struct SourceFanOut {
+    commands: crossbeam::channel::Receiver<(usize, Command)>,
+    syncs: Vec<FanOutSync>,
+    /// The position of each source entry in the walk, so that files can be fetched in the same order as the syncs copy them.
+    entry_order: HashMap<RootRelativePath, usize>,
+    /// Source files which are small enough to be fetched in batches (see SMALL_FILE_MAX_SIZE).
+    small_files: HashSet<RootRelativePath>,
+    /// Small files which have been fetched from the source, kept until all the syncs have moved on past them.
+    small_file_contents: HashMap<RootRelativePath, SmallFileContent>,
+}
+
+struct FanOutSync {
+    /// Commands received from this sync which haven't been done yet.
+    commands: VecDeque<Command>,
+    responses: memory_bound_channel::Sender<Response>,
+    /// Cleared once the sync has finished with the source.
+    active: bool,
+}
+
+impl SourceFanOut {
+    /// Makes a Comms for each sync to use as its source, which must be shut down once the sync has finished,
+    /// as until then `run` will be waiting for it.
+    pub fn new(debug_names: Vec<String>) -> (SourceFanOut, Vec<Comms>) {
+        let (command_sender, command_receiver) = crossbeam::channel::unbounded();
+        let mut syncs = vec![];
+        let mut comms = vec![];
+        for (i, debug_name) in debug_names.into_iter().enumerate() {
+            let (sender, receiver) = memory_bound_channel::new::<Command>(BOSS_DOER_CHANNEL_MEMORY_CAPACITY);
+            let (response_sender, response_receiver) = memory_bound_channel::new(BOSS_DOER_CHANNEL_MEMORY_CAPACITY);
+            let debug_name = debug_name + " (shared source)";
+            // Commands are forwarded onto a single channel, so that we can wait for any of the syncs
+            let command_sender = command_sender.clone();
+            let thread = thread::Builder::new().name(debug_name.clone()).spawn(move || {
+                loop {
+                    match receiver.recv() {
+                        Ok(Command::Shutdown) | Err(_) => {
+                            let _ = command_sender.send((i, Command::Shutdown));
+                            return Ok(());
+                        }
+                        Ok(c) => if command_sender.send((i, c)).is_err() {
+                            return Err("Shared source has stopped".to_string());
+                        }
+                    }
+                }
+            }).unwrap();
+            syncs.push(FanOutSync { commands: VecDeque::new(), responses: response_sender, active: true });
+            comms.push(Comms::Local { debug_name, thread, sender, receiver: response_receiver });
+        }
+        (SourceFanOut { commands: command_receiver, syncs, entry_order: HashMap::new(), small_files: HashSet::new(),
+            small_file_contents: HashMap::new() }, comms)
+    }
+
+    /// Forwards commands from the syncs to the real source, until all the syncs have finished with it.
+    /// An error is only returned if we lose communication with the real source.
+    pub fn run(mut self, src_comms: &mut Comms) -> Result<(), String> {
+        profile_this!();
+        loop {
+            // Wait until each sync that hasn't finished is waiting for the source, so that we know
+            // what all of them need next
+            while self.syncs.iter().any(|s| s.active && s.commands.is_empty()) {
+                match self.commands.recv() {
+                    Ok((i, Command::Shutdown)) => {
+                        self.syncs[i].active = false;
+                        self.syncs[i].commands.clear();
+                    }
+                    Ok((i, c)) => self.syncs[i].commands.push_back(c),
+                    Err(_) => return Ok(()), // Only possible if all the syncs have gone
+                }
+            }
+            let chosen = match self.next_command() {
+                Some(i) => i,
+                None => return Ok(()), // All the syncs have finished
+            };
+            if self.small_files_wanted(chosen).is_some() {
+                self.fetch_small_files(src_comms, chosen)?;
+                continue;
+            }
+
+            // Take this command from all the syncs that are waiting for the same thing
+            let request = Request::of(self.syncs[chosen].commands.front().expect("The chosen sync is always waiting"));
+            let waiting: Vec<usize> = (0..self.syncs.len()).filter(|i| self.syncs[*i].active &&
+                self.syncs[*i].commands.front().map(Request::of) == Some(request)).collect();
+            let mut command = None;
+            for i in &waiting {
+                command = self.syncs[*i].commands.pop_front();
+            }
+            let command = command.expect("The chosen sync is always waiting");
+            debug!("Sending {:?} to the source for {} dest(s)", command, waiting.len());
+
+            let responses = ExpectedResponses::of(&command);
+            src_comms.send_command(command)?;
+            loop {
+                let response = src_comms.receive_response()?;
+                if let Response::Entry((p, d)) = &response {
+                    let n = self.entry_order.len();
+                    self.entry_order.insert(p.clone(), n);
+                    if matches!(d, EntryDetails::File { size, .. } if *size <= SMALL_FILE_MAX_SIZE) {
+                        self.small_files.insert(p.clone());
+                    }
+                }
+                let last = responses.is_last(&response);
+                // Each sync needs its own copy of the response, apart from the last one which can have the original
+                let (last_sync, others) = waiting.split_last().expect("The chosen sync is always waiting");
+                for i in others {
+                    self.send_response(*i, copy_response(&response)?);
+                }
+                self.send_response(*last_sync, response);
+                if last {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// The small files that the given sync is waiting for, if that's what it's waiting for (see small_file_contents).
+    fn small_files_wanted(&self, sync: usize) -> Option<&[RootRelativePath]> {
+        match self.syncs[sync].commands.front() {
+            Some(Command::GetFileContents { paths }) => Some(paths),
+            Some(Command::GetFileContent { path }) if self.small_files.contains(path) => Some(std::slice::from_ref(path)),
+            _ => None,
+        }
+    }
+
+    /// Fetches the small files that the given sync is waiting for which haven't been fetched already,
+    /// and then sends them to all the syncs that were waiting for just those files.
+    fn fetch_small_files(&mut self, src_comms: &mut Comms, chosen: usize) -> Result<(), String> {
+        // Files before the earliest one that any of the syncs are waiting for won't be asked for again
+        let oldest_wanted = self.syncs.iter().filter(|s| s.active)
+            .filter_map(|s| s.commands.front().and_then(|c| self.fetch_order(c))).min().unwrap_or(usize::MAX);
+        self.small_file_contents.retain(|p, _| !matches!(self.entry_order.get(p), Some(o) if *o < oldest_wanted));
+
+        let paths = self.small_files_wanted(chosen).expect("The chosen sync is waiting for small files");
+        let missing: Vec<RootRelativePath> = paths.iter().filter(|p| !self.small_file_contents.contains_key(*p)).cloned().collect();
+        if !missing.is_empty() {
+            let command = Command::GetFileContents { paths: missing.clone() };
+            debug!("Sending {:?} to the source", command);
+            src_comms.send_command(command)?;
+            match src_comms.receive_response()? {
+                Response::FileContents(c) if c.len() == missing.len() => self.small_file_contents.extend(missing.into_iter().zip(c)),
+                x => return Err(format!("Unexpected response fetching files from the source: {:?}", x)),
+            }
+        }
+
+        for i in 0..self.syncs.len() {
+            let waiting = self.syncs[i].active && self.small_files_wanted(i)
+                .is_some_and(|paths| paths.iter().all(|p| self.small_file_contents.contains_key(p)));
+            if !waiting {
+                continue;
+            }
+            let response = match self.syncs[i].commands.pop_front() {
+                Some(Command::GetFileContents { paths }) =>
+                    Response::FileContents(paths.iter().map(|p| self.small_file_contents[p].clone()).collect()),
+                // Answer this the same as the source would have done
+                Some(Command::GetFileContent { path }) => match self.small_file_contents[&path].clone() {
+                    SmallFileContent::Content { data, hash } => Response::FileContent { data, more_to_follow: false, hash: Some(hash) },
+                    SmallFileContent::Error { message, not_found } => Response::EntryError { path, message, not_found },
+                },
+                _ => unreachable!("Checked by small_files_wanted"),
+            };
+            self.send_response(i, response);
+        }
+        Ok(())
+    }
+
+    fn send_response(&self, sync: usize, response: Response) {
+        // If the sync has stopped listening then it will have failed, which it will report itself
+        if self.syncs[sync].responses.send(response).is_err() {
+            trace!("Sync {} has stopped listening to the source", sync);
+        }
+    }
+
+    /// Chooses which of the syncs to do the next command for, or None if they have all finished.
+    fn next_command(&self) -> Option<usize> {
+        let waiting = (0..self.syncs.len()).filter(|i| self.syncs[*i].active);
+        // Files are fetched last and in the order of the walk, so that any other syncs which will need the same file
+        // have a chance to ask for it too
+        waiting.min_by_key(|i| self.syncs[*i].commands.front().and_then(|c| self.fetch_order(c)).map_or((0, 0), |o| (1, o)))
+    }
+
+    /// The position in the walk of the (first) file that the command fetches, or None if it doesn't fetch any files.
+    fn fetch_order(&self, command: &Command) -> Option<usize> {
+        match command {
+            Command::GetFileContent { path } => Some(self.entry_order.get(path).copied().unwrap_or(usize::MAX)),
+            Command::GetFileContents { paths } => Some(paths.first().and_then(|p| self.entry_order.get(p)).copied().unwrap_or(usize::MAX)),
+            _ => None,
+        }
+    }
+}
+
+/// What a sync is asking the source for, so that the syncs which are asking for the same thing can share it.
+/// All the syncs have the same source root and filters (they only differ in their dest), so the commands which
+/// aren't about a particular entry are the same for all of them.
+#[derive(PartialEq, Clone, Copy)]
+enum Request<'a> {
+    FileContent(&'a RootRelativePath),
+    FileContents(&'a [RootRelativePath]),
+    FileHash(&'a RootRelativePath),
+    EntryDetails(&'a RootRelativePath),
+    Other(std::mem::Discriminant<Command>),
+}
+impl<'a> Request<'a> {
+    fn of(command: &'a Command) -> Request<'a> {
+        match command {
+            Command::GetFileContent { path } => Request::FileContent(path),
+            Command::GetFileContents { paths } => Request::FileContents(paths),
+            Command::GetFileHash { path } => Request::FileHash(path),
+            Command::GetEntryDetails { path } => Request::EntryDetails(path),
+            c => Request::Other(std::mem::discriminant(c)),
+        }
+    }
+}
+
+/// Which responses the source sends back for a command. Anything sent to the source from a sync
+/// gets at least one response.
+enum ExpectedResponses {
+    /// Entries until EndOfEntries
+    Entries,
+    /// FileContent until one that has no more_to_follow
+    FileContent,
+    Single,
+}
+impl ExpectedResponses {
+    fn of(command: &Command) -> ExpectedResponses {
+        match command {
+            Command::GetEntries { .. } => ExpectedResponses::Entries,
+            Command::GetFileContent { .. } => ExpectedResponses::FileContent,
+            _ => ExpectedResponses::Single,
+        }
+    }
+
+    /// Checks if this is the final response to the command.
+    fn is_last(&self, response: &Response) -> bool {
+        match (self, response) {
+            (_, Response::Error(_)) => true,
+            (ExpectedResponses::Entries, r) => matches!(r, Response::EndOfEntries),
+            (ExpectedResponses::FileContent, Response::FileContent { more_to_follow, .. }) => !more_to_follow,
+            _ => true,
+        }
+    }
+}
+
+/// Copies a response from the source, for each of the syncs that are sharing it.
+/// Only the responses that the source sends to the commands from a sync are supported.
+fn copy_response(response: &Response) -> Result<Response, String> {
+    Ok(match response {
+        Response::RootDetails { root_details, platform_differentiates_symlinks, platform_dir_separator, case_sensitive } =>
+            Response::RootDetails { root_details: root_details.clone(), platform_differentiates_symlinks: *platform_differentiates_symlinks,
+                platform_dir_separator: *platform_dir_separator, case_sensitive: *case_sensitive },
+        Response::Entry(e) => Response::Entry(e.clone()),
+        Response::ExcludedEntry(e) => Response::ExcludedEntry(e.clone()),
+        Response::EndOfEntries => Response::EndOfEntries,
+        Response::EntryDetails(d) => Response::EntryDetails(d.clone()),
+        Response::FileContent { data, more_to_follow, hash } =>
+            Response::FileContent { data: data.clone(), more_to_follow: *more_to_follow, hash: *hash },
+        Response::FileContents(c) => Response::FileContents(c.clone()),
+        Response::FileHash(h) => Response::FileHash(h.clone()),
+        Response::Error(e) => Response::Error(e.clone()),
+        Response::EntryError { path, message, not_found } =>
+            Response::EntryError { path: path.clone(), message: message.clone(), not_found: *not_found },
+        r => return Err(format!("Unexpected response from the shared source: {:?}", r)),
+    })
+}
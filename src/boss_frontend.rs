@@ -2,20 +2,26 @@ use std::path::Path;
 use std::process::ExitCode;
 use std::io::Write;
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 use clap::{Parser, ValueEnum, CommandFactory};
 use env_logger::{Env, fmt::Color};
 use indicatif::{ProgressBar, HumanBytes, ProgressStyle};
 use log::info;
-use log::{debug, error};
+use log::{debug, error, warn};
 use regex::Regex;
+use serde::{Serialize, Deserialize};
 use yaml_rust::{YamlLoader, Yaml};
 use lazy_static::{lazy_static};
 
 use crate::profiling::{dump_all_profiling, start_timer, stop_timer, self};
 use crate::logger_and_progress::LoggerAndProgress;
 use crate::{boss_launch::*, profile_this, function_name, boss_deploy};
+use crate::boss_fan_out::SourceFanOut;
+use crate::encrypted_comms::SessionOptions;
+use crate::bandwidth_limit::{BandwidthLimiter, parse_rate};
 use crate::boss_sync::*;
+use crate::root_relative_path::RootRelativePath;
 
 /// Fast rsync-like tool for incrementally copying files.
 ///
@@ -31,13 +37,16 @@ pub struct BossCliArgs {
     ///
     /// If a file or symlink is provided, only that single item will be copied (symlinks are not followed).
     /// If a folder is provided, all its contents will be copied as well, recursively. Symlinks inside the folder are never followed.
-    #[arg(required_unless_present_any=["spec", "generate_auto_complete_script", "list_embedded_binaries"], conflicts_with="spec")]
+    #[arg(required_unless_present_any=["spec", "generate_auto_complete_script", "list_embedded_binaries", "explain_filters", "apply_plan"], conflicts_with="spec")]
     src: Option<RemotePathDesc>,
     /// The destination path. Can be existent or non-existent, local or remote. Format: [[username@]hostname:]path
     ///
     /// The sync will make the destination path equivalent to the source path, replacing whatever
     /// is there already.
     ///
+    /// More than one destination can be given, in which case each one is synced with the source
+    /// (and updated separately), but the source is only walked once and its files are only read once.
+    ///
     /// One exception to this is that if the source is a file/symlink and the destination path ends
     /// with a trailing slash, the file will be placed inside a destination folder (created if necessary).
     ///
@@ -51,8 +60,8 @@ pub struct BossCliArgs {
     ///
     ///   * Syncing a file to a symlink will delete the destination symlink and copy the source file its place
     ///
-    #[arg(required_unless_present_any=["spec", "generate_auto_complete_script", "list_embedded_binaries"], conflicts_with="spec")]
-    dest: Option<RemotePathDesc>,
+    #[arg(required_unless_present_any=["spec", "generate_auto_complete_script", "list_embedded_binaries", "explain_filters", "apply_plan"], conflicts_with="spec")]
+    dest: Vec<RemotePathDesc>,
 
     /// Instead of providing SRC and DEST, a YAML file can be used to define the sync.
     ///
@@ -72,9 +81,16 @@ pub struct BossCliArgs {
     ///         dest_file_older_behaviour: skip
     ///         dest_entry_needs_deleting_behaviour: prompt
     ///         dest_root_needs_deleting_behaviour: delete
+    ///         delete_excluded: true
+    ///         normalize_unicode: true
+    ///         windows_incompatible_name_behaviour: escape
     ///       # Multiple paths can be synced
     ///       - src: /root/source2
     ///         dest: /home/myuser/dest2
+    ///       # The same source can be synced to several dests (see DEST), which can be on other computers.
+    ///       # Each sync must have the same dest computers, in the same order.
+    ///       - src: /root/source3
+    ///         dests: [ /home/myuser/dest3, other.domain.com:/dest3 ]
     ///
     /// If the same argument is given in both the spec file and on the command-line,
     /// the command-line value will take precedence.
@@ -105,17 +121,81 @@ pub struct BossCliArgs {
     /// If a folder is excluded, then the contents of the folder will not be inspected,
     /// even if they would otherwise be included by the filters.
     ///
+    /// A filter can instead start with a 'P' to protect matching entries on the destination. Protected entries
+    /// are still compared and updated as normal, but will never be deleted (nor will the folders containing them).
+    /// Protect filters don't affect which entries are included or excluded.
+    ///
     /// For example:
     ///
     ///     * --filter '+.*\.txt' --filter '-subfolder'  Syncs all files with the extension .txt, but not inside `subfolder`
     ///
+    ///     * --filter 'P.*\.local\.cfg'  Syncs everything, but never deletes files with the extension .local.cfg from the destination
+    ///
     #[arg(name="filter", long, allow_hyphen_values(true))]
     filter: Vec<String>,
 
+    /// Delete entries on the destination which are excluded by the filters.
+    ///
+    /// Normally excluded entries are ignored on both the source and destination, so any that are already
+    /// on the destination (e.g. from before the filter was added) are left alone. With this option, they
+    /// will be deleted instead (including the contents of excluded folders), subject to --dest-entry-needs-deleting.
+    /// Entries matching a protect filter are still never deleted.
+    #[arg(long)]
+    delete_excluded: bool,
+
+    /// Compare file and folder names after Unicode normalization.
+    ///
+    /// Names which look the same can be encoded differently, for example macOS stores accented characters
+    /// in decomposed form (NFD) whereas Linux and Windows usually use precomposed form (NFC). Without this option,
+    /// such names are considered different and the entries will be deleted and copied on every sync.
+    /// With this option, they are considered the same, and existing names on the destination are kept as they are.
+    /// If two entries on the same side have the same name once normalized, a warning is shown and only one is synced.
+    #[arg(long)]
+    normalize_unicode: bool,
+
+    /// Show how the filters apply to the given paths, instead of performing a sync.
+    ///
+    /// For each path (relative to the source/dest root, e.g. 'build/output.exe'), this lists every filter
+    /// that matches it, whether it ends up included or excluded, and whether it is protected.
+    /// It also shows if the path would never be reached because one of its ancestor folders is excluded.
+    ///
+    /// The filters are taken from --filter and/or --spec. SRC and DEST aren't needed and nothing is
+    /// connected to. To see this information for every excluded entry during a sync, use --dry-run with --verbose.
+    #[arg(long, num_args=1.., value_name="PATH")]
+    explain_filters: Vec<String>,
+
     /// Show which files/folders will be copied or deleted, without making any real changes.
     #[arg(long)]
     dry_run: bool,
 
+    /// Write the list of actions for each sync (what will be deleted and copied, and why) as JSON to the given file,
+    /// or to stdout if '-'. This is mostly useful with --dry-run, for scripts that need to check what will be done.
+    ///
+    /// The document has a 'syncs' array, with an entry for each sync containing 'src', 'dest', 'skipped'
+    /// (if the entire sync was skipped), and arrays of 'delete' and 'copy' actions in the order that they will be done.
+    /// Each action has 'path' (relative to the root, with forward slashes), 'reason' and 'kind' ('file', 'folder' or 'symlink'),
+    /// plus 'size' and 'modified_time' (seconds since the UNIX epoch) for files and 'symlink_kind' and 'target' for symlinks.
+    /// Copy actions also have 'dest_path', which might differ from 'path' (see --normalize-unicode and --windows-incompatible-name).
+    /// The reasons are 'not_on_source', 'incompatible', 'excluded' or 'case_differs' for deletes, and 'not_on_dest',
+    /// 'dest_newer', 'dest_older' or 'same_time' for copies. Any actions that were declined at a prompt are not included.
+    #[arg(long, value_name="FILE")]
+    plan_json: Option<String>,
+
+    /// Work out what needs doing (including prompting if configured to), then save this to the given file
+    /// instead of doing it. The plan can then be reviewed (e.g. with the output of this, which is the same as --dry-run)
+    /// and later performed with --apply-plan.
+    #[arg(long, value_name="FILE")]
+    write_plan: Option<String>,
+
+    /// Perform the actions previously saved with --write-plan, instead of working out what needs doing.
+    ///
+    /// The same source and dest are used as when the plan was made, so SRC, DEST and --spec aren't needed.
+    /// Before anything is done, every entry that will be copied, overwritten or deleted is checked to be the same as
+    /// when the plan was made. If anything has changed, nothing is done and an error is raised, as the plan
+    /// might no longer be what was intended. No prompts are shown, as the actions were confirmed when making the plan.
+    #[arg(long, value_name="FILE", conflicts_with_all=["spec", "write_plan", "filter"])]
+    apply_plan: Option<String>,
+
     /// Hide the progress bar.
     ///
     /// In some cases this can increase performance, especially on systems with a lower number of CPU cores.
@@ -128,6 +208,75 @@ pub struct BossCliArgs {
     #[arg(long)]
     stats: bool,
 
+    /// Show a line for each entry that is deleted or copied, in a compact format suitable for searching logs.
+    ///
+    /// A line is shown once the entry's command has been sent to the dest, so an entry that then fails on the dest
+    /// is reported separately as an error (see --keep-going). With --dry-run, these lines replace the usual "Would copy" lines.
+    ///
+    /// Each line looks like '>f older     st path/to/file', with the following parts:
+    ///
+    ///   * The action: '-' deleted from the dest, '+' created on the dest, '>' overwrote the dest entry.
+    ///
+    ///   * The kind of entry: 'f' file, 'd' folder, 'L' symlink.
+    ///
+    ///   * The reason: 'not-src' (not on the source), 'incompat' (incompatible with the source entry), 'excluded' (see --delete-excluded)
+    ///     or 'case' (the source entry's name differs only in case) for deletes, and 'not-dest' (not on the dest),
+    ///     'older'/'newer' (the dest file is older/newer) or 'same-time' for copies.
+    ///
+    ///   * What changed: 's' size, 't' modified time, '.' unchanged, '++' new entry, '--' deleted entry.
+    ///
+    ///   * The path, relative to the dest root ('.' for the root itself).
+    #[arg(long)]
+    itemize: bool,
+
+    /// After copying, check that each copied file has the same contents on the dest as on the source.
+    ///
+    /// A hash of each file is calculated on both sides and compared. Files which don't match are copied again,
+    /// and if they still don't match then an error is raised. This makes the sync slower, as every copied file
+    /// needs reading again.
+    #[arg(long)]
+    verify: bool,
+
+    /// Carry on with the rest of the sync if an individual entry can't be copied or deleted.
+    ///
+    /// Normally the sync stops at the first error. With this option, errors for individual entries (e.g. a file that
+    /// can't be written due to permissions) are collected and the sync carries on with the other entries. Source files
+    /// that no longer exist are skipped. At the end, a summary of the entries that failed is shown and the exit code is 15.
+    #[arg(long)]
+    keep_going: bool,
+
+    /// After syncing, keep watching the source for changes and sync them as soon as they happen, until stopped with Ctrl+C.
+    ///
+    /// Only the entries that changed are synced each time, so this is much quicker than running rjrssync again.
+    /// Changes are collected until nothing has changed for a short time, so that e.g. saving lots of files at once
+    /// results in a single sync.
+    #[arg(long, conflicts_with_all=["dry_run", "plan_json", "write_plan", "apply_plan", "report", "prometheus_textfile"])]
+    watch: bool,
+
+    /// Run the syncs from the spec file at the same time, rather than one after another.
+    ///
+    /// Each sync uses its own connection to the source and destination, so this can be much quicker when there
+    /// are many syncs that are each limited by latency rather than bandwidth. The sync roots must not overlap.
+    /// This can also be set in the spec file.
+    #[arg(long)]
+    parallel: bool,
+
+    /// Once finished, write a summary of each sync as JSON to the given file, for monitoring scheduled syncs.
+    ///
+    /// The document has 'exit_code' and a 'syncs' array, with an entry for each sync that was started containing 'src', 'dest',
+    /// 'dry_run', 'success' and 'error' (null if successful). There are counts of 'files', 'folders', 'symlinks' and 'bytes'
+    /// for 'src_entries', 'dest_entries', 'deleted' and 'copied', plus 'verified' (see --verify), 'failed_entries'
+    /// (see --keep-going), 'durations_secs' for the 'query', 'delete', 'copy', 'verify' and 'total' phases, and
+    /// 'src_file_size_histogram' and 'copied_file_size_histogram', where element N is the number of files with between
+    /// 10^N and 10^(N+1) bytes. Anything that wasn't reached before an error is null.
+    #[arg(long, value_name="FILE")]
+    report: Option<String>,
+
+    /// Like --report, but writes the same information as Prometheus metrics, in the format read by
+    /// node_exporter's textfile collector. The file is replaced atomically so a partially written file is never collected.
+    #[arg(long, value_name="FILE")]
+    prometheus_textfile: Option<String>,
+
     /// Hide all output except warnings, errors and prompts.
     #[arg(short, long, group="verbosity")]
     quiet: bool,
@@ -142,6 +291,52 @@ pub struct BossCliArgs {
     #[arg(long)]
     remote_port: Option<u16>,
 
+    /// Leave remote rjrssync processes running for this many seconds after finishing, so that later runs
+    /// can reuse them and skip launching a new one over ssh.
+    ///
+    /// Later runs must also pass this option to reuse them. Details of how to reconnect are kept in a file which
+    /// only the current user can read. If the remote process has gone, a new one is launched as normal.
+    #[arg(long, value_name="SECONDS")]
+    persist_doers: Option<u64>,
+
+    /// For syncs between two remote targets, the destination fetches file contents directly from the source,
+    /// rather than them being sent via this computer.
+    ///
+    /// Optionally, the address that the destination uses to connect to the source can be given (--direct-transfer=HOST), if it is different
+    /// to the one used from here (e.g. if they share a private network).
+    #[arg(long, value_name="HOST", num_args=0..=1, require_equals=true, default_missing_value="")]
+    direct_transfer: Option<String>,
+
+    /// The number of TCP connections to use with each remote target, which can make transfers faster over links
+    /// with high latency.
+    ///
+    /// Each TCP connection can only have so much data in flight before it waits for acknowledgement, so over a long distance
+    /// a single connection may not be able to use all the available bandwidth. Data is spread over all the connections
+    /// and put back in order at the other end. Remote rjrssync processes left running by --persist-doers allow
+    /// at most the number of connections used by the run that launched them.
+    #[arg(long, value_name="N", default_value_t=1, value_parser=clap::value_parser!(u32).range(1..=64))]
+    streams: u32,
+
+    /// Compresses data sent to and from remote targets, which can make transfers faster over slow links,
+    /// especially for text-heavy folders.
+    ///
+    /// Optionally, the compression level can be given from 1 (fastest) to 9 (smallest), e.g. --compress=9. The default is 6.
+    /// Files which are already compressed (e.g. .zip or .jpg) are sent as they are, as is any other data that
+    /// barely shrinks when a sample of it is compressed. Remote rjrssync processes left running by --persist-doers
+    /// only compress if the run that launched them did.
+    #[arg(long, value_name="LEVEL", num_args=0..=1, require_equals=true, default_missing_value="6",
+        value_parser=clap::value_parser!(u32).range(1..=9))]
+    compress: Option<u32>,
+
+    /// Limits the rate that file contents are sent at, so that syncing doesn't use all of a shared link.
+    ///
+    /// The rate is in bytes per second, optionally with a K, M or G suffix (e.g. 500K or 1.5M). This applies to all
+    /// file contents that pass through this computer, shared between all the syncs that are running at once,
+    /// but not to --direct-transfer or when the source and destination share a remote copy, as the contents
+    /// don't pass through this computer then. --stats shows the effective rate. This can also be set in the spec file.
+    #[arg(long, value_name="RATE", value_parser=parse_rate)]
+    bwlimit: Option<u64>,
+
     /// Behaviour for deploying rjrssync to remote targets.
     ///
     /// If a remote target doesn't have rjrssync, or the version it has is incompatible with this version,
@@ -204,6 +399,19 @@ pub struct BossCliArgs {
     #[arg(long)]
     files_same_time: Option<DestFileUpdateBehaviour>,
 
+    /// Behaviour when a source file/folder/symlink has a name which can't be used on a Windows destination.
+    ///
+    /// For example, names containing any of the characters <>:"|?* , names ending with a dot or space,
+    /// or names reserved by Windows such as 'CON' or 'aux.c'.
+    /// These are checked before anything is copied or deleted.
+    /// When using 'escape', escaped names are restored when syncing from Windows back to another platform
+    /// (as long as 'escape' is used for that sync too).
+    /// The default is 'error'.
+    // (the default isn't defined here, because it's defined in SyncSpec::default() and if we duplicate it
+    //  here then we'll have no way of knowing if the user provided it on the cmd prompt as an override or not)
+    #[arg(long)]
+    windows_incompatible_name: Option<WindowsIncompatibleNameBehaviour>,
+
     /// Behaviour when any destructive action is required.
     ///
     /// This might indicate that data is about to be unintentionally lost.
@@ -344,6 +552,17 @@ pub enum DestEntryNeedsDeletingBehaviour {
     Delete,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum WindowsIncompatibleNameBehaviour {
+    /// An error will be raised and the sync will stop, before anything is copied or deleted.
+    Error,
+    /// The entry (and anything inside it) will not be copied and a warning will be shown. The rest of the sync will continue.
+    Skip,
+    /// The entry will be copied with a different name on the destination, replacing the problem characters with
+    /// characters from the Unicode Private Use Area (the same approach that Cygwin uses).
+    Escape,
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
 pub enum DestRootNeedsDeletingBehaviour {
     /// The user will be asked what to do. (In a non-interactive environment, this is equivalent to 'error')
@@ -381,6 +600,12 @@ struct Spec {
     dest_hostname: String,
     dest_username: String,
     deploy_behaviour: DeployBehaviour,
+    /// Run the syncs at the same time (see --parallel).
+    parallel: bool,
+    /// Limits the rate that file contents are sent at, in bytes per second (see --bwlimit).
+    bwlimit: Option<u64>,
+    /// Any other computers that each sync's source is synced to as well as the dest above (see SyncSpec::extra_dests).
+    extra_dests: Vec<DestTarget>,
     syncs: Vec<SyncSpec>,
 }
 impl Default for Spec {
@@ -391,33 +616,52 @@ impl Default for Spec {
             dest_hostname: String::from(""),
             dest_username: String::from(""),
             deploy_behaviour: DeployBehaviour::Prompt,
+            parallel: false,
+            bwlimit: None,
+            extra_dests: vec![],
             syncs: vec![],
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// A computer that the source is synced to, when there is more than one (see DEST).
+#[derive(Debug, PartialEq, Clone, Default)]
+struct DestTarget {
+    hostname: String,
+    username: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct SyncSpec {
     pub src: String,
     pub dest: String,
+    /// The paths on each of the Spec's extra_dests (if any) that the source is synced to as well as `dest`.
+    pub extra_dests: Vec<String>,
     pub filters: Vec<String>,
     pub dest_file_newer_behaviour: DestFileUpdateBehaviour,
     pub dest_file_older_behaviour: DestFileUpdateBehaviour,
     pub files_same_time_behaviour: DestFileUpdateBehaviour,
     pub dest_entry_needs_deleting_behaviour: DestEntryNeedsDeletingBehaviour,
     pub dest_root_needs_deleting_behaviour: DestRootNeedsDeletingBehaviour,
+    pub delete_excluded: bool,
+    pub normalize_unicode: bool,
+    pub windows_incompatible_name_behaviour: WindowsIncompatibleNameBehaviour,
 }
 impl Default for SyncSpec {
     fn default() -> Self {
         Self {
             src: String::new(),
             dest: String::new(),
+            extra_dests: vec![],
             filters: vec![],
             dest_file_newer_behaviour: DestFileUpdateBehaviour::Prompt,
             dest_file_older_behaviour: DestFileUpdateBehaviour::Overwrite,
             files_same_time_behaviour: DestFileUpdateBehaviour::Skip,
             dest_entry_needs_deleting_behaviour: DestEntryNeedsDeletingBehaviour::Delete,
             dest_root_needs_deleting_behaviour: DestRootNeedsDeletingBehaviour::Prompt,
+            delete_excluded: false,
+            normalize_unicode: false,
+            windows_incompatible_name_behaviour: WindowsIncompatibleNameBehaviour::Error,
         }
     }
 }
@@ -429,12 +673,45 @@ fn parse_string(yaml: &Yaml, key_name: &str) -> Result<String, String> {
     }
 }
 
-fn parse_sync_spec(yaml: &Yaml) -> Result<SyncSpec, String> {
+fn parse_bool(yaml: &Yaml, key_name: &str) -> Result<bool, String> {
+    match yaml {
+        Yaml::Boolean(x) => Ok(*x),
+        x => Err(format!("Unexpected value for '{}'. Expected a boolean, but got {:?}", key_name, x)),
+    }
+}
+
+/// Parses a rate in bytes per second (see parse_rate), which may be written as a plain number or with a suffix.
+fn parse_rate_yaml(yaml: &Yaml, key_name: &str) -> Result<u64, String> {
+    match yaml {
+        Yaml::String(x) | Yaml::Real(x) => parse_rate(x),
+        Yaml::Integer(x) => parse_rate(&x.to_string()),
+        x => Err(format!("Unexpected value for '{}'. Expected a rate (e.g. 500K), but got {:?}", key_name, x)),
+    }.map_err(|e| format!("Unexpected value for '{}': {}", key_name, e))
+}
+
+/// Also returns the dests from the 'dests' list (if present), as the computers that they're on are needed for the whole Spec.
+fn parse_sync_spec(yaml: &Yaml) -> Result<(SyncSpec, Option<Vec<RemotePathDesc>>), String> {
     let mut result = SyncSpec::default();
+    let mut dests = None;
     for (root_key, root_value) in yaml.as_hash().ok_or("Sync value must be a dictionary")? {
         match root_key {
             Yaml::String(x) if x == "src" => result.src = parse_string(root_value, "src")?,
             Yaml::String(x) if x == "dest" => result.dest = parse_string(root_value, "dest")?,
+            Yaml::String(x) if x == "dests" => {
+                match root_value {
+                    Yaml::Array(array_yaml) => {
+                        let mut d = vec![];
+                        for element_yaml in array_yaml {
+                            match element_yaml {
+                                Yaml::String(x) => d.push(x.parse::<RemotePathDesc>().map_err(|e| format!("Invalid dest '{}': {}", x, e))?),
+                                x => return Err(format!("Unexpected value in 'dests' array. Expected string, but got {:?}", x)),
+                            }
+                        }
+                        dests = Some(d);
+                    }
+                    x => return Err(format!("Unexpected value for 'dests'. Expected an array, but got {:?}", x)),
+                }
+            },
             Yaml::String(x) if x == "filters" => {
                 match root_value {
                     Yaml::Array(array_yaml) => {
@@ -458,10 +735,24 @@ fn parse_sync_spec(yaml: &Yaml) -> Result<SyncSpec, String> {
                 result.dest_entry_needs_deleting_behaviour = DestEntryNeedsDeletingBehaviour::from_str(&parse_string(root_value, "dest_entry_needs_deleting_behaviour")?, true)?,
             Yaml::String(x) if x == "dest_root_needs_deleting_behaviour" =>
                 result.dest_root_needs_deleting_behaviour = DestRootNeedsDeletingBehaviour::from_str(&parse_string(root_value, "dest_root_needs_deleting_behaviour")?, true)?,
+            Yaml::String(x) if x == "delete_excluded" => result.delete_excluded = parse_bool(root_value, "delete_excluded")?,
+            Yaml::String(x) if x == "normalize_unicode" => result.normalize_unicode = parse_bool(root_value, "normalize_unicode")?,
+            Yaml::String(x) if x == "windows_incompatible_name_behaviour" =>
+                result.windows_incompatible_name_behaviour = WindowsIncompatibleNameBehaviour::from_str(&parse_string(root_value, "windows_incompatible_name_behaviour")?, true)?,
             x => return Err(format!("Unexpected key in 'syncs' entry: {:?}", x)),
         }
     }
 
+    if let Some(d) = &dests {
+        if !result.dest.is_empty() {
+            return Err("dest and dests can't both be provided".to_string());
+        }
+        if let Some((first, others)) = d.split_first() {
+            result.dest = first.path.clone();
+            result.extra_dests = others.iter().map(|d| d.path.clone()).collect();
+        }
+    }
+
     if result.src.is_empty() {
         return Err("src must be provided and non-empty".to_string());
     }
@@ -469,7 +760,7 @@ fn parse_sync_spec(yaml: &Yaml) -> Result<SyncSpec, String> {
         return Err("dest must be provided and non-empty".to_string());
     }
 
-    Ok(result)
+    Ok((result, dests))
 }
 
 fn parse_spec_file(path: &Path) -> Result<Spec, String> {
@@ -484,6 +775,7 @@ fn parse_spec_file(path: &Path) -> Result<Spec, String> {
     }
     let doc = &docs[0];
 
+    let mut syncs_dests = vec![];
     for (root_key, root_value) in doc.as_hash().ok_or("Document root must be a dictionary")? {
         match root_key {
             Yaml::String(x) if x == "src_hostname" => result.src_hostname = parse_string(root_value, "src_hostname")?,
@@ -491,11 +783,13 @@ fn parse_spec_file(path: &Path) -> Result<Spec, String> {
             Yaml::String(x) if x == "dest_hostname" => result.dest_hostname = parse_string(root_value, "dest_hostname")?,
             Yaml::String(x) if x == "dest_username" => result.dest_username = parse_string(root_value, "dest_username")?,
             Yaml::String(x) if x == "deploy_behaviour" => result.deploy_behaviour = DeployBehaviour::from_str(&parse_string(root_value, "deploy_behaviour")?, true)?,
+            Yaml::String(x) if x == "parallel" => result.parallel = parse_bool(root_value, "parallel")?,
+            Yaml::String(x) if x == "bwlimit" => result.bwlimit = Some(parse_rate_yaml(root_value, "bwlimit")?),
             Yaml::String(x) if x == "syncs" => {
                 match root_value {
                     Yaml::Array(syncs_yaml) => {
                         for sync_yaml in syncs_yaml {
-                            result.syncs.push(parse_sync_spec(sync_yaml)?);
+                            syncs_dests.push(parse_sync_spec(sync_yaml)?);
                         }
                     }
                     x => return Err(format!("Unexpected value for 'syncs'. Expected an array, but got {:?}", x)),
@@ -505,6 +799,30 @@ fn parse_spec_file(path: &Path) -> Result<Spec, String> {
         }
     }
 
+    // The computers are fixed for the whole program (see Spec), so each sync must have the same dests.
+    // Dests without a hostname are on the dest_hostname (if any).
+    let mut dest_targets = None;
+    for (sync, dests) in syncs_dests {
+        let targets = match dests {
+            None => vec![DestTarget { hostname: result.dest_hostname.clone(), username: result.dest_username.clone() }],
+            Some(d) => d.into_iter().map(|d| match d.hostname.is_empty() {
+                true => DestTarget { hostname: result.dest_hostname.clone(), username: result.dest_username.clone() },
+                false => DestTarget { hostname: d.hostname, username: d.username },
+            }).collect(),
+        };
+        match &dest_targets {
+            None => dest_targets = Some(targets),
+            Some(t) if *t != targets => return Err("Each sync must have the same dest hostnames/usernames".to_string()),
+            Some(_) => (),
+        }
+        result.syncs.push(sync);
+    }
+    if let Some((first, others)) = dest_targets.as_ref().and_then(|t| t.split_first()) {
+        result.dest_hostname = first.hostname.clone();
+        result.dest_username = first.username.clone();
+        result.extra_dests = others.to_vec();
+    }
+
     Ok(result)
 }
 
@@ -541,12 +859,19 @@ pub fn boss_main() -> ExitCode {
 
         let level_style = buf.default_level_style(record.level());
 
+        // When syncs are running in parallel, their messages would be confusing without saying which sync they're from
+        let sync_prefix = match std::thread::current().name().and_then(|n| n.strip_prefix(PARALLEL_SYNC_THREAD_PREFIX)) {
+            Some(n) => format!("[{n}] "),
+            None => String::new(),
+        };
+
         match record.level() {
             log::Level::Info => {
                 // Info messages are intended for the average user, so format them plainly
                 writeln!(
                     buf,
-                    "{}",
+                    "{}{}",
+                    sync_prefix,
                     record.args()
                 )
             }
@@ -555,7 +880,8 @@ pub fn boss_main() -> ExitCode {
                 // that they are an error/warning
                 writeln!(
                     buf,
-                    "{}: {}",
+                    "{}{}: {}",
+                    sync_prefix,
                     level_style.value(record.level()),
                     record.args()
                 )
@@ -564,10 +890,11 @@ pub fn boss_main() -> ExitCode {
                 // Debug/trace messages are for developers or power-users, so have more detail
                 writeln!(
                     buf,
-                    "{} {:5} | {}: {}",
+                    "{} {:5} | {}: {}{}",
                     buf.timestamp_nanos(),
                     level_style.value(record.level()),
                     target_style.value(target),
+                    sync_prefix,
                     record.args()
                 )
             }
@@ -621,16 +948,79 @@ fn boss_main_impl(args: BossCliArgs, progress_bar: &ProgressBar) -> ExitCode {
         }
     }
 
-    // Decide what to sync - defined either on the command line or in a spec file if provided
-    let spec = match resolve_spec(&args) {
-        Ok(s) => s,
-        Err(e) => {
+    if !args.explain_filters.is_empty() {
+        return explain_filters(&args);
+    }
+
+    // Decide what to sync - defined either on the command line or in a spec file if provided,
+    // or in a previously saved plan.
+    let (spec, saved_plan) = match &args.apply_plan {
+        Some(plan_file) => match load_plan(plan_file) {
+            Ok(p) => (spec_for_plan(&p, &args), Some(p)),
+            Err(e) => {
+                error!("Failed to load plan from '{}': {}", plan_file, e);
+                return ExitCode::from(18);
+            }
+        },
+        None => match resolve_spec(&args) {
+            Ok(s) => (s, None),
+            Err(e) => {
+                error!("{}", e);
+                return ExitCode::from(18);
+            }
+        }
+    };
+
+    if args.direct_transfer.is_some() && (spec.src_hostname.is_empty() || spec.dest_hostname.is_empty()) {
+        error!("--direct-transfer can only be used when both the source and destination are remote");
+        return ExitCode::from(18);
+    }
+    if !spec.extra_dests.is_empty() {
+        let unsupported = [(args.watch, "--watch"), (args.direct_transfer.is_some(), "--direct-transfer"),
+            (spec.parallel, "--parallel"), (args.write_plan.is_some(), "--write-plan")];
+        if let Some((_, name)) = unsupported.iter().find(|(used, _)| *used) {
+            error!("Multiple dests can't be used with {}", name);
+            return ExitCode::from(18);
+        }
+        if let Err(e) = check_dests_dont_overlap(&spec) {
             error!("{}", e);
             return ExitCode::from(18);
         }
-    };
+    }
+    if spec.parallel {
+        if let Err(e) = check_parallel_syncs_dont_overlap(&spec) {
+            error!("{}", e);
+            return ExitCode::from(18);
+        }
+    }
 
-    let exit_code = execute_spec(spec, &args, progress_bar);
+    let mut outputs = SyncOutputs {
+        json: args.plan_json.as_ref().map(|_| json::JsonValue::new_array()),
+        saved: args.write_plan.as_ref().map(|_| vec![]),
+        report: (args.report.is_some() || args.prometheus_textfile.is_some()).then(json::JsonValue::new_array),
+    };
+    let mut exit_code = execute_spec(spec, &args, progress_bar, saved_plan.as_ref(), &mut outputs);
+
+    // The report is written even if the sync failed, as that is when it is most useful
+    if let Some(report_syncs) = outputs.report.take() {
+        let report = json::object! {
+            version: 1,
+            exit_code: exit_code,
+            syncs: report_syncs,
+        };
+        if let Some(report_file) = &args.report {
+            if let Err(e) = std::fs::write(report_file, report.pretty(2)) {
+                error!("Error writing report to '{}': {}", report_file, e);
+                exit_code = 14;
+            }
+        }
+        if let Some(prometheus_file) = &args.prometheus_textfile {
+            if let Err(e) = write_prometheus_textfile(prometheus_file, &report) {
+                error!("Error writing Prometheus metrics to '{}': {}", prometheus_file, e);
+                exit_code = 14;
+            }
+        }
+    }
 
     stop_timer(timer);
 
@@ -642,7 +1032,34 @@ fn boss_main_impl(args: BossCliArgs, progress_bar: &ProgressBar) -> ExitCode {
         println!("Boss peak memory usage: {}", profiling::get_peak_memory_usage());
     }
 
-    exit_code
+    ExitCode::from(exit_code)
+}
+
+/// Implements --explain-filters, for each of the syncs that would otherwise be performed.
+fn explain_filters(args: &BossCliArgs) -> ExitCode {
+    let syncs = if args.spec.is_none() && args.src.is_none() {
+        // Only filters were provided on the command line, which is all we need
+        vec![SyncSpec { filters: args.filter.clone(), ..Default::default() }]
+    } else {
+        match resolve_spec(args) {
+            Ok(s) => s.syncs,
+            Err(e) => {
+                error!("{}", e);
+                return ExitCode::from(18);
+            }
+        }
+    };
+
+    for (i, sync_spec) in syncs.iter().enumerate() {
+        if syncs.len() > 1 {
+            info!("Sync #{}: {} -> {}", i + 1, sync_spec.src, sync_spec.dest);
+        }
+        if let Err(e) = explain_filters_for_paths(sync_spec, &args.explain_filters) {
+            error!("{}", e);
+            return ExitCode::from(13);
+        }
+    }
+    ExitCode::SUCCESS
 }
 
 /// Figures out the Spec that we should execute, from a combination of the command-line args
@@ -662,14 +1079,16 @@ fn resolve_spec(args: &BossCliArgs) -> Result<Spec, String> {
         None => {
             // No spec - the command-line must have the src and dest specified
             let src = args.src.as_ref().unwrap(); // Command-line parsing rules means these must be valid, if spec is not provided
-            let dest = args.dest.as_ref().unwrap();
+            let (dest, extra_dests) = args.dest.split_first().unwrap();
             spec.src_hostname = src.hostname.clone();
             spec.src_username = src.username.clone();
             spec.dest_hostname = dest.hostname.clone();
             spec.dest_username = dest.username.clone();
+            spec.extra_dests = extra_dests.iter().map(|d| DestTarget { hostname: d.hostname.clone(), username: d.username.clone() }).collect();
             spec.syncs.push(SyncSpec {
                 src: src.path.clone(),
                 dest: dest.path.clone(),
+                extra_dests: extra_dests.iter().map(|d| d.path.clone()).collect(),
                 ..Default::default()
             });
             // The rest of the command-line arguments are applied below (as they are also relevant
@@ -681,10 +1100,22 @@ fn resolve_spec(args: &BossCliArgs) -> Result<Spec, String> {
     if let Some(b) = args.deploy {
         spec.deploy_behaviour = b;
     }
+    if args.parallel {
+        spec.parallel = true;
+    }
+    if args.bwlimit.is_some() {
+        spec.bwlimit = args.bwlimit;
+    }
     for mut sync in &mut spec.syncs {
         if !args.filter.is_empty() {
             sync.filters = args.filter.clone();
         }
+        if args.delete_excluded {
+            sync.delete_excluded = true;
+        }
+        if args.normalize_unicode {
+            sync.normalize_unicode = true;
+        }
 
         if let Some(b) = args.all_destructive_behaviour {
             // We don't want --all-destructive-behaviour
@@ -753,12 +1184,16 @@ fn resolve_spec(args: &BossCliArgs) -> Result<Spec, String> {
         if let Some(b) = args.dest_root_needs_deleting {
             sync.dest_root_needs_deleting_behaviour = b;
         }
+        if let Some(b) = args.windows_incompatible_name {
+            sync.windows_incompatible_name_behaviour = b;
+        }
     }
 
     Ok(spec)
 }
 
-fn execute_spec(spec: Spec, args: &BossCliArgs, progress_bar: &ProgressBar) -> ExitCode {
+fn execute_spec(spec: Spec, args: &BossCliArgs, progress_bar: &ProgressBar, saved_plan: Option<&SavedPlan>,
+    outputs: &mut SyncOutputs) -> u8 {
     // The src and/or dest may be on another computer. We need to run a copy of rjrssync on the remote
     // computer(s) and set up network commmunication.
     // There are therefore up to three copies of our program involved (although some may actually be the same as each other)
@@ -767,11 +1202,10 @@ fn execute_spec(spec: Spec, args: &BossCliArgs, progress_bar: &ProgressBar) -> E
     //            then this may be the same copy as the Boss. If it's remote then it will be a remote doer process.
     //   Dest - the computer specified by the `dest` command-line arg, and so if this is the local computer
     //          then this may be the same copy as the Boss. If it's remote then it will be a remote doer process.
-    //          If Source and Dest are the same computer, they are still separate copies for simplicity.
-    //          (It might be more efficient to just have one remote copy, but remember that there could be different users specified
-    //           on the Source and Dest, with separate permissions to the paths being synced, so they can't access each others' paths,
-    //           in which case we couldn't share a copy. Also might need to make it multithreaded on the other end to handle
-    //           doing one command at the same time for each Source and Dest, which might be more complicated.)
+    //          If Source and Dest are the same remote computer and user, then one remote copy serves both (on separate
+    //          connections, each handled by its own thread), which also lets it copy files without sending them via us.
+    //          If the users are different, they are still separate copies, as each user might have separate permissions
+    //          to the paths being synced, so they can't access each others' paths.
 
     // Configure the progress bar for the first phase (connecting to remote doers).
     // Functions inside setup_comms will set the message appropriately.
@@ -780,6 +1214,23 @@ fn execute_spec(spec: Spec, args: &BossCliArgs, progress_bar: &ProgressBar) -> E
     // Unfortunately we can't use enable_steady_tick to get a nice animation as we connect, because
     // this will clash with potential ssh output/prompts
 
+    let share_doer = !spec.src_hostname.is_empty() && spec.src_hostname == spec.dest_hostname &&
+        spec.src_username == spec.dest_username;
+    // There's no need for the dest to connect to the source when they're sharing a doer, as it can copy files itself
+    let connect_dest_to_src = args.direct_transfer.is_some() && !share_doer;
+    // Each sync that runs in parallel needs its own sessions with the doers (the dest's connections to the source count too)
+    let num_parallel = if spec.parallel { spec.syncs.len().max(1) as u32 } else { 1 };
+    let num_src_sessions = num_parallel * (1 + share_doer as u32 + connect_dest_to_src as u32);
+
+    let session_options = SessionOptions { num_streams: args.streams, compression_level: args.compress.unwrap_or(0) };
+    // Shared by all the syncs, so that they stay within the limit together
+    let bwlimit = spec.bwlimit.map(BandwidthLimiter::new);
+    // File contents which don't pass through us can't be limited, so make sure this doesn't go unnoticed
+    if bwlimit.is_some() && (connect_dest_to_src || share_doer) {
+        warn!("--bwlimit has no effect when {}, as file contents are sent without passing through this computer",
+            if share_doer { "the source and destination share a remote copy" } else { "using --direct-transfer" });
+    }
+
     // Launch doers on remote hosts or threads on local targets and estabilish communication (check version etc.)
     let mut src_comms = match setup_comms(
         &spec.src_hostname,
@@ -788,61 +1239,563 @@ fn execute_spec(spec: Spec, args: &BossCliArgs, progress_bar: &ProgressBar) -> E
         args.ssh_identity_file.clone(),
         "src".to_string(),
         spec.deploy_behaviour,
+        args.persist_doers,
+        num_src_sessions,
+        session_options,
         &progress_bar,
     ) {
         Ok(c) => c,
         Err(e) => {
             error!("Error connecting to {}: {}", spec.src_hostname, e);
-            return ExitCode::from(10);
+            return 10;
         }
     };
-    let mut dest_comms = match setup_comms(
-        &spec.dest_hostname,
-        &spec.dest_username,
-        args.remote_port,
-        args.ssh_identity_file.clone(),
-        "dest".to_string(),
-        spec.deploy_behaviour,
-        &progress_bar,
-    ) {
+    let dest_comms = if share_doer {
+        connect_to_shared_doer(&src_comms, "dest".to_string())
+    } else {
+        setup_comms(
+            &spec.dest_hostname,
+            &spec.dest_username,
+            args.remote_port,
+            args.ssh_identity_file.clone(),
+            "dest".to_string(),
+            spec.deploy_behaviour,
+            args.persist_doers,
+            num_parallel,
+            session_options,
+            &progress_bar,
+        )
+    };
+    let mut dest_comms = match dest_comms {
         Ok(c) => c,
         Err(e) => {
             error!("Error connecting to {}: {}", spec.dest_hostname, e);
             src_comms.shutdown(); // Clean shutdown
-            return ExitCode::from(11);
+            return 11;
         }
     };
+    if connect_dest_to_src {
+        let hostname = args.direct_transfer.as_deref().filter(|h| !h.is_empty());
+        if let Err(e) = connect_dest_to_source(&src_comms, &mut dest_comms, hostname) {
+            error!("Error connecting {} to {}: {}", spec.dest_hostname, spec.src_hostname, e);
+            dest_comms.shutdown();
+            src_comms.shutdown();
+            return 11;
+        }
+    }
+    // Any other dests each get their own doer, even if they're on the same computer as another, to keep things simple
+    let mut extra_dest_comms = vec![];
+    for (i, target) in spec.extra_dests.iter().enumerate() {
+        match setup_comms(
+            &target.hostname,
+            &target.username,
+            args.remote_port,
+            args.ssh_identity_file.clone(),
+            format!("dest #{}", i + 2),
+            spec.deploy_behaviour,
+            args.persist_doers,
+            1,
+            session_options,
+            &progress_bar,
+        ) {
+            Ok(c) => extra_dest_comms.push(c),
+            Err(e) => {
+                error!("Error connecting to {}: {}", target.hostname, e);
+                shutdown_with_extra_dests(extra_dest_comms, dest_comms, src_comms);
+                return 11;
+            }
+        }
+    }
+
+    // Making a plan doesn't change anything, so is the same as a dry run (and shows the same output, so the user can see what's in the plan)
+    let dry_run = args.dry_run || args.write_plan.is_some();
+
+    // Start watching before the first sync, so that anything which changes during it isn't missed
+    if args.watch {
+        if let Err(e) = start_watching(&mut src_comms, spec.syncs.iter().map(|s| s.src.clone()).collect()) {
+            error!("Error watching for changes: {}", e);
+            // Clean shutdown
+            shutdown_with_extra_dests(extra_dest_comms, dest_comms, src_comms);
+            return 12;
+        }
+    }
 
     // Perform the actual file sync(s)
-    for sync_spec in &spec.syncs {
-        // Indicate which sync this is, if there are many
-        if spec.syncs.len() > 1 {
-            info!("{} => {}:", sync_spec.src, sync_spec.dest);
+    let mut num_failed_entries = 0;
+    if num_parallel > 1 {
+        let hostname = args.direct_transfer.as_deref().filter(|h| !h.is_empty());
+        match sync_in_parallel(&spec, args, saved_plan, dry_run, outputs, progress_bar, bwlimit.as_ref(),
+            &mut src_comms, &mut dest_comms, connect_dest_to_src.then_some(hostname))
+        {
+            Ok(n) => num_failed_entries += n,
+            Err(code) => {
+                // Clean shutdown
+                shutdown_with_extra_dests(extra_dest_comms, dest_comms, src_comms);
+                return code;
+            }
+        }
+    } else if !extra_dest_comms.is_empty() {
+        for sync_spec in &spec.syncs {
+            if spec.syncs.len() > 1 {
+                info!("{}:", sync_spec.src);
+            }
+            let all_dest_comms = std::iter::once(&mut dest_comms).chain(extra_dest_comms.iter_mut()).collect();
+            match sync_to_all_dests(sync_spec, &spec, args, dry_run, outputs, progress_bar, bwlimit.as_ref(), &mut src_comms, all_dest_comms) {
+                Ok(n) => num_failed_entries += n,
+                Err(()) => {
+                    // Clean shutdown
+                    shutdown_with_extra_dests(extra_dest_comms, dest_comms, src_comms);
+                    return 12;
+                }
+            }
         }
+    } else {
+        for (i, sync_spec) in spec.syncs.iter().enumerate() {
+            // Indicate which sync this is, if there are many
+            if spec.syncs.len() > 1 {
+                info!("{} => {}:", sync_spec.src, sync_spec.dest);
+            }
 
-        // No point showing progress when doing a dry run
-        let show_progress = !args.no_progress && !args.dry_run;
-        let sync_result = sync(&sync_spec, args.dry_run, &progress_bar, show_progress,
-            args.stats, &mut src_comms, &mut dest_comms);
+            // No point showing progress when doing a dry run
+            let show_progress = !args.no_progress && !dry_run;
+            let sync_result = match saved_plan {
+                Some(p) => apply_plan(&p.syncs[i], dry_run, outputs, progress_bar, show_progress,
+                    args.stats, args.itemize, args.verify, args.keep_going, bwlimit.as_ref(), &mut src_comms, &mut dest_comms),
+                None => sync(sync_spec, None, dry_run, outputs, progress_bar, show_progress,
+                    args.stats, args.itemize, args.verify, args.keep_going, bwlimit.as_ref(), &mut src_comms, &mut dest_comms),
+            };
+
+            match sync_result {
+                Ok(n) => num_failed_entries += n,
+                Err(e) => {
+                    error!("Sync error: {}", e);
+                     // Clean shutdown
+                    shutdown_with_extra_dests(extra_dest_comms, dest_comms, src_comms);
+                    return 12;
+                }
+            }
+        }
+    }
 
-        match sync_result {
-            Ok(()) => (),
-            Err(e) => {
-                error!("Sync error: {}", e);
-                 // Clean shutdown
-                src_comms.shutdown();
-                dest_comms.shutdown();
-                return ExitCode::from(12);
+    // Keep syncing whatever changes, re-using the same doers (and connections to them) each time.
+    // This only stops if there is an error, otherwise the user stops it with Ctrl+C.
+    if args.watch {
+        // No point showing progress when doing a dry run
+        let show_progress = !args.no_progress && !dry_run;
+        loop {
+            info!("Watching for changes...");
+            let changes = match wait_for_changes(&mut src_comms, WATCH_DEBOUNCE) {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("Error watching for changes: {}", e);
+                    // Clean shutdown
+                    shutdown_with_extra_dests(extra_dest_comms, dest_comms, src_comms);
+                    return 12;
+                }
+            };
+            for (i, sync_spec) in spec.syncs.iter().enumerate() {
+                let paths: Vec<RootRelativePath> = changes.iter().filter(|c| c.0 == i).map(|c| c.1.clone()).collect();
+                if paths.is_empty() {
+                    continue;
+                }
+                if spec.syncs.len() > 1 {
+                    info!("{} => {}:", sync_spec.src, sync_spec.dest);
+                }
+                let sync_result = sync(sync_spec, Some(&paths), dry_run, outputs, progress_bar, show_progress,
+                    args.stats, args.itemize, args.verify, args.keep_going, bwlimit.as_ref(), &mut src_comms, &mut dest_comms);
+                if let Err(e) = sync_result {
+                    error!("Sync error: {}", e);
+                    // Clean shutdown
+                    shutdown_with_extra_dests(extra_dest_comms, dest_comms, src_comms);
+                    return 12;
+                }
             }
         }
     }
 
     // Shutdown the comms before dumping profiling, so that any doer threads and comms threads have cleanly exited,
     // and their profiling data is saved, and we have received profiling data from any remote doer processes.
-    src_comms.shutdown();
+    // Dest first, as it may be sharing the src's doer, which won't exit until both are done with it
+    shutdown_with_extra_dests(extra_dest_comms, dest_comms, src_comms);
+
+    if let (Some(plan_file), Some(plan_syncs)) = (&args.plan_json, outputs.json.take()) {
+        let plan = json::object! {
+            version: 1,
+            dry_run: dry_run,
+            syncs: plan_syncs,
+        };
+        if let Err(e) = write_plan_json(plan_file, &plan) {
+            error!("Error writing plan to '{}': {}", plan_file, e);
+            return 14;
+        }
+    }
+    if let (Some(plan_file), Some(saved_syncs)) = (&args.write_plan, outputs.saved.take()) {
+        let plan = SavedPlan {
+            version: PLAN_VERSION.to_string(),
+            src_hostname: spec.src_hostname,
+            src_username: spec.src_username,
+            dest_hostname: spec.dest_hostname,
+            dest_username: spec.dest_username,
+            syncs: saved_syncs,
+        };
+        match save_plan(plan_file, &plan) {
+            Ok(()) => info!("Plan saved to '{}'. Use --apply-plan to perform it.", plan_file),
+            Err(e) => {
+                error!("Error writing plan to '{}': {}", plan_file, e);
+                return 14;
+            }
+        }
+    }
+
+    if num_failed_entries > 0 {
+        return 15;
+    }
+
+    0
+}
+
+/// Names of the threads that perform each sync when they run in parallel are this followed by which sync it is,
+/// so that log messages can say which sync they're from.
+const PARALLEL_SYNC_THREAD_PREFIX: &str = "sync: ";
+
+/// Checks that none of the syncs could interfere with each other if they were run at the same time (see --parallel),
+/// i.e. that no sync's dest is inside (or contains) another sync's src or dest.
+/// This only compares the paths as written, so it won't spot e.g. overlaps via symlinks, but will catch the likely mistakes.
+fn check_parallel_syncs_dont_overlap(spec: &Spec) -> Result<(), String> {
+    // A src and dest can only overlap if they're on the same computer
+    let same_computer = spec.src_hostname == spec.dest_hostname;
+    for (i, a) in spec.syncs.iter().enumerate() {
+        for (j, b) in spec.syncs.iter().enumerate() {
+            if i < j && paths_overlap(&a.dest, &b.dest) {
+                return Err(format!("Syncs can't be run in parallel as their dests overlap: '{}' and '{}'", a.dest, b.dest));
+            }
+            if i != j && same_computer && paths_overlap(&a.dest, &b.src) {
+                return Err(format!("Syncs can't be run in parallel as the dest '{}' overlaps the src '{}' of another sync", a.dest, b.src));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that none of the dests that each source is synced to are the same as (or inside) each other, as they would
+/// be updated at the same time. Like check_parallel_syncs_dont_overlap, this only compares the paths as written.
+fn check_dests_dont_overlap(spec: &Spec) -> Result<(), String> {
+    let first_target = DestTarget { hostname: spec.dest_hostname.clone(), username: spec.dest_username.clone() };
+    let targets: Vec<&DestTarget> = std::iter::once(&first_target).chain(&spec.extra_dests).collect();
+    for sync in &spec.syncs {
+        let dests: Vec<(&DestTarget, &String)> = targets.iter().copied().zip(std::iter::once(&sync.dest).chain(&sync.extra_dests)).collect();
+        for (i, (a_target, a)) in dests.iter().enumerate() {
+            for (b_target, b) in &dests[i + 1..] {
+                if a_target.hostname == b_target.hostname && paths_overlap(a, b) {
+                    return Err(format!("The dests of a sync can't overlap: '{}' and '{}'", a, b));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks if two paths on the same computer are the same, or one is inside the other.
+fn paths_overlap(a: &str, b: &str) -> bool {
+    let normalize = |p: &str| p.replace('\\', "/").trim_end_matches('/').to_string();
+    let (a, b) = (normalize(a), normalize(b));
+    a == b || a.starts_with(&format!("{b}/")) || b.starts_with(&format!("{a}/"))
+}
+
+/// Performs all the syncs at the same time (see --parallel), each with its own sessions with the doers.
+/// The first sync uses the given Comms, and the others start new sessions (see start_another_session), which are also
+/// connected to each other if `connect_dest_to_src` is set (see --direct-transfer).
+/// Returns the total number of entries which failed (see --keep-going), or the exit code if something else failed.
+#[allow(clippy::too_many_arguments)]
+fn sync_in_parallel(spec: &Spec, args: &BossCliArgs, saved_plan: Option<&SavedPlan>, dry_run: bool, outputs: &mut SyncOutputs,
+    progress_bar: &ProgressBar, bwlimit: Option<&BandwidthLimiter>, src_comms: &mut Comms, dest_comms: &mut Comms, connect_dest_to_src: Option<Option<&str>>,
+) -> Result<usize, u8> {
+    // Start all the sessions first, so that any problems connecting are found before anything is synced
+    let mut more_comms = vec![];
+    for i in 2..=spec.syncs.len() {
+        let src = match start_another_session(src_comms, format!("src #{i}")) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Error connecting to {}: {}", spec.src_hostname, e);
+                shutdown_all(more_comms);
+                return Err(10);
+            }
+        };
+        let mut dest = match start_another_session(dest_comms, format!("dest #{i}")) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Error connecting to {}: {}", spec.dest_hostname, e);
+                shutdown_all(more_comms);
+                src.shutdown();
+                return Err(11);
+            }
+        };
+        if let Some(hostname) = connect_dest_to_src {
+            if let Err(e) = connect_dest_to_source(&src, &mut dest, hostname) {
+                error!("Error connecting {} to {}: {}", spec.dest_hostname, spec.src_hostname, e);
+                shutdown_all(more_comms);
+                dest.shutdown();
+                src.shutdown();
+                return Err(11);
+            }
+        }
+        more_comms.push((src, dest));
+    }
+
+    let all_comms = std::iter::once((src_comms, dest_comms)).chain(more_comms.iter_mut().map(|(s, d)| (s, d)));
+    let syncs = spec.syncs.iter().enumerate().zip(all_comms).map(|((i, sync_spec), (src_comms, dest_comms))| {
+        let f = move |outputs: &mut SyncOutputs, progress_bar: &ProgressBar| match saved_plan {
+            Some(p) => apply_plan(&p.syncs[i], dry_run, outputs, progress_bar, false,
+                args.stats, args.itemize, args.verify, args.keep_going, bwlimit, src_comms, dest_comms),
+            None => sync(sync_spec, None, dry_run, outputs, progress_bar, false,
+                args.stats, args.itemize, args.verify, args.keep_going, bwlimit, src_comms, dest_comms),
+        };
+        (format!("{} => {}", sync_spec.src, sync_spec.dest), Box::new(f) as ConcurrentSyncFn)
+    }).collect();
+    let result = run_syncs_concurrently(syncs, outputs, progress_bar);
+    shutdown_all(more_comms);
+    result.map_err(|_| 12)
+}
+
+/// Performs a sync (see run_syncs_concurrently), using the given SyncOutputs and ProgressBar.
+type ConcurrentSyncFn<'a> = Box<dyn FnOnce(&mut SyncOutputs, &ProgressBar) -> Result<usize, String> + Send + 'a>;
+
+/// Runs each of the given syncs on its own thread, each with a name to identify it in messages.
+/// The syncs can't all show their progress on the one progress bar, so that just shows how many have finished.
+/// One sync failing doesn't stop the others, and the result of each is reported once they have all finished.
+/// Returns the total number of entries which failed (see --keep-going), or an error if any of the syncs failed.
+fn run_syncs_concurrently(syncs: Vec<(String, ConcurrentSyncFn)>, outputs: &mut SyncOutputs, progress_bar: &ProgressBar) -> Result<usize, ()> {
+    // The progress bar isn't animated (and is only updated while no prompt is showing), so that it doesn't get drawn over any prompts.
+    let num_syncs = syncs.len();
+    progress_bar.set_style(ProgressStyle::with_template("{wide_msg}").unwrap());
+    progress_bar.set_message(format!("Running {num_syncs} syncs at once (0 finished)..."));
+    let (names, results): (Vec<String>, Vec<_>) = std::thread::scope(|scope| {
+        let (finished_sender, finished_receiver) = std::sync::mpsc::channel();
+        let threads: Vec<_> = syncs.into_iter().map(|(name, f)| {
+            let mut sync_outputs = outputs.new_like();
+            let finished_sender = finished_sender.clone();
+            let thread = std::thread::Builder::new().name(format!("{PARALLEL_SYNC_THREAD_PREFIX}{name}"))
+                .spawn_scoped(scope, move || {
+                    let result = f(&mut sync_outputs, &ProgressBar::hidden());
+                    let _ = finished_sender.send(());
+                    (result, sync_outputs)
+                }).expect("Failed to spawn thread");
+            (name, thread)
+        }).collect();
+        drop(finished_sender); // So that we stop waiting if a thread panics
+
+        for num_finished in 1..=num_syncs {
+            if finished_receiver.recv().is_err() {
+                break;
+            }
+            let _prompt_lock = PROMPT_LOCK.lock().expect("Mutex problem");
+            progress_bar.set_message(format!("Running {num_syncs} syncs at once ({num_finished} finished)..."));
+        }
+        threads.into_iter().map(|(name, t)| (name, t.join().expect("Sync thread panicked"))).unzip()
+    });
+    progress_bar.finish_and_clear();
+
+    // Report the result of each sync, in the same order as they were given
+    let mut num_failed_entries = 0;
+    let mut any_failed = false;
+    for (name, (result, sync_outputs)) in names.iter().zip(results) {
+        outputs.append(sync_outputs);
+        match result {
+            Ok(0) => info!("{}: Done", name),
+            Ok(n) => {
+                warn!("{}: Done, but {} entries failed", name, n);
+                num_failed_entries += n;
+            }
+            Err(e) => {
+                error!("{}: Sync error: {}", name, e);
+                any_failed = true;
+            }
+        }
+    }
+    if any_failed {
+        return Err(());
+    }
+    Ok(num_failed_entries)
+}
+
+/// Syncs the source to each of its dests at the same time (see multiple DESTs), sharing the source between them
+/// (see SourceFanOut). The extra dests' paths come from the sync spec, and their Comms are given in the same order,
+/// after the main dest's.
+/// Returns the total number of entries which failed (see --keep-going), or an error if any of the syncs failed.
+#[allow(clippy::too_many_arguments)]
+fn sync_to_all_dests(sync_spec: &SyncSpec, spec: &Spec, args: &BossCliArgs, dry_run: bool, outputs: &mut SyncOutputs,
+    progress_bar: &ProgressBar, bwlimit: Option<&BandwidthLimiter>, src_comms: &mut Comms, dest_comms: Vec<&mut Comms>,
+) -> Result<usize, ()> {
+    let first_target = DestTarget { hostname: spec.dest_hostname.clone(), username: spec.dest_username.clone() };
+    let targets = std::iter::once(&first_target).chain(&spec.extra_dests);
+    let dest_paths = std::iter::once(&sync_spec.dest).chain(&sync_spec.extra_dests);
+    let names: Vec<String> = targets.zip(dest_paths).map(|(t, path)| match (t.hostname.is_empty(), t.username.is_empty()) {
+        (true, _) => format!("{} => {}", sync_spec.src, path),
+        (false, true) => format!("{} => {}:{}", sync_spec.src, t.hostname, path),
+        (false, false) => format!("{} => {}@{}:{}", sync_spec.src, t.username, t.hostname, path),
+    }).collect();
+
+    let (fan_out, proxies) = SourceFanOut::new(names.clone());
+    let (result, fan_out_result) = std::thread::scope(|scope| {
+        let fan_out_thread = std::thread::Builder::new().name("shared source".to_string())
+            .spawn_scoped(scope, || fan_out.run(src_comms)).expect("Failed to spawn thread");
+
+        let dest_paths = std::iter::once(&sync_spec.dest).chain(&sync_spec.extra_dests);
+        let syncs = names.into_iter().zip(dest_paths).zip(proxies.into_iter().zip(dest_comms))
+            .map(|((name, path), (mut proxy, dest_comms))| {
+                let f = move |outputs: &mut SyncOutputs, progress_bar: &ProgressBar| {
+                    let this_sync = SyncSpec { dest: path.clone(), extra_dests: vec![], ..sync_spec.clone() };
+                    let result = sync(&this_sync, None, dry_run, outputs, progress_bar, false,
+                        args.stats, args.itemize, args.verify, args.keep_going, bwlimit, &mut proxy, dest_comms);
+                    // The shared source waits for every sync to finish with it
+                    proxy.shutdown();
+                    result
+                };
+                (name, Box::new(f) as ConcurrentSyncFn)
+            }).collect();
+        let result = run_syncs_concurrently(syncs, outputs, progress_bar);
+        (result, fan_out_thread.join().expect("Shared source thread panicked"))
+    });
+
+    if let Err(e) = fan_out_result {
+        error!("Sync error: {}", e);
+        return Err(());
+    }
+    result
+}
+
+/// Shuts down the Comms for any extra dests (see multiple DESTs), then the dest and then the src.
+fn shutdown_with_extra_dests(extra_dest_comms: Vec<Comms>, dest_comms: Comms, src_comms: Comms) {
+    for c in extra_dest_comms {
+        c.shutdown();
+    }
+    // Dest before src, as it may be sharing the src's doer
     dest_comms.shutdown();
+    src_comms.shutdown();
+}
 
-    ExitCode::SUCCESS
+fn shutdown_all(comms: Vec<(Comms, Comms)>) {
+    for (src_comms, dest_comms) in comms {
+        // Dest first, as it may be sharing the src's doer
+        dest_comms.shutdown();
+        src_comms.shutdown();
+    }
+}
+
+/// How long to wait for things to stop changing before syncing them (see --watch).
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Plans can only be applied by the same version of rjrssync that made them.
+const PLAN_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The file format for --write-plan and --apply-plan.
+/// This isn't intended to be read by anything other than the same version of rjrssync, so it uses the same
+/// (compact, but not self-describing) serialization as the boss <-> doer communication.
+#[derive(Serialize, Deserialize)]
+struct SavedPlan {
+    /// Checked when loading, as the format of the rest of this might be different for other versions.
+    version: String,
+    src_hostname: String,
+    src_username: String,
+    dest_hostname: String,
+    dest_username: String,
+    syncs: Vec<SavedSync>,
+}
+
+fn save_plan(plan_file: &str, plan: &SavedPlan) -> Result<(), String> {
+    let data = bincode::serialize(plan).map_err(|e| e.to_string())?;
+    std::fs::write(plan_file, data).map_err(|e| e.to_string())
+}
+
+fn load_plan(plan_file: &str) -> Result<SavedPlan, String> {
+    let data = std::fs::read(plan_file).map_err(|e| e.to_string())?;
+    // Check the version first, as the rest of the data won't make sense if it was saved by another version
+    let version: String = bincode::deserialize(&data).map_err(|_| "Not a valid plan file".to_string())?;
+    if version != PLAN_VERSION {
+        return Err(format!("The plan was made by a different version of rjrssync ({version}, but this is {PLAN_VERSION})"));
+    }
+    bincode::deserialize(&data).map_err(|e| format!("Not a valid plan file: {e}"))
+}
+
+/// Makes a Spec which connects to the same places as when the plan was made.
+fn spec_for_plan(plan: &SavedPlan, args: &BossCliArgs) -> Spec {
+    Spec {
+        src_hostname: plan.src_hostname.clone(),
+        src_username: plan.src_username.clone(),
+        dest_hostname: plan.dest_hostname.clone(),
+        dest_username: plan.dest_username.clone(),
+        deploy_behaviour: args.deploy.unwrap_or(DeployBehaviour::Prompt),
+        parallel: args.parallel,
+        bwlimit: args.bwlimit,
+        extra_dests: vec![],
+        syncs: plan.syncs.iter().map(|s| SyncSpec { src: s.src.clone(), dest: s.dest.clone(), ..Default::default() }).collect(),
+    }
+}
+
+fn write_plan_json(plan_file: &str, plan: &json::JsonValue) -> Result<(), String> {
+    if plan_file == "-" {
+        println!("{}", plan.pretty(2));
+        Ok(())
+    } else {
+        std::fs::write(plan_file, plan.pretty(2)).map_err(|e| e.to_string())
+    }
+}
+
+/// Converts the --report document into Prometheus metrics (see --prometheus-textfile).
+/// The file is written to a temporary name first and then renamed, so that the collector never sees a partial file.
+fn write_prometheus_textfile(prometheus_file: &str, report: &json::JsonValue) -> Result<(), String> {
+    // Label values need backslashes, quotes and newlines escaping
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    let value = |v: &json::JsonValue| v.as_f64().map_or("NaN".to_string(), |f| f.to_string());
+
+    let mut metrics: Vec<(&str, &str, &str, Vec<String>)> = vec![
+        ("rjrssync_exit_code", "gauge", "Exit code of the last run.", vec![format!("rjrssync_exit_code {}", report["exit_code"])]),
+        ("rjrssync_last_run_timestamp_seconds", "gauge", "When the last run finished, in seconds since the UNIX epoch.",
+            vec![format!("rjrssync_last_run_timestamp_seconds {}",
+                SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64()))]),
+        ("rjrssync_sync_success", "gauge", "Whether each sync completed without error (1) or not (0).", vec![]),
+        ("rjrssync_deleted_entries", "gauge", "Number of entries deleted from the dest, by kind.", vec![]),
+        ("rjrssync_deleted_bytes", "gauge", "Total size of files deleted from the dest.", vec![]),
+        ("rjrssync_copied_entries", "gauge", "Number of entries copied to the dest, by kind.", vec![]),
+        ("rjrssync_copied_bytes", "gauge", "Total size of files copied to the dest.", vec![]),
+        ("rjrssync_failed_entries", "gauge", "Number of entries which couldn't be synced (see --keep-going).", vec![]),
+        ("rjrssync_duration_seconds", "gauge", "Time taken by each phase of each sync.", vec![]),
+    ];
+    for sync in report["syncs"].members() {
+        let labels = format!("src=\"{}\",dest=\"{}\"", escape(sync["src"].as_str().unwrap_or("")), escape(sync["dest"].as_str().unwrap_or("")));
+        let mut add = |name: &str, extra_labels: &str, v: String| {
+            let samples = &mut metrics.iter_mut().find(|m| m.0 == name).expect("Unknown metric").3;
+            samples.push(format!("{name}{{{labels}{extra_labels}}} {v}"));
+        };
+        add("rjrssync_sync_success", "", ((sync["success"].as_bool() == Some(true)) as u8).to_string());
+        for (action, entries_metric, bytes_metric) in [("deleted", "rjrssync_deleted_entries", "rjrssync_deleted_bytes"),
+            ("copied", "rjrssync_copied_entries", "rjrssync_copied_bytes")]
+        {
+            for kind in ["files", "folders", "symlinks"] {
+                add(entries_metric, &format!(",kind=\"{kind}\""), value(&sync[action][kind]));
+            }
+            add(bytes_metric, "", value(&sync[action]["bytes"]));
+        }
+        add("rjrssync_failed_entries", "", sync["failed_entries"].len().to_string());
+        for (phase, secs) in sync["durations_secs"].entries() {
+            if !secs.is_null() {
+                add("rjrssync_duration_seconds", &format!(",phase=\"{phase}\""), value(secs));
+            }
+        }
+    }
+
+    let mut text = String::new();
+    for (name, kind, help, samples) in metrics {
+        text += &format!("# HELP {name} {help}\n# TYPE {name} {kind}\n");
+        for s in samples {
+            text += &s;
+            text += "\n";
+        }
+    }
+
+    let temp_file = format!("{prometheus_file}.tmp");
+    std::fs::write(&temp_file, text).map_err(|e| e.to_string())?;
+    std::fs::rename(&temp_file, prometheus_file).map_err(|e| e.to_string())
 }
 
 /// For testing purposes, this env var can be set to a list of responses to prompts
@@ -851,9 +1804,11 @@ fn execute_spec(spec: Spec, args: &BossCliArgs, progress_bar: &ProgressBar) -> E
 const TEST_PROMPT_RESPONSE_ENV_VAR: &str = "RJRSSYNC_TEST_PROMPT_RESPONSE";
 
 lazy_static! {
-    // We're only accessing this on one thread, but the compiler doesn't know that so we need a mutex.
+    // This is accessed by each sync that's running, which might be on different threads (see --parallel).
     // It's only used for the prompt code, so performance should not be a concern.
     static ref TEST_PROMPT_RESPONSES: Mutex<TestPromptResponses> = Mutex::new(TestPromptResponses::from_env());
+    // Held while showing a prompt, so that syncs running in parallel don't show prompts over the top of each other.
+    static ref PROMPT_LOCK: Mutex<()> = Mutex::new(());
 }
 
 struct TestPromptResponses {
@@ -924,6 +1879,8 @@ pub fn resolve_prompt<B: Copy>(prompt: String, progress_bar: Option<&ProgressBar
     }
     items.push((String::from("Cancel sync"), ResolvePromptResult::once(cancel_behaviour)));
 
+    let _prompt_lock = PROMPT_LOCK.lock().expect("Mutex problem");
+
     // Allow overriding the prompt response for testing
     let mut response_idx = None;
     if let Some(auto_response) = TEST_PROMPT_RESPONSES.lock().expect("Mutex problem").get_response(&prompt) {
@@ -1209,6 +2166,8 @@ mod tests {
             dest_hostname: "computer2"
             dest_username: "user2"
             deploy_behaviour: ok
+            parallel: true
+            bwlimit: 1.5M
             syncs:
             - src: T:\Source1
               dest: T:\Dest1
@@ -1218,6 +2177,9 @@ mod tests {
               files_same_time_behaviour: overwrite
               dest_entry_needs_deleting_behaviour: prompt
               dest_root_needs_deleting_behaviour: delete
+              delete_excluded: true
+              normalize_unicode: true
+              windows_incompatible_name_behaviour: skip
             - src: T:\Source2
               dest: T:\Dest2
               filters: [ "-exclude3", "-exclude4" ]
@@ -1234,26 +2196,37 @@ mod tests {
             dest_hostname: "computer2".to_string(),
             dest_username: "user2".to_string(),
             deploy_behaviour: DeployBehaviour::Ok,
+            parallel: true,
+            bwlimit: Some(1536 * 1024),
+            extra_dests: vec![],
             syncs: vec![
                 SyncSpec {
                     src: "T:\\Source1".to_string(),
                     dest: "T:\\Dest1".to_string(),
+                    extra_dests: vec![],
                     filters: vec![ "-exclude1".to_string(), "-exclude2".to_string() ],
                     dest_file_newer_behaviour: DestFileUpdateBehaviour::Error,
                     dest_file_older_behaviour: DestFileUpdateBehaviour::Skip,
                     files_same_time_behaviour: DestFileUpdateBehaviour::Overwrite,
                     dest_entry_needs_deleting_behaviour: DestEntryNeedsDeletingBehaviour::Prompt,
                     dest_root_needs_deleting_behaviour: DestRootNeedsDeletingBehaviour::Delete,
+                    delete_excluded: true,
+                    normalize_unicode: true,
+                    windows_incompatible_name_behaviour: WindowsIncompatibleNameBehaviour::Skip,
                 },
                 SyncSpec {
                     src: "T:\\Source2".to_string(),
                     dest: "T:\\Dest2".to_string(),
+                    extra_dests: vec![],
                     filters: vec![ "-exclude3".to_string(), "-exclude4".to_string() ],
                     dest_file_newer_behaviour: DestFileUpdateBehaviour::Prompt,
                     dest_file_older_behaviour: DestFileUpdateBehaviour::Overwrite,
                     files_same_time_behaviour: DestFileUpdateBehaviour::Error,
                     dest_entry_needs_deleting_behaviour: DestEntryNeedsDeletingBehaviour::Error,
                     dest_root_needs_deleting_behaviour: DestRootNeedsDeletingBehaviour::Skip,
+                    delete_excluded: false,
+                    normalize_unicode: false,
+                    windows_incompatible_name_behaviour: WindowsIncompatibleNameBehaviour::Error,
                 }
             ]
         };
@@ -1277,6 +2250,9 @@ mod tests {
             dest_hostname: "".to_string(), // Default - not specified in the YAML
             dest_username: "".to_string(), // Default - not specified in the YAML
             deploy_behaviour: DeployBehaviour::Prompt, // Default - not specified in the YAML
+            parallel: false, // Default - not specified in the YAML
+            bwlimit: None, // Default - not specified in the YAML
+            extra_dests: vec![], // Default - not specified in the YAML
             syncs: vec![
                 SyncSpec {
                     src: "T:\\Source1".to_string(),
@@ -1290,6 +2266,57 @@ mod tests {
         assert_eq!(parse_spec_file(s.path()), Ok(expected_result));
     }
 
+    /// Checks that parse_spec_file() reads a list of dests, which can be on other computers.
+    #[test]
+    fn test_parse_spec_file_multiple_dests() {
+        let mut s = NamedTempFile::new().unwrap();
+        write!(s, r#"
+            dest_hostname: "computer2"
+            syncs:
+            - src: T:\Source1
+              dests: [ T:\Dest1, user@computer3:/dest1 ]
+            - src: T:\Source2
+              dests: [ T:\Dest2, user@computer3:/dest2 ]
+        "#).unwrap();
+
+        let expected_result = Spec {
+            dest_hostname: "computer2".to_string(),
+            extra_dests: vec![DestTarget { hostname: "computer3".to_string(), username: "user".to_string() }],
+            syncs: vec![
+                SyncSpec {
+                    src: "T:\\Source1".to_string(),
+                    dest: "T:\\Dest1".to_string(),
+                    extra_dests: vec!["/dest1".to_string()],
+                    ..Default::default()
+                },
+                SyncSpec {
+                    src: "T:\\Source2".to_string(),
+                    dest: "T:\\Dest2".to_string(),
+                    extra_dests: vec!["/dest2".to_string()],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(parse_spec_file(s.path()), Ok(expected_result));
+    }
+
+    /// Checks that parse_spec_file() errors if the syncs have different dest computers.
+    #[test]
+    fn test_parse_spec_file_mismatched_dests() {
+        let mut s = NamedTempFile::new().unwrap();
+        write!(s, r#"
+            syncs:
+            - src: T:\Source1
+              dests: [ T:\Dest1, computer3:/dest1 ]
+            - src: T:\Source2
+              dest: T:\Dest2
+        "#).unwrap();
+
+        assert!(parse_spec_file(s.path()).unwrap_err().contains("Each sync must have the same dest hostnames/usernames"));
+    }
+
     /// Checks that parse_spec_file() errors if required fields are omitted.
     #[test]
     fn test_parse_spec_file_missing_required_src() {
@@ -1382,6 +2409,16 @@ mod tests {
         assert!(parse_spec_file(s.path()).unwrap_err().contains("Unexpected value in 'filters' array"));
     }
 
+    #[test]
+    fn test_parse_spec_file_invalid_bool_field() {
+        let mut s = NamedTempFile::new().unwrap();
+        write!(s, r#"
+            syncs:
+            - delete_excluded: 7
+        "#).unwrap();
+        assert!(parse_spec_file(s.path()).unwrap_err().contains("Unexpected value for 'delete_excluded'"));
+    }
+
     /// Checks that an invalid enum value for dest_file_newer_behaviour is rejected.
     /// We don't bother to test all the different behaviours in the same way, just this one.
     #[test]
@@ -1394,6 +2431,22 @@ mod tests {
         assert!(parse_spec_file(s.path()).unwrap_err().contains("invalid variant: notallowed"));
     }
 
+    /// Checks that bwlimit can be given as a plain number of bytes per second, and that invalid rates are rejected.
+    #[test]
+    fn test_parse_spec_file_bwlimit() {
+        let mut s = NamedTempFile::new().unwrap();
+        write!(s, r#"
+            bwlimit: 100000
+        "#).unwrap();
+        assert_eq!(parse_spec_file(s.path()).unwrap().bwlimit, Some(100000));
+
+        let mut s = NamedTempFile::new().unwrap();
+        write!(s, r#"
+            bwlimit: fast
+        "#).unwrap();
+        assert!(parse_spec_file(s.path()).unwrap_err().contains("Unexpected value for 'bwlimit'"));
+    }
+
     /// Tests that command-line args can be used to override things set in the spec file.
     #[test]
     fn resolve_spec_overrides() {
@@ -1499,4 +2552,42 @@ mod tests {
             ..Default::default()
         });
     }
+
+    /// Checks that check_parallel_syncs_dont_overlap() spots syncs that would interfere with each other.
+    #[test]
+    fn test_check_parallel_syncs_dont_overlap() {
+        let spec = |src_hostname: &str, dest_hostname: &str, syncs: &[(&str, &str)]| Spec {
+            src_hostname: src_hostname.to_string(),
+            dest_hostname: dest_hostname.to_string(),
+            syncs: syncs.iter().map(|(src, dest)| SyncSpec { src: src.to_string(), dest: dest.to_string(), ..Default::default() }).collect(),
+            ..Default::default()
+        };
+
+        // Separate folders, and the same src synced to different places, are fine
+        assert_eq!(check_parallel_syncs_dont_overlap(&spec("", "", &[("a", "b"), ("c", "d"), ("a", "e")])), Ok(()));
+        // Folders whose names only start the same are fine
+        assert_eq!(check_parallel_syncs_dont_overlap(&spec("", "", &[("a", "dest"), ("c", "dest2")])), Ok(()));
+        // Dests overlapping each other aren't, regardless of separators
+        assert!(check_parallel_syncs_dont_overlap(&spec("", "", &[("a", "b"), ("c", "b/")])).is_err());
+        assert!(check_parallel_syncs_dont_overlap(&spec("", "", &[("a", "T:\\b"), ("c", "T:/b/d")])).is_err());
+        // A dest overlapping another sync's src isn't either, but only if they're on the same computer
+        assert!(check_parallel_syncs_dont_overlap(&spec("", "", &[("a", "b"), ("b/c", "d")])).is_err());
+        assert_eq!(check_parallel_syncs_dont_overlap(&spec("computer1", "computer2", &[("a", "b"), ("b/c", "d")])), Ok(()));
+    }
+
+    /// Checks that check_dests_dont_overlap() spots a source being synced to the same place twice.
+    #[test]
+    fn test_check_dests_dont_overlap() {
+        let spec = |extra_hostname: &str, dest: &str, extra_dest: &str| Spec {
+            extra_dests: vec![DestTarget { hostname: extra_hostname.to_string(), ..Default::default() }],
+            syncs: vec![SyncSpec { src: "a".to_string(), dest: dest.to_string(), extra_dests: vec![extra_dest.to_string()], ..Default::default() }],
+            ..Default::default()
+        };
+
+        assert_eq!(check_dests_dont_overlap(&spec("", "b", "c")), Ok(()));
+        assert!(check_dests_dont_overlap(&spec("", "b", "b/")).is_err());
+        assert!(check_dests_dont_overlap(&spec("", "b", "b/c")).is_err());
+        // The same path is fine on different computers
+        assert_eq!(check_dests_dont_overlap(&spec("computer2", "b", "b")), Ok(()));
+    }
 }
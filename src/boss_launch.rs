@@ -1,14 +1,15 @@
-use aes_gcm::aead::{OsRng};
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
 use aes_gcm::{Aes128Gcm, KeyInit, Key};
 use indicatif::ProgressBar;
 use log::{debug, error, info, log, trace};
 use std::io::LineWriter;
-use std::net::{TcpStream};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use std::{
     fmt::{self, Display},
     io::{BufRead, BufReader, Write},
@@ -20,7 +21,8 @@ use std::{
 use crate::*;
 use crate::boss_deploy::deploy_to_remote;
 use crate::boss_doer_interface::{Response, Command, HANDSHAKE_STARTED_MSG, HANDSHAKE_COMPLETED_MSG};
-use crate::encrypted_comms::AsyncEncryptedComms;
+use crate::encrypted_comms::{AsyncEncryptedComms, session_key_handshake, join_session, make_transfer_key, SessionOptions, BossId, DOER_BUSY};
+use lazy_static::lazy_static;
 
 pub const REMOTE_TEMP_UNIX: &str = "/var/tmp"; // Use /var/tmp rather than /tmp so it doesn't get wiped on reboot (and thus requiring a re-deploy)
 pub const REMOTE_TEMP_WINDOWS: &str = r"%TEMP%";
@@ -32,6 +34,16 @@ pub const REMOTE_TEMP_WINDOWS: &str = r"%TEMP%";
 /// and this could lead to reduced performance.
 pub const BOSS_DOER_CHANNEL_MEMORY_CAPACITY : usize = 100*1024*1024;
 
+lazy_static! {
+    // Sent when starting sessions, so that a persistent doer can tell our sessions apart from another boss's
+    // (see session_key_handshake).
+    static ref BOSS_ID: BossId = {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        id
+    };
+}
+
 /// Abstraction of two-way communication channel between this boss and a doer, which might be
 /// remote (communicating over an encrypted TCP connection) or local (communicating via a channel to a background thread).
 #[allow(clippy::large_enum_variant)]
@@ -44,17 +56,54 @@ pub enum Comms {
     },
     Remote {
         debug_name: String, // To identify this Comms against others for debugging, when there are several
-        ssh_process: std::process::Child,
-        // Once the network socket is set up, we don't need to communicate over stdin/stdout any more,
-        // but we keep these around anyway in case.
-        stdin: LineWriter<ChildStdin>,
-        stdout: BufReader<ChildStdout>,
-        stderr_reading_thread: JoinHandle<()>,
+        // None if we connected to a persistent doer that was launched by an earlier run
+        ssh: Option<SshDoerProcess>,
+        // Set if the doer will keep running after we're done with it, so we can record it for later runs
+        persistent_doer: Option<PersistentDoer>,
+        // Set if the doer accepts more than one session, so that we can start another (see connect_to_shared_doer)
+        sessions: Option<DoerSessions>,
+        // Set if this is the dest doer and it fetches file contents from the source doer itself (see connect_dest_to_source)
+        connected_to_source: bool,
 
         encrypted_comms: AsyncEncryptedComms<Command, Response>,
     },
 }
+
+/// How to start more sessions with a remote doer that accepts more than one.
+#[derive(Clone)]
+pub struct DoerSessions {
+    hostname: String,
+    port: u16,
+    shared_key: Key<Aes128Gcm>,
+    // The number of streams etc. to ask for in each session (see start_session)
+    options: SessionOptions,
+}
+
+/// The ssh process that we launched a remote doer with.
+pub struct SshDoerProcess {
+    process: std::process::Child,
+    // Once the network socket is set up, we don't need to communicate over stdin/stdout any more,
+    // but we keep these around anyway in case.
+    stdin: LineWriter<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+    stderr_reading_thread: JoinHandle<()>,
+}
 impl Comms {
+    /// Checks if this and the other Comms are both sessions with the same remote doer, in which case
+    /// the doer can access both roots itself (see Command::CopyLocalFile).
+    pub fn shares_doer_with(&self, other: &Comms) -> bool {
+        match (self, other) {
+            (Comms::Remote { sessions: Some(a), .. }, Comms::Remote { sessions: Some(b), .. }) =>
+                a.hostname == b.hostname && a.port == b.port,
+            _ => false,
+        }
+    }
+
+    /// Checks if this is a dest doer which fetches file contents from the source doer itself (see Command::CopyFromSource).
+    pub fn is_connected_to_source(&self) -> bool {
+        matches!(self, Comms::Remote { connected_to_source: true, .. })
+    }
+
     pub fn get_sender(&self) -> &memory_bound_channel::Sender<Command> {
         match self {
             Comms::Local { sender, .. } => &sender,
@@ -143,26 +192,49 @@ impl Comms {
                 let _ = self.send_command(Command::Shutdown);
 
                 // Shutdown the comms cleanly, potentially getting profiling data at the same time
-                if let Comms::Remote { encrypted_comms, mut ssh_process, stdin, stdout, stderr_reading_thread, .. } = self { // This is always true, we just need a way of getting the fields
+                if let Comms::Remote { encrypted_comms, ssh, persistent_doer, .. } = self { // This is always true, we just need a way of getting the fields
                     // Wait for remote doers to send back any profiling data, if enabled
-                    match encrypted_comms.receiver.recv() {
-                        Ok(Response::ProfilingData(x)) => add_remote_profiling(x, _debug_name, profiling_offset),
-                        x => error!("Unexpected response as final message (expected ProfilingData): {:?}", x),
+                    loop {
+                        match encrypted_comms.receiver.recv() {
+                            Ok(Response::ProfilingData(x)) => add_remote_profiling(x, _debug_name, profiling_offset),
+                            // If the sync stopped part way through, there may still be responses to earlier commands
+                            // (e.g. file contents that were requested ahead of time), which aren't needed any more
+                            Ok(r) => {
+                                trace!("Ignoring response while shutting down: {:?}", r);
+                                continue;
+                            }
+                            x => error!("Unexpected response as final message (expected ProfilingData): {:?}", x),
+                        }
+                        break;
                     }
 
                     encrypted_comms.shutdown();
 
-                    // Wait for the ssh process to cleanly shutdown.
-                    // We don't strictly need to do this for most cases, but it's nice to have a clean shutdown.
-                    // We do however need to do this when the doer is printing its memory usage, to make sure that we receive it
-                    // before closing down ourself.
-                    drop(stdin);
-                    drop(stdout);
-                    debug!("Waiting for stderr_reading_thread");
-                    stderr_reading_thread.join().expect("Failed to join stderr_reading_thread");
-                    debug!("Waiting for ssh child process");
-                    let result = ssh_process.wait();
-                    debug!("ssh child process wait result = {:?}", result);
+                    if let Some(SshDoerProcess { mut process, stdin, stdout, stderr_reading_thread }) = ssh {
+                        if persistent_doer.is_some() {
+                            // A persistent doer won't exit, so there's no point waiting for it. Close the ssh connection
+                            // ourselves instead, which leaves the doer running on the remote (it has no terminal to be hung up).
+                            debug!("Closing ssh connection to persistent doer");
+                            let _ = process.kill();
+                        }
+
+                        // Wait for the ssh process to cleanly shutdown.
+                        // We don't strictly need to do this for most cases, but it's nice to have a clean shutdown.
+                        // We do however need to do this when the doer is printing its memory usage, to make sure that we receive it
+                        // before closing down ourself.
+                        drop(stdin);
+                        drop(stdout);
+                        debug!("Waiting for stderr_reading_thread");
+                        stderr_reading_thread.join().expect("Failed to join stderr_reading_thread");
+                        debug!("Waiting for ssh child process");
+                        let result = process.wait();
+                        debug!("ssh child process wait result = {:?}", result);
+                    }
+
+                    // The doer's idle timeout starts now, so record when it will expire
+                    if let Some(p) = persistent_doer {
+                        p.save();
+                    }
                 }
             }
         }
@@ -178,6 +250,10 @@ impl Display for Comms {
 }
 
 // Sets up communications with the given computer, which may be either remote or local (if remote_hostname is empty).
+// A remote doer will accept `num_sessions` sessions in total, so if this is more than one then further sessions can be
+// started with it (see connect_to_shared_doer and connect_dest_to_source).
+// Sessions with a remote doer use up to `session_options.num_streams` TCP connections (see --streams),
+// and compress their messages if `session_options.compression_level` is set (see --compress).
 pub fn setup_comms(
     remote_hostname: &str,
     remote_user: &str,
@@ -185,6 +261,9 @@ pub fn setup_comms(
     identity_file: Option<String>,
     debug_name: String,
     deploy_behaviour: DeployBehaviour,
+    persist_doers: Option<u64>,
+    num_sessions: u32,
+    session_options: SessionOptions,
     progress_bar: &ProgressBar,
 ) -> Result<Comms, String> {
     profile_this!(format!("setup_comms {}", debug_name));
@@ -197,22 +276,23 @@ pub fn setup_comms(
     // Use a separate thread to avoid synchronisation with the Boss (and both Source and Dest may be on same PC, so all three in one process),
     // and for consistency with remote doers.
     if remote_hostname.is_empty() {
-        debug!("Spawning local thread for {} doer", debug_name);
-        let debug_name = "Local ".to_string() + &debug_name + " doer";
-        let (command_sender, command_receiver) = memory_bound_channel::new(BOSS_DOER_CHANNEL_MEMORY_CAPACITY);
-        let (response_sender, response_receiver) = memory_bound_channel::new(BOSS_DOER_CHANNEL_MEMORY_CAPACITY);
-        let thread_builder = thread::Builder::new().name(debug_name.clone());
-        let thread = thread_builder.spawn(move || {
-            doer_thread_running_on_boss(command_receiver, response_sender)
-        }).unwrap();
-        return Ok(Comms::Local {
-            debug_name,
-            thread,
-            sender: command_sender,
-            receiver: response_receiver,
-        });
+        return Ok(spawn_local_doer(debug_name));
     }
 
+    // If we're allowed to use persistent doers, then first try to reconnect to one left running by an earlier run,
+    // which is much quicker than launching a new one. If there's nowhere to record a persistent doer,
+    // then there's no point launching one as we won't be able to find it again.
+    let mut persistent_doer_cache_file = persist_doers.and_then(|_| persistent_doer_cache_file(remote_hostname, remote_user, &debug_name));
+    if let Some(cache_file) = persistent_doer_cache_file.clone() {
+        match connect_to_persistent_doer(remote_hostname, &debug_name, &cache_file, session_options, progress_bar) {
+            PersistentDoerConnection::Connected(c) => return Ok(c),
+            // Leave it recorded for when it's free again, and launch a doer of our own which isn't kept around
+            PersistentDoerConnection::Busy => persistent_doer_cache_file = None,
+            PersistentDoerConnection::Unavailable => (),
+        }
+    }
+    let persist_doers = persistent_doer_cache_file.as_ref().and(persist_doers);
+
     // We first attempt to run a previously-deployed copy of the program on the remote, to save time.
     // If it exists and is a compatible version, we can use that. Otherwise we deploy a new version
     // and try again
@@ -220,7 +300,7 @@ pub fn setup_comms(
         format!("--deploy=force was set")
     }
     else {
-        match launch_doer_via_ssh(remote_hostname, remote_user, &identity_file, remote_port_for_comms, progress_bar) {
+        match launch_doer_via_ssh(remote_hostname, remote_user, &identity_file, remote_port_for_comms, persist_doers, num_sessions, session_options, progress_bar) {
             SshDoerLaunchResult::FailedToRunSsh(e) |
             SshDoerLaunchResult::CommunicationError(e) |
             SshDoerLaunchResult::ExitedUnexpectedly(e) => {
@@ -233,11 +313,14 @@ pub fn setup_comms(
             SshDoerLaunchResult::HandshakeIncompatibleVersion { expected, actual } => {
                 format!("the rjrssync version present on the remote target ({actual}) is not compatible with this version ({expected})") // Will attempt to deploy
             }
-            SshDoerLaunchResult::Success { ssh_process, stdin, stdout, stderr, secret_key, actual_port } =>
-                match connect_to_remote_doer(remote_hostname, debug_name, ssh_process, stdin, stdout, stderr, secret_key, actual_port) {
+            SshDoerLaunchResult::Success { ssh_process, stdin, stdout, stderr, secret_key, actual_port } => {
+                let persistent_doer = persist_doers.zip(persistent_doer_cache_file).map(|(idle_timeout, cache_file)|
+                    PersistentDoer { cache_file, port: actual_port, shared_key: secret_key, idle_timeout });
+                match connect_to_remote_doer(remote_hostname, debug_name, ssh_process, stdin, stdout, stderr, secret_key, actual_port, persistent_doer, num_sessions, session_options) {
                     Ok(c) => return Ok(c),
                     Err(e) => return Err(format!("Failed to connect to remote: {e}")),
                 }
+            }
         }
     };
 
@@ -249,7 +332,7 @@ pub fn setup_comms(
     debug!("Successfully deployed, attempting to run again");
 
     // Check again
-    match launch_doer_via_ssh(remote_hostname, remote_user, &identity_file, remote_port_for_comms, progress_bar) {
+    match launch_doer_via_ssh(remote_hostname, remote_user, &identity_file, remote_port_for_comms, persist_doers, num_sessions, session_options, progress_bar) {
         SshDoerLaunchResult::FailedToRunSsh(e) |
         SshDoerLaunchResult::CommunicationError(e) |
         SshDoerLaunchResult::ExitedUnexpectedly(e) => {
@@ -259,14 +342,35 @@ pub fn setup_comms(
         SshDoerLaunchResult::HandshakeIncompatibleVersion { .. }) => {
             return Err(format!("Failed to launch, even after deployment: {:?}", x));
         }
-        SshDoerLaunchResult::Success { ssh_process, stdin, stdout, stderr, secret_key, actual_port } =>
-            match connect_to_remote_doer(remote_hostname, debug_name, ssh_process, stdin, stdout, stderr, secret_key, actual_port) {
+        SshDoerLaunchResult::Success { ssh_process, stdin, stdout, stderr, secret_key, actual_port } => {
+            let persistent_doer = persist_doers.zip(persistent_doer_cache_file).map(|(idle_timeout, cache_file)|
+                PersistentDoer { cache_file, port: actual_port, shared_key: secret_key, idle_timeout });
+            match connect_to_remote_doer(remote_hostname, debug_name, ssh_process, stdin, stdout, stderr, secret_key, actual_port, persistent_doer, num_sessions, session_options) {
                 Ok(c) => return Ok(c),
                 Err(e) => return Err(format!("Failed to connect to remote: {e}")),
             }
+        }
     };
 }
 
+fn spawn_local_doer(debug_name: String) -> Comms {
+    debug!("Spawning local thread for {} doer", debug_name);
+    let debug_name = "Local ".to_string() + &debug_name + " doer";
+    let (command_sender, command_receiver) = memory_bound_channel::new(BOSS_DOER_CHANNEL_MEMORY_CAPACITY);
+    let (response_sender, response_receiver) = memory_bound_channel::new(BOSS_DOER_CHANNEL_MEMORY_CAPACITY);
+    let thread_builder = thread::Builder::new().name(debug_name.clone());
+    let thread = thread_builder.spawn(move || {
+        doer_thread_running_on_boss(command_receiver, response_sender)
+    }).unwrap();
+    Comms::Local {
+        debug_name,
+        thread,
+        sender: command_sender,
+        receiver: response_receiver,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn connect_to_remote_doer(
     remote_hostname: &str,
     debug_name: String,
@@ -275,7 +379,10 @@ fn connect_to_remote_doer(
     stdout: BufReader<ChildStdout>,
     stderr: BufReader<ChildStderr>,
     secret_key: Key<Aes128Gcm>,
-    actual_port: u16
+    actual_port: u16,
+    persistent_doer: Option<PersistentDoer>,
+    num_sessions: u32,
+    session_options: SessionOptions,
 ) -> Result<Comms, String> {
     // Start a background thread to print out log messages from the remote doer,
     // which it can send over its stderr.
@@ -296,16 +403,34 @@ fn connect_to_remote_doer(
         }
     };
 
+    // Doers which accept more than one session use a different key for each one (see session_key_handshake),
+    // which is also how more than one stream and compression are set up
+    let sessions = (persistent_doer.is_some() || num_sessions > 1 || session_options != SessionOptions::default()).then(|| DoerSessions {
+        hostname: remote_hostname.to_string(), port: actual_port, shared_key: secret_key, options: session_options });
+    let (tcp_connections, session_key, session_options) = match &sessions {
+        None => (vec![tcp_connection], secret_key, SessionOptions::default()),
+        Some(s) => start_session(tcp_connection, s)?,
+    };
+    if let Some(p) = &persistent_doer {
+        p.save();
+    }
+
     let debug_comms_name = "Remote ".to_string() + &debug_name;
     return Ok(Comms::Remote {
         debug_name: debug_comms_name.clone(),
-        ssh_process,
-        stdin,
-        stdout,
-        stderr_reading_thread,
+        ssh: Some(SshDoerProcess {
+            process: ssh_process,
+            stdin,
+            stdout,
+            stderr_reading_thread,
+        }),
+        persistent_doer,
+        sessions,
+        connected_to_source: false,
         encrypted_comms: AsyncEncryptedComms::new(
-            tcp_connection,
-            secret_key,
+            tcp_connections,
+            session_key,
+            session_options.compression_level,
             0, // Nonce counters must be different, so sender and receiver don't reuse
             1,
             ("boss", &debug_comms_name)
@@ -313,6 +438,259 @@ fn connect_to_remote_doer(
     });
 }
 
+/// Details of a doer that was launched with --persist, so will keep running for a while after we're done with it,
+/// which we record in a file so that later runs can connect to it again without going through ssh
+/// (similar to ssh's ControlMaster). The file contains the doer's secret key, so is only readable by the current user.
+pub struct PersistentDoer {
+    cache_file: PathBuf,
+    port: u16,
+    shared_key: Key<Aes128Gcm>,
+    idle_timeout: u64,
+}
+impl PersistentDoer {
+    /// Records this doer in its cache file, with an expiry time based on the idle timeout starting from now.
+    fn save(&self) {
+        let expires = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs() + self.idle_timeout;
+        let contents = json::object! {
+            version: boss_doer_interface::get_version_string(),
+            port: self.port,
+            key: format!("{:x}", self.shared_key),
+            idle_timeout: self.idle_timeout,
+            expires: expires,
+        };
+        if let Err(e) = write_private_file(&self.cache_file, contents.dump().as_bytes()) {
+            // Not fatal, we just won't be able to reuse the doer
+            debug!("Failed to save persistent doer details to {}: {e}", self.cache_file.display());
+        }
+    }
+
+    /// Reads the details of a doer from its cache file, if there is one which hasn't expired and was launched by
+    /// this version of rjrssync (we skip the version handshake when reconnecting).
+    fn load(cache_file: &std::path::Path) -> Option<PersistentDoer> {
+        let contents = json::parse(&std::fs::read_to_string(cache_file).ok()?).ok()?;
+        if contents["version"].as_str()? != boss_doer_interface::get_version_string() {
+            debug!("Persistent doer in {} is a different version", cache_file.display());
+            return None;
+        }
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        if contents["expires"].as_u64()? <= now {
+            debug!("Persistent doer in {} has expired", cache_file.display());
+            return None;
+        }
+        let key_bytes = u128::from_str_radix(contents["key"].as_str()?, 16).ok()?.to_be_bytes();
+        Some(PersistentDoer {
+            cache_file: cache_file.to_path_buf(),
+            port: contents["port"].as_u16()?,
+            shared_key: *Key::<Aes128Gcm>::from_slice(&key_bytes),
+            idle_timeout: contents["idle_timeout"].as_u64()?,
+        })
+    }
+}
+
+/// Gets the file used to record a persistent doer for the given target, or None if there is nowhere suitable.
+/// Source and dest doers are recorded separately, as each doer can only handle one boss at a time.
+fn persistent_doer_cache_file(remote_hostname: &str, remote_user: &str, debug_name: &str) -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        PathBuf::from(std::env::var_os("LOCALAPPDATA")?).join("rjrssync")
+    } else {
+        // Prefer the runtime dir, as this is private to the user and cleared on logout
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(d) => PathBuf::from(d).join("rjrssync"),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".cache").join("rjrssync"),
+        }
+    };
+    let user = if remote_user.is_empty() { "default" } else { remote_user };
+    let name: String = format!("{debug_name}-{user}@{remote_hostname}").chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.@".contains(c) { c } else { '_' }).collect();
+    Some(dir.join("persistent-doers").join(name + ".json"))
+}
+
+/// Writes a file that only the current user can read, replacing it atomically so that a concurrent
+/// run never sees it half-written.
+fn write_private_file(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    let dir = path.parent().expect("Cache file has no parent");
+    let mut dir_builder = std::fs::DirBuilder::new();
+    dir_builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut dir_builder, 0o700);
+    dir_builder.create(dir)?;
+
+    // The mode is only applied when the file is created, so make sure that we never reuse one left behind
+    // (e.g. by an earlier run that was killed part way), which might be readable by others
+    let temp_path = path.with_extension("tmp");
+    match std::fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&temp_path)?.write_all(contents)?;
+    std::fs::rename(&temp_path, path)
+}
+
+#[allow(clippy::large_enum_variant)]
+enum PersistentDoerConnection {
+    Connected(Comms),
+    /// The doer is serving another boss, but is still there for later runs.
+    Busy,
+    /// There isn't a doer, or we failed to connect to it (e.g. it has exited).
+    Unavailable,
+}
+
+/// Attempts to start a new session with a persistent doer left running by an earlier run.
+/// If this fails, the caller should launch a new doer as normal.
+fn connect_to_persistent_doer(remote_hostname: &str, debug_name: &str, cache_file: &std::path::Path,
+    session_options: SessionOptions, progress_bar: &ProgressBar) -> PersistentDoerConnection
+{
+    profile_this!();
+    let persistent_doer = match PersistentDoer::load(cache_file) {
+        Some(p) => p,
+        None => return PersistentDoerConnection::Unavailable,
+    };
+    progress_bar.set_message("Connecting to persistent doer...");
+    let sessions = DoerSessions {
+        hostname: remote_hostname.to_string(), port: persistent_doer.port, shared_key: persistent_doer.shared_key,
+        options: session_options };
+
+    let result = (|| {
+        let addr = (remote_hostname, persistent_doer.port).to_socket_addrs().map_err(|e| e.to_string())?
+            .next().ok_or("Failed to resolve address")?;
+        debug!("Connecting to persistent doer at {:?}", addr);
+        let tcp_connection = TcpStream::connect_timeout(&addr, Duration::from_secs(2)).map_err(|e| e.to_string())?;
+        start_session(tcp_connection, &sessions)
+    })();
+    let (tcp_connections, session_key, session_options) = match result {
+        Ok(x) => x,
+        Err(e) if e == DOER_BUSY => {
+            debug!("Persistent doer is busy with another boss, will launch a new one");
+            return PersistentDoerConnection::Busy;
+        }
+        Err(e) => {
+            debug!("Failed to connect to persistent doer, will launch a new one: {e}");
+            let _ = std::fs::remove_file(cache_file);
+            return PersistentDoerConnection::Unavailable;
+        }
+    };
+
+    let debug_comms_name = "Remote ".to_string() + debug_name;
+    PersistentDoerConnection::Connected(Comms::Remote {
+        debug_name: debug_comms_name.clone(),
+        ssh: None,
+        persistent_doer: Some(persistent_doer),
+        sessions: Some(sessions),
+        connected_to_source: false,
+        encrypted_comms: AsyncEncryptedComms::new(
+            tcp_connections,
+            session_key,
+            session_options.compression_level,
+            0, // Nonce counters must be different, so sender and receiver don't reuse
+            1,
+            ("boss", &debug_comms_name)
+        )
+    })
+}
+
+/// Starts another session with the same doer as the given Comms, so that it can be used at the same time
+/// (see `parallel` in the spec file). For local targets this is simply another thread, and for remote targets
+/// the doer must have been set up with enough sessions (see setup_comms).
+pub fn start_another_session(comms: &Comms, debug_name: String) -> Result<Comms, String> {
+    match comms {
+        Comms::Local { .. } => Ok(spawn_local_doer(debug_name)),
+        Comms::Remote { .. } => connect_to_shared_doer(comms, debug_name),
+    }
+}
+
+/// Starts another session with the same remote doer as the given Comms, rather than launching a new one.
+/// This is used when the source and dest are on the same computer and user, so that one doer can serve both,
+/// which must have been set up with more than one session (see setup_comms).
+pub fn connect_to_shared_doer(comms: &Comms, debug_name: String) -> Result<Comms, String> {
+    profile_this!();
+    let sessions = match comms {
+        Comms::Remote { sessions: Some(s), .. } => s.clone(),
+        _ => return Err(format!("{comms} can't be shared")),
+    };
+
+    let addr = (sessions.hostname.as_str(), sessions.port);
+    debug!("Connecting to shared doer over network at {:?}", addr);
+    let tcp_connection = TcpStream::connect(addr).map_err(|e| format!("Failed to connect to network address {:?}: {}", addr, e))?;
+    let (tcp_connections, session_key, session_options) = start_session(tcp_connection, &sessions)?;
+
+    let debug_comms_name = "Remote ".to_string() + &debug_name;
+    Ok(Comms::Remote {
+        debug_name: debug_comms_name.clone(),
+        ssh: None, // Owned by the other Comms
+        persistent_doer: None, // Recorded by the other Comms
+        sessions: Some(sessions),
+        connected_to_source: false,
+        encrypted_comms: AsyncEncryptedComms::new(
+            tcp_connections,
+            session_key,
+            session_options.compression_level,
+            0, // Nonce counters must be different, so sender and receiver don't reuse
+            1,
+            ("boss", &debug_comms_name)
+        )
+    })
+}
+
+/// Starts a new session on the given connection to a doer that accepts more than one, and opens the other streams
+/// for it (see AsyncEncryptedComms). The doer might allow fewer streams than we ask for, e.g. if it's a persistent
+/// doer that was launched by an earlier run, and similarly for compression. Returns the connections for all the streams,
+/// along with the session's key and the options that were agreed.
+fn start_session(mut tcp_connection: TcpStream, sessions: &DoerSessions)
+    -> Result<(Vec<TcpStream>, Key<Aes128Gcm>, SessionOptions), String>
+{
+    let (session_key, options) = session_key_handshake(&mut tcp_connection, &sessions.shared_key, &BOSS_ID, sessions.options)?;
+    let num_streams = options.num_streams;
+    if num_streams < sessions.options.num_streams {
+        debug!("Doer only allows {num_streams} stream(s) per session");
+    }
+    if options.compression_level < sessions.options.compression_level {
+        debug!("Doer only allows compression level {} per session", options.compression_level);
+    }
+    let mut tcp_connections = vec![tcp_connection];
+    let addr = (sessions.hostname.as_str(), sessions.port);
+    for i in 1..num_streams {
+        let mut tcp_connection = TcpStream::connect(addr).map_err(|e| format!("Failed to connect to network address {:?}: {}", addr, e))?;
+        join_session(&mut tcp_connection, &session_key, i)?;
+        tcp_connections.push(tcp_connection);
+    }
+    Ok((tcp_connections, session_key, options))
+}
+
+/// Tells the dest doer to connect directly to the source doer, so that it can fetch file contents itself rather than
+/// them being sent via us (see --direct-transfer). The source doer must have been set up with more than one session (see setup_comms).
+/// The dest doer connects to the given hostname, or the one that we used if None (it might need to use a different address,
+/// e.g. if the two are on a private network).
+pub fn connect_dest_to_source(src_comms: &Comms, dest_comms: &mut Comms, hostname: Option<&str>) -> Result<(), String> {
+    profile_this!();
+    let sessions = match src_comms {
+        Comms::Remote { sessions: Some(s), .. } => s.clone(),
+        _ => return Err(format!("{src_comms} can't accept a connection from the dest")),
+    };
+
+    // The source doer's key stays with us, and the dest doer gets one that is only good for this connection
+    let (transfer_id, transfer_key) = make_transfer_key(&sessions.shared_key);
+    dest_comms.send_command(Command::ConnectToSource {
+        hostname: hostname.unwrap_or(&sessions.hostname).to_string(),
+        port: sessions.port,
+        transfer_id,
+        key: transfer_key.into(),
+    })?;
+    match dest_comms.receive_response()? {
+        Response::ConnectedToSource => (),
+        Response::Error(e) => return Err(e),
+        x => return Err(format!("Unexpected response (expected ConnectedToSource): {:?}", x)),
+    }
+
+    if let Comms::Remote { connected_to_source, .. } = dest_comms {
+        *connected_to_source = true;
+    }
+    Ok(())
+}
+
 fn remote_doer_logging_thread(mut stderr: BufReader<ChildStderr>, debug_name: String) {
     loop {
         let mut l: String = "".to_string();
@@ -471,9 +849,10 @@ fn output_reader_thread_main(
 /// listening for an incoming network connection on the requested port. It is also provided
 /// with a randomly generated secret shared key for encryption, which is returned to the caller
 /// for setting up encrypted communication over the network connection.
+#[allow(clippy::too_many_arguments)]
 fn launch_doer_via_ssh(remote_hostname: &str, remote_user: &str,
     ssh_identity: &Option<String>,
-    remote_port_for_comms: Option<u16>, progress_bar: &ProgressBar,
+    remote_port_for_comms: Option<u16>, persist: Option<u64>, num_sessions: u32, session_options: SessionOptions, progress_bar: &ProgressBar,
 ) -> SshDoerLaunchResult
 {
     profile_this!();
@@ -506,6 +885,24 @@ fn launch_doer_via_ssh(remote_hostname: &str, remote_user: &str,
         None => "".to_string()
     };
 
+    // Ask the remote doer to stay running for later runs, if requested
+    let persist_arg = match persist {
+        Some(p) => format!(" --persist {p}"),
+        None => "".to_string()
+    };
+
+    // Let the remote doer know how many sessions it will be serving (e.g. both the source and dest)
+    let sessions_arg = if num_sessions > 1 { format!(" --sessions {num_sessions}") } else { "".to_string() };
+
+    // Let the remote doer know how many streams each session may use
+    let streams_arg = match session_options.num_streams {
+        n if n > 1 => format!(" --streams {n}"),
+        _ => "".to_string()
+    };
+
+    // Let the remote doer know that it may compress messages
+    let compress_arg = if session_options.compression_level > 0 { " --compress" } else { "" };
+
     // Forward memory dumping flag to the remote doer
     let memory_dump_arg = match std::env::var("RJRSSYNC_TEST_DUMP_MEMORY_USAGE") {
         Ok(_) => format!(" --dump-memory-usage"),
@@ -514,7 +911,7 @@ fn launch_doer_via_ssh(remote_hostname: &str, remote_user: &str,
 
     // Note we don't cd, so that relative paths for the path specified by the user on the remote
     // will be correct (relative to their ssh default dir, e.g. home dir)
-    let doer_args = format!("--doer {} {} {}", log_arg, port_arg, memory_dump_arg);
+    let doer_args = format!("--doer {} {} {} {} {} {} {}", log_arg, port_arg, persist_arg, sessions_arg, streams_arg, compress_arg, memory_dump_arg);
     // Try launching using both Unix and Windows paths, as we don't know what the remote system is
     // We run a command that doesn't print out anything on both Windows and Linux, so we don't pollute the output
     // (we show all output from ssh, in case it contains prompts etc. that are useful/required for the user to see).
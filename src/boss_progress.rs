@@ -32,6 +32,8 @@ struct ProgressValues {
     copy: u32,
     /// Number of bytes of file copies.
     copy_bytes: u64,
+    /// Number of files checked after being copied (see --verify).
+    verify: u32,
 }
 impl ProgressValues {
     /// Creates a set of ProgressValues to represent the copying of a single entry.
@@ -85,6 +87,15 @@ impl ProgressValues {
         }
     }
 
+    /// Creates a set of ProgressValues to represent the verification of a single copied file.
+    fn for_verify(size: u64) -> Self {
+        ProgressValues {
+            work: std::cmp::max(size, MIN_FILE_SIZE), // Similar to copying, as the whole file needs reading on both sides
+            verify: 1,
+            ..Default::default()
+        }
+    }
+
     /// Creates a set of ProgressValues to represent the deletion of a single entry.
     fn for_delete(_e: &EntryDetails) -> Self {
         ProgressValues {
@@ -100,6 +111,7 @@ impl AddAssign for ProgressValues {
         self.delete += rhs.delete;
         self.copy += rhs.copy;
         self.copy_bytes += rhs.copy_bytes;
+        self.verify += rhs.verify;
     }
 }
 impl SubAssign for ProgressValues {
@@ -108,12 +120,14 @@ impl SubAssign for ProgressValues {
         self.delete -= rhs.delete;
         self.copy -= rhs.copy;
         self.copy_bytes -= rhs.copy_bytes;
+        self.verify -= rhs.verify;
     }
 }
 
 /// State to communicate with the background thread.
 struct BarState {
     is_deleting: bool,
+    is_verifying: bool,
     completed: ProgressValues,
     total: ProgressValues,
     current_entry: Option<RootRelativePath>,
@@ -166,14 +180,21 @@ pub struct Progress<'a> {
     /// The time at which we received a progress marker from the dest doer showing that it had finished
     /// the deletes and had moved on to the copies.
     first_copy_time: Option<Instant>,
+    /// The time at which we received a progress marker from the dest doer showing that it had finished
+    /// the copies and had moved on to verifying them (see --verify).
+    first_verify_time: Option<Instant>,
+    /// Whether the most recent progress marker from the dest doer was for verifying. This can go back to false
+    /// if files that failed verification are copied again.
+    is_verifying: bool,
 
     /// Lists of source and dest paths, so that we can match up progress markers
     /// to filenames to display on the progress bar.
     to_copy_paths: Vec<RootRelativePath>,
     to_delete_paths: Vec<RootRelativePath>,
+    to_verify_paths: Vec<RootRelativePath>,
 }
 impl<'a> Progress<'a> {
-    pub fn new(actions: &Actions, progress_bar: &'a ProgressBar, mut detailed: bool) -> Self {
+    pub fn new(actions: &Actions, progress_bar: &'a ProgressBar, mut detailed: bool, verify: bool) -> Self {
         if progress_bar.is_hidden() {
             // No point doing extra work if the progress bar isn't visible anyway (e.g. unattended terminal)
             detailed = false;
@@ -188,9 +209,14 @@ impl<'a> Progress<'a> {
             to_delete_paths.push(p.clone());
         }
         let mut to_copy_paths = vec![];
+        let mut to_verify_paths = vec![];
         for (p, (c, _)) in actions.to_copy.iter() {
             total += ProgressValues::for_copy(c);
             to_copy_paths.push(p.clone());
+            if let (true, EntryDetails::File { size, .. }) = (verify, c) {
+                total += ProgressValues::for_verify(*size);
+                to_verify_paths.push(p.clone());
+            }
         }
 
         // Set up the UI element
@@ -226,8 +252,11 @@ impl<'a> Progress<'a> {
             new_bar_state,
             last_progress_marker: 0,
             first_copy_time: None,
+            first_verify_time: None,
+            is_verifying: false,
             to_delete_paths,
             to_copy_paths,
+            to_verify_paths,
         }
     }
 
@@ -262,7 +291,7 @@ impl<'a> Progress<'a> {
                     num_entries_deleted: self.sent.delete,
                 },
             }
-        } else {
+        } else if self.sent.copy < self.total.copy || self.total.verify == 0 {
             // Finished sending deletes, but still sending copies
             // Note that we might have actually finished sending all the copies too, and so we are Done,
             // but we don't return that here otherwise we might end up with two Done markers, which can
@@ -274,6 +303,14 @@ impl<'a> Progress<'a> {
                     num_bytes_copied: self.sent.copy_bytes,
                 }
             }
+        } else {
+            // Finished sending copies, and now verifying them. As above, we don't return Done here.
+            ProgressMarker {
+                completed_work: self.sent.work,
+                phase: ProgressPhase::Verifying {
+                    num_files_verified: self.sent.verify,
+                }
+            }
         }
     }
 
@@ -290,6 +327,29 @@ impl<'a> Progress<'a> {
         self.sent += ProgressValues::for_copy_partial(chunk_start, chunk_size, file_size);
     }
 
+    /// Called when the dest doer has written part of a file that it's fetching from the source doer itself (see --direct-transfer),
+    /// so that the progress bar moves during large files rather than all at once when they're done. This must not be the final
+    /// chunk, which is covered by the next marker from the dest doer (which includes the whole file).
+    pub fn copy_completed_partial(&mut self, chunk_start: u64, chunk_size: u64, file_size: u64) {
+        debug_assert!(chunk_start + chunk_size < file_size);
+        self.completed += ProgressValues::for_copy_partial(chunk_start, chunk_size, file_size);
+        self.update_bar_limited();
+    }
+
+    /// Increases the sent counters to account for the given file being verified.
+    pub fn verify_sent(&mut self, size: u64) {
+        self.sent += ProgressValues::for_verify(size);
+    }
+
+    /// Increases the total amount of work to account for the given file being copied and verified again,
+    /// because it failed verification the first time.
+    pub fn add_retry(&mut self, path: &RootRelativePath, size: u64) {
+        self.total += ProgressValues::for_copy_partial(0, size, size);
+        self.total += ProgressValues::for_verify(size);
+        self.to_copy_paths.push(path.clone());
+        self.to_verify_paths.push(path.clone());
+    }
+
     /// Called when all work has been sent to the dest doer.
     /// Returns a ProgressMarker that should be sent to the dest doer to mark this point of progress.
     pub fn all_work_sent(&mut self) -> ProgressMarker {
@@ -321,10 +381,22 @@ impl<'a> Progress<'a> {
 
                 self.completed.copy = num_entries_copied;
                 self.completed.copy_bytes = num_bytes_copied;
+                self.is_verifying = false;
 
                 // Update the progress bar based on the progress that the dest doer has made.
                 self.update_bar_limited();
             }
+            ProgressPhase::Verifying { num_files_verified } => {
+                // Similar to Copying, the first marker tells us that all the copies have finished
+                if self.first_verify_time.is_none() && num_files_verified == 0 {
+                    self.first_verify_time = Some(Instant::now());
+                }
+
+                self.completed.verify = num_files_verified;
+                self.is_verifying = true;
+
+                self.update_bar_limited();
+            }
             ProgressPhase::Done => {
                 self.bar.finish_and_clear();
             }
@@ -346,12 +418,15 @@ impl<'a> Progress<'a> {
         let current_entry =
             if self.first_copy_time.is_none() {
                 self.to_delete_paths.get(self.completed.delete as usize).cloned()
+            } else if self.is_verifying {
+                self.to_verify_paths.get(self.completed.verify as usize).cloned()
             } else {
                 self.to_copy_paths.get(self.completed.copy as usize).cloned()
             };
 
         let new_state = Box::new(BarState {
             is_deleting: self.first_copy_time.is_none(),
+            is_verifying: self.is_verifying,
             completed: self.completed.clone(),
             total: self.total.clone(),
             current_entry,
@@ -365,6 +440,10 @@ impl<'a> Progress<'a> {
         self.first_copy_time
     }
 
+    pub fn get_first_verify_time(&self) -> Option<Instant> {
+        self.first_verify_time
+    }
+
     /// If we update the progress bar too often then the performance cost is too high.
     /// Even though the ProgressBar is supposed to have some kind of rate limiter/framerate to avoid
     /// this, this wasn't enough, especially when we were calling set_length() a lot which happened
@@ -398,6 +477,11 @@ impl<'a> Progress<'a> {
                     format!("Deleting {:>7}/{}",
                         HumanCount(new_state.completed.delete as u64).to_string(),
                         HumanCount(new_state.total.delete as u64).to_string())
+                } else if new_state.is_verifying {
+                    // The doer is now calculating hashes of the copied files, to compare against the source.
+                    format!("Verifying {:>6}/{}",
+                        HumanCount(new_state.completed.verify as u64).to_string(),
+                        HumanCount(new_state.total.verify as u64).to_string())
                 } else {
                     // The doer is now copying entries (i.e. writing them to disk), and will be some amount behind the boss
                     // which may have queued up more copies.
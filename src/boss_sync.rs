@@ -1,12 +1,13 @@
 use std::{
-    cmp::Ordering, time::{Instant, SystemTime, Duration},
+    cmp::Ordering, time::{Instant, SystemTime, Duration}, collections::{HashSet, HashMap, VecDeque}, path::Path,
 };
 
 use indicatif::{HumanCount, HumanBytes, ProgressBar, ProgressStyle};
-use log::{debug, info, trace};
+use log::{debug, error, info, trace, warn};
 use regex::{RegexSet};
+use serde::{Serialize, Deserialize};
 
-use crate::{*, boss_progress::{Progress}, histogram::FileSizeHistogram, root_relative_path::{RootRelativePath, PrettyPath, Side}, boss_doer_interface::{ProgressPhase, EntryDetails, Response, Command, Filters, FilterKind}, ordered_map::OrderedMap};
+use crate::{*, windows_names::{is_valid_windows_name, escape_windows_name, unescape_windows_name}, boss_progress::{Progress}, histogram::FileSizeHistogram, root_relative_path::{RootRelativePath, PrettyPath, Side}, boss_doer_interface::{ProgressPhase, EntryDetails, SymlinkKind, SymlinkTarget, Response, Command, Filters, FilterKind, SmallFile, SmallFileContent, SMALL_FILE_MAX_SIZE, MAX_FILES_PER_BATCH}, ordered_map::OrderedMap, bandwidth_limit::BandwidthLimiter};
 
 #[derive(Default)]
 struct Stats {
@@ -23,6 +24,9 @@ struct Stats {
     pub num_dest_symlinks: u32,
     pub dest_total_bytes: u64,
 
+    pub query_start_time: Option<Instant>,
+    pub query_end_time: Option<Instant>,
+
     pub delete_start_time: Option<Instant>,
     pub num_files_deleted: u32,
     pub num_bytes_deleted: u64,
@@ -37,6 +41,19 @@ struct Stats {
     pub num_symlinks_copied: u32,
     pub copied_file_size_hist: FileSizeHistogram,
     pub copy_end_time: Option<Instant>,
+
+    pub verify_start_time: Option<Instant>,
+    pub num_files_verified: u32,
+    pub num_bytes_verified: u64,
+    /// Files which failed verification and so were copied again.
+    pub num_files_recopied: u32,
+    pub verify_end_time: Option<Instant>,
+
+    /// File contents sent to the dest via us, which are subject to --bwlimit.
+    pub relay_start_time: Option<Instant>,
+    pub num_bytes_relayed: u64,
+    /// Time spent waiting to stay within --bwlimit.
+    pub bwlimit_wait: Duration,
 }
 
 /// Validates if a trailing slash was provided incorrectly on the given entry.
@@ -67,8 +84,9 @@ fn validate_trailing_slash(root_path: &str, entry_details: &EntryDetails) -> Res
 /// until it finds a response with a progress marker that shows the doer is finished.
 /// If not set, this function won't block and will return once it's processed all pending responses from the doer.
 /// If an error is encountered though, it will return rather than blocking.
+/// If failed_entries is provided (see --keep-going), then errors for individual entries are recorded there instead.
 fn process_dest_responses(dest_comms: &mut Comms, progress: &mut Progress,
-    mut block_until_done: bool) -> Result<(), String>
+    mut block_until_done: bool, mut failed_entries: Option<&mut FailedEntries>) -> Result<(), String>
 {
     // To make the rest of this function consistent for both cases of block_until_done,
     // this helper function will block or not as appropriate.
@@ -87,27 +105,32 @@ fn process_dest_responses(dest_comms: &mut Comms, progress: &mut Progress,
 
     let mut errors = vec![]; // There might be multiple errors reported before we get round to checking for them
     while let Some(x) = next_fn(block_until_done) {
-        match x {
-            Ok(Response::Error(e)) => {
+        match (x, failed_entries.as_deref_mut()) {
+            (Ok(Response::EntryError { path, message, not_found: _ }), Some(f)) => {
+                record_failed_entry(f, path, message);
+            }
+            (Ok(Response::Error(e)) | Ok(Response::EntryError { message: e, .. }), _) => {
                 errors.push(e);
                 // If an error was encountered, don't block - just process the remaining messages to see if there
                 // were any other errors to report, then return the error(s)
                 block_until_done = false;
             }
-            Ok(Response::Marker(m)) => {
+            (Ok(Response::Marker(m)), _) => {
                 // Update the progress bar based on the progress that the dest doer has made.
                 progress.update_completed(&m);
                 if m.phase == ProgressPhase::Done {
                     break;
                 }
             }
-            Err(e) => {
+            (Ok(Response::ChunkFetched { chunk_start, chunk_size, file_size }), _) =>
+                progress.copy_completed_partial(chunk_start, chunk_size, file_size),
+            (Err(e), _) => {
                 // Communications error - return immediately as we won't be able to receive any more messages and doing
                 // so might lead to an infinite loop
                 errors.push(format!("{}", e));
                 break;
             }
-            _ => errors.push(format!("Unexpected response (expected Error or Marker): {:?}", x)),
+            (x, _) => errors.push(format!("Unexpected response (expected Error or Marker): {:?}", x)),
         }
     }
     if errors.is_empty() {
@@ -117,6 +140,18 @@ fn process_dest_responses(dest_comms: &mut Comms, progress: &mut Progress,
     }
 }
 
+/// Errors for individual entries which didn't stop the sync (see --keep-going), keyed by the path
+/// that the doer reported the error for.
+type FailedEntries = OrderedMap<RootRelativePath, String>;
+
+fn record_failed_entry(failed_entries: &mut FailedEntries, path: RootRelativePath, message: String) {
+    // Only the first error for each entry is kept, as any later ones are likely to be a consequence of it
+    // (e.g. each chunk of a file that can't be written)
+    if failed_entries.lookup(&path).is_none() {
+        failed_entries.add(path, message);
+    }
+}
+
 /// A bunch of fields related to the current sync that would otherwise need to be passed
 /// around as individual variables.
 struct SyncContext<'a> {
@@ -130,9 +165,57 @@ struct SyncContext<'a> {
     files_same_time_behaviour: DestFileUpdateBehaviour,
     dest_entry_needs_deleting_behaviour: DestEntryNeedsDeletingBehaviour,
     dest_root_needs_deleting_behaviour: DestRootNeedsDeletingBehaviour,
+    delete_excluded: bool,
+    /// Logs the reason for each entry excluded by the filters (on --dry-run --verbose).
+    trace_filters: bool,
+    /// Reported by the dest doer. If false, then source entries that differ only in case
+    /// would collide on the dest.
+    dest_case_sensitive: bool,
+    /// See --normalize-unicode. When this is set, entries are compared (and stored in the actions lists)
+    /// by their normalized path, and these maps are used to find the path actually on disk.
+    normalize_unicode: bool,
+    /// Only contains source entries whose path is different once normalized.
+    src_names: HashMap<RootRelativePath, RootRelativePath>,
+    /// Contains all dest entries (not just those which are different once normalized), so that we can tell
+    /// which name to use when creating or updating entries (see dest_path()).
+    dest_names: HashMap<RootRelativePath, RootRelativePath>,
+    windows_incompatible_name_behaviour: WindowsIncompatibleNameBehaviour,
+    /// Source entries whose names can't be used on the dest, to be reported all together once we've found them all.
+    windows_incompatible_name_errors: Vec<String>,
+    /// Source folders which are being skipped as their name can't be used on the dest, so that
+    /// we can skip their contents too.
+    windows_incompatible_folders: HashSet<RootRelativePath>,
+    outputs: &'a mut SyncOutputs,
     progress_bar: &'a ProgressBar,
     show_progress: bool,
     show_stats: bool,
+    /// Print a line for each action as it is done (see --itemize).
+    itemize: bool,
+    /// Check that copied files match the source once they have been copied (see --verify).
+    verify: bool,
+    /// Set if the src and dest are being served by the same doer, which can then copy files itself
+    /// rather than sending their contents via us.
+    local_copy: bool,
+    /// Set if the dest doer fetches file contents directly from the source doer (see --direct-transfer).
+    direct_transfer: bool,
+    /// Limits the rate that file contents are sent to the dest via us (see --bwlimit).
+    bwlimit: Option<&'a BandwidthLimiter>,
+    /// Only present for --keep-going, in which case errors for individual entries are recorded here
+    /// rather than stopping the sync.
+    failed_entries: Option<FailedEntries>,
+    /// Files (and their sizes) whose contents will be fetched from the source, in the order they will be copied,
+    /// which haven't been requested yet (see request_file_contents).
+    files_to_fetch: VecDeque<(RootRelativePath, u64)>,
+    /// Files whose contents have been requested from the source but not yet fully received, in the order requested.
+    /// The source sends them back in this same order.
+    files_being_fetched: VecDeque<FileFetch>,
+    /// Contents of small files which have been received from the source (see GetFileContents), but not copied yet.
+    small_files_fetched: VecDeque<(RootRelativePath, SmallFileContent)>,
+    /// Small files waiting to be sent to the dest together (see CreateOrUpdateFiles), along with their sizes.
+    small_files_to_write: Vec<(SmallFile, u64)>,
+    /// If set, only these entries (and the contents of folders) are synced, rather than everything
+    /// in the root. These are what changed on the source, when watching for changes (see --watch).
+    only_paths: Option<Vec<RootRelativePath>>,
     src_root: String,
     dest_root: String,
 
@@ -164,6 +247,55 @@ impl<'a> SyncContext<'a> {
         PrettyPath { side: Side::Dest, dir_separator: self.dest_dir_separator.unwrap_or('/'), root: &self.dest_root, path, kind }
     }
 
+    /// Gets the path of a source entry as it is on disk, from the path that we use to refer to it
+    /// (which might have been normalized, see --normalize-unicode).
+    fn src_path(&self, path: &RootRelativePath) -> RootRelativePath {
+        self.src_names.get(path).unwrap_or(path).clone()
+    }
+
+    /// Gets the path to use on the dest for the entry that we refer to by the given path
+    /// (which might have been normalized, see --normalize-unicode).
+    /// If the entry is already on the dest, we keep its existing name so that we update it rather than
+    /// creating another entry alongside it. Otherwise the name comes from the source, inside whatever
+    /// name its parent folder has on the dest.
+    fn dest_path(&self, path: &RootRelativePath) -> RootRelativePath {
+        if !self.normalize_unicode {
+            return path.clone();
+        }
+        if let Some(d) = self.dest_names.get(path) {
+            return d.clone();
+        }
+        match path.parent() {
+            None => path.clone(),
+            Some(parent) => self.translate_src_path(&self.src_path(path)).with_parent(&self.dest_path(&parent)),
+        }
+    }
+
+    /// Converts the path of a source entry (as it is on disk) to the path that it should have on the dest.
+    /// These are the same, unless we're escaping names for Windows (see --windows-incompatible-name).
+    fn translate_src_path(&self, path: &RootRelativePath) -> RootRelativePath {
+        if self.windows_incompatible_name_behaviour != WindowsIncompatibleNameBehaviour::Escape {
+            path.clone()
+        } else if self.dest_is_windows() {
+            path.map_names(escape_windows_name)
+        } else if self.src_is_windows() {
+            // Restore names which were escaped when they were previously copied to Windows
+            path.map_names(unescape_windows_name)
+        } else {
+            path.clone()
+        }
+    }
+
+    /// Whether the source is a Windows platform, based on the dir separator that it reported.
+    fn src_is_windows(&self) -> bool {
+        self.src_dir_separator == Some('\\')
+    }
+
+    /// Whether the dest is a Windows platform, based on the dir separator that it reported.
+    fn dest_is_windows(&self) -> bool {
+        self.dest_dir_separator == Some('\\')
+    }
+
     fn send_progress_marker_limited(&self, progress: &mut Progress) -> Result<(), String> {
         if let Some(m) = progress.get_progress_marker_limited() {
             self.dest_comms.send_command(Command::Marker(m))
@@ -171,57 +303,215 @@ impl<'a> SyncContext<'a> {
             Ok(())
         }
     }
+
+    /// Called before sending file contents to the dest, to wait if needed to stay within --bwlimit.
+    fn limit_bandwidth(&mut self, bytes: u64) {
+        self.stats.relay_start_time.get_or_insert_with(Instant::now);
+        self.stats.num_bytes_relayed += bytes;
+        if let Some(l) = self.bwlimit {
+            self.stats.bwlimit_wait += l.take(bytes);
+        }
+    }
 }
 
+/// Records the actions of each sync, for --plan-json and/or --write-plan, and a summary of what happened
+/// for --report. Each sync that is performed appends to whichever of these are present.
+#[derive(Default)]
+pub struct SyncOutputs {
+    pub json: Option<json::JsonValue>,
+    pub saved: Option<Vec<SavedSync>>,
+    pub report: Option<json::JsonValue>,
+}
+impl SyncOutputs {
+    /// Makes an empty SyncOutputs that records the same things as this one, for a sync to record into separately
+    /// and then be appended back afterwards (see --parallel).
+    pub fn new_like(&self) -> SyncOutputs {
+        SyncOutputs {
+            json: self.json.as_ref().map(|_| json::JsonValue::new_array()),
+            saved: self.saved.as_ref().map(|_| vec![]),
+            report: self.report.as_ref().map(|_| json::JsonValue::new_array()),
+        }
+    }
+
+    pub fn append(&mut self, other: SyncOutputs) {
+        fn append_json(a: &mut Option<json::JsonValue>, b: Option<json::JsonValue>) {
+            if let (Some(a), Some(b)) = (a, b) {
+                for x in b.members() {
+                    a.push(x.clone()).expect("Should be an array");
+                }
+            }
+        }
+        append_json(&mut self.json, other.json);
+        append_json(&mut self.report, other.report);
+        if let (Some(a), Some(b)) = (&mut self.saved, other.saved) {
+            a.extend(b);
+        }
+    }
+}
+
+/// Returns the number of entries which failed but didn't stop the sync (only possible with --keep-going).
+#[allow(clippy::too_many_arguments)]
 pub fn sync(
     sync_spec: &SyncSpec,
+    only_paths: Option<&[RootRelativePath]>,
+    dry_run: bool,
+    outputs: &mut SyncOutputs,
+    progress_bar: &ProgressBar,
+    show_progress: bool,
+    show_stats: bool,
+    itemize: bool,
+    verify: bool,
+    keep_going: bool,
+    bwlimit: Option<&BandwidthLimiter>,
+    src_comms: &mut Comms,
+    dest_comms: &mut Comms,
+) -> Result<usize, String> {
+    let mut context = match make_context(sync_spec, dry_run, outputs, progress_bar, show_progress, show_stats, itemize, verify, keep_going, bwlimit, src_comms, dest_comms) {
+        Ok(c) => c,
+        Err(e) => {
+            add_error_to_report(outputs, sync_spec, dry_run, &e);
+            return Err(e);
+        }
+    };
+    context.only_paths = only_paths.map(|p| p.to_vec());
+    // Call into separate function, to avoid the original function parameters being mis-used instead
+    // of the context fields
+    let result = sync_impl(&mut context);
+    add_to_report(&mut context, &result);
+    result
+}
+
+/// Performs the actions previously saved by --write-plan, after checking that none of the entries involved
+/// have changed since. Nothing is queried apart from the entries in the plan, and the user isn't prompted
+/// again as they will have already confirmed the actions when making the plan.
+#[allow(clippy::too_many_arguments)]
+pub fn apply_plan(
+    saved_sync: &SavedSync,
     dry_run: bool,
+    outputs: &mut SyncOutputs,
     progress_bar: &ProgressBar,
     show_progress: bool,
     show_stats: bool,
+    itemize: bool,
+    verify: bool,
+    keep_going: bool,
+    bwlimit: Option<&BandwidthLimiter>,
     src_comms: &mut Comms,
     dest_comms: &mut Comms,
-) -> Result<(), String> {
+) -> Result<usize, String> {
+    let sync_spec = SyncSpec { src: saved_sync.src.clone(), dest: saved_sync.dest.clone(), ..Default::default() };
+    let mut context = match make_context(&sync_spec, dry_run, outputs, progress_bar, show_progress, show_stats, itemize, verify, keep_going, bwlimit, src_comms, dest_comms) {
+        Ok(c) => c,
+        Err(e) => {
+            add_error_to_report(outputs, &sync_spec, dry_run, &e);
+            return Err(e);
+        }
+    };
+    let result = apply_plan_impl(&mut context, saved_sync);
+    add_to_report(&mut context, &result);
+    result
+}
+
+/// Asks the source doer to start watching the source roots of all the syncs for changes (see --watch).
+pub fn start_watching(src_comms: &mut Comms, roots: Vec<String>) -> Result<(), String> {
+    src_comms.send_command(Command::Watch { roots })?;
+    match src_comms.receive_response()? {
+        Response::Watching => Ok(()),
+        Response::Error(e) => Err(e),
+        r => Err(format!("Unexpected response starting to watch for changes: {:?}", r)),
+    }
+}
+
+/// Blocks until something changes in the source roots being watched (see start_watching), returning the
+/// index of the sync and path of each entry that changed.
+pub fn wait_for_changes(src_comms: &mut Comms, debounce: Duration) -> Result<Vec<(usize, RootRelativePath)>, String> {
+    src_comms.send_command(Command::WaitForChanges { debounce })?;
+    match src_comms.receive_response()? {
+        Response::Changes(c) => Ok(c),
+        Response::Error(e) => Err(e),
+        r => Err(format!("Unexpected response waiting for changes: {:?}", r)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn make_context<'a>(
+    sync_spec: &SyncSpec,
+    dry_run: bool,
+    outputs: &'a mut SyncOutputs,
+    progress_bar: &'a ProgressBar,
+    show_progress: bool,
+    show_stats: bool,
+    itemize: bool,
+    verify: bool,
+    keep_going: bool,
+    bwlimit: Option<&'a BandwidthLimiter>,
+    src_comms: &'a mut Comms,
+    dest_comms: &'a mut Comms,
+) -> Result<SyncContext<'a>, String> {
     // Parse and compile the filter strings
     let filters = match compile_filters(sync_spec) {
         Ok(f) => f,
         Err(e) => return Err(e),
     };
 
+    let local_copy = src_comms.shares_doer_with(dest_comms);
+    let direct_transfer = dest_comms.is_connected_to_source();
+
     // Make context object, to avoid having to pass around a bunch of individual variables everywhere
-    let context = SyncContext {
+    Ok(SyncContext {
         src_comms,
         dest_comms,
         filters,
         stats: Stats::default(),
         dry_run,
+        outputs,
         progress_bar,
         show_progress,
         show_stats,
+        itemize,
+        verify,
+        local_copy,
+        direct_transfer,
+        bwlimit,
+        failed_entries: if keep_going { Some(FailedEntries::new()) } else { None },
+        files_to_fetch: VecDeque::new(),
+        files_being_fetched: VecDeque::new(),
+        small_files_fetched: VecDeque::new(),
+        small_files_to_write: vec![],
+        only_paths: None,
         dest_file_newer_behaviour: sync_spec.dest_file_newer_behaviour,
         dest_file_older_behaviour: sync_spec.dest_file_older_behaviour,
         files_same_time_behaviour: sync_spec.files_same_time_behaviour,
         dest_entry_needs_deleting_behaviour: sync_spec.dest_entry_needs_deleting_behaviour,
         dest_root_needs_deleting_behaviour: sync_spec.dest_root_needs_deleting_behaviour,
+        delete_excluded: sync_spec.delete_excluded,
+        // Note that this means excluded folders will be walked too, which can be slow, so we only do this
+        // when the user is debugging what will be synced.
+        trace_filters: dry_run && log::log_enabled!(log::Level::Debug),
+        dest_case_sensitive: true, // Filled in once we hear from the dest doer
+        normalize_unicode: sync_spec.normalize_unicode,
+        src_names: HashMap::new(),
+        dest_names: HashMap::new(),
+        windows_incompatible_name_behaviour: sync_spec.windows_incompatible_name_behaviour,
+        windows_incompatible_name_errors: vec![],
+        windows_incompatible_folders: HashSet::new(),
         src_root: sync_spec.src.clone(),
         dest_root: sync_spec.dest.clone(),
         src_dir_separator: None,
         dest_dir_separator: None,
-    };
-    // Call into separate function, to avoid the original function parameters being mis-used instead
-    // of the context fields
-    sync_impl(context)
+    })
 }
 
 fn compile_filters(sync_spec: &SyncSpec) -> Result<Filters, String> {
     let mut patterns = vec![];
     let mut kinds = vec![];
     for f in &sync_spec.filters {
-        // Check if starts with a + (include) or a - (exclude)
+        // Check if starts with a + (include), a - (exclude) or a P (protect)
         match f.chars().nth(0) {
             Some('+') => kinds.push(FilterKind::Include),
             Some('-') => kinds.push(FilterKind::Exclude),
-            _ => return Err(format!("Invalid filter '{}': Must start with a '+' or '-'", f)),
+            Some('P') => kinds.push(FilterKind::Protect),
+            _ => return Err(format!("Invalid filter '{}': Must start with a '+', '-' or 'P'", f)),
         }
         let pattern = f.split_at(1).1.to_string();
         // Wrap in ^...$ to make it match the whole string, otherwise it's too easy
@@ -239,10 +529,77 @@ fn compile_filters(sync_spec: &SyncSpec) -> Result<Filters, String> {
     Ok(Filters { regex_set, kinds })
 }
 
-fn sync_impl(mut ctx: SyncContext) -> Result<(), String> {
+/// Checks if the given dest path matches any of the protect filters, meaning that it must never be deleted.
+/// Unlike include/exclude filters, these are evaluated only on the boss, as the dest doer still needs to
+/// report these entries so that they can be compared and updated.
+fn is_protected(path: &RootRelativePath, filters: &Filters) -> bool {
+    path.regex_set_matches(&filters.regex_set).into_iter().any(|i| matches!(filters.kinds[i], FilterKind::Protect))
+}
+
+/// Checks if the entry, or any folder that it's inside, is protected from deletion (see is_protected).
+fn is_protected_or_inside_protected(path: &RootRelativePath, filters: &Filters) -> bool {
+    std::iter::successors(Some(path.clone()), |p| p.parent()).any(|p| !p.is_root() && is_protected(&p, filters))
+}
+
+/// Gets the filter string at the given index, as the user originally wrote it.
+fn describe_filter(filters: &Filters, i: usize) -> String {
+    let prefix = match filters.kinds[i] {
+        FilterKind::Include => '+',
+        FilterKind::Exclude => '-',
+        FilterKind::Protect => 'P',
+    };
+    let pattern = &filters.regex_set.patterns()[i];
+    // Undo the ^...$ wrapping added by compile_filters
+    let pattern = pattern.strip_prefix('^').and_then(|p| p.strip_suffix('$')).unwrap_or(pattern);
+    format!("{prefix}{pattern}")
+}
+
+/// Lists the filters that matched, for debug output. Each filter is shown with its (1-based) position
+/// in the list, as the order that filters are specified in is significant.
+fn describe_matched_filters(filters: &Filters, explanation: &FilterExplanation) -> String {
+    if explanation.matched_filters.is_empty() {
+        "no filters matched".to_string()
+    } else {
+        explanation.matched_filters.iter().map(|i| format!("#{} '{}'", i + 1, describe_filter(filters, *i)))
+            .collect::<Vec<String>>().join(", ")
+    }
+}
+
+/// Prints a breakdown of how the filters in the given sync spec apply to each of the given
+/// root-relative paths, to help the user understand why something is (or isn't) being synced.
+/// This doesn't need to connect to the source or dest, as filters are evaluated purely on the path.
+pub fn explain_filters_for_paths(sync_spec: &SyncSpec, paths: &[String]) -> Result<(), String> {
+    let filters = compile_filters(sync_spec)?;
+    for p in paths {
+        let path = match RootRelativePath::try_from(Path::new(p)) {
+            Ok(p) => p,
+            Err(e) => return Err(format!("Invalid path '{p}': {e}")),
+        };
+        let explanation = doer::explain_filters(&path, &filters);
+
+        let result = match (&explanation.excluded_ancestor, explanation.result) {
+            (Some(a), _) => format!("excluded, as its ancestor folder '{a}' is excluded so will not be walked"),
+            (None, FilterResult::Include) => "included".to_string(),
+            (None, FilterResult::Exclude) => "excluded".to_string(),
+        };
+        info!("'{path}': {result}");
+        for i in &explanation.matched_filters {
+            info!("    Matched filter #{}: '{}'", i + 1, describe_filter(&filters, *i));
+        }
+        if explanation.matched_filters.is_empty() {
+            info!("    No filters matched");
+        }
+        if is_protected_or_inside_protected(&path, &filters) {
+            info!("    Protected from deletion on the dest");
+        }
+    }
+    Ok(())
+}
+
+fn sync_impl(ctx: &mut SyncContext) -> Result<usize, String> {
     profile_this!();
 
-    let sync_start = Instant::now();
+    ctx.stats.query_start_time = Some(Instant::now());
 
     // We don't have a good way of estimating how long the querying phase will take,
     // so we just show a spinner.
@@ -252,16 +609,18 @@ fn sync_impl(mut ctx: SyncContext) -> Result<(), String> {
 
     // First get details of the root file/folder etc. of each side, as this might affect the sync
     // before we start it (e.g. errors, or changing the dest root)
-    let (src_root_details, dest_root_details, dest_platform_differentiates_symlinks) = get_root_details(&mut ctx)?;
+    let (src_root_details, dest_root_details, dest_platform_differentiates_symlinks) = get_root_details(ctx)?;
 
     // Check if the dest root will need deleting, and potentially prompt the user.
     // We do this before we start querying everything to show this prompt as the first one
     // (otherwise it would be the last prompt, as we delete in reverse order)
     if let Some(d) = &dest_root_details {
         if needs_delete(&src_root_details, d, dest_platform_differentiates_symlinks) {
-            if !check_dest_root_delete_ok(&mut ctx, &src_root_details, d)? {
+            if !check_dest_root_delete_ok(ctx, &src_root_details, d)? {
                 // Don't raise an error if we've been told to skip, but we can't continue as it will fail, so skip the entire sync
-                return Ok(());
+                add_to_plan(ctx, None);
+                save_actions(ctx, None)?;
+                return Ok(0);
             }
         }
     }
@@ -276,43 +635,75 @@ fn sync_impl(mut ctx: SyncContext) -> Result<(), String> {
 
     // Get the lists of entries to delete and copy, by querying both source and dest
     // for what they have and checking for differences.
-    let mut actions = query_entries(&mut ctx, src_root_details, dest_root_details, dest_platform_differentiates_symlinks)?;
+    let mut actions = query_entries(ctx, src_root_details, dest_root_details, dest_platform_differentiates_symlinks)?;
 
     // Stop the progress bar before we (potentially) prompt the user, so the progress bar
     // redrawing doesn't interfere with the prompts
     ctx.progress_bar.finish_and_clear();
 
-    let query_elapsed_secs = sync_start.elapsed().as_secs_f32();
-    show_post_query_stats(&ctx, query_elapsed_secs);
+    ctx.stats.query_end_time = Some(Instant::now());
+    let query_elapsed_secs = (ctx.stats.query_end_time.unwrap() - ctx.stats.query_start_time.unwrap()).as_secs_f32();
+    show_post_query_stats(ctx, query_elapsed_secs);
 
     // Confirm that the user is happy to take these actions
-    confirm_actions(&mut ctx, &mut actions)?;
+    confirm_actions(ctx, &mut actions)?;
+
+    add_to_plan(ctx, Some(&actions));
+    save_actions(ctx, Some(&actions))?;
 
+    execute_actions(ctx, &actions)
+}
+
+/// Sends the commands to do the given (already confirmed) actions, and waits for them to be done.
+/// Returns the number of entries which failed (only possible with --keep-going).
+fn execute_actions(ctx: &mut SyncContext, actions: &Actions) -> Result<usize, String> {
     // Start the proper progress bar. We still need this even for --no-progress, because we use
     // some of the features for tracking the timings for --stats, for example. We just put it into
     // a simpler 'mode'.
-    let mut progress = Progress::new(&actions, ctx.progress_bar, ctx.show_progress);
+    let mut progress = Progress::new(actions, ctx.progress_bar, ctx.show_progress, ctx.verify && !ctx.dry_run);
 
     // Delete dest entries that don't exist on the source. This needs to be done first in case there
     // are entries with the same name but incompatible (e.g. files vs folders).
     {
         profile_this!("Sending delete commands");
         ctx.stats.delete_start_time = Some(Instant::now());
-        for (dest_path, (dest_details, _reason)) in actions.to_delete.iter() {
-            delete_dest_entry(&mut ctx, &mut progress, dest_path, dest_details)?;
-            process_dest_responses(ctx.dest_comms, &mut progress, false)?;
+        for (dest_path, (dest_details, reason)) in actions.to_delete.iter() {
+            delete_dest_entry(ctx, &mut progress, dest_path, dest_details)?;
+            if ctx.itemize {
+                itemize_delete(ctx, dest_path, dest_details, reason);
+            }
+            process_dest_responses(ctx.dest_comms, &mut progress, false, ctx.failed_entries.as_mut())?;
         }
     }
 
     // Copy entries that don't exist, or do exist but are out-of-date.
+    let mut not_copied = HashSet::new();
     {
         profile_this!("Sending copy commands");
         // Mark the exact start of copying, to make sure our timing stats are split accurately between copying and deleting
         ctx.dest_comms.send_command(Command::Marker(progress.get_progress_marker()))?;
-        for (src_path, (src_details, _reason)) in actions.to_copy.iter() {
-            copy_entry(&mut ctx, &mut progress, &src_path, &src_details)?;
-            process_dest_responses(ctx.dest_comms, &mut progress, false)?;
+        // Files whose contents come via us are requested ahead of when they're needed (see request_file_contents)
+        if !ctx.dry_run && !ctx.local_copy && !ctx.direct_transfer {
+            ctx.files_to_fetch = actions.to_copy.iter().filter_map(|(p, (d, _))| match d {
+                EntryDetails::File { size, .. } => Some((p.clone(), *size)),
+                _ => None,
+            }).collect();
         }
+        for (src_path, (src_details, reason)) in actions.to_copy.iter() {
+            if !copy_entry(ctx, &mut progress, src_path, src_details)? {
+                not_copied.insert(src_path.clone());
+            } else if ctx.itemize {
+                itemize_copy(ctx, src_path, src_details, reason, actions.overwritten.get(src_path));
+            }
+            process_dest_responses(ctx.dest_comms, &mut progress, false, ctx.failed_entries.as_mut())?;
+        }
+        write_small_files(ctx, &mut progress)?;
+    }
+
+    // Check that the copied files arrived intact, once the dest doer has finished writing them all.
+    if ctx.verify && !ctx.dry_run {
+        profile_this!("Verifying copied files");
+        verify_copied_files(ctx, &mut progress, actions, &not_copied)?;
     }
 
     // Wait for the dest doer to finish processing all its Commands so that everything is finished.
@@ -321,16 +712,28 @@ fn sync_impl(mut ctx: SyncContext) -> Result<(), String> {
     ctx.dest_comms.send_command(Command::Marker(m))?;
     {
         profile_this!("Waiting for dest to finish");
-        process_dest_responses(ctx.dest_comms, &mut progress, true)?;
+        process_dest_responses(ctx.dest_comms, &mut progress, true, ctx.failed_entries.as_mut())?;
     }
 
+    let end_time = Instant::now();
     ctx.stats.delete_end_time = progress.get_first_copy_time();
     ctx.stats.copy_start_time = progress.get_first_copy_time();
-    ctx.stats.copy_end_time = Some(Instant::now());
-
-    show_post_sync_stats(&ctx);
+    ctx.stats.copy_end_time = Some(progress.get_first_verify_time().unwrap_or(end_time));
+    ctx.stats.verify_start_time = progress.get_first_verify_time();
+    ctx.stats.verify_end_time = Some(end_time);
+
+    show_post_sync_stats(ctx);
+
+    // Summarise the entries that failed, as the errors may be mixed in with lots of other output
+    let num_failed = ctx.failed_entries.as_ref().map_or(0, |f| f.len());
+    if let (Some(failed_entries), true) = (&ctx.failed_entries, num_failed > 0) {
+        error!("Failed to sync {} entries:", HumanCount(num_failed as u64));
+        for (_, message) in failed_entries.iter() {
+            error!("  {}", message);
+        }
+    }
 
-    Ok(())
+    Ok(num_failed)
 }
 
 fn get_root_details(ctx: &mut SyncContext) -> Result<(EntryDetails, Option<EntryDetails>, bool), String> {
@@ -338,7 +741,7 @@ fn get_root_details(ctx: &mut SyncContext) -> Result<(EntryDetails, Option<Entry
     let timer = start_timer("SetRoot src");
     ctx.src_comms.send_command(Command::SetRoot { root: ctx.src_root.to_string() })?;
     let src_root_details = match ctx.src_comms.receive_response()? {
-        Response::RootDetails { root_details, platform_differentiates_symlinks: _, platform_dir_separator } => {
+        Response::RootDetails { root_details, platform_differentiates_symlinks: _, platform_dir_separator, case_sensitive: _ } => {
             match &root_details {
                 None => return Err(format!("src path '{}' doesn't exist!", ctx.src_root)),
                 Some(d) => if let Err(e) = validate_trailing_slash(&ctx.src_root, &d) {
@@ -357,7 +760,7 @@ fn get_root_details(ctx: &mut SyncContext) -> Result<(EntryDetails, Option<Entry
     let timer = start_timer("SetRoot dest");
     ctx.dest_comms.send_command(Command::SetRoot { root: ctx.dest_root.clone() })?;
     let (mut dest_root_details, dest_platform_differentiates_symlinks) = match ctx.dest_comms.receive_response()? {
-        Response::RootDetails { root_details, platform_differentiates_symlinks, platform_dir_separator } => {
+        Response::RootDetails { root_details, platform_differentiates_symlinks, platform_dir_separator, case_sensitive } => {
             match &root_details {
                 None => (), // Dest root doesn't exist, but that's fine (we will create it later)
                 Some(d) => if let Err(e) = validate_trailing_slash(&ctx.dest_root, &d) {
@@ -365,6 +768,7 @@ fn get_root_details(ctx: &mut SyncContext) -> Result<(EntryDetails, Option<Entry
                 }
             }
             ctx.dest_dir_separator = Some(platform_dir_separator);
+            ctx.dest_case_sensitive = case_sensitive;
             (root_details, platform_differentiates_symlinks)
         }
         r => return Err(format!("Unexpected response getting root details from dest: {:?}", r)),
@@ -388,7 +792,10 @@ fn get_root_details(ctx: &mut SyncContext) -> Result<(EntryDetails, Option<Entry
 
             ctx.dest_comms.send_command(Command::SetRoot { root: ctx.dest_root.clone() })?;
             dest_root_details = match ctx.dest_comms.receive_response()? {
-                Response::RootDetails { root_details, platform_differentiates_symlinks: _, platform_dir_separator: _ } => root_details,
+                Response::RootDetails { root_details, platform_differentiates_symlinks: _, platform_dir_separator: _, case_sensitive } => {
+                    ctx.dest_case_sensitive = case_sensitive;
+                    root_details
+                }
                 r => return Err(format!("Unexpected response getting root details from dest: {:?}", r)),
             }
         }
@@ -424,13 +831,18 @@ fn check_dest_root_delete_ok(ctx: &mut SyncContext,
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DeleteReason {
     NotOnSource,
     Incompatible,
+    /// Excluded by the filters, and --delete-excluded was specified.
+    Excluded,
+    /// There is a source entry whose name differs only in case, and the dest is case-insensitive
+    /// so the dest entry needs deleting before the source entry can be copied with the correct name.
+    CaseDiffers,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CopyReason {
     NotOnDest,
     DestNewer,
@@ -445,6 +857,306 @@ type ToCopy = OrderedMap<RootRelativePath, (EntryDetails, CopyReason)>;
 pub struct Actions {
     pub to_delete: ToDelete,
     pub to_copy: ToCopy,
+    /// Pairs of (dest path, source path) which differ only in case. The source entry can only be
+    /// copied if the dest entry is deleted, otherwise it would overwrite it (keeping the old name).
+    pub case_renames: Vec<(RootRelativePath, RootRelativePath)>,
+    /// The current details of dest entries which will be overwritten by a copy (rather than deleted first),
+    /// so that we can show what is changing.
+    pub overwritten: HashMap<RootRelativePath, EntryDetails>,
+}
+
+/// Describes an entry for --plan-json. The fields are kept the same for all kinds of entry where possible,
+/// so that scripts don't need to special-case them.
+fn entry_to_json(details: &EntryDetails) -> json::JsonValue {
+    match details {
+        EntryDetails::File { modified_time, size } => json::object! {
+            kind: "file",
+            size: *size,
+            // Seconds since the UNIX epoch, which is what most scripting languages can easily convert from
+            modified_time: modified_time.duration_since(SystemTime::UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64()),
+        },
+        EntryDetails::Folder => json::object! { kind: "folder" },
+        EntryDetails::Symlink { kind, target } => json::object! {
+            kind: "symlink",
+            symlink_kind: match kind {
+                SymlinkKind::File => "file",
+                SymlinkKind::Folder => "folder",
+                SymlinkKind::Unknown => "unknown",
+            },
+            target: match target {
+                SymlinkTarget::Normalized(t) => t.clone(),
+                SymlinkTarget::NotNormalized(t) => t.clone(),
+            },
+        },
+    }
+}
+
+/// Adds the given actions to the plan (see --plan-json), or records that the sync was skipped if there are none.
+/// The format of this is documented in the --plan-json help, so shouldn't be changed without good reason as
+/// people will have scripts relying on it.
+fn add_to_plan(ctx: &mut SyncContext, actions: Option<&Actions>) {
+    if ctx.outputs.json.is_none() {
+        return;
+    }
+
+    let mut delete = json::JsonValue::new_array();
+    let mut copy = json::JsonValue::new_array();
+    if let Some(actions) = actions {
+        for (path, (details, reason)) in actions.to_delete.iter() {
+            let mut e = json::object! {
+                path: ctx.dest_path(path).to_platform_path('/'),
+                reason: match reason {
+                    DeleteReason::NotOnSource => "not_on_source",
+                    DeleteReason::Incompatible => "incompatible",
+                    DeleteReason::Excluded => "excluded",
+                    DeleteReason::CaseDiffers => "case_differs",
+                },
+            };
+            for (k, v) in entry_to_json(details).entries() {
+                e[k] = v.clone();
+            }
+            delete.push(e).expect("Should be an array");
+        }
+        for (path, (details, reason)) in actions.to_copy.iter() {
+            let mut e = json::object! {
+                path: ctx.src_path(path).to_platform_path('/'),
+                dest_path: ctx.dest_path(path).to_platform_path('/'),
+                reason: match reason {
+                    CopyReason::NotOnDest => "not_on_dest",
+                    CopyReason::DestNewer => "dest_newer",
+                    CopyReason::DestOlder => "dest_older",
+                    CopyReason::SameTimeAndNotSkipped => "same_time",
+                },
+            };
+            for (k, v) in entry_to_json(details).entries() {
+                e[k] = v.clone();
+            }
+            copy.push(e).expect("Should be an array");
+        }
+    }
+
+    let sync = json::object! {
+        src: ctx.src_root.clone(),
+        dest: ctx.dest_root.clone(),
+        skipped: actions.is_none(),
+        delete: delete,
+        copy: copy,
+    };
+    ctx.outputs.json.as_mut().unwrap().push(sync).expect("Should be an array");
+}
+
+/// Describes the outcome of a sync for --report.
+/// The format of this is documented in the --report help, so shouldn't be changed without good reason as
+/// people will have monitoring relying on it.
+fn add_to_report(ctx: &mut SyncContext, result: &Result<usize, String>) {
+    if ctx.outputs.report.is_none() {
+        return;
+    }
+
+    let stats = &ctx.stats;
+    let secs = |start: Option<Instant>, end: Option<Instant>| match (start, end) {
+        (Some(s), Some(e)) => json::JsonValue::from((e - s).as_secs_f64()),
+        _ => json::JsonValue::Null,
+    };
+    let counts = |files: u32, folders: u32, symlinks: u32, bytes: u64| json::object! {
+        files: files,
+        folders: folders,
+        symlinks: symlinks,
+        bytes: bytes,
+    };
+    let failed_entries: Vec<json::JsonValue> = ctx.failed_entries.iter().flat_map(|f| f.iter())
+        .map(|(path, message)| json::object! { path: path.to_string(), message: message.clone() }).collect();
+
+    let sync = json::object! {
+        src: ctx.src_root.clone(),
+        dest: ctx.dest_root.clone(),
+        dry_run: ctx.dry_run,
+        success: result.is_ok(),
+        error: result.as_ref().err().cloned(),
+        failed_entries: failed_entries,
+        src_entries: counts(stats.num_src_files, stats.num_src_folders, stats.num_src_symlinks, stats.src_total_bytes),
+        dest_entries: counts(stats.num_dest_files, stats.num_dest_folders, stats.num_dest_symlinks, stats.dest_total_bytes),
+        deleted: counts(stats.num_files_deleted, stats.num_folders_deleted, stats.num_symlinks_deleted, stats.num_bytes_deleted),
+        copied: counts(stats.num_files_copied, stats.num_folders_created, stats.num_symlinks_copied, stats.num_bytes_copied),
+        verified: json::object! {
+            files: stats.num_files_verified,
+            bytes: stats.num_bytes_verified,
+            recopied: stats.num_files_recopied,
+        },
+        durations_secs: json::object! {
+            query: secs(stats.query_start_time, stats.query_end_time),
+            delete: secs(stats.delete_start_time, stats.delete_end_time),
+            copy: secs(stats.copy_start_time, stats.copy_end_time),
+            verify: secs(stats.verify_start_time, stats.verify_end_time),
+            total: secs(stats.query_start_time, Some(Instant::now())),
+        },
+        src_file_size_histogram: stats.src_file_size_hist.buckets.clone(),
+        copied_file_size_histogram: stats.copied_file_size_hist.buckets.clone(),
+    };
+    ctx.outputs.report.as_mut().unwrap().push(sync).expect("Should be an array");
+}
+
+/// Adds an entry to the --report for a sync which failed before it could start.
+fn add_error_to_report(outputs: &mut SyncOutputs, sync_spec: &SyncSpec, dry_run: bool, error: &str) {
+    if let Some(report) = &mut outputs.report {
+        let sync = json::object! {
+            src: sync_spec.src.clone(),
+            dest: sync_spec.dest.clone(),
+            dry_run: dry_run,
+            success: false,
+            error: error,
+        };
+        report.push(sync).expect("Should be an array");
+    }
+}
+
+/// The actions for a sync, as saved by --write-plan so that they can be done later by --apply-plan.
+/// Entries are stored with the paths that they actually have on disk (rather than the normalized/escaped
+/// paths that we use during the sync), along with their details at the time, so that we can check that
+/// nothing has changed before doing anything.
+#[derive(Serialize, Deserialize)]
+pub struct SavedSync {
+    /// The root paths (without the hostname), so that we can connect to the same place again.
+    pub src: String,
+    pub dest: String,
+    to_delete: Vec<SavedDelete>,
+    to_copy: Vec<SavedCopy>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedDelete {
+    dest_path: RootRelativePath,
+    dest_details: EntryDetails,
+    reason: DeleteReason,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedCopy {
+    src_path: RootRelativePath,
+    src_details: EntryDetails,
+    dest_path: RootRelativePath,
+    /// Whatever was at the dest path when the plan was made (if anything), which might be about to be
+    /// overwritten (or deleted first, if it's incompatible).
+    dest_details: Option<EntryDetails>,
+    reason: CopyReason,
+}
+
+/// Gets the current details of each of the given entries (None if it doesn't exist). The requests are all sent
+/// before waiting for any responses, so that we don't wait for a round-trip to the doer for each one.
+fn get_entry_details(comms: &mut Comms, paths: &[RootRelativePath]) -> Result<Vec<Result<Option<EntryDetails>, String>>, String> {
+    for p in paths {
+        comms.send_command(Command::GetEntryDetails { path: p.clone() })?;
+    }
+    let mut result = vec![];
+    for _ in paths {
+        match comms.receive_response()? {
+            Response::EntryDetails(d) => result.push(Ok(d)),
+            Response::Error(e) => result.push(Err(e)),
+            r => return Err(format!("Unexpected response getting entry details: {:?}", r)),
+        }
+    }
+    Ok(result)
+}
+
+/// Adds the given actions to the saved plan (see --write-plan), if we're saving one.
+/// Nothing will be done for this sync when applying the plan if there are no actions (e.g. the sync was skipped).
+fn save_actions(ctx: &mut SyncContext, actions: Option<&Actions>) -> Result<(), String> {
+    if ctx.outputs.saved.is_none() {
+        return Ok(());
+    }
+
+    let mut to_delete = vec![];
+    let mut to_copy = vec![];
+    if let Some(actions) = actions {
+        for (path, (details, reason)) in actions.to_delete.iter() {
+            to_delete.push(SavedDelete { dest_path: ctx.dest_path(path), dest_details: details.clone(), reason: reason.clone() });
+        }
+
+        // We only know the details of the dest entries that need deleting, so we need to get the details of
+        // the dest entries that will be overwritten. Note that we can't assume that there is nothing there if the
+        // entry was missing from the dest (NotOnDest), as it might exist under a different name that the dest
+        // filesystem considers the same (e.g. case-only renames).
+        let dest_paths: Vec<RootRelativePath> = actions.to_copy.iter().map(|(p, _)| ctx.dest_path(p)).collect();
+        let dest_details = get_entry_details(ctx.dest_comms, &dest_paths)?;
+        for ((path, (details, reason)), (dest_path, dest_details)) in actions.to_copy.iter().zip(dest_paths.into_iter().zip(dest_details)) {
+            to_copy.push(SavedCopy {
+                src_path: ctx.src_path(path),
+                src_details: details.clone(),
+                dest_path,
+                dest_details: dest_details?,
+                reason: reason.clone(),
+            });
+        }
+    }
+
+    let saved_sync = SavedSync { src: ctx.src_root.clone(), dest: ctx.dest_root.clone(), to_delete, to_copy };
+    ctx.outputs.saved.as_mut().unwrap().push(saved_sync);
+    Ok(())
+}
+
+fn apply_plan_impl(ctx: &mut SyncContext, saved_sync: &SavedSync) -> Result<usize, String> {
+    profile_this!();
+
+    // Checking the entries in the plan takes the place of querying everything, so is reported as that
+    ctx.stats.query_start_time = Some(Instant::now());
+
+    // Note that the saved dest root already has any adjustment for a trailing slash applied (see get_root_details),
+    // so this won't be changed again.
+    let (_, dest_root_details, _) = get_root_details(ctx)?;
+
+    // Check that each entry is the same as when the plan was made, otherwise the actions might no longer be
+    // what the user wants (e.g. we would overwrite a file that's been modified since)
+    let src_paths: Vec<RootRelativePath> = saved_sync.to_copy.iter().map(|c| c.src_path.clone()).collect();
+    let src_details = get_entry_details(ctx.src_comms, &src_paths)?;
+    let dest_paths: Vec<RootRelativePath> = saved_sync.to_delete.iter().map(|d| d.dest_path.clone())
+        .chain(saved_sync.to_copy.iter().map(|c| c.dest_path.clone())).collect();
+    let dest_details = get_entry_details(ctx.dest_comms, &dest_paths)?;
+
+    let mut changed = vec![];
+    let expected_src = saved_sync.to_copy.iter().map(|c| (&c.src_path, Some(&c.src_details)));
+    for ((path, expected), actual) in expected_src.zip(src_details) {
+        if actual.as_ref().map(|a| a.as_ref()) != Ok(expected) {
+            changed.push(format!("{}", ctx.pretty_src_kind(path, "entry")));
+        }
+    }
+    let expected_dest = saved_sync.to_delete.iter().map(|d| (&d.dest_path, Some(&d.dest_details)))
+        .chain(saved_sync.to_copy.iter().map(|c| (&c.dest_path, c.dest_details.as_ref())));
+    for ((path, expected), actual) in expected_dest.zip(dest_details) {
+        if actual.as_ref().map(|a| a.as_ref()) != Ok(expected) {
+            changed.push(format!("{}", ctx.pretty_dest_kind(path, "entry")));
+        }
+    }
+    if !changed.is_empty() {
+        return Err(format!("The following have changed since the plan was made, so the plan will not be applied: {}. Please make a new plan.",
+            changed.join(", ")));
+    }
+
+    // The paths that we use to refer to each entry are the dest paths, with the source path being
+    // looked up from these if it's different (see SyncContext::src_path)
+    let mut actions = Actions { to_delete: ToDelete::new(), to_copy: ToCopy::new(), case_renames: vec![], overwritten: HashMap::new() };
+    for d in &saved_sync.to_delete {
+        actions.to_delete.add(d.dest_path.clone(), (d.dest_details.clone(), d.reason.clone()));
+    }
+    for c in &saved_sync.to_copy {
+        if c.src_path != c.dest_path {
+            ctx.src_names.insert(c.dest_path.clone(), c.src_path.clone());
+        }
+        actions.to_copy.add(c.dest_path.clone(), (c.src_details.clone(), c.reason.clone()));
+        if let (Some(d), true) = (&c.dest_details, c.reason != CopyReason::NotOnDest) {
+            actions.overwritten.insert(c.dest_path.clone(), d.clone());
+        }
+    }
+
+    if dest_root_details.is_none() && !ctx.dry_run {
+        ctx.dest_comms.send_command(Command::CreateRootAncestors)?;
+    }
+
+    ctx.progress_bar.finish_and_clear();
+    ctx.stats.query_end_time = Some(Instant::now());
+
+    add_to_plan(ctx, Some(&actions));
+
+    execute_actions(ctx, &actions)
 }
 
 fn query_entries(ctx: &mut SyncContext, src_root_details: EntryDetails, dest_root_details: Option<EntryDetails>,
@@ -466,23 +1178,39 @@ fn query_entries(ctx: &mut SyncContext, src_root_details: EntryDetails, dest_roo
     let mut dest_entries = EntriesList::new();
     let mut dest_done = true;
 
-    // Add the source root entry
-    process_src_entry(ctx, RootRelativePath::root(), src_root_details.clone(),
-        &mut src_entries, &dest_entries, dest_platform_differentiates_symlinks,
-        &mut to_delete, &mut to_copy);
+    // When only some entries need syncing (see --watch), we query just those on both sides, so anything else is
+    // left alone. This is only possible when both roots are already folders, otherwise the roots themselves need syncing.
+    // The names of entries on the dest can't be predicted with --normalize-unicode, so everything is queried in that case.
+    let only_paths = match (&ctx.only_paths, &src_root_details, &dest_root_details) {
+        (Some(p), EntryDetails::Folder, Some(EntryDetails::Folder)) if !ctx.normalize_unicode && !p.iter().any(|p| p.is_root()) => Some(p.clone()),
+        _ => None,
+    };
+
+    // Add the source root entry (unless we're only syncing some entries inside it)
+    if only_paths.is_none() {
+        process_src_entry(ctx, RootRelativePath::root(), src_root_details.clone(),
+            &mut src_entries, &dest_entries, dest_platform_differentiates_symlinks,
+            &mut to_delete, &mut to_copy);
+    }
 
     if matches!(src_root_details, EntryDetails::Folder) {
-        ctx.src_comms.send_command(Command::GetEntries { filters: ctx.filters.clone() })?;
+        ctx.src_comms.send_command(Command::GetEntries { filters: ctx.filters.clone(), report_excluded: ctx.trace_filters,
+            paths: only_paths.clone() })?;
         src_done = false;
     }
 
     if let Some(d) = &dest_root_details {
         // Add the dest root entry
-        process_dest_entry(ctx, RootRelativePath::root(), d.clone(), &src_entries,
-            &mut dest_entries, dest_platform_differentiates_symlinks, &mut to_delete, &mut to_copy);
+        if only_paths.is_none() {
+            process_dest_entry(ctx, RootRelativePath::root(), d.clone(), &src_entries,
+                &mut dest_entries, dest_platform_differentiates_symlinks, &mut to_delete, &mut to_copy);
+        }
 
         if let EntryDetails::Folder = d {
-            ctx.dest_comms.send_command(Command::GetEntries { filters: ctx.filters.clone() })?;
+            // Excluded entries on the dest are only needed if we're going to delete them (or explain them)
+            ctx.dest_comms.send_command(Command::GetEntries { filters: ctx.filters.clone(),
+                report_excluded: ctx.delete_excluded || ctx.trace_filters,
+                paths: only_paths.as_ref().map(|p| p.iter().map(|p| ctx.translate_src_path(p)).collect()) })?;
             dest_done = false;
         }
     }
@@ -495,6 +1223,7 @@ fn query_entries(ctx: &mut SyncContext, src_root_details: EntryDetails, dest_roo
                 Response::Entry((p, src_entry)) => process_src_entry(ctx, p, src_entry,
                     &mut src_entries, &dest_entries, dest_platform_differentiates_symlinks,
                     &mut to_delete, &mut to_copy),
+                Response::ExcludedEntry((p, _)) => trace_excluded_entry(ctx, Side::Source, &p),
                 Response::EndOfEntries => src_done = true,
                 r => return Err(format!("Unexpected response getting entries from src: {:?}", r)),
             },
@@ -503,6 +1232,12 @@ fn query_entries(ctx: &mut SyncContext, src_root_details: EntryDetails, dest_roo
                 Response::Entry((p, dest_entry)) => process_dest_entry(ctx, p, dest_entry,
                     &src_entries, &mut dest_entries, dest_platform_differentiates_symlinks,
                     &mut to_delete, &mut to_copy),
+                Response::ExcludedEntry((p, dest_entry)) => {
+                    trace_excluded_entry(ctx, Side::Dest, &p);
+                    if ctx.delete_excluded {
+                        process_excluded_dest_entry(ctx, p, dest_entry, &mut dest_entries, &mut to_delete);
+                    }
+                }
                 Response::EndOfEntries => dest_done = true,
                 r => return Err(format!("Unexpected response getting entries from dest: {:?}", r)),
             },
@@ -510,6 +1245,10 @@ fn query_entries(ctx: &mut SyncContext, src_root_details: EntryDetails, dest_roo
         }
     }
 
+    if !ctx.windows_incompatible_name_errors.is_empty() {
+        return Err(format!("{}. See --windows-incompatible-name.", ctx.windows_incompatible_name_errors.join(", ")));
+    }
+
     ctx.stats.num_src_entries = src_entries.len() as u32;
     ctx.stats.num_dest_entries = dest_entries.len() as u32;
 
@@ -520,7 +1259,16 @@ fn query_entries(ctx: &mut SyncContext, src_root_details: EntryDetails, dest_roo
     // see test_remove_dest_folder_with_excluded_files())
     to_delete.reverse_order();
 
-    Ok(Actions { to_delete, to_copy })
+    let case_renames = if ctx.dest_case_sensitive {
+        vec![]
+    } else {
+        check_case_insensitive_dest(&src_entries, &mut to_delete)?
+    };
+
+    let overwritten = to_copy.iter().filter(|(_, (_, r))| *r != CopyReason::NotOnDest)
+        .filter_map(|(p, _)| dest_entries.lookup(p).map(|d| (p.clone(), d.clone()))).collect();
+
+    Ok(Actions { to_delete, to_copy, case_renames, overwritten })
 }
 
 fn process_src_entry(ctx: &mut SyncContext, p: RootRelativePath, src_entry: EntryDetails,
@@ -529,6 +1277,26 @@ fn process_src_entry(ctx: &mut SyncContext, p: RootRelativePath, src_entry: Entr
     to_delete: &mut ToDelete, to_copy: &mut ToCopy,
 ) {
     trace!("Source entry '{}': {:?}", p, src_entry);
+    // Names which aren't valid Unicode are stored in a platform-specific way, which can't be converted
+    // to work on a different kind of platform (e.g. we don't know which encoding Latin-1 bytes on Linux were meant to be).
+    if p.is_from_windows().is_some_and(|w| w != ctx.dest_is_windows()) {
+        // Everything inside such a folder will have the same problem, so only warn for the folder itself
+        #[allow(clippy::unnecessary_map_or)] // is_none_or needs a newer Rust than we support
+        let parent_is_unicode = p.parent().map_or(true, |parent| parent.is_unicode());
+        if parent_is_unicode {
+            warn!("{} has a name which isn't valid Unicode, so can't be represented on the dest platform. It will be skipped (along with anything inside it).",
+                ctx.pretty_src(&p, &src_entry));
+        }
+        return;
+    }
+    let p = match resolve_windows_incompatible_name(ctx, p, &src_entry) {
+        Some(p) => p,
+        None => return,
+    };
+    let p = match normalize_entry_path(ctx, Side::Source, p, src_entries) {
+        Some(p) => p,
+        None => return,
+    };
     match src_entry {
         EntryDetails::File { size, .. } => {
             ctx.stats.num_src_files += 1;
@@ -567,6 +1335,10 @@ fn process_dest_entry(ctx: &mut SyncContext, p: RootRelativePath, dest_entry: En
     to_delete: &mut ToDelete, to_copy: &mut ToCopy,
 ) {
     trace!("Dest entry '{}': {:?}", p, dest_entry);
+    let p = match normalize_entry_path(ctx, Side::Dest, p, dest_entries) {
+        Some(p) => p,
+        None => return,
+    };
     match dest_entry {
         EntryDetails::File { size, .. } => {
             ctx.stats.num_dest_files += 1;
@@ -598,6 +1370,147 @@ fn process_dest_entry(ctx: &mut SyncContext, p: RootRelativePath, dest_entry: En
     }
 }
 
+/// Applies --windows-incompatible-name to a source entry, if the dest is Windows (or if we're restoring escaped names
+/// from Windows). Returns the path that we should use for the entry from now on, or None if it should be ignored.
+fn resolve_windows_incompatible_name(ctx: &mut SyncContext, p: RootRelativePath, src_entry: &EntryDetails) -> Option<RootRelativePath> {
+    if ctx.windows_incompatible_name_behaviour == WindowsIncompatibleNameBehaviour::Escape {
+        let translated = ctx.translate_src_path(&p);
+        if translated != p {
+            ctx.src_names.insert(translated.clone(), p);
+        }
+        return Some(translated);
+    }
+    if !ctx.dest_is_windows() {
+        return Some(p);
+    }
+
+    // Anything inside a folder that we're skipping is skipped too (and has already been reported, via the folder)
+    let inside_skipped_folder = p.parent().is_some_and(|parent| ctx.windows_incompatible_folders.contains(&parent));
+    if !inside_skipped_folder {
+        if is_valid_windows_name(p.file_name()) {
+            return Some(p);
+        }
+        let msg = format!("{} has a name which can't be used on Windows", ctx.pretty_src(&p, src_entry));
+        match ctx.windows_incompatible_name_behaviour {
+            WindowsIncompatibleNameBehaviour::Error => ctx.windows_incompatible_name_errors.push(msg),
+            WindowsIncompatibleNameBehaviour::Skip => warn!("{msg}. It will be skipped (along with anything inside it)."),
+            WindowsIncompatibleNameBehaviour::Escape => panic!("Should have already been handled"),
+        }
+    }
+    if matches!(src_entry, EntryDetails::Folder) {
+        ctx.windows_incompatible_folders.insert(p);
+    }
+    None
+}
+
+/// If --normalize-unicode is used, converts the path of an entry received from a doer to the normalized path that
+/// we will use to refer to it, remembering the original so that we can use that when accessing it.
+/// Returns None if the entry should be ignored, because it's indistinguishable from another entry on the same side
+/// once normalized.
+fn normalize_entry_path(ctx: &mut SyncContext, side: Side, p: RootRelativePath, entries: &EntriesList) -> Option<RootRelativePath> {
+    if !ctx.normalize_unicode {
+        return Some(p);
+    }
+    let normalized = p.normalize_unicode();
+    if entries.lookup(&normalized).is_some() {
+        let (existing, pretty) = match side {
+            Side::Source => (ctx.src_path(&normalized), ctx.pretty_src_kind(&p, "entry")),
+            Side::Dest => (ctx.dest_path(&normalized), ctx.pretty_dest_kind(&p, "entry")),
+        };
+        warn!("{} has the same name as '{}' after Unicode normalization, so will be ignored", pretty, existing);
+        return None;
+    }
+    match side {
+        Side::Source => {
+            // The path might have already been changed from what's on disk (see resolve_windows_incompatible_name),
+            // in which case we need to keep the original
+            let original = ctx.src_names.remove(&p).unwrap_or(p);
+            if normalized != original {
+                ctx.src_names.insert(normalized.clone(), original);
+            }
+        }
+        Side::Dest => {
+            ctx.dest_names.insert(normalized.clone(), p);
+        }
+    }
+    Some(normalized)
+}
+
+/// Handles a dest entry which was excluded by the filters, which is only reported by the doer
+/// when --delete-excluded is used. The source never reports the equivalent entry (as it's excluded there too),
+/// so there's nothing to compare against and it always needs deleting.
+fn process_excluded_dest_entry(ctx: &mut SyncContext, p: RootRelativePath, dest_entry: EntryDetails,
+    dest_entries: &mut EntriesList, to_delete: &mut ToDelete,
+) {
+    trace!("Excluded dest entry '{}': {:?}", p, dest_entry);
+    let p = match normalize_entry_path(ctx, Side::Dest, p, dest_entries) {
+        Some(p) => p,
+        None => return,
+    };
+    match dest_entry {
+        EntryDetails::File { size, .. } => {
+            ctx.stats.num_dest_files += 1;
+            ctx.stats.dest_total_bytes += size;
+        }
+        EntryDetails::Folder => ctx.stats.num_dest_folders += 1,
+        EntryDetails::Symlink { .. } => ctx.stats.num_dest_symlinks += 1,
+    }
+
+    dest_entries.add(p.clone(), dest_entry.clone());
+    to_delete.add(p, (dest_entry, DeleteReason::Excluded));
+}
+
+/// When the dest is case-insensitive, the source and dest entries need matching up differently, as entries
+/// that we consider different (e.g. 'README.md' and 'readme.md') would actually be the same entry on the dest.
+/// This checks for source entries that would collide with each other (which is an error, as one would
+/// silently overwrite the other), and finds dest entries which are being replaced by a source entry
+/// whose name differs only in case (i.e. it has been renamed on the source).
+fn check_case_insensitive_dest(src_entries: &EntriesList, to_delete: &mut ToDelete)
+    -> Result<Vec<(RootRelativePath, RootRelativePath)>, String>
+{
+    let mut src_keys = HashMap::<String, &RootRelativePath>::new();
+    let mut collisions = vec![];
+    for (p, _) in src_entries.iter() {
+        if let Some(existing) = src_keys.insert(p.case_insensitive_key(), p) {
+            collisions.push(format!("'{existing}' and '{p}'"));
+        }
+    }
+    if !collisions.is_empty() {
+        return Err(format!("The dest is case-insensitive, so source entries which differ only in case would overwrite each other: {}",
+            collisions.join(", ")));
+    }
+
+    let mut case_renames = vec![];
+    for (dest_path, (_, reason)) in to_delete.iter() {
+        if *reason == DeleteReason::NotOnSource {
+            if let Some(src_path) = src_keys.get(&dest_path.case_insensitive_key()) {
+                case_renames.push((dest_path.clone(), (*src_path).clone()));
+            }
+        }
+    }
+    for (dest_path, _) in &case_renames {
+        let (dest_entry, _) = to_delete.lookup(dest_path).unwrap().clone();
+        to_delete.update(dest_path, (dest_entry, DeleteReason::CaseDiffers));
+    }
+    Ok(case_renames)
+}
+
+/// Logs why an entry was excluded by the filters, if requested.
+/// Entries inside an excluded folder aren't logged, as the folder itself will have been.
+fn trace_excluded_entry(ctx: &SyncContext, side: Side, p: &RootRelativePath) {
+    if !ctx.trace_filters {
+        return;
+    }
+    let explanation = doer::explain_filters(p, &ctx.filters);
+    if explanation.excluded_ancestor.is_none() {
+        let pretty = match side {
+            Side::Source => ctx.pretty_src_kind(p, "entry"),
+            Side::Dest => ctx.pretty_dest_kind(p, "entry"),
+        };
+        debug!("{} is excluded by the filters ({})", pretty, describe_matched_filters(&ctx.filters, &explanation));
+    }
+}
+
 /// Checks if an existing dest entry needs to be deleted to make way for a source entry.
 /// Some entries like files can be updated without needing to delete then recreate, but others
 /// like symlinks need deleting and recreating.
@@ -672,13 +1585,36 @@ fn needs_copy(ctx: &SyncContext, path: &RootRelativePath, src_details: &EntryDet
 fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), String> {
     // Confirm deletes
     let mut to_remove = vec![]; // Rather than removing things as we go, we remove them at the end
+    // Folders which contain a protected entry can't be deleted either (it would fail anyway as they won't be empty).
+    // Entries are deleted children-first (see query_entries), so we will always see the protected entry before its ancestors.
+    let mut protected_ancestors = HashSet::new();
+    // Source entries which would replace a protected dest entry, so can't be copied
+    let mut blocked_copies = HashSet::new();
     for (path, (entry_to_delete, reason)) in actions.to_delete.iter() {
+        if is_protected_or_inside_protected(path, &ctx.filters) || protected_ancestors.contains(path) {
+            debug!("{} is protected by a filter. Will not delete.", ctx.pretty_dest(path, entry_to_delete));
+            to_remove.push(path.clone());
+            if matches!(reason, DeleteReason::Incompatible) {
+                warn!("{} is protected by a filter, so {} (and anything inside it) won't be copied",
+                    ctx.pretty_dest(path, entry_to_delete), ctx.pretty_src_kind(path, "entry"));
+                blocked_copies.insert(path.clone());
+            }
+            let mut p = path.parent();
+            while let Some(a) = p {
+                p = a.parent();
+                protected_ancestors.insert(a);
+            }
+            continue;
+        }
+
         let msg = format!(
             "{} needs deleting {}",
             ctx.pretty_dest(path, entry_to_delete),
             match reason {
                 DeleteReason::NotOnSource => "as it doesn't exist on the src",
                 DeleteReason::Incompatible => "to allow the source entry to be copied",
+                DeleteReason::Excluded => "as it is excluded by the filters",
+                DeleteReason::CaseDiffers => "as the source entry's name differs only in case",
             });
 
         // Resolve any behaviour resulting from a prompt first
@@ -712,6 +1648,24 @@ fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), S
     for p in to_remove {
         actions.to_delete.remove(&p);
     }
+    if !blocked_copies.is_empty() {
+        let to_remove: Vec<RootRelativePath> = actions.to_copy.iter().map(|(p, _)| p)
+            .filter(|p| std::iter::successors(Some((*p).clone()), |a| a.parent()).any(|a| blocked_copies.contains(&a)))
+            .cloned().collect();
+        for p in to_remove {
+            actions.to_copy.remove(&p);
+        }
+    }
+
+    // If a dest entry whose name differs in case isn't going to be deleted, then the source entry can't be copied either,
+    // as on a case-insensitive dest it would overwrite the entry that we've just decided to keep.
+    for (dest_path, src_path) in &actions.case_renames {
+        if actions.to_delete.lookup(dest_path).is_none() && actions.to_copy.lookup(src_path).is_some() {
+            debug!("{} is not being deleted, so {} won't be copied", ctx.pretty_dest_kind(dest_path, "entry"),
+                ctx.pretty_src_kind(src_path, "entry"));
+            actions.to_copy.remove(src_path);
+        }
+    }
 
     // Confirm copies
     let mut to_remove = vec![]; // Rather than removing things as we go, we remove them at the end
@@ -863,19 +1817,19 @@ fn delete_dest_entry(ctx: &mut SyncContext, progress: &mut Progress,
             ctx.stats.num_files_deleted += 1;
             ctx.stats.num_bytes_deleted += size;
             Command::DeleteFile {
-                path: dest_path.clone(),
+                path: ctx.dest_path(dest_path),
             }
         }
         EntryDetails::Folder => {
             ctx.stats.num_folders_deleted += 1;
             Command::DeleteFolder {
-                path: dest_path.clone(),
+                path: ctx.dest_path(dest_path),
             }
         }
         EntryDetails::Symlink { kind, .. } => {
             ctx.stats.num_symlinks_deleted += 1;
             Command::DeleteSymlink {
-                path: dest_path.clone(),
+                path: ctx.dest_path(dest_path),
                 kind: *kind,
             }
         }
@@ -884,7 +1838,7 @@ fn delete_dest_entry(ctx: &mut SyncContext, progress: &mut Progress,
 
     let result = Ok(if !ctx.dry_run {
         ctx.dest_comms.send_command(c)?;
-    } else {
+    } else if !ctx.itemize { // --itemize shows the same thing (and more), so don't repeat it
         // Print dry-run as info level, as presumably the user is interested in exactly _what_ will be deleted
         info!("Would delete {}", ctx.pretty_dest(dest_path, dest_details));
     });
@@ -894,24 +1848,32 @@ fn delete_dest_entry(ctx: &mut SyncContext, progress: &mut Progress,
     result
 }
 
+/// Returns false if the entry couldn't be copied but the sync can carry on anyway (see --keep-going).
 fn copy_entry(ctx: &mut SyncContext, progress: &mut Progress,
-    path: &RootRelativePath, src_details: &EntryDetails) -> Result<(), String>
+    path: &RootRelativePath, src_details: &EntryDetails) -> Result<bool, String>
 {
     match src_details {
         EntryDetails::File { size, modified_time: src_modified_time } => {
             debug!("Copying {}", ctx.pretty_src(&path, &src_details));
-            copy_file(&path, *size, *src_modified_time, ctx, progress)?
+            if !copy_file(&path, *size, *src_modified_time, ctx, progress)? {
+                return Ok(false);
+            }
+            ctx.stats.num_files_copied += 1;
+            ctx.stats.num_bytes_copied += size;
+            ctx.stats.copied_file_size_hist.add(*size);
         }
         EntryDetails::Folder => {
             debug!("Creating {}", ctx.pretty_src(&path, &src_details));
+            // Anything inside this folder will come after it, but any files before it need to be written first
+            write_small_files(ctx, progress)?;
             ctx.send_progress_marker_limited(progress)?;
             ctx.stats.num_folders_created += 1;
             if !ctx.dry_run {
                 ctx.dest_comms
                     .send_command(Command::CreateFolder {
-                        path: path.clone(),
+                        path: ctx.dest_path(path),
                     })?;
-            } else {
+            } else if !ctx.itemize {
                 // Print dry-run as info level, as presumably the user is interested in exactly _what_ will be copied
                 info!("Would create {}", ctx.pretty_dest_kind(&path, "folder"));
             }
@@ -919,48 +1881,100 @@ fn copy_entry(ctx: &mut SyncContext, progress: &mut Progress,
         },
         EntryDetails::Symlink { ref kind, ref target } => {
             debug!("Copying {}", ctx.pretty_src(&path, &src_details));
+            write_small_files(ctx, progress)?;
             ctx.send_progress_marker_limited(progress)?;
             ctx.stats.num_symlinks_copied += 1;
             if !ctx.dry_run {
                 ctx.dest_comms
                     .send_command(Command::CreateSymlink {
-                        path: path.clone(),
+                        path: ctx.dest_path(path),
                         kind: *kind,
                         target: target.clone(),
                     })?;
-            } else {
+            } else if !ctx.itemize {
                 // Print dry-run as info level, as presumably the user is interested in exactly _what_ will be copied
                 info!("Would create {}", ctx.pretty_dest_kind(&path, "symlink"));
             }
             progress.copy_sent(&src_details);
         }
     }
-    Ok(())
+    Ok(true)
 }
 
+/// Returns false if the file couldn't be copied but the sync can carry on anyway (see --keep-going).
 fn copy_file(
     path: &RootRelativePath,
     size: u64,
     modified_time: SystemTime,
     ctx: &mut SyncContext,
-    progress: &mut Progress) -> Result<(), String>
+    progress: &mut Progress) -> Result<bool, String>
 {
     ctx.send_progress_marker_limited(progress)?;
 
-    if !ctx.dry_run {
+    if !ctx.dry_run && ctx.local_copy {
+        trace!("Copying {} locally on the doer", ctx.pretty_src_kind(&path, "file"));
+        ctx.dest_comms.send_command(Command::CopyLocalFile {
+            src_root: ctx.src_root.clone(),
+            src_path: ctx.src_path(path),
+            path: ctx.dest_path(path),
+            size,
+            set_modified_time: modified_time,
+        })?;
+        progress.copy_sent_partial(0, size, size);
+    } else if !ctx.dry_run && ctx.direct_transfer {
+        trace!("Dest fetching {} from source", ctx.pretty_src_kind(&path, "file"));
+        ctx.dest_comms.send_command(Command::CopyFromSource {
+            src_root: ctx.src_root.clone(),
+            src_path: ctx.src_path(path),
+            path: ctx.dest_path(path),
+            size,
+            set_modified_time: modified_time,
+        })?;
+        // This is counted as sent straight away so that our progress markers are right, but the progress bar
+        // only moves as the dest doer reports each chunk that it has fetched (see Response::ChunkFetched)
+        progress.copy_sent_partial(0, size, size);
+    } else if !ctx.dry_run {
         trace!("Fetching from {}", ctx.pretty_src_kind(&path, "file"));
-        ctx.src_comms
-            .send_command(Command::GetFileContent {
-                path: path.clone(),
-            })?;
+        request_file_contents(ctx, path, size)?;
+        if let Some(content) = receive_small_file(ctx)? {
+            return copy_small_file(path, size, modified_time, content, ctx, progress);
+        }
+        // Keep the commands to the dest in the same order as the entries
+        write_small_files(ctx, progress)?;
+        let dest_path = ctx.dest_path(path);
         // Large files are split into chunks, loop until all chunks are transferred.
         let mut chunk_offset: u64 = 0;
         loop {
             // Add progress markers during copies of large files, so we can see the progress (in bytes)
             ctx.send_progress_marker_limited(progress)?;
 
-            let (data, more_to_follow) = match ctx.src_comms.receive_response()? {
-                Response::FileContent { data, more_to_follow } => (data, more_to_follow),
+            let (data, more_to_follow, hash) = match ctx.src_comms.receive_response()? {
+                Response::FileContent { data, more_to_follow, hash } => {
+                    if !more_to_follow {
+                        ctx.files_being_fetched.pop_front();
+                    }
+                    (data, more_to_follow, hash)
+                }
+                Response::EntryError { path: src_path, message, not_found } if ctx.failed_entries.is_some() => {
+                    // Nothing more will be sent for this file
+                    ctx.files_being_fetched.pop_front();
+                    if chunk_offset > 0 {
+                        // Finish off the partial file on the dest so that the dest doer isn't left waiting for more of it,
+                        // and then delete it rather than leave a truncated file behind.
+                        ctx.dest_comms.send_command(Command::CreateOrUpdateFile {
+                            path: dest_path.clone(), data: vec![], set_modified_time: None, more_to_follow: false, hash: None })?;
+                        ctx.dest_comms.send_command(Command::DeleteFile { path: dest_path })?;
+                    }
+                    // The rest of the file won't be sent, but needs accounting for so that the progress adds up
+                    progress.copy_sent_partial(chunk_offset, size - chunk_offset, size);
+                    if not_found && chunk_offset == 0 {
+                        // The file was deleted since we queried it, which isn't really a failure - it just doesn't need copying any more
+                        warn!("Skipping {} as it no longer exists", ctx.pretty_src_kind(&path, "file"));
+                    } else {
+                        record_failed_entry(ctx.failed_entries.as_mut().unwrap(), src_path, message);
+                    }
+                    return Ok(false);
+                }
                 x => return Err(format!(
                     "Unexpected response fetching {}: {:?}", ctx.pretty_src_kind(&path, "file"), x
                 )),
@@ -975,12 +1989,14 @@ fn copy_file(
                 break;
             }
 
+            ctx.limit_bandwidth(chunk_size as u64);
             ctx.dest_comms
                 .send_command(Command::CreateOrUpdateFile {
-                    path: path.clone(),
+                    path: dest_path.clone(),
                     data,
                     set_modified_time: if more_to_follow { None } else { Some(modified_time) }, // Only set the modified time after the final chunk
                     more_to_follow,
+                    hash,
                 })?;
 
             // This needs to be inside the chunking loop so we can update progress as the file is copied
@@ -989,7 +2005,7 @@ fn copy_file(
 
             // For large files, it might be a while before process_dest_responses is called in the main sync function,
             // so check it periodically here too.
-            process_dest_responses(ctx.dest_comms, progress, false)?;
+            process_dest_responses(ctx.dest_comms, progress, false, ctx.failed_entries.as_mut())?;
 
             if !more_to_follow {
                 break;
@@ -1004,19 +2020,412 @@ fn copy_file(
         }
     } else {
         progress.copy_sent_partial(0, size, size);
-        // Print dry-run as info level, as presumably the user is interested in exactly _what_ will be copied
-        info!("Would copy {} => {}",
-            ctx.pretty_src_kind(&path, "file"),
-            ctx.pretty_dest_kind(&path, "file"));
+        if !ctx.itemize {
+            // Print dry-run as info level, as presumably the user is interested in exactly _what_ will be copied
+            info!("Would copy {} => {}",
+                ctx.pretty_src_kind(&path, "file"),
+                ctx.pretty_dest_kind(&path, "file"));
+        }
     }
 
-    ctx.stats.num_files_copied += 1;
-    ctx.stats.num_bytes_copied += size;
-    ctx.stats.copied_file_size_hist.add(size);
+    Ok(true)
+}
 
+/// The most requests for file contents that we will have sent to the source at once (see request_file_contents).
+const MAX_FILES_BEING_FETCHED: usize = 1000;
+/// The most data (in bytes) that we will have requested from the source at once (see request_file_contents),
+/// which is well within the memory limit of the channel that the responses are received on.
+const MAX_BYTES_BEING_FETCHED: u64 = BOSS_DOER_CHANNEL_MEMORY_CAPACITY as u64 / 4;
+
+/// A request for file contents that has been sent to the source (see request_file_contents).
+enum FileFetch {
+    /// A file requested with GetFileContent.
+    Whole(RootRelativePath, u64),
+    /// Several small files requested together with GetFileContents.
+    Batch(Vec<(RootRelativePath, u64)>),
+}
+impl FileFetch {
+    fn first_path(&self) -> &RootRelativePath {
+        match self {
+            FileFetch::Whole(p, _) => p,
+            FileFetch::Batch(files) => &files[0].0,
+        }
+    }
+    fn size(&self) -> u64 {
+        match self {
+            FileFetch::Whole(_, s) => *s,
+            FileFetch::Batch(files) => files.iter().map(|(_, s)| s).sum(),
+        }
+    }
+}
+
+/// Makes sure that the given file has been requested from the source, and also requests the next few files that
+/// will be needed (see files_to_fetch), so that the source can be reading and sending those while we forward this one to the dest.
+/// Otherwise we would be waiting for a round trip to the source for every file, which adds up when there are lots of small files.
+/// Small files next to each other are requested together (see GetFileContents), to save on messages.
+/// The amount requested at once is limited (see MAX_BYTES_BEING_FETCHED), so that the responses don't use too much memory.
+/// With --bwlimit, only about a second's worth is requested at once, so that the source doesn't send much faster than the
+/// limit while we are waiting to forward it.
+fn request_file_contents(ctx: &mut SyncContext, path: &RootRelativePath, size: u64) -> Result<(), String> {
+    loop {
+        // Small files already received from the source are ahead of those still being fetched
+        let next_path = match (ctx.small_files_fetched.front(), ctx.files_being_fetched.front()) {
+            (Some((p, _)), _) => Some(p),
+            (None, Some(f)) => Some(f.first_path()),
+            (None, None) => None,
+        };
+        match next_path {
+            Some(p) if p == path => break, // Already requested
+            Some(p) => {
+                // Files requested ahead of this one that aren't being copied after all can be thrown away
+                debug!("Discarding the contents of {} as it is no longer being copied", ctx.pretty_src_kind(p, "file"));
+                discard_fetched_file(ctx)?;
+            }
+            None => {
+                // Files that weren't planned (e.g. copying again after --verify failed) are requested on their own
+                if ctx.files_to_fetch.front().map(|(p, _)| p) == Some(path) {
+                    ctx.files_to_fetch.pop_front();
+                }
+                send_file_request(ctx, vec![(path.clone(), size)])?;
+                break;
+            }
+        }
+    }
+
+    let bytes_being_fetched = |ctx: &SyncContext| ctx.files_being_fetched.iter().map(|f| f.size()).sum::<u64>();
+    let max_bytes_being_fetched = ctx.bwlimit.map_or(MAX_BYTES_BEING_FETCHED, |l| l.rate().min(MAX_BYTES_BEING_FETCHED));
+    while let Some((_, next_size)) = ctx.files_to_fetch.front() {
+        if ctx.files_being_fetched.len() >= MAX_FILES_BEING_FETCHED || bytes_being_fetched(ctx) + next_size > max_bytes_being_fetched {
+            break;
+        }
+        let mut files = vec![ctx.files_to_fetch.pop_front().unwrap()];
+        while files[0].1 <= SMALL_FILE_MAX_SIZE && files.len() < MAX_FILES_PER_BATCH &&
+            ctx.files_to_fetch.front().is_some_and(|(_, s)| *s <= SMALL_FILE_MAX_SIZE)
+        {
+            files.push(ctx.files_to_fetch.pop_front().unwrap());
+        }
+        send_file_request(ctx, files)?;
+    }
+    Ok(())
+}
+
+fn send_file_request(ctx: &mut SyncContext, mut files: Vec<(RootRelativePath, u64)>) -> Result<(), String> {
+    if files.len() == 1 {
+        let (path, size) = files.pop().unwrap();
+        ctx.src_comms.send_command(Command::GetFileContent { path: ctx.src_path(&path) })?;
+        ctx.files_being_fetched.push_back(FileFetch::Whole(path, size));
+    } else {
+        let paths = files.iter().map(|(p, _)| ctx.src_path(p)).collect();
+        ctx.src_comms.send_command(Command::GetFileContents { paths })?;
+        ctx.files_being_fetched.push_back(FileFetch::Batch(files));
+    }
     Ok(())
 }
 
+/// Receives the contents of the next file requested from the source (see request_file_contents) and throws them away.
+fn discard_fetched_file(ctx: &mut SyncContext) -> Result<(), String> {
+    if ctx.small_files_fetched.pop_front().is_some() {
+        return Ok(());
+    }
+    match ctx.files_being_fetched.pop_front() {
+        Some(FileFetch::Whole(path, _)) => loop {
+            match ctx.src_comms.receive_response()? {
+                Response::FileContent { more_to_follow: true, .. } => (),
+                Response::FileContent { more_to_follow: false, .. } | Response::EntryError { .. } => return Ok(()),
+                x => return Err(format!("Unexpected response fetching {}: {:?}", ctx.pretty_src_kind(&path, "file"), x)),
+            }
+        },
+        Some(FileFetch::Batch(files)) => match ctx.src_comms.receive_response()? {
+            // The rest of the batch may still be needed
+            Response::FileContents(c) if c.len() == files.len() =>
+                ctx.small_files_fetched.extend(files.into_iter().map(|(p, _)| p).zip(c).skip(1)),
+            x => return Err(format!("Unexpected response fetching files: {:?}", x)),
+        },
+        None => (),
+    }
+    Ok(())
+}
+
+/// If the next file to be copied was requested along with other small files (see request_file_contents), then
+/// this returns its contents, receiving them from the source if they haven't been already.
+fn receive_small_file(ctx: &mut SyncContext) -> Result<Option<SmallFileContent>, String> {
+    if ctx.small_files_fetched.is_empty() {
+        if !matches!(ctx.files_being_fetched.front(), Some(FileFetch::Batch(_))) {
+            return Ok(None);
+        }
+        let files = match ctx.files_being_fetched.pop_front() {
+            Some(FileFetch::Batch(files)) => files,
+            _ => unreachable!(),
+        };
+        match ctx.src_comms.receive_response()? {
+            Response::FileContents(c) if c.len() == files.len() =>
+                ctx.small_files_fetched.extend(files.into_iter().map(|(p, _)| p).zip(c)),
+            x => return Err(format!("Unexpected response fetching files: {:?}", x)),
+        }
+    }
+    Ok(ctx.small_files_fetched.pop_front().map(|(_, c)| c))
+}
+
+/// Copies a file which was fetched along with other small files (see receive_small_file). It is sent to the dest
+/// along with other small files too (see write_small_files).
+/// Returns false if the file couldn't be copied but the sync can carry on anyway (see --keep-going).
+fn copy_small_file(path: &RootRelativePath, size: u64, modified_time: SystemTime, content: SmallFileContent,
+    ctx: &mut SyncContext, progress: &mut Progress) -> Result<bool, String>
+{
+    match content {
+        SmallFileContent::Content { data, hash } => {
+            if data.len() as u64 != size {
+                // See the same check for larger files in copy_file
+                return Err(format!("Size of {} has changed during the sync.", ctx.pretty_src_kind(path, "file")));
+            }
+            trace!("Create/update {}", ctx.pretty_dest_kind(path, "file"));
+            let file = SmallFile { path: ctx.dest_path(path), data, set_modified_time: modified_time, hash: Some(hash) };
+            ctx.small_files_to_write.push((file, size));
+            if ctx.small_files_to_write.len() >= MAX_FILES_PER_BATCH {
+                write_small_files(ctx, progress)?;
+            }
+            Ok(true)
+        }
+        SmallFileContent::Error { message, not_found } => {
+            // The file won't be sent, but needs accounting for so that the progress adds up
+            progress.copy_sent_partial(0, size, size);
+            if ctx.failed_entries.is_none() {
+                return Err(message);
+            }
+            if not_found {
+                // See the same case in copy_file
+                warn!("Skipping {} as it no longer exists", ctx.pretty_src_kind(path, "file"));
+            } else {
+                let src_path = ctx.src_path(path);
+                record_failed_entry(ctx.failed_entries.as_mut().unwrap(), src_path, message);
+            }
+            Ok(false)
+        }
+    }
+}
+
+/// Sends any small files waiting to be written (see copy_small_file) to the dest.
+/// This needs calling before sending anything else to the dest, so that it happens in the right order.
+fn write_small_files(ctx: &mut SyncContext, progress: &mut Progress) -> Result<(), String> {
+    if ctx.small_files_to_write.is_empty() {
+        return Ok(());
+    }
+    let (files, sizes): (Vec<SmallFile>, Vec<u64>) = std::mem::take(&mut ctx.small_files_to_write).into_iter().unzip();
+    ctx.limit_bandwidth(sizes.iter().sum());
+    ctx.dest_comms.send_command(Command::CreateOrUpdateFiles { files })?;
+    for size in sizes {
+        progress.copy_sent_partial(0, size, size);
+    }
+    Ok(())
+}
+
+/// Checks that each copied file has the same contents on the dest as on the source (see --verify).
+/// Files which don't match are copied again, and if they still don't match then an error is raised.
+/// Files in not_copied are skipped, as they failed to be copied in the first place (see --keep-going).
+fn verify_copied_files(ctx: &mut SyncContext, progress: &mut Progress, actions: &Actions,
+    not_copied: &HashSet<RootRelativePath>) -> Result<(), String>
+{
+    let mut to_verify = vec![];
+    for (path, (details, _)) in actions.to_copy.iter() {
+        if let EntryDetails::File { size, modified_time } = details {
+            if not_copied.contains(path) {
+                // This still needs accounting for, so that the progress adds up
+                progress.verify_sent(*size);
+            } else {
+                to_verify.push((path.clone(), *size, *modified_time));
+            }
+        }
+    }
+    if to_verify.is_empty() {
+        return Ok(());
+    }
+
+    let mut is_retry = false;
+    loop {
+        // Mark the exact start of verifying, so that our timing stats know when the copies finished
+        ctx.dest_comms.send_command(Command::Marker(progress.get_progress_marker()))?;
+
+        let mismatches = verify_files(ctx, progress, to_verify)?;
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+
+        if is_retry {
+            let failures: Vec<(RootRelativePath, String)> = mismatches.iter().map(|(path, _, _)| (ctx.dest_path(path),
+                format!("Contents of {} don't match the source, even after copying again", ctx.pretty_dest_kind(path, "file")))).collect();
+            if let Some(failed_entries) = ctx.failed_entries.as_mut() {
+                for (dest_path, message) in failures {
+                    record_failed_entry(failed_entries, dest_path, message);
+                }
+                return Ok(());
+            }
+            let paths: Vec<String> = mismatches.iter().map(|(p, _, _)| ctx.pretty_dest_kind(p, "file").to_string()).collect();
+            return Err(format!("Contents of {} don't match the source, even after copying again", paths.join(", ")));
+        }
+        let mut recopied = vec![];
+        for (path, size, modified_time) in mismatches {
+            warn!("Contents of {} don't match {} after copying. Copying again.",
+                ctx.pretty_dest_kind(&path, "file"), ctx.pretty_src_kind(&path, "file"));
+            progress.add_retry(&path, size);
+            if copy_file(&path, size, modified_time, ctx, progress)? {
+                recopied.push((path, size, modified_time));
+            } else {
+                progress.verify_sent(size);
+            }
+            process_dest_responses(ctx.dest_comms, progress, false, ctx.failed_entries.as_mut())?;
+            ctx.stats.num_files_recopied += 1;
+        }
+        if recopied.is_empty() {
+            return Ok(());
+        }
+        to_verify = recopied;
+        is_retry = true;
+    }
+}
+
+/// The most GetFileHash requests that we will have sent to each doer at once (see verify_files).
+const MAX_FILES_BEING_HASHED: usize = 100;
+
+/// Gets a hash of each of the given copied files from both doers, and returns the files that don't match.
+/// Requests are sent ahead of the responses being received, so that neither doer waits for a round trip per file.
+fn verify_files(ctx: &mut SyncContext, progress: &mut Progress, files: Vec<(RootRelativePath, u64, SystemTime)>)
+    -> Result<Vec<(RootRelativePath, u64, SystemTime)>, String>
+{
+    let mut mismatches = vec![];
+    let mut files = files.into_iter();
+    let mut being_hashed = VecDeque::new();
+    loop {
+        while being_hashed.len() < MAX_FILES_BEING_HASHED {
+            match files.next() {
+                Some(f) => {
+                    request_file_hashes(ctx, progress, &f.0, f.1)?;
+                    being_hashed.push_back(f);
+                }
+                None => break,
+            }
+        }
+        let (path, size, modified_time) = match being_hashed.pop_front() {
+            Some(f) => f,
+            None => return Ok(mismatches),
+        };
+        match receive_file_hashes(ctx, progress, &path)? {
+            Some(true) => {
+                ctx.stats.num_files_verified += 1;
+                ctx.stats.num_bytes_verified += size;
+            }
+            Some(false) => mismatches.push((path, size, modified_time)),
+            None => (), // Already recorded as failed
+        }
+    }
+}
+
+/// Asks both doers for a hash of a copied file. The results are received by receive_file_hashes.
+fn request_file_hashes(ctx: &mut SyncContext, progress: &mut Progress, path: &RootRelativePath, size: u64) -> Result<(), String> {
+    trace!("Verifying {}", ctx.pretty_dest_kind(path, "file"));
+    ctx.src_comms.send_command(Command::GetFileHash { path: ctx.src_path(path) })?;
+    ctx.dest_comms.send_command(Command::GetFileHash { path: ctx.dest_path(path) })?;
+    progress.verify_sent(size);
+    ctx.send_progress_marker_limited(progress)
+}
+
+/// Receives the hashes of a copied file asked for by request_file_hashes, and returns whether they match.
+/// Returns None if the file couldn't be checked, but the sync can carry on anyway (see --keep-going).
+fn receive_file_hashes(ctx: &mut SyncContext, progress: &mut Progress, path: &RootRelativePath) -> Result<Option<bool>, String> {
+    let src_path = ctx.src_path(path);
+    let dest_path = ctx.dest_path(path);
+
+    // The source doer doesn't have anything else outstanding, so an error here must be for this file
+    let src_hash = match ctx.src_comms.receive_response()? {
+        Response::FileHash(h) => h,
+        Response::Error(e) | Response::EntryError { message: e, .. } => Err(e),
+        x => return Err(format!("Unexpected response hashing {}: {:?}", ctx.pretty_src_kind(path, "file"), x)),
+    };
+    // The dest doer might also send back progress markers, and errors from earlier commands (e.g. writing this file),
+    // which we need to handle along the way
+    let dest_hash = loop {
+        match ctx.dest_comms.receive_response()? {
+            Response::FileHash(h) => break h,
+            Response::Marker(m) => progress.update_completed(&m),
+            Response::ChunkFetched { chunk_start, chunk_size, file_size } => progress.copy_completed_partial(chunk_start, chunk_size, file_size),
+            Response::EntryError { path, message, not_found: _ } if ctx.failed_entries.is_some() =>
+                record_failed_entry(ctx.failed_entries.as_mut().unwrap(), path, message),
+            Response::Error(e) | Response::EntryError { message: e, .. } => return Err(e),
+            x => return Err(format!("Unexpected response hashing {}: {:?}", ctx.pretty_dest_kind(path, "file"), x)),
+        }
+    };
+
+    let failed_entries = match ctx.failed_entries.as_mut() {
+        Some(f) => f,
+        None => return Ok(Some(src_hash? == dest_hash?)),
+    };
+    // If the file failed to be written, it won't match but there's no need to report it again
+    if failed_entries.lookup(&dest_path).is_some() {
+        return Ok(None);
+    }
+    match (src_hash, dest_hash) {
+        (Ok(s), Ok(d)) => Ok(Some(s == d)),
+        (Err(e), _) => {
+            record_failed_entry(failed_entries, src_path, e);
+            Ok(None)
+        }
+        (_, Err(e)) => {
+            record_failed_entry(failed_entries, dest_path, e);
+            Ok(None)
+        }
+    }
+}
+
+/// The path shown for --itemize, which is relative to the dest root so that it is short and the same
+/// regardless of platform.
+fn itemize_path(ctx: &SyncContext, path: &RootRelativePath) -> String {
+    if path.is_root() {
+        ".".to_string()
+    } else {
+        ctx.dest_path(path).to_platform_path('/')
+    }
+}
+
+fn itemize_kind(details: &EntryDetails) -> char {
+    match details {
+        EntryDetails::File { .. } => 'f',
+        EntryDetails::Folder => 'd',
+        EntryDetails::Symlink { .. } => 'L',
+    }
+}
+
+/// Prints the --itemize line for an entry being deleted. See --itemize for the format.
+fn itemize_delete(ctx: &SyncContext, path: &RootRelativePath, details: &EntryDetails, reason: &DeleteReason) {
+    let reason = match reason {
+        DeleteReason::NotOnSource => "not-src",
+        DeleteReason::Incompatible => "incompat",
+        DeleteReason::Excluded => "excluded",
+        DeleteReason::CaseDiffers => "case",
+    };
+    info!("-{} {:<9} -- {}", itemize_kind(details), reason, itemize_path(ctx, path));
+}
+
+/// Prints the --itemize line for an entry being copied. See --itemize for the format.
+fn itemize_copy(ctx: &SyncContext, path: &RootRelativePath, details: &EntryDetails, reason: &CopyReason,
+    overwritten: Option<&EntryDetails>)
+{
+    let (op, attrs) = match (details, overwritten) {
+        (EntryDetails::File { size, modified_time }, Some(EntryDetails::File { size: dest_size, modified_time: dest_modified_time })) => {
+            ('>', format!("{}{}",
+                if size != dest_size { 's' } else { '.' },
+                if modified_time != dest_modified_time { 't' } else { '.' }))
+        }
+        (_, Some(_)) => ('>', "..".to_string()),
+        (_, None) => ('+', "++".to_string()),
+    };
+    let reason = match reason {
+        CopyReason::NotOnDest => "not-dest",
+        CopyReason::DestOlder => "older",
+        CopyReason::DestNewer => "newer",
+        CopyReason::SameTimeAndNotSkipped => "same-time",
+    };
+    info!("{}{} {:<9} {} {}", op, itemize_kind(details), reason, attrs, itemize_path(ctx, path));
+}
+
 fn show_post_sync_stats(ctx: &SyncContext) {
     // Note that we print all the stats at the end (even though we could print the delete stats earlier),
     // so that they are together in the output (e.g. for dry run or --verbose, they could be a lot of other
@@ -1051,6 +2460,17 @@ fn show_post_sync_stats(ctx: &SyncContext) {
                     copy_elapsed.as_secs_f32(), HumanBytes((ctx.stats.num_bytes_copied as f32 / copy_elapsed.as_secs_f32()).round() as u64))
             } else { "".to_string() },
         );
+        // The limit doesn't apply to files that don't pass through us
+        let relayed = !ctx.dry_run && !ctx.local_copy && !ctx.direct_transfer;
+        if let (Some(l), true, true) = (ctx.bwlimit, ctx.show_stats, relayed) {
+            // Timed from when we started sending, as the copy timer starts once the first file has been sent
+            let relay_elapsed = ctx.stats.relay_start_time.map_or(copy_elapsed, |t| ctx.stats.copy_end_time.unwrap() - t);
+            info!("Sent {} of file contents at an effective {}/s (limited to {}/s), waiting {:.2} seconds for the limit",
+                HumanBytes(ctx.stats.num_bytes_relayed),
+                HumanBytes((ctx.stats.num_bytes_relayed as f32 / relay_elapsed.as_secs_f32()).round() as u64),
+                HumanBytes(l.rate()),
+                ctx.stats.bwlimit_wait.as_secs_f32());
+        }
         if ctx.show_stats {
             info!("{} file size distribution:",
                 if !ctx.dry_run { "Copied" } else { "Would copy" },
@@ -1058,6 +2478,20 @@ fn show_post_sync_stats(ctx: &SyncContext) {
             info!("{}", ctx.stats.copied_file_size_hist);
         }
     }
+    if ctx.verify && !ctx.dry_run && (ctx.stats.num_files_copied > 0 || ctx.show_stats) {
+        info!(
+            "Verified {} file(s) totalling {}{}{}",
+            HumanCount(ctx.stats.num_files_verified as u64),
+            HumanBytes(ctx.stats.num_bytes_verified),
+            if ctx.stats.num_files_recopied > 0 {
+                format!(", {} of which were copied again", HumanCount(ctx.stats.num_files_recopied as u64))
+            } else { "".to_string() },
+            match (ctx.show_stats, ctx.stats.verify_start_time, ctx.stats.verify_end_time) {
+                (true, Some(start), Some(end)) => format!(", in {:.2} seconds", (end - start).as_secs_f32()),
+                _ => "".to_string(),
+            },
+        );
+    }
     if ctx.stats.num_files_deleted
         + ctx.stats.num_folders_deleted
         + ctx.stats.num_symlinks_deleted
@@ -1,20 +1,26 @@
 use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::{Aes128Gcm, Key};
 
 use clap::Parser;
 use env_logger::Env;
 use log::{debug, error, trace, info};
+use notify::Watcher;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::io::{ErrorKind, Read};
 use std::path;
 use std::{
     fmt::{self, Display},
     io::{Write},
     path::{Path, PathBuf},
-    time::{Instant}, net::{TcpListener},
+    time::{Duration, Instant, SystemTime}, net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}},
 };
 
 use crate::*;
-use crate::boss_doer_interface::{EntryDetails, SymlinkTarget, Response, Command, SymlinkKind, Filters, FilterKind, HANDSHAKE_STARTED_MSG, HANDSHAKE_COMPLETED_MSG};
-use crate::encrypted_comms::AsyncEncryptedComms;
+use crate::boss_doer_interface::{EntryDetails, SymlinkTarget, Response, Command, SymlinkKind, Filters, FilterKind, SmallFileContent, SMALL_FILE_MAX_SIZE, HANDSHAKE_STARTED_MSG, HANDSHAKE_COMPLETED_MSG};
+use crate::encrypted_comms::{AsyncEncryptedComms, transfer_session_handshake, accept_connection, accept_extra_stream, session_id, IncomingConnection, BossId, TransferId,
+    SessionOptions};
 use crate::memory_bound_channel::{Sender, Receiver};
 use crate::parallel_walk_dir::parallel_walk_dir;
 use crate::root_relative_path::RootRelativePath;
@@ -29,6 +35,20 @@ struct DoerCliArgs {
     /// If not specified, a free port is chosen.
     #[arg(long)]
     port: Option<u16>,
+    /// Keeps running for this many seconds after the boss disconnects, accepting new sessions from later
+    /// bosses that were given the same secret key. The timeout is reset after each session.
+    #[arg(long, value_name="SECONDS")]
+    persist: Option<u64>,
+    /// The number of sessions (connections from the boss) to accept before exiting. More than one is used when
+    /// the same doer serves both the source and dest, or several syncs at once.
+    #[arg(long, default_value_t=1)]
+    sessions: u32,
+    /// The most TCP connections that each session may use (see the boss's --streams).
+    #[arg(long, default_value_t=1)]
+    streams: u32,
+    /// Allows the boss to ask for messages to be compressed (see the boss's --compress).
+    #[arg(long)]
+    compress: bool,
     /// Logging configuration.
     #[arg(long, default_value="info")]
     log_filter: String,
@@ -58,8 +78,10 @@ fn entry_details_from_metadata(m: std::fs::Metadata, path: &Path) -> Result<Entr
         // Attempt to normalize the target, if possible, so that we can convert the slashes on
         // the destination platform (which might be different).
         // We use RootRelativePath for this even though it might not be root-relative, but this does the right thing
+        // (as long as it's valid Unicode, otherwise the string form would be lossy)
         let target = match RootRelativePath::try_from(&target as &Path) {
-            Ok(r) => SymlinkTarget::Normalized(r.to_string()),
+            Ok(r) if r.is_unicode() => SymlinkTarget::Normalized(r.to_string()),
+            Ok(_) => SymlinkTarget::NotNormalized(target.to_string_lossy().to_string()),
             Err(_) => SymlinkTarget::NotNormalized(target.to_string_lossy().to_string()),
         };
 
@@ -254,47 +276,75 @@ pub fn doer_main() -> ExitCode {
             return ExitCode::from(25);
         }
     };
-
     stop_timer(timer);
 
+    if args.persist.is_some() || args.sessions > 1 || args.streams > 1 || args.compress {
+        // Persistent doers outlive the ssh connection that launched them, so from now on stdin closing is expected
+        if args.persist.is_some() {
+            BOSS_CONNECTED.store(true, Ordering::SeqCst);
+        }
+        stop_timer(main_timer);
+        let allowed_options = SessionOptions { num_streams: args.streams, compression_level: if args.compress { 9 } else { 0 } };
+        serve_sessions(listener, tcp_connection, *secret_key, args.persist, args.sessions, allowed_options);
+    } else if let Err(e) = run_session(vec![tcp_connection], *secret_key, 0, false, || stop_timer(main_timer)) {
+        debug!("doer process finished with error: {:?}", e);
+        return ExitCode::from(20)
+    }
+
+    // Dump memory usage figures when used for benchmarking. There isn't a good way of determining this from the benchmarking app
+    // (especially for remote processes), so we instrument it instead.
+    if args.dump_memory_usage {
+        info!("Doer peak memory usage: {}", profiling::get_peak_memory_usage());
+    }
+
+    debug!("doer process finished successfully!");
+    ExitCode::SUCCESS
+}
+
+/// Processes commands from one boss connection (which may have several streams) until it shuts down or disconnects.
+/// The given function is called once the commands are done with, before shutting down the connection.
+/// Transfer sessions (from a dest doer, see --direct-transfer) can only fetch file contents (see message_loop).
+fn run_session(tcp_connections: Vec<TcpStream>, key: Key<Aes128Gcm>, compression_level: u32, transfer_only: bool,
+    on_finished: impl FnOnce())
+    -> Result<(), ()>
+{
     // Start command processing loop, receiving commands and sending responses over the TCP connection, with encryption
     // so that we know it's the boss.
     let mut comms = Comms::Remote {
         encrypted_comms: AsyncEncryptedComms::new(
-            tcp_connection,
-            *secret_key,
+            tcp_connections,
+            key,
+            compression_level,
             1, // Nonce counters must be different, so sender and receiver don't reuse
             0,
             ("doer", "remote boss"),
     )};
 
-    if let Err(e) = message_loop(&mut comms) {
-        debug!("doer process finished with error: {:?}", e);
-        return ExitCode::from(20)
-    }
+    let result = message_loop(&mut comms, transfer_only);
 
-    stop_timer(main_timer);
+    on_finished();
 
     if let Comms::Remote{ encrypted_comms } = comms { // This is always true, we just need a way of getting the fields
         // Send our profiling data (if enabled) back to the boss process so it can combine it with its own
         encrypted_comms.shutdown_with_final_message_sent_after_threads_joined(|| Response::ProfilingData(get_local_process_profiling()));
     }
 
-    // Dump memory usage figures when used for benchmarking. There isn't a good way of determining this from the benchmarking app
-    // (especially for remote processes), so we instrument it instead.
-    if args.dump_memory_usage {
-        info!("Doer peak memory usage: {}", profiling::get_peak_memory_usage());
-    }
-
-    debug!("doer process finished successfully!");
-    ExitCode::SUCCESS
+    result
 }
 
+/// Set once a persistent doer has had its first connection from a boss, after which the ssh connection
+/// that launched it is no longer needed.
+static BOSS_CONNECTED: AtomicBool = AtomicBool::new(false);
+
 fn stdin_reading_thread() {
     loop {
         let mut l: String = "".to_string();
         match std::io::stdin().read_line(&mut l) {
             Ok(0) | Err(_) => {
+                if BOSS_CONNECTED.load(Ordering::SeqCst) {
+                    debug!("ssh disconnected - continuing as a persistent doer");
+                    return;
+                }
                 // Boss has disconnected prematurely - nothing we can do, just exit.
                 error!("Boss disconnected from stdin - exiting process");
                 std::process::exit(321);
@@ -304,12 +354,182 @@ fn stdin_reading_thread() {
     }
 }
 
+/// For doers which accept more than one session (see --sessions and --persist), serves each one on its own thread,
+/// so that e.g. the source and dest can be using the same doer at the same time. Returns once the expected number of
+/// sessions have all finished and, for persistent doers, no new one has started within the idle timeout.
+/// Each session derives its own key, as the nonce counters start again for each one.
+/// Sessions can also use more than one connection (see --streams), in which case the other connections are handed
+/// over to the thread for their session. The boss may ask for fewer streams etc. than the given options allow.
+/// Persistent doers only serve one boss at a time (which may have several sessions), and tell any other boss
+/// that they're busy so that it can launch its own doer instead.
+fn serve_sessions(listener: TcpListener, first_connection: TcpStream, secret_key: Key<Aes128Gcm>,
+    persist: Option<u64>, num_sessions: u32, allowed_options: SessionOptions)
+{
+    let active_connections = Arc::new(AtomicUsize::new(0));
+    let num_started = Arc::new(AtomicUsize::new(0));
+    let waiting_sessions = Arc::new(Mutex::new(HashMap::new()));
+    // The boss that we're serving, and how many sessions it has running
+    let current_boss: Arc<Mutex<Option<(BossId, usize)>>> = Arc::new(Mutex::new(None));
+    // Each transfer key is only good for one session (see make_transfer_key)
+    let used_transfer_ids = Arc::new(Mutex::new(HashSet::new()));
+    let mut num_connections = 0;
+    let mut next_connection = Some(first_connection);
+    let mut idle_since = Instant::now();
+    // There's no accept with a timeout, so poll instead
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("Failed to set listener to non-blocking: {e}");
+        return;
+    }
+    loop {
+        if let Some(mut tcp_connection) = next_connection.take() {
+            num_connections += 1;
+            active_connections.fetch_add(1, Ordering::SeqCst);
+            let active_connections = active_connections.clone();
+            let num_started = num_started.clone();
+            let waiting_sessions = waiting_sessions.clone();
+            let current_boss = current_boss.clone();
+            let used_transfer_ids = used_transfer_ids.clone();
+            std::thread::Builder::new().name(format!("connection {num_connections}")).spawn(move || {
+                let is_busy = |boss_id: &BossId| persist.is_some() &&
+                    matches!(*current_boss.lock().expect("Failed to lock mutex"), Some((b, _)) if b != *boss_id);
+                match accept_connection(&mut tcp_connection, &secret_key, allowed_options, is_busy) {
+                    Ok(IncomingConnection::NewSession { session_key, options, boss_id }) => {
+                        num_started.fetch_add(1, Ordering::SeqCst);
+                        current_boss.lock().expect("Failed to lock mutex").get_or_insert((boss_id, 0)).1 += 1;
+                        serve_session(tcp_connection, session_key, options, false, &waiting_sessions);
+                        let mut current_boss = current_boss.lock().expect("Failed to lock mutex");
+                        if let Some((_, n)) = current_boss.as_mut() {
+                            *n -= 1;
+                            if *n == 0 {
+                                *current_boss = None;
+                            }
+                        }
+                    }
+                    Ok(IncomingConnection::Busy) => debug!("Turned away a session from another boss, as already busy"),
+                    Ok(IncomingConnection::TransferSession { session_key, options, transfer_id }) => {
+                        if used_transfer_ids.lock().expect("Failed to lock mutex").insert(transfer_id) {
+                            num_started.fetch_add(1, Ordering::SeqCst);
+                            serve_session(tcp_connection, session_key, options, true, &waiting_sessions);
+                        } else {
+                            error!("Failed to start session: the transfer key has already been used");
+                        }
+                    }
+                    Ok(IncomingConnection::ExtraStream { session_id, stream_index, proof }) => {
+                        match find_waiting_session(&waiting_sessions, &session_id) {
+                            Some((session_key, sender)) => match accept_extra_stream(&mut tcp_connection, &session_key, stream_index, &proof) {
+                                // The session's thread takes it from here
+                                Ok(()) => { let _ = sender.send((stream_index, tcp_connection)); }
+                                Err(e) => error!("Failed to add stream to session: {e}"),
+                            },
+                            None => error!("Failed to add stream to session: no session is waiting for it"),
+                        }
+                    }
+                    Err(e) => error!("Failed to start session: {e}"),
+                }
+                active_connections.fetch_sub(1, Ordering::SeqCst);
+            }).expect("Failed to spawn thread");
+        }
+
+        match listener.accept() {
+            Ok((socket, addr)) => {
+                debug!("Client connected: {socket:?} {addr:?}");
+                if let Err(e) = socket.set_nonblocking(false) {
+                    error!("Failed to set socket to blocking: {e}");
+                } else {
+                    next_connection = Some(socket);
+                }
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+            Err(e) => {
+                error!("Failed to accept: {}", e);
+                break;
+            }
+        }
+
+        if active_connections.load(Ordering::SeqCst) > 0 {
+            idle_since = Instant::now();
+        } else if num_started.load(Ordering::SeqCst) >= num_sessions as usize {
+            match persist {
+                None => break,
+                Some(p) if idle_since.elapsed() >= Duration::from_secs(p) => {
+                    debug!("No boss connected within the idle timeout - exiting");
+                    break;
+                }
+                Some(_) => (),
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Runs a session accepted by serve_sessions, once the boss has opened its other streams.
+fn serve_session(tcp_connection: TcpStream, session_key: Key<Aes128Gcm>, options: SessionOptions, transfer_only: bool,
+    waiting_sessions: &WaitingSessions)
+{
+    match wait_for_streams(tcp_connection, session_key, options.num_streams, waiting_sessions) {
+        Ok(tcp_connections) => if run_session(tcp_connections, session_key, options.compression_level, transfer_only, || ()).is_err() {
+            // A problem with one session shouldn't stop the doer from serving the others
+            debug!("Session finished with error");
+        },
+        Err(e) => error!("Failed to start session: {e}"),
+    }
+}
+
+/// Sessions which are waiting for the boss to open their other streams, by session ID (see encrypted_comms::join_session).
+type WaitingSessions = Mutex<HashMap<[u8; 16], WaitingSession>>;
+/// The key for a session waiting for its other streams, and where to send them.
+type WaitingSession = (Key<Aes128Gcm>, crossbeam::channel::Sender<(u32, TcpStream)>);
+
+/// Finds the session that an extra stream is for (see wait_for_streams). The boss opens the other streams as soon as
+/// it has the session key, which can be before the session's thread has started waiting for them, so this waits a
+/// little while for the session to appear.
+fn find_waiting_session(waiting_sessions: &WaitingSessions, session_id: &[u8; 16]) -> Option<WaitingSession> {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    loop {
+        if let Some(session) = waiting_sessions.lock().expect("Failed to lock mutex").get(session_id) {
+            return Some(session.clone());
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Waits for the boss to open the other streams for a new session, which are accepted on other threads
+/// (see serve_sessions). Returns the connections for all the streams, in order.
+fn wait_for_streams(first_connection: TcpStream, session_key: Key<Aes128Gcm>, num_streams: u32,
+    waiting_sessions: &WaitingSessions) -> Result<Vec<TcpStream>, String>
+{
+    let mut tcp_connections: Vec<Option<TcpStream>> = (0..num_streams).map(|_| None).collect();
+    tcp_connections[0] = Some(first_connection);
+    if num_streams > 1 {
+        let id = session_id(&session_key);
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        waiting_sessions.lock().expect("Failed to lock mutex").insert(id, (session_key, sender));
+        // The boss opens them straight away, so this shouldn't take long
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let result = (1..num_streams).try_for_each(|_| {
+            let (i, tcp_connection) = receiver.recv_deadline(deadline).map_err(|_| "Timed out waiting for the other streams".to_string())?;
+            match tcp_connections.get_mut(i as usize) {
+                Some(c @ None) => *c = Some(tcp_connection),
+                _ => return Err(format!("Unexpected stream index {i}")),
+            }
+            Ok(())
+        });
+        waiting_sessions.lock().expect("Failed to lock mutex").remove(&id);
+        result?;
+    }
+    Ok(tcp_connections.into_iter().map(|c| c.expect("All streams should be connected")).collect())
+}
+
 // When the source and/or dest is local, the doer is run as a thread in the boss process,
 // rather than over ssh.
 pub fn doer_thread_running_on_boss(receiver: Receiver<Command>, sender: Sender<Response>) -> Result<(), String> {
     debug!("doer thread running");
     profile_this!();
-    match message_loop(&mut Comms::Local { sender, receiver }) {
+    match message_loop(&mut Comms::Local { sender, receiver }, false) {
         Ok(_) => {
             debug!("doer thread finished successfully!");
             Ok(())
@@ -326,56 +546,81 @@ pub fn doer_thread_running_on_boss(receiver: Receiver<Command>, sender: Sender<R
 /// (if these are local doers).
 struct DoerContext {
     root: PathBuf,
-    /// Stores details of a file we're partway through receiving.
-    in_progress_file_receive: Option<(RootRelativePath, std::fs::File)>,
+    /// Stores details of a file we're partway through receiving, including a running hash of the
+    /// data written so far to check against the source's hash once it's complete.
+    in_progress_file_receive: Option<(RootRelativePath, std::fs::File, Sha256)>,
 }
 
 // Repeatedly waits for Commands from the boss and processes them (possibly sending back Responses).
 // This function returns when we receive a Shutdown Command, or there is an unrecoverable error
 // (recoverable errors while handling Commands will not stop the loop).
-fn message_loop(comms: &mut Comms) -> Result<(), ()> {
+// For transfer sessions, the other end is a dest doer rather than the boss, so we only let it fetch file contents
+// (see is_transfer_command), and refuse anything else.
+fn message_loop(comms: &mut Comms, transfer_only: bool) -> Result<(), ()> {
     profile_this!();
     let mut context : Option<DoerContext> = None;
-    loop {
+    // Kept separately from the context, as these are for all the syncs rather than the current root
+    let mut watcher : Option<SourceWatcher> = None;
+    let mut source : Option<SourceConnection> = None;
+    let result = loop {
         match comms.receive_command() {
+            Ok(c) if transfer_only && !is_transfer_command(&c) => {
+                debug!("Refusing command in transfer session: {:?}", c);
+                if let Err(e) = comms.send_response(Response::Error(format!("Command not allowed in a transfer session: {:?}", c))) {
+                    error!("Error processing command: {}", e);
+                    break Err(());
+                }
+            }
             Ok(c) => {
-                match exec_command(c, comms, &mut context) {
+                match exec_command(c, comms, &mut context, &mut watcher, &mut source) {
                     Ok(false) => {
                         debug!("Shutdown command received - finishing message_loop");
-                        return Ok(());
+                        break Ok(());
                     }
                     Ok(true) => (), // Continue processing commands
                     Err(e) => {
                         error!("Error processing command: {}", e);
-                        return Err(());
+                        break Err(());
                     }
                 }
             }
             Err(_) => {
                 // Boss has disconnected
                 debug!("Boss disconnected - finishing message loop");
-                return Ok(());
+                break Ok(());
             }
         }
+    };
+
+    if let Some(s) = source {
+        s.disconnect();
     }
+    result
+}
+
+/// Checks if a command is one that a dest doer needs to fetch file contents from us (see handle_copy_from_source).
+fn is_transfer_command(command: &Command) -> bool {
+    matches!(command, Command::SetRoot { .. } | Command::GetFileContent { .. } | Command::Shutdown)
 }
 
 /// Handles a Command from the boss, possibly replying with one or more Responses.
 /// Returns false if we received a Shutdown Command, otherwise true.
 /// Note that if processing a command results in an error which is related to the command itself (e.g. we are asked
-/// to fetch details of a file that doesn't exist), then this is reported back to the boss in a Response::Error,
-/// and this function still returns Ok(). Error() variants returned from this function indicate a more catastrophic
+/// to fetch details of a file that doesn't exist), then this is reported back to the boss in a Response::Error
+/// (or Response::EntryError, if it relates to a specific entry), and this function still returns Ok(). Error() variants returned from this function indicate a more catastrophic
 /// error, like a communication failure.
-fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerContext>) -> Result<bool, String> {
+fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerContext>,
+    watcher: &mut Option<SourceWatcher>, source: &mut Option<SourceConnection>) -> Result<bool, String>
+{
     match command {
         Command::SetRoot { root } => {
             if let Err(e) = handle_set_root(comms, context, root) {
                 comms.send_response(Response::Error(e))?;
             }
         }
-        Command::GetEntries { filters } => {
+        Command::GetEntries { filters, report_excluded, paths } => {
             profile_this!("GetEntries");
-            if let Err(e) = handle_get_entries(comms, context.as_mut().unwrap(), filters) {
+            if let Err(e) = handle_get_entries(comms, context.as_mut().unwrap(), filters, report_excluded, paths) {
                 comms.send_response(Response::Error(e))?;
             }
         }
@@ -389,66 +634,96 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
                 }
             }
         }
+        Command::GetEntryDetails { path } => {
+            let full_path = path.get_full_path(&context.as_ref().unwrap().root);
+            profile_this!(format!("GetEntryDetails {}", path.to_string()));
+            // We use symlink_metadata so that we see the metadata of a symlink, not its target
+            let r = match std::fs::symlink_metadata(&full_path) {
+                Ok(m) => entry_details_from_metadata(m, &full_path).map(|d| Response::EntryDetails(Some(d))),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(Response::EntryDetails(None)),
+                Err(e) => Err(format!("Error getting details of '{}': {e}", full_path.display())),
+            };
+            match r {
+                Ok(r) => comms.send_response(r)?,
+                Err(e) => comms.send_response(Response::Error(e))?,
+            }
+        }
         Command::GetFileContent { path } => {
             let full_path = path.get_full_path(&context.as_ref().unwrap().root);
             profile_this!(format!("GetFileContent {}", path.to_string()));
             if let Err(e) = handle_get_file_contents(comms, &full_path) {
-                comms.send_response(Response::Error(e))?;
+                send_entry_error(comms, &path, &full_path, e)?;
             }
         }
+        Command::GetFileContents { paths } => {
+            profile_this!(format!("GetFileContents ({})", paths.len()));
+            let root = &context.as_ref().unwrap().root;
+            let contents = paths.iter().map(|path| {
+                let full_path = path.get_full_path(root);
+                match handle_get_small_file_contents(&full_path) {
+                    Ok((data, hash)) => SmallFileContent::Content { data, hash },
+                    Err(message) => {
+                        let not_found = matches!(std::fs::symlink_metadata(&full_path), Err(e) if e.kind() == ErrorKind::NotFound);
+                        SmallFileContent::Error { message, not_found }
+                    }
+                }
+            }).collect();
+            comms.send_response(Response::FileContents(contents))?;
+        }
+        Command::GetFileHash { path } => {
+            let full_path = path.get_full_path(&context.as_ref().unwrap().root);
+            profile_this!(format!("GetFileHash {}", path.to_string()));
+            comms.send_response(Response::FileHash(handle_get_file_hash(&full_path)))?;
+        }
         Command::CreateOrUpdateFile {
             path,
             data,
             set_modified_time,
-            more_to_follow
+            more_to_follow,
+            hash,
         } => {
+            handle_create_or_update_file(comms, context.as_mut().unwrap(), path, data, set_modified_time, more_to_follow, hash)?;
+        }
+        Command::CreateOrUpdateFiles { files } => {
+            profile_this!(format!("CreateOrUpdateFiles ({})", files.len()));
+            for f in files {
+                handle_create_or_update_file(comms, context.as_mut().unwrap(), f.path, f.data, Some(f.set_modified_time), false, f.hash)?;
+            }
+        }
+        Command::CopyLocalFile { src_root, src_path, path, size, set_modified_time } => {
+            let src_full_path = src_path.get_full_path(Path::new(&src_root));
             let full_path = path.get_full_path(&context.as_ref().unwrap().root);
-            trace!("Creating/updating content of '{}'", full_path.display());
-            profile_this!(format!("CreateOrUpdateFile {}", path.to_string()));
-        //    std::thread::sleep(std::time::Duration::from_nanos(1));
-
-            // Check if this is the continuation of an existing file
-            let mut f = match context.as_mut().unwrap().in_progress_file_receive.take() {
-                Some((in_progress_path, f)) => {
-                    if in_progress_path == path {
-                        f
-                    } else {
-                        comms.send_response(Response::Error(format!("Unexpected continued file transfer!")))?;
-                        return Ok(true);
-                    }
-                },
-                None => match std::fs::File::create(&full_path) {
-                    Ok(f) => f,
-                    Err(e) => {
-                        comms.send_response(Response::Error(format!("Error writing file contents to '{}': {e}", full_path.display())))?;
-                        return Ok(true);
-                    }
+            trace!("Copying '{}' to '{}'", src_full_path.display(), full_path.display());
+            profile_this!(format!("CopyLocalFile {}", path.to_string()));
+            if let Err(e) = handle_copy_local_file(&src_full_path, &full_path, size, set_modified_time) {
+                send_entry_error(comms, &path, &full_path, e)?;
+            }
+        }
+        Command::ConnectToSource { hostname, port, transfer_id, key } => {
+            match SourceConnection::connect(&hostname, port, &transfer_id, key) {
+                Ok(s) => {
+                    *source = Some(s);
+                    comms.send_response(Response::ConnectedToSource)?;
                 }
-            };
-
-            let r = f.write_all(&data);
-            if let Err(e) = r {
-                comms.send_response(Response::Error(format!("Error writing file contents to '{}': {e}", full_path.display())))?;
-                return Ok(true);
+                Err(e) => comms.send_response(Response::Error(e))?,
             }
-
-            // If there is more data to follow, store the open file handle for next time
-            context.as_mut().unwrap().in_progress_file_receive = if more_to_follow {
-                Some((path, f))
-            } else {
-                None
-            };
-
-            // After changing the content, we need to override the modified time of the file to that of the original,
-            // otherwise it will immediately count as modified again if we do another sync.
-            if let Some(t) = set_modified_time {
-                trace!("Setting modifited time of '{}'", full_path.display());
-                let r =
-                    filetime::set_file_mtime(&full_path, filetime::FileTime::from_system_time(t));
-                if let Err(e) = r {
-                    comms.send_response(Response::Error(format!("Error setting modified time of '{}': {e}", full_path.display())))?;
+        }
+        Command::CopyFromSource { src_root, src_path, path, size, set_modified_time } => {
+            let full_path = path.get_full_path(&context.as_ref().unwrap().root);
+            trace!("Fetching '{}' from source", full_path.display());
+            profile_this!(format!("CopyFromSource {}", path.to_string()));
+            let source = match source.as_mut() {
+                Some(s) => s,
+                None => {
+                    comms.send_response(Response::Error("Not connected to source".to_string()))?;
                     return Ok(true);
                 }
+            };
+            match handle_copy_from_source(comms, source, &src_root, src_path, &full_path, size, set_modified_time) {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => send_entry_error(comms, &path, &full_path, e)?,
+                // Losing the source means nothing else can be copied, so stop the sync rather than reporting every file
+                Err(e) => comms.send_response(Response::Error(e))?,
             }
         }
         Command::CreateFolder { path } => {
@@ -456,12 +731,13 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
             trace!("Creating folder '{}'", full_path.display());
             profile_this!(format!("CreateFolder {}", full_path.to_str().unwrap().to_string()));
             if let Err(e) = std::fs::create_dir(&full_path) {
-                comms.send_response(Response::Error(format!("Error creating folder '{}': {e}", full_path.display())))?;
+                send_entry_error(comms, &path, &full_path, format!("Error creating folder '{}': {e}", full_path.display()))?;
             }
         }
         Command::CreateSymlink { path, kind, target } => {
-            if let Err(e) = handle_create_symlink(path, context.as_mut().unwrap(), kind, target) {
-                comms.send_response(Response::Error(e))?;
+            let full_path = path.get_full_path(&context.as_ref().unwrap().root);
+            if let Err(e) = handle_create_symlink(path.clone(), context.as_mut().unwrap(), kind, target) {
+                send_entry_error(comms, &path, &full_path, e)?;
             }
         },
         Command::DeleteFile { path } => {
@@ -469,7 +745,7 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
             trace!("Deleting file '{}'", full_path.display());
             profile_this!(format!("DeleteFile {}", path.to_string()));
             if let Err(e) = std::fs::remove_file(&full_path) {
-                comms.send_response(Response::Error(format!("Error deleting file '{}': {e}", full_path.display())))?;
+                send_entry_error(comms, &path, &full_path, format!("Error deleting file '{}': {e}", full_path.display()))?;
             }
         }
         Command::DeleteFolder { path } => {
@@ -477,7 +753,7 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
             trace!("Deleting folder '{}'", full_path.display());
             profile_this!(format!("DeleteFolder {}", path.to_string()));
             if let Err(e) = std::fs::remove_dir(&full_path) {
-                comms.send_response(Response::Error(format!("Error deleting folder '{}': {e}", full_path.display())))?;
+                send_entry_error(comms, &path, &full_path, format!("Error deleting folder '{}': {e}", full_path.display()))?;
             }
         }
         Command::DeleteSymlink { path, kind } => {
@@ -490,7 +766,7 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
                     SymlinkKind::Folder => std::fs::remove_dir(&full_path),
                     // We should never be asked to delete an Unknown symlink on Windows, but just in case:
                     SymlinkKind::Unknown => {
-                        comms.send_response(Response::Error(format!("Can't delete symlink of unknown type '{}'", full_path.display())))?;
+                        send_entry_error(comms, &path, &full_path, format!("Can't delete symlink of unknown type '{}'", full_path.display()))?;
                         return Ok(true);
                     }
                 }
@@ -499,9 +775,28 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
                 std::fs::remove_file(&full_path)
             };
             if let Err(e) = res {
-                comms.send_response(Response::Error(format!("Error deleting symlink '{}': {e}", full_path.display())))?;
+                send_entry_error(comms, &path, &full_path, format!("Error deleting symlink '{}': {e}", full_path.display()))?;
             }
         },
+        Command::Watch { roots } => {
+            match handle_watch(roots) {
+                Ok(w) => {
+                    *watcher = Some(w);
+                    comms.send_response(Response::Watching)?;
+                }
+                Err(e) => comms.send_response(Response::Error(e))?,
+            }
+        }
+        Command::WaitForChanges { debounce } => {
+            let r = match watcher {
+                Some(w) => handle_wait_for_changes(w, debounce),
+                None => Err("Not watching for changes".to_string()),
+            };
+            match r {
+                Ok(changes) => comms.send_response(Response::Changes(changes))?,
+                Err(e) => comms.send_response(Response::Error(e))?,
+            }
+        }
         Command::ProfilingTimeSync => {
             comms.send_response(Response::ProfilingTimeSync(PROFILING_START.elapsed()))?;
         },
@@ -515,6 +810,13 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
     Ok(true)
 }
 
+/// Reports an error about a single entry, so that the boss knows which entry it was about (see --keep-going).
+fn send_entry_error(comms: &mut Comms, path: &RootRelativePath, full_path: &Path, message: String) -> Result<(), String> {
+    // Check if the entry has gone missing, as the boss might treat this differently (e.g. a source file deleted mid-sync)
+    let not_found = matches!(std::fs::symlink_metadata(full_path), Err(e) if e.kind() == ErrorKind::NotFound);
+    comms.send_response(Response::EntryError { path: path.clone(), message, not_found })
+}
+
 fn handle_set_root(comms: &mut Comms, context: &mut Option<DoerContext>, root: String) -> Result<(), String> {
     // Store the root path for future operations
     *context = Some(DoerContext {
@@ -525,6 +827,8 @@ fn handle_set_root(comms: &mut Comms, context: &mut Option<DoerContext>, root: S
 
     let platform_differentiates_symlinks = cfg!(windows);
     let platform_dir_separator = std::path::MAIN_SEPARATOR;
+    // Allow tests to simulate a case-insensitive filesystem, as these aren't available on all test platforms
+    let case_sensitive = std::env::var("RJRSSYNC_TEST_CASE_INSENSITIVE").is_err() && is_case_sensitive(&context.root);
 
     // Respond to the boss with what type of file/folder the root is, as it makes some decisions
     // based on this.
@@ -533,11 +837,11 @@ fn handle_set_root(comms: &mut Comms, context: &mut Option<DoerContext>, root: S
     match metadata {
         Ok(m) => {
             let entry_details = entry_details_from_metadata(m, &context.root)?;
-            comms.send_response(Response::RootDetails { root_details: Some(entry_details), platform_differentiates_symlinks, platform_dir_separator })?;
+            comms.send_response(Response::RootDetails { root_details: Some(entry_details), platform_differentiates_symlinks, platform_dir_separator, case_sensitive })?;
         },
         Err(e) if e.kind() == ErrorKind::NotFound => {
             // Report this as a special error, as we handle it differently on the boss side
-            comms.send_response(Response::RootDetails { root_details: None, platform_differentiates_symlinks, platform_dir_separator })?;
+            comms.send_response(Response::RootDetails { root_details: None, platform_differentiates_symlinks, platform_dir_separator, case_sensitive })?;
         }
         Err(e) => return Err(format!(
                     "root '{}' can't be read: {}", context.root.display(), e)),
@@ -546,12 +850,96 @@ fn handle_set_root(comms: &mut Comms, context: &mut Option<DoerContext>, root: S
     Ok(())
 }
 
-#[derive(PartialEq, Debug)]
-enum FilterResult {
+/// Checks if the filesystem containing the given path (which may not exist yet) is case-sensitive.
+/// There isn't a portable API for this, so we find the closest existing ancestor that has some letters in
+/// its name, and check if the same name with the case flipped refers to the same entry.
+/// Note that this could in theory vary within the tree (e.g. mount points, or per-folder case sensitivity on Windows),
+/// but we only check the root.
+fn is_case_sensitive(path: &Path) -> bool {
+    let path = match std::env::current_dir() {
+        Ok(cwd) => cwd.join(path), // Relative paths would run out of ancestors too early
+        Err(_) => path.to_path_buf(),
+    };
+    for a in path.ancestors() {
+        let metadata = match std::fs::symlink_metadata(a) {
+            Ok(m) => m,
+            Err(_) => continue, // Doesn't exist (yet)
+        };
+        let name = match a.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n,
+            None => continue,
+        };
+        let flipped: String = name.chars().map(|c| {
+            if c.is_lowercase() { c.to_uppercase().collect::<String>() } else { c.to_lowercase().collect() }
+        }).collect();
+        if flipped == name {
+            continue; // No letters, so can't tell from this one
+        }
+        return match std::fs::symlink_metadata(a.with_file_name(flipped)) {
+            Ok(flipped_metadata) => !is_same_entry(&metadata, &flipped_metadata),
+            Err(_) => true,
+        };
+    }
+    // Couldn't find anything to check, so assume the platform's usual behaviour
+    !cfg!(any(windows, target_os="macos"))
+}
+
+/// Checks if two sets of metadata refer to the same filesystem entry.
+fn is_same_entry(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    // On Windows there isn't a stable way of getting the file ID, but it's very unlikely that
+    // there would be two separate entries whose names differ only in case.
+    #[cfg(windows)]
+    {
+        let _ = (a, b);
+        true
+    }
+    #[cfg(not(windows))]
+    {
+        use std::os::unix::fs::MetadataExt;
+        a.dev() == b.dev() && a.ino() == b.ino()
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum FilterResult {
     Include,
     Exclude
 }
 
+/// Detailed breakdown of how the filters apply to a path, used to help users debug their filters
+/// (see --explain-filters).
+#[derive(PartialEq, Debug)]
+pub struct FilterExplanation {
+    /// Indices (into Filters::kinds) of every filter that matched the path, in order.
+    pub matched_filters: Vec<usize>,
+    /// The result of applying the filters to the path itself.
+    pub result: FilterResult,
+    /// The outermost ancestor folder that is excluded, if any. If present, this path would never be seen
+    /// as the walk doesn't descend into excluded folders, regardless of `result`.
+    pub excluded_ancestor: Option<RootRelativePath>,
+}
+
+/// Works out how the filters apply to the given path. This is much slower than apply_filters,
+/// so is only for diagnostics, not for use during the walk.
+pub fn explain_filters(path: &RootRelativePath, filters: &Filters) -> FilterExplanation {
+    let matched_filters = if path.is_root() {
+        vec![] // Filters aren't checked for the root (see apply_filters)
+    } else {
+        path.regex_set_matches(&filters.regex_set).into_iter().collect()
+    };
+
+    // Check the ancestors from the outermost inwards, as the first excluded one is where the walk would stop
+    let mut ancestors = vec![];
+    let mut p = path.parent();
+    while let Some(a) = p {
+        p = a.parent();
+        ancestors.push(a);
+    }
+    let excluded_ancestor = ancestors.into_iter().rev().find(|a| apply_filters(a, filters) == FilterResult::Exclude);
+
+    FilterExplanation { matched_filters, result: apply_filters(path, filters), excluded_ancestor }
+}
+
 fn apply_filters(path: &RootRelativePath, filters: &Filters) -> FilterResult {
     if path.is_root() {
         // The root is always included, otherwise it would be difficult to write filter lists that start with include,
@@ -559,10 +947,12 @@ fn apply_filters(path: &RootRelativePath, filters: &Filters) -> FilterResult {
         return FilterResult::Include;
     }
 
-    // Depending on whether the first filter is include or exclude, the default state is the opposite
-    let mut result = match filters.kinds.get(0) {
+    // Depending on whether the first filter is include or exclude, the default state is the opposite.
+    // Protect filters don't affect this, as they are only relevant to the boss.
+    let mut result = match filters.kinds.iter().find(|k| !matches!(k, FilterKind::Protect)) {
         Some(FilterKind::Include) => FilterResult::Exclude,
         Some(FilterKind::Exclude) => FilterResult::Include,
+        Some(FilterKind::Protect) => panic!("Should have been skipped"),
         None => FilterResult::Include
     };
 
@@ -578,6 +968,7 @@ fn apply_filters(path: &RootRelativePath, filters: &Filters) -> FilterResult {
         match filter_kind {
             FilterKind::Include => result = FilterResult::Include,
             FilterKind::Exclude => result = FilterResult::Exclude,
+            FilterKind::Protect => (), // Only used on the boss side, for deciding what can be deleted
         }
     }
 
@@ -585,7 +976,11 @@ fn apply_filters(path: &RootRelativePath, filters: &Filters) -> FilterResult {
 }
 
 /// Filter callback used when iterating over directory contents.
-fn filter_func(entry: &std::fs::DirEntry, root: &Path, filters: &Filters) -> Result<parallel_walk_dir::FilterResult<RootRelativePath>, String> {
+/// If report_excluded is set, excluded entries are not skipped, but are instead marked as excluded
+/// in the additional data, so that they can still be reported to the boss.
+fn filter_func(entry: &std::fs::DirEntry, root: &Path, filters: &Filters, report_excluded: bool)
+    -> Result<parallel_walk_dir::FilterResult<(RootRelativePath, FilterResult)>, String>
+{
     // First normalize the path to our platform-independent representation, so that the filters
     // apply equally well on both source and dest sides, if they are different platforms.
 
@@ -598,37 +993,91 @@ fn filter_func(entry: &std::fs::DirEntry, root: &Path, filters: &Filters) -> Res
         Err(e) => return Err(format!("normalize_path failed on '{}': {e}", path.display())),
     };
 
-    let skip = apply_filters(&path, &filters) == FilterResult::Exclude;
+    let filter_result = apply_filters(&path, filters);
+    let skip = filter_result == FilterResult::Exclude && !report_excluded;
     if skip {
         trace!("Skipping '{}' due to filter", path);
     }
     // Store the normalized root-relative path so that we don't need to re-calculate this when we process
     // this entry
-    Ok(parallel_walk_dir::FilterResult::<RootRelativePath> {
+    Ok(parallel_walk_dir::FilterResult::<(RootRelativePath, FilterResult)> {
         skip,
-        additional_data: path,
+        additional_data: (path, filter_result),
     })
 }
 
-fn handle_get_entries(comms: &mut Comms, context: &mut DoerContext, filters: Filters) -> Result<(), String> {
+fn handle_get_entries(comms: &mut Comms, context: &mut DoerContext, filters: Filters, report_excluded: bool,
+    paths: Option<Vec<RootRelativePath>>) -> Result<(), String>
+{
     let start = Instant::now();
+    // When reporting excluded entries, everything inside an excluded folder counts as excluded too,
+    // regardless of what the filters say about it (consistent with how the folder would not be walked at all otherwise).
+    // The walk always provides a folder before its children, so we can keep track of these as we go.
+    let mut excluded_folders = HashSet::new();
+    let mut count = 0;
+    match paths {
+        None => count += walk_entries(comms, &context.root, &context.root, &filters, report_excluded, &mut excluded_folders)?,
+        Some(paths) => for path in paths {
+            let full_path = path.get_full_path(&context.root);
+            // We use symlink_metadata so that we see the metadata of a symlink, not its target
+            let metadata = match std::fs::symlink_metadata(&full_path) {
+                Ok(m) => m,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue, // Nothing to report, e.g. it's been deleted
+                Err(e) => return Err(format!("Unable to get metadata for '{}': {e}", path)),
+            };
+            let d = entry_details_from_metadata(metadata, &full_path)?;
+
+            // This entry wouldn't have been seen at all by a full walk if one of its ancestors is excluded
+            let explanation = explain_filters(&path, &filters);
+            let filter_result = if explanation.excluded_ancestor.is_some() { FilterResult::Exclude } else { explanation.result };
+            if filter_result == FilterResult::Exclude && !report_excluded {
+                trace!("Skipping '{}' due to filter", path);
+                continue;
+            }
+
+            count += 1;
+            let is_folder = matches!(d, EntryDetails::Folder);
+            send_entry(comms, path, d, filter_result == FilterResult::Exclude, &mut excluded_folders)?;
+            if is_folder {
+                count += walk_entries(comms, &context.root, &full_path, &filters, report_excluded, &mut excluded_folders)?;
+            }
+        }
+    }
+
+    let elapsed = start.elapsed().as_millis();
+    comms.send_response(Response::EndOfEntries)?;
+    debug!(
+        "Walked {} in {}ms ({}/s)",
+        count,
+        elapsed,
+        1000.0 * count as f32 / elapsed as f32
+    );
+
+    Ok(())
+}
+
+/// Reports everything inside the given folder (which is the root, or inside it), returning how many entries were found.
+fn walk_entries(comms: &mut Comms, root: &Path, folder: &Path, filters: &Filters, report_excluded: bool,
+    excluded_folders: &mut HashSet<RootRelativePath>) -> Result<usize, String>
+{
     // Note that we can't use this to get metadata for a single root entry when that entry is a symlink,
     // as the iteration will fail before we can get the metadata for the root. Therefore we only use this
     // when walking what's known to be a directory (discovered in SetRoot).
-    let root = context.root.clone();
-    let entry_receiver = parallel_walk_dir(&context.root, move |e| filter_func(e, &root, &filters));
+    let root_clone = root.to_path_buf();
+    let filters = filters.clone();
+    let entry_receiver = parallel_walk_dir(folder, move |e| filter_func(e, &root_clone, &filters, report_excluded));
     let mut count = 0;
     while let Ok(entry) = entry_receiver.recv() {
         count += 1;
         match entry {
-            Err(e) => return Err(format!("Error fetching entries of root '{}': {e}", context.root.display())),
+            Err(e) => return Err(format!("Error fetching entries of root '{}': {e}", root.display())),
             Ok(e) => {
                 trace!("Processing entry {:?}", e);
                 profile_this!("Processing entry");
 
                 // The root-relative path was stored when this entry was tested against the filter,
                 // so that we don't need to re-normalize it here.
-                let path = e.additional_data;
+                let (path, filter_result) = e.additional_data;
 
                 let metadata = match e.dir_entry.metadata() {
                     Ok(m) => m,
@@ -637,20 +1086,284 @@ fn handle_get_entries(comms: &mut Comms, context: &mut DoerContext, filters: Fil
 
                 let d = entry_details_from_metadata(metadata, &e.dir_entry.path())?;
 
-                comms.send_response(Response::Entry((path, d)))?;
+                send_entry(comms, path, d, filter_result == FilterResult::Exclude, excluded_folders)?;
             }
         }
     }
+    Ok(count)
+}
 
-    let elapsed = start.elapsed().as_millis();
-    comms.send_response(Response::EndOfEntries)?;
-    debug!(
-        "Walked {} in {}ms ({}/s)",
-        count,
-        elapsed,
-        1000.0 * count as f32 / elapsed as f32
-    );
+fn send_entry(comms: &mut Comms, path: RootRelativePath, d: EntryDetails, excluded: bool,
+    excluded_folders: &mut HashSet<RootRelativePath>) -> Result<(), String>
+{
+    let excluded = excluded || path.parent().is_some_and(|p| excluded_folders.contains(&p));
+    if excluded {
+        if matches!(d, EntryDetails::Folder) {
+            excluded_folders.insert(path.clone());
+        }
+        comms.send_response(Response::ExcludedEntry((path, d)))
+    } else {
+        comms.send_response(Response::Entry((path, d)))
+    }
+}
+
+/// A connection from this (dest) doer to the source doer, acting like a boss, to fetch file contents
+/// directly rather than them being sent via the boss (see --direct-transfer).
+struct SourceConnection {
+    comms: AsyncEncryptedComms<Command, Response>,
+    /// The root that the source doer has been told about, which changes for each sync.
+    root: Option<String>,
+}
+impl SourceConnection {
+    fn connect(hostname: &str, port: u16, transfer_id: &TransferId, key: [u8; 16]) -> Result<SourceConnection, String> {
+        debug!("Connecting to source doer at {:?}", (hostname, port));
+        let mut tcp_connection = TcpStream::connect((hostname, port))
+            .map_err(|e| format!("Failed to connect to source doer at {hostname}:{port}: {e}"))?;
+        // We don't ask for more than one stream or for compression, as the source and dest doers are usually close to each other
+        let (session_key, options) = transfer_session_handshake(&mut tcp_connection, transfer_id, GenericArray::from_slice(&key), SessionOptions::default())?;
+        Ok(SourceConnection {
+            comms: AsyncEncryptedComms::new(
+                vec![tcp_connection],
+                session_key,
+                options.compression_level,
+                0, // Nonce counters must be different, so sender and receiver don't reuse. We are the 'boss' here.
+                1,
+                ("dest doer", "source doer"),
+            ),
+            root: None,
+        })
+    }
+
+    fn send_command(&self, c: Command) -> Result<(), String> {
+        self.comms.sender.send(c).map_err(|_| "Lost communication with source doer".to_string())
+    }
+
+    fn receive_response(&self) -> Result<Response, String> {
+        self.comms.receiver.recv().map_err(|_| "Lost communication with source doer".to_string())
+    }
+
+    /// Tells the source doer that we're done, so that it finishes the session cleanly.
+    fn disconnect(self) {
+        if self.send_command(Command::Shutdown).is_ok() {
+            // The source doer always sends back a final message (see doer_main). We don't need its profiling data.
+            let _ = self.receive_response();
+        }
+        self.comms.shutdown();
+    }
+}
 
+/// Fetches a file from the source doer and writes it to full_path. The outer error is for problems communicating with
+/// the source, which mean nothing else can be fetched, and the inner error is for problems with just this file.
+fn handle_copy_from_source(comms: &mut Comms, source: &mut SourceConnection, src_root: &str, src_path: RootRelativePath, full_path: &Path,
+    size: u64, set_modified_time: SystemTime) -> Result<Result<(), String>, String>
+{
+    if source.root.as_deref() != Some(src_root) {
+        source.send_command(Command::SetRoot { root: src_root.to_string() })?;
+        match source.receive_response()? {
+            Response::RootDetails { .. } => source.root = Some(src_root.to_string()),
+            x => return Err(format!("Unexpected response from source doer (expected RootDetails): {:?}", x)),
+        }
+    }
+
+    source.send_command(Command::GetFileContent { path: src_path })?;
+    // The file isn't created until the first chunk arrives, so that it is left alone if the source can't be read at all.
+    // After a failure writing, the rest of the chunks still need receiving, but are thrown away.
+    let mut file: Option<Result<(std::fs::File, Sha256), String>> = None;
+    let mut written = 0;
+    loop {
+        match source.receive_response()? {
+            Response::FileContent { data, more_to_follow, hash } => {
+                let f = file.get_or_insert_with(|| std::fs::File::create(full_path).map(|f| (f, Sha256::new()))
+                    .map_err(|e| format!("Error writing file contents to '{}': {e}", full_path.display())));
+                if let Ok((f, hasher)) = f {
+                    match f.write_all(&data) {
+                        Ok(()) => {
+                            hasher.update(&data);
+                            if more_to_follow {
+                                comms.send_response(Response::ChunkFetched { chunk_start: written, chunk_size: data.len() as u64, file_size: size })?;
+                            }
+                            written += data.len() as u64;
+                        }
+                        Err(e) => *file.as_mut().unwrap() = Err(format!("Error writing file contents to '{}': {e}", full_path.display())),
+                    }
+                }
+                if !more_to_follow {
+                    let (f, hasher) = match file.unwrap() {
+                        Ok(x) => x,
+                        Err(e) => {
+                            let _ = std::fs::remove_file(full_path);
+                            return Ok(Err(e));
+                        }
+                    };
+                    drop(f);
+                    // Check the file we wrote is the same as what the source read, and that it is what the boss expected,
+                    // and if not then delete it rather than leave a file that looks up-to-date.
+                    let actual_hash: [u8; 32] = hasher.finalize().into();
+                    if Some(actual_hash) != hash {
+                        let _ = std::fs::remove_file(full_path);
+                        return Ok(Err(format!("Checksum mismatch for '{}', so it has been deleted", full_path.display())));
+                    }
+                    if written != size {
+                        let _ = std::fs::remove_file(full_path);
+                        return Ok(Err(format!("Size of '{}' has changed during the sync, so it has been deleted", full_path.display())));
+                    }
+                    break;
+                }
+            }
+            Response::EntryError { message, .. } => {
+                // Don't leave a partial file behind
+                if file.is_some() {
+                    let _ = std::fs::remove_file(full_path);
+                }
+                return Ok(Err(message));
+            }
+            x => return Err(format!("Unexpected response from source doer (expected FileContent): {:?}", x)),
+        }
+    }
+
+    // After changing the content, we need to override the modified time of the file to that of the original,
+    // otherwise it will immediately count as modified again if we do another sync.
+    Ok(filetime::set_file_mtime(full_path, filetime::FileTime::from_system_time(set_modified_time))
+        .map_err(|e| format!("Error setting modified time of '{}': {e}", full_path.display())))
+}
+
+/// Watches the roots of the syncs for changes (see --watch). The watcher reports events on its own thread,
+/// which are queued up until the boss asks for them (see WaitForChanges).
+struct SourceWatcher {
+    /// Stops watching when dropped.
+    _watcher: notify::RecommendedWatcher,
+    roots: Vec<PathBuf>,
+    events: std::sync::mpsc::Receiver<notify::Result<notify::Event>>,
+}
+
+fn handle_watch(roots: Vec<String>) -> Result<SourceWatcher, String> {
+    let (sender, events) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(|e| format!("Error starting to watch for changes: {e}"))?;
+    // The watcher reports absolute paths, which on some platforms have symlinks resolved (e.g. /tmp on macOS),
+    // so we need the roots in the same form to find which root each event is for
+    let roots = roots.into_iter().map(|r| std::fs::canonicalize(&r).map_err(|e| format!("Error watching '{r}' for changes: {e}")))
+        .collect::<Result<Vec<PathBuf>, String>>()?;
+    for r in &roots {
+        watcher.watch(r, notify::RecursiveMode::Recursive).map_err(|e| format!("Error watching '{}' for changes: {e}", r.display()))?;
+    }
+    Ok(SourceWatcher { _watcher: watcher, roots, events })
+}
+
+fn handle_wait_for_changes(watcher: &SourceWatcher, debounce: Duration) -> Result<Vec<(usize, RootRelativePath)>, String> {
+    let mut changes = HashSet::new();
+    // Block until the first change, then keep collecting changes until it has been quiet for the debounce time
+    let mut next = watcher.events.recv();
+    loop {
+        let event = match next {
+            Ok(Ok(e)) => e,
+            Ok(Err(e)) => return Err(format!("Error watching for changes: {e}")),
+            Err(_) => return Err("Stopped watching for changes unexpectedly".to_string()),
+        };
+        trace!("Watch event {:?}", event);
+        if event.need_rescan() {
+            // Some events were missed, so we don't know what changed
+            for i in 0..watcher.roots.len() {
+                changes.insert((i, RootRelativePath::root()));
+            }
+        }
+        // Reading the source files (e.g. when syncing them!) doesn't change anything
+        if !matches!(event.kind, notify::EventKind::Access(_)) {
+            for p in &event.paths {
+                if let Some(c) = watcher.roots.iter().enumerate().find_map(|(i, r)| p.strip_prefix(r).ok().map(|rel| (i, rel))) {
+                    match RootRelativePath::try_from(c.1) {
+                        Ok(path) => { changes.insert((c.0, path)); },
+                        Err(e) => return Err(format!("normalize_path failed on '{}': {e}", p.display())),
+                    }
+                }
+            }
+        }
+
+        next = match watcher.events.recv_timeout(debounce) {
+            Ok(e) => Ok(e),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) if !changes.is_empty() => break,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => watcher.events.recv(), // Nothing relevant yet, so keep waiting
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => Err(std::sync::mpsc::RecvError),
+        };
+    }
+
+    // Leave out anything inside another changed folder, as the folder will be synced including its contents
+    let has_changed_ancestor = |(i, p): &(usize, RootRelativePath)| {
+        let mut a = p.parent();
+        while let Some(x) = a {
+            if changes.contains(&(*i, x.clone())) {
+                return true;
+            }
+            a = x.parent();
+        }
+        false
+    };
+    let mut result: Vec<(usize, RootRelativePath)> = changes.iter().filter(|c| !has_changed_ancestor(c)).cloned().collect();
+    result.sort_by_key(|(root, path)| (*root, path.to_string()));
+    Ok(result)
+}
+
+/// Errors writing the file are reported to the boss (see send_entry_error), so an error is only returned if that fails.
+fn handle_create_or_update_file(comms: &mut Comms, context: &mut DoerContext, path: RootRelativePath, data: Vec<u8>,
+    set_modified_time: Option<SystemTime>, more_to_follow: bool, hash: Option<[u8; 32]>) -> Result<(), String>
+{
+    let full_path = path.get_full_path(&context.root);
+    trace!("Creating/updating content of '{}'", full_path.display());
+    profile_this!(format!("CreateOrUpdateFile {}", path.to_string()));
+
+    // Check if this is the continuation of an existing file
+    let (mut f, mut hasher) = match context.in_progress_file_receive.take() {
+        Some((in_progress_path, f, hasher)) => {
+            if in_progress_path == path {
+                (f, hasher)
+            } else {
+                comms.send_response(Response::Error(format!("Unexpected continued file transfer!")))?;
+                return Ok(());
+            }
+        },
+        None => match std::fs::File::create(&full_path) {
+            Ok(f) => (f, Sha256::new()),
+            Err(e) => {
+                send_entry_error(comms, &path, &full_path, format!("Error writing file contents to '{}': {e}", full_path.display()))?;
+                return Ok(());
+            }
+        }
+    };
+
+    let r = f.write_all(&data);
+    if let Err(e) = r {
+        send_entry_error(comms, &path, &full_path, format!("Error writing file contents to '{}': {e}", full_path.display()))?;
+        return Ok(());
+    }
+    hasher.update(&data);
+
+    // If there is more data to follow, store the open file handle for next time
+    if more_to_follow {
+        context.in_progress_file_receive = Some((path.clone(), f, hasher));
+    } else {
+        drop(f);
+        // Check that the file we wrote is the same as the file that the source doer read, and if not
+        // then delete it rather than leave a corrupt file that looks up-to-date.
+        if let Some(expected_hash) = hash {
+            let actual_hash: [u8; 32] = hasher.finalize().into();
+            if actual_hash != expected_hash {
+                let _ = std::fs::remove_file(&full_path);
+                send_entry_error(comms, &path, &full_path, format!("Checksum mismatch for '{}', so it has been deleted", full_path.display()))?;
+                return Ok(());
+            }
+        }
+    }
+
+    // After changing the content, we need to override the modified time of the file to that of the original,
+    // otherwise it will immediately count as modified again if we do another sync.
+    if let Some(t) = set_modified_time {
+        trace!("Setting modified time of '{}'", full_path.display());
+        let r =
+            filetime::set_file_mtime(&full_path, filetime::FileTime::from_system_time(t));
+        if let Err(e) = r {
+            send_entry_error(comms, &path, &full_path, format!("Error setting modified time of '{}': {e}", full_path.display()))?;
+            return Ok(());
+        }
+    }
     Ok(())
 }
 
@@ -671,6 +1384,8 @@ fn handle_get_file_contents(comms: &mut Comms, full_path: &Path) -> Result<(), S
     // Start with a small chunk size to minimize initialization overhead for small files,
     // but we'll increase this if the file is big
     let mut chunk_size = 4 * 1024;
+    // Hash of the whole file, sent with the final chunk so that the dest doer can check that it has written the same data
+    let mut hasher = Sha256::new();
     let mut prev_buf = vec![0; 0];
     let mut prev_buf_valid = 0;
     let mut next_buf = vec![0; chunk_size];
@@ -680,14 +1395,16 @@ fn handle_get_file_contents(comms: &mut Comms, full_path: &Path) -> Result<(), S
             Ok(n) if n == 0 => {
                 // End of file - send the data that we got previously, and report that there is no more data to follow.
                 prev_buf.truncate(prev_buf_valid);
-                comms.send_response(Response::FileContent { data: prev_buf, more_to_follow: false })?;
+                hasher.update(&prev_buf);
+                comms.send_response(Response::FileContent { data: prev_buf, more_to_follow: false, hash: Some(hasher.finalize().into()) })?;
                 return Ok(());
             },
             Ok(n) => {
                 // Some data read - send any previously retrieved data, and report that there is more data to follow
                 if prev_buf_valid > 0 {
                     prev_buf.truncate(prev_buf_valid);
-                    comms.send_response(Response::FileContent { data: prev_buf, more_to_follow: true })?;
+                    hasher.update(&prev_buf);
+                    comms.send_response(Response::FileContent { data: prev_buf, more_to_follow: true, hash: None })?;
                 }
 
                 // The data we just retrieved will be sent in the next iteration (once we know if there is more data to follow or not)
@@ -711,6 +1428,62 @@ fn handle_get_file_contents(comms: &mut Comms, full_path: &Path) -> Result<(), S
     }
 }
 
+/// Reads the whole of a small file (see GetFileContents), returning its contents and hash.
+fn handle_get_small_file_contents(full_path: &Path) -> Result<(Vec<u8>, [u8; 32]), String> {
+    trace!("Getting content of '{}'", full_path.display());
+    let f = match std::fs::File::open(&full_path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Error opening file '{}': {e}", full_path.display())),
+    };
+    // Limit how much is read in case the file has grown, so that the response doesn't get too big.
+    // The boss will see that the size is different.
+    let mut data = vec![];
+    if let Err(e) = f.take(SMALL_FILE_MAX_SIZE + 1).read_to_end(&mut data) {
+        return Err(format!("Error getting file content of '{}': {e}", full_path.display()));
+    }
+    let hash = Sha256::digest(&data).into();
+    Ok((data, hash))
+}
+
+fn handle_get_file_hash(full_path: &Path) -> Result<[u8; 32], String> {
+    trace!("Hashing content of '{}'", full_path.display());
+
+    let mut f = match std::fs::File::open(&full_path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Error opening file '{}': {e}", full_path.display())),
+    };
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1024*1024];
+    loop {
+        match f.read(&mut buf) {
+            Ok(0) => return Ok(hasher.finalize().into()),
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) => return Err(format!("Error getting file content of '{}': {e}", full_path.display())),
+        }
+    }
+}
+
+/// Copies the contents of a file on this computer to another. This writes to the dest the same way as
+/// CreateOrUpdateFile, rather than using std::fs::copy, so that the permissions of the dest file aren't changed.
+fn handle_copy_local_file(src_full_path: &Path, full_path: &Path, size: u64, set_modified_time: SystemTime) -> Result<(), String> {
+    let mut src_file = std::fs::File::open(src_full_path).map_err(|e| format!("Error opening '{}': {e}", src_full_path.display()))?;
+    let mut dest_file = std::fs::File::create(full_path).map_err(|e| format!("Error writing file contents to '{}': {e}", full_path.display()))?;
+    let copied = std::io::copy(&mut src_file, &mut dest_file).map_err(|e| format!("Error copying '{}' to '{}': {e}", src_full_path.display(), full_path.display()))?;
+    drop(dest_file);
+
+    if copied != size {
+        // Rather than leave a file that looks up-to-date but might not be what the boss expected
+        let _ = std::fs::remove_file(full_path);
+        return Err(format!("Size of '{}' has changed during the sync, so '{}' has been deleted", src_full_path.display(), full_path.display()));
+    }
+
+    // After changing the content, we need to override the modified time of the file to that of the original,
+    // otherwise it will immediately count as modified again if we do another sync.
+    filetime::set_file_mtime(full_path, filetime::FileTime::from_system_time(set_modified_time))
+        .map_err(|e| format!("Error setting modified time of '{}': {e}", full_path.display()))
+}
+
 fn handle_create_symlink(path: RootRelativePath, context: &mut DoerContext, #[allow(unused)] kind: SymlinkKind, target: SymlinkTarget) -> Result<(), String> {
     let full_path = path.get_full_path(&context.root);
     trace!("Creating symlink at '{}'", full_path.display());
@@ -815,4 +1588,163 @@ mod tests {
         assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("src/build/file.o")).unwrap(), &filters), FilterResult::Exclude);
         assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("src/source.cpp")).unwrap(), &filters), FilterResult::Include);
     }
+
+    #[test]
+    fn test_explain_filters() {
+        let filters = Filters {
+            regex_set: RegexSet::new([
+                "^build$",
+                "^build/output.exe$",
+                "^.*\\.exe$",
+            ]).unwrap(),
+            kinds: vec![
+                FilterKind::Exclude,
+                FilterKind::Include,
+                FilterKind::Protect,
+            ]
+        };
+        // Included by the filters themselves, but can never be reached because the parent folder is excluded
+        assert_eq!(explain_filters(&RootRelativePath::try_from(Path::new("build/output.exe")).unwrap(), &filters), FilterExplanation {
+            matched_filters: vec![1, 2],
+            result: FilterResult::Include,
+            excluded_ancestor: Some(RootRelativePath::try_from(Path::new("build")).unwrap()),
+        });
+        assert_eq!(explain_filters(&RootRelativePath::try_from(Path::new("build")).unwrap(), &filters), FilterExplanation {
+            matched_filters: vec![0],
+            result: FilterResult::Exclude,
+            excluded_ancestor: None,
+        });
+        assert_eq!(explain_filters(&RootRelativePath::try_from(Path::new("src/main.c")).unwrap(), &filters), FilterExplanation {
+            matched_filters: vec![],
+            result: FilterResult::Include,
+            excluded_ancestor: None,
+        });
+    }
+
+    #[test]
+    fn test_is_case_sensitive() {
+        let temp = tempdir::TempDir::new("rjrssync-test").unwrap();
+        // Also check a path that doesn't exist yet, which should use its parent instead
+        let missing = temp.path().join("does-not-exist");
+        let expected = !cfg!(any(windows, target_os="macos"));
+        assert_eq!(is_case_sensitive(temp.path()), expected);
+        assert_eq!(is_case_sensitive(&missing), expected);
+    }
+
+    /// A file whose contents don't match the hash from the source is deleted rather than left looking
+    /// up-to-date, and the boss is told about it.
+    #[test]
+    fn test_create_or_update_file_checksum_mismatch() {
+        let temp = tempdir::TempDir::new("rjrssync-test").unwrap();
+        let (sender, response_receiver) = crate::memory_bound_channel::new(1024 * 1024);
+        let (_command_sender, receiver) = crate::memory_bound_channel::new(1024 * 1024);
+        let mut comms = Comms::Local { sender, receiver };
+        let mut context = DoerContext { root: temp.path().to_path_buf(), in_progress_file_receive: None };
+        let path = RootRelativePath::try_from(Path::new("file")).unwrap();
+
+        // Split across two chunks, to check that the hash covers all of them
+        handle_create_or_update_file(&mut comms, &mut context, path.clone(), b"cont".to_vec(), None, true, None).unwrap();
+        handle_create_or_update_file(&mut comms, &mut context, path.clone(), b"ents".to_vec(), Some(SystemTime::UNIX_EPOCH), false,
+            Some([0; 32])).unwrap();
+        assert!(!temp.path().join("file").exists());
+        match response_receiver.try_recv() {
+            Ok(Response::EntryError { path: p, message, not_found: _ }) => {
+                assert_eq!(p, path);
+                assert!(message.contains("Checksum mismatch"), "{message}");
+            }
+            r => panic!("Unexpected response: {r:?}"),
+        }
+        assert!(response_receiver.try_recv().is_err());
+
+        // Whereas the right hash keeps the file
+        let hash: [u8; 32] = Sha256::digest(b"contents").into();
+        handle_create_or_update_file(&mut comms, &mut context, path.clone(), b"contents".to_vec(), None, false, Some(hash)).unwrap();
+        assert_eq!(std::fs::read(temp.path().join("file")).unwrap(), b"contents");
+        assert!(response_receiver.try_recv().is_err());
+    }
+
+    /// Changes are collected until it has been quiet for the debounce time, and anything inside a changed folder
+    /// is left out, as the folder will be synced including its contents.
+    #[test]
+    fn test_wait_for_changes() {
+        let temp = tempdir::TempDir::new("rjrssync-test").unwrap();
+        let root = temp.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        // Watch the root via a symlink, to check that the paths of the events still match it
+        #[cfg(unix)]
+        let watched = {
+            let link = temp.path().join("link");
+            std::os::unix::fs::symlink(&root, &link).unwrap();
+            link
+        };
+        #[cfg(not(unix))]
+        let watched = root.clone();
+        let watcher = handle_watch(vec![watched.to_str().unwrap().to_string()]).unwrap();
+
+        let writer = std::thread::spawn({
+            let root = root.clone();
+            move || {
+                std::fs::write(root.join("file1"), "contents").unwrap();
+                // Within the debounce time of the first change, so should be reported along with it
+                std::thread::sleep(Duration::from_millis(200));
+                std::fs::create_dir_all(root.join("folder/sub")).unwrap();
+                std::fs::write(root.join("folder/sub/file2"), "contents").unwrap();
+            }
+        });
+        let changes = handle_wait_for_changes(&watcher, Duration::from_secs(1)).unwrap();
+        writer.join().unwrap();
+        assert_eq!(changes, vec![
+            (0, RootRelativePath::try_from(Path::new("file1")).unwrap()),
+            (0, RootRelativePath::try_from(Path::new("folder")).unwrap()),
+        ]);
+    }
+
+    /// A transfer session (see --direct-transfer) can fetch file contents, but can't change anything.
+    #[test]
+    fn test_transfer_session_refuses_other_commands() {
+        let temp = tempdir::TempDir::new("rjrssync-test").unwrap();
+        std::fs::write(temp.path().join("file"), "contents").unwrap();
+        let (sender, response_receiver) = crate::memory_bound_channel::new(1024 * 1024);
+        let (command_sender, receiver) = crate::memory_bound_channel::new(1024 * 1024);
+        let doer = std::thread::spawn(move || message_loop(&mut Comms::Local { sender, receiver }, true));
+
+        let path = RootRelativePath::try_from(Path::new("file")).unwrap();
+        command_sender.send(Command::SetRoot { root: temp.path().to_str().unwrap().to_string() }).unwrap();
+        assert!(matches!(response_receiver.recv(), Ok(Response::RootDetails { .. })));
+        command_sender.send(Command::DeleteFile { path: path.clone() }).unwrap();
+        match response_receiver.recv() {
+            Ok(Response::Error(message)) => assert!(message.contains("not allowed"), "{message}"),
+            r => panic!("Unexpected response: {r:?}"),
+        }
+        command_sender.send(Command::CreateOrUpdateFile { path: path.clone(), data: b"changed".to_vec(),
+            set_modified_time: None, more_to_follow: false, hash: None }).unwrap();
+        assert!(matches!(response_receiver.recv(), Ok(Response::Error(_))));
+
+        // Fetching the contents is still fine, and the file is untouched
+        command_sender.send(Command::GetFileContent { path }).unwrap();
+        match response_receiver.recv() {
+            Ok(Response::FileContent { data, more_to_follow: false, .. }) => assert_eq!(data, b"contents"),
+            r => panic!("Unexpected response: {r:?}"),
+        }
+        command_sender.send(Command::Shutdown).unwrap();
+        assert_eq!(doer.join().unwrap(), Ok(()));
+    }
+
+    /// Protect filters are for the boss only, so shouldn't change what the doer includes or excludes,
+    /// including the default state which is based on the first include/exclude filter.
+    #[test]
+    fn test_apply_filters_protect_ignored() {
+        let filters = Filters {
+            regex_set: RegexSet::new(&[
+                "^.*\\.cfg$",
+                "^yes.*$",
+            ]).unwrap(),
+            kinds: vec![
+                FilterKind::Protect,
+                FilterKind::Include,
+            ]
+        };
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("yes.cfg")).unwrap(), &filters), FilterResult::Include);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("no.cfg")).unwrap(), &filters), FilterResult::Exclude);
+    }
 }
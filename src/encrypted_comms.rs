@@ -1,9 +1,12 @@
-use std::{net::TcpStream, io::{Write, Read}, thread::{JoinHandle, self}, fmt::{Display, Debug}};
+use std::{net::TcpStream, io::{Write, Read}, thread::{JoinHandle, self}, fmt::{Display, Debug}, time::Duration,
+    sync::{Arc, Condvar, Mutex, atomic::{AtomicU64, Ordering}}, collections::BTreeMap};
 
-use aead::{Key, KeyInit};
+use aead::{Key, KeyInit, OsRng, rand_core::RngCore};
 use aes_gcm::{Aes128Gcm, aead::{Nonce}, AeadInPlace};
+use flate2::{Compression, write::DeflateEncoder, read::DeflateDecoder};
 use log::{trace, error};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::{profile_this, memory_bound_channel::{Sender, Receiver, self}, BOSS_DOER_CHANNEL_MEMORY_CAPACITY};
 
@@ -11,6 +14,12 @@ pub trait IsFinalMessage {
     fn is_final_message(&self) -> bool;
 }
 
+/// Lets messages which are known to contain data that won't compress (e.g. the contents of a zip file)
+/// skip compression (see --compress), rather than wasting time trying.
+pub trait WorthCompressing {
+    fn worth_compressing(&self) -> bool;
+}
+
 /// Provides asynchronous, encrypted communication over a TcpStream, sending messages of type S
 /// and receiving messages of type R.
 /// A background thread is spawned for each sending and receiving, and a cross-thread channel is used
@@ -19,102 +28,172 @@ pub trait IsFinalMessage {
 /// up for sending instantly, even if a previous message is still being encrypted or the network
 /// is blocking. Similary, received messages don't need to be retrieved immediately as the background
 /// thread will keep receiving and decrypting messages and storing them in the channel for later processing.
+///
+/// The messages can also be spread over several TcpStreams (see --streams), which is faster over links with high latency,
+/// as each TCP connection can only have so much data in flight at once. Each stream has its own sending and receiving threads,
+/// and each message is numbered so that the receiving side can put them back in order. The final message (see IsFinalMessage)
+/// is always sent on the first stream, and the other streams end with an empty message instead, so that shutting down works
+/// the same as with a single stream.
+///
+/// Messages can also be compressed before they are encrypted (see --compress), which is agreed along with the number
+/// of streams (see SessionOptions). Each message says whether it was compressed, so that messages which wouldn't
+/// get any smaller can be sent as they are (see compress).
 pub struct AsyncEncryptedComms<S: Serialize, R: for<'a> Deserialize<'a>> {
+    /// The first stream, which is used to send the final message (see shutdown_with_final_message_sent_after_threads_joined).
     tcp_connection: TcpStream,
 
-    sending_thread: JoinHandle<Result<(Aes128Gcm, u64, u64, Vec<u8>), String>>,
+    /// Shares out the messages between the sending threads, if there is more than one stream.
+    dispatching_thread: Option<JoinHandle<()>>,
+    sending_threads: Vec<JoinHandle<Result<SendingState, String>>>,
+    next_sequence_number: Arc<AtomicU64>,
     pub sender: Sender<S>,
 
-    receiving_thread: JoinHandle<Result<(), String>>,
+    receiving_threads: Vec<JoinHandle<Result<(), String>>>,
     pub receiver: Receiver<R>,
 }
-impl<S: Serialize + Send + 'static + Debug, R: for<'a> Deserialize<'a> + Serialize + Send + 'static + Debug + IsFinalMessage> AsyncEncryptedComms<S, R> {
-    pub fn new(tcp_connection: TcpStream, secret_key: Key<Aes128Gcm>, sending_nonce_lsb: u64, receiving_nonce_lsb: u64,
-        debug_local_remote_name: (&str, &str)) -> AsyncEncryptedComms<S, R>
+impl<S: Serialize + Send + 'static + Debug + IsFinalMessage + WorthCompressing, R: for<'a> Deserialize<'a> + Serialize + Send + 'static + Debug + IsFinalMessage> AsyncEncryptedComms<S, R> {
+    pub fn new(tcp_connections: Vec<TcpStream>, secret_key: Key<Aes128Gcm>, compression_level: u32, sending_nonce_lsb: u64,
+        receiving_nonce_lsb: u64, debug_local_remote_name: (&str, &str)) -> AsyncEncryptedComms<S, R>
     {
-        let mut tcp_connection_clone1 = tcp_connection.try_clone().expect("Failed to clone TCP stream");
-        let mut tcp_connection_clone2 = tcp_connection.try_clone().expect("Failed to clone TCP stream");
+        let num_streams = tcp_connections.len();
+        let tcp_connection = tcp_connections[0].try_clone().expect("Failed to clone TCP stream");
+        let next_sequence_number = Arc::new(AtomicU64::new(0));
+        let stream_name = |from: &str, to: &str, i: usize| if num_streams == 1 {
+            format!("{} -> {}", from, to)
+        } else {
+            format!("{} -> {} #{}", from, to, i)
+        };
 
-        let sending_thread_name = format!("{} -> {}", debug_local_remote_name.0, debug_local_remote_name.1);
+        // With a single stream, the sending thread takes messages straight from the channel, otherwise another thread
+        // shares them out between the sending threads.
         let (sender, thread_receiver) = memory_bound_channel::new(BOSS_DOER_CHANNEL_MEMORY_CAPACITY);
-        let sending_thread = thread::Builder::new()
-            .name(sending_thread_name.clone())
-            .spawn(move || {
-                let mut sending_nonce_counter = sending_nonce_lsb;
-                let cipher = Aes128Gcm::new(&secret_key);
-                // Allocate a buffer up front, to be used for all serialization, encryption etc. on this thread.
-                // This avoids having to allocate new buffers each time we send a message, which should give better performance.
-                // 8MB should be plenty, as the max message size should be 4MB (max chunk size of a file)
-                let mut buffer = vec![0u8; 8192 * 1024];
-                loop {
-                    let s = match thread_receiver.recv() {
-                        Ok(s) => s,
-                        Err(_) => {
-                            // The sender on the main thread has been dropped, which means that there are no more messages to send,
-                            // so we finish this background thread successfully (this is the expected clean shutdown process)
-                            trace!("Sending thread '{sending_thread_name}' shutting down due to closed channel");
-                            // Return stuff needed to send one more message from the main thread (needed for profiling)
-                            return Ok((cipher, sending_nonce_counter, sending_nonce_lsb, buffer));
-                        }
-                    };
-                    if let Err(e) = send(s, &mut tcp_connection_clone1, &cipher, &mut sending_nonce_counter,
-                        sending_nonce_lsb, &mut buffer) {
-                        // There was an error sending a message, which shouldn't happen in normal operation.
-                        // Stop this background thread, which will close the receiving side of the
-                        // channel. The main thread will detect this as a closed channel.
-                        // Note we don't log this as an error, as we leave this up to the main thread to report when this thread
-                        // gets joined, otherwise we might report the error at an inappropriate time, e.g. if the sync is cancelled due
-                        // to an error and the connection drops, we don't want to report the connection dropping as well as the original error.
-                        trace!("Sending thread '{sending_thread_name}' shutting down due to error sending on TCP: {e}");
-                        return Err(e);
-                    }
-                }
-            }).expect("Failed to spawn thread");
+        let mut next_message_funcs: Vec<NextMessageFunc<S>> = vec![];
+        let mut dispatching_thread = None;
+        if num_streams == 1 {
+            let next_sequence_number = next_sequence_number.clone();
+            next_message_funcs.push(Box::new(move || {
+                let s = thread_receiver.recv().ok()?;
+                Some((next_sequence_number.fetch_add(1, Ordering::Relaxed), s))
+            }));
+        } else {
+            let mut stream_senders = vec![];
+            for _ in 0..num_streams {
+                // Only one message is queued up for each stream, so that the messages go to whichever streams are keeping up
+                let (s, r) = crossbeam::channel::bounded(1);
+                stream_senders.push(s);
+                next_message_funcs.push(Box::new(move || r.recv().ok()));
+            }
+            let next_sequence_number = next_sequence_number.clone();
+            dispatching_thread = Some(thread::Builder::new()
+                .name(format!("{} -> {}", debug_local_remote_name.0, debug_local_remote_name.1))
+                .spawn(move || dispatching_thread_main(thread_receiver, stream_senders, next_sequence_number))
+                .expect("Failed to spawn thread"));
+        }
 
-        let receiving_thread_name = format!("{} -> {}", debug_local_remote_name.1, debug_local_remote_name.0);
         let (thread_sender, receiver) = memory_bound_channel::new(BOSS_DOER_CHANNEL_MEMORY_CAPACITY);
-        let receiving_thread = thread::Builder::new()
-            .name(receiving_thread_name.clone())
-            .spawn(move || {
-                let mut receiving_nonce_counter = receiving_nonce_lsb;
-                let cipher = Aes128Gcm::new(&secret_key);
-                // Allocate a buffer up front, to be used for all serialization, encryption etc. on this thread.
-                // This avoids having to allocate new buffers each time we send a message, which should give better performance.
-                // 8MB should be plenty, as the max message size should be 4MB (max chunk size of a file)
-                let mut buffer = vec![0u8; 8192 * 1024];
-                loop {
-                    let r: R = match receive(&mut tcp_connection_clone2, &cipher, &mut receiving_nonce_counter,
-                        receiving_nonce_lsb, &mut buffer) {
-                        Ok(r) => r,
-                        Err(e) => {
-                            // There was an error receiving a message, which shouldn't happen in normal operation.
-                            // Log an error, and stop this background thread, which will close the sending side of the
+        let reorderer = Arc::new(Reorderer::new(thread_sender, MAX_REORDERING_MEMORY));
+
+        let mut sending_threads = vec![];
+        let mut receiving_threads = vec![];
+        for (stream_index, (tcp_connection, mut next_message)) in tcp_connections.into_iter().zip(next_message_funcs).enumerate() {
+            let mut tcp_connection_clone1 = tcp_connection.try_clone().expect("Failed to clone TCP stream");
+            let mut tcp_connection_clone2 = tcp_connection;
+
+            let sending_thread_name = stream_name(debug_local_remote_name.0, debug_local_remote_name.1, stream_index);
+            sending_threads.push(thread::Builder::new()
+                .name(sending_thread_name.clone())
+                .spawn(move || {
+                    let mut state = SendingState {
+                        cipher: Aes128Gcm::new(&secret_key),
+                        nonce_counter: NonceCounter::new(sending_nonce_lsb, stream_index as u32),
+                        // Allocate a buffer up front, to be used for all serialization, encryption etc. on this thread.
+                        // This avoids having to allocate new buffers each time we send a message, which should give better performance.
+                        // 8MB should be plenty, as the max message size should be 4MB (max chunk size of a file)
+                        buffer: vec![0u8; 8192 * 1024],
+                        compression_level,
+                        compression_buffer: vec![],
+                    };
+                    loop {
+                        let (sequence_number, s) = match next_message() {
+                            Some(x) => x,
+                            None => {
+                                // The sender on the main thread has been dropped, which means that there are no more messages to send,
+                                // so we finish this background thread successfully (this is the expected clean shutdown process)
+                                trace!("Sending thread '{sending_thread_name}' shutting down due to closed channel");
+                                // The first stream is ended by the final message instead, which may be sent after this
+                                if stream_index > 0 {
+                                    send_end_of_stream(&mut tcp_connection_clone1)?;
+                                }
+                                // Return stuff needed to send one more message from the main thread (needed for profiling)
+                                return Ok(state);
+                            }
+                        };
+                        if let Err(e) = send(s, sequence_number, &mut tcp_connection_clone1, &mut state) {
+                            // There was an error sending a message, which shouldn't happen in normal operation.
+                            // Stop this background thread, which will close the receiving side of the
                             // channel. The main thread will detect this as a closed channel.
                             // Note we don't log this as an error, as we leave this up to the main thread to report when this thread
                             // gets joined, otherwise we might report the error at an inappropriate time, e.g. if the sync is cancelled due
                             // to an error and the connection drops, we don't want to report the connection dropping as well as the original error.
-                            trace!("Receiving thread '{receiving_thread_name}' shutting down due to error receiving from TCP: {e}");
+                            trace!("Sending thread '{sending_thread_name}' shutting down due to error sending on TCP: {e}");
                             return Err(e);
                         }
-                    };
-                    let is_final_message = r.is_final_message();
-                    if thread_sender.send(r).is_err() {
-                        // The main thread receiver has been dropped, which shouldn't happen during normal operation
-                        // Note we don't log this as an error, as we leave this up to the main thread to report when this thread
-                        // gets joined, otherwise we might report the error at an inappropriate time, e.g. if the sync is cancelled due
-                        // to an error and the connection drops, we don't want to report the connection dropping as well as the original error.
-                        trace!("Receiving thread '{receiving_thread_name}' shutting down due to closed channel");
-                        return Err("Communications with main thread broken".to_string());
-                    };
-                    // Stop this thread cleanly if that was the final message
-                    if is_final_message {
-                        trace!("Receiving thread '{receiving_thread_name}' shutting down due to receiving final message");
-                        return Ok(());
                     }
-                }
-            }).expect("Failed to spawn thread");
+                }).expect("Failed to spawn thread"));
 
-        AsyncEncryptedComms { tcp_connection, sending_thread, sender, receiving_thread, receiver }
+            let receiving_thread_name = stream_name(debug_local_remote_name.1, debug_local_remote_name.0, stream_index);
+            let reorderer = reorderer.clone();
+            receiving_threads.push(thread::Builder::new()
+                .name(receiving_thread_name.clone())
+                .spawn(move || {
+                    let mut nonce_counter = NonceCounter::new(receiving_nonce_lsb, stream_index as u32);
+                    let cipher = Aes128Gcm::new(&secret_key);
+                    // Allocate a buffer up front, to be used for all serialization, encryption etc. on this thread.
+                    // This avoids having to allocate new buffers each time we send a message, which should give better performance.
+                    // 8MB should be plenty, as the max message size should be 4MB (max chunk size of a file)
+                    let mut buffer = vec![0u8; 8192 * 1024];
+                    let mut decompression_buffer = vec![];
+                    loop {
+                        let (sequence_number, r): (u64, R) = match receive(&mut tcp_connection_clone2, &cipher, &mut nonce_counter,
+                            &mut buffer, &mut decompression_buffer)
+                        {
+                            Ok(Some(x)) => x,
+                            Ok(None) => {
+                                trace!("Receiving thread '{receiving_thread_name}' shutting down due to end of stream");
+                                return Ok(());
+                            }
+                            Err(e) => {
+                                // There was an error receiving a message, which shouldn't happen in normal operation.
+                                // Log an error, and stop this background thread, which will close the sending side of the
+                                // channel. The main thread will detect this as a closed channel.
+                                // Note we don't log this as an error, as we leave this up to the main thread to report when this thread
+                                // gets joined, otherwise we might report the error at an inappropriate time, e.g. if the sync is cancelled due
+                                // to an error and the connection drops, we don't want to report the connection dropping as well as the original error.
+                                trace!("Receiving thread '{receiving_thread_name}' shutting down due to error receiving from TCP: {e}");
+                                // Other streams may still be open, so close the channel ourselves
+                                reorderer.close();
+                                return Err(e);
+                            }
+                        };
+                        let is_final_message = r.is_final_message();
+                        if reorderer.add(sequence_number, r).is_err() {
+                            // The main thread receiver has been dropped, which shouldn't happen during normal operation
+                            // Note we don't log this as an error, as we leave this up to the main thread to report when this thread
+                            // gets joined, otherwise we might report the error at an inappropriate time, e.g. if the sync is cancelled due
+                            // to an error and the connection drops, we don't want to report the connection dropping as well as the original error.
+                            trace!("Receiving thread '{receiving_thread_name}' shutting down due to closed channel");
+                            return Err("Communications with main thread broken".to_string());
+                        };
+                        // Stop this thread cleanly if that was the final message
+                        if is_final_message {
+                            trace!("Receiving thread '{receiving_thread_name}' shutting down due to receiving final message");
+                            return Ok(());
+                        }
+                    }
+                }).expect("Failed to spawn thread"));
+        }
+
+        AsyncEncryptedComms { tcp_connection, dispatching_thread, sending_threads, next_sequence_number, sender, receiving_threads, receiver }
     }
 
     /// Clean shutdown which joins the background threads, making sure all messages are flushed etc.
@@ -125,14 +204,21 @@ impl<S: Serialize + Send + 'static + Debug, R: for<'a> Deserialize<'a> + Seriali
         // I had some issues with windows -> remote linux when shutting down the writing half of the connection
         // from windows. The new approach of stopping the receiving thread using IsFinalMessage seems to be working better.
 
-        // Stop the sending thread, which will be blocked on the channel waiting for a new message to send.
+        // Stop the sending threads, which will be blocked on the channel waiting for a new message to send.
         drop(self.sender);
-        trace!("Waiting for sending thread");
-        join_with_err_log(self.sending_thread);
+        trace!("Waiting for sending threads");
+        if let Some(t) = self.dispatching_thread {
+            t.join().expect("Failed to join dispatching thread");
+        }
+        for t in self.sending_threads {
+            join_with_err_log(t);
+        }
 
-        // The receiving thread should already have been stopped once it saw the final message
-        trace!("Waiting for receiving thread");
-        join_with_err_log(self.receiving_thread);
+        // The receiving threads should already have been stopped once they saw the final message or the end of their stream
+        trace!("Waiting for receiving threads");
+        for t in self.receiving_threads {
+            join_with_err_log(t);
+        }
     }
 
     /// Clean shutdown which joins the background threads, making sure all messages are flushed etc.
@@ -146,23 +232,28 @@ impl<S: Serialize + Send + 'static + Debug, R: for<'a> Deserialize<'a> + Seriali
         // I had some issues with windows -> remote linux when shutting down the writing half of the connection
         // from windows. The new approach of stopping the receiving thread using IsFinalMessage seems to be working better.
 
-        // Stop the sending thread, which will be blocked on the channel waiting for a new message to send,
-        // and retrieve the cipher etc. needed to send one more final message
+        // Stop the sending threads, which will be blocked on the channel waiting for a new message to send,
+        // and retrieve the cipher etc. needed to send one more final message on the first stream
         drop(self.sender);
-        trace!("Waiting for sending thread");
-        let sending_thread_result = join_with_err_log(self.sending_thread);
+        trace!("Waiting for sending threads");
+        if let Some(t) = self.dispatching_thread {
+            t.join().expect("Failed to join dispatching thread");
+        }
+        let sending_thread_results: Vec<_> = self.sending_threads.into_iter().map(join_with_err_log).collect();
 
-        // The receiving thread should already have been stopped once it saw the final message
-        trace!("Waiting for receiving thread");
-        join_with_err_log(self.receiving_thread);
+        // The receiving threads should already have been stopped once they saw the final message or the end of their stream
+        trace!("Waiting for receiving threads");
+        for t in self.receiving_threads {
+            join_with_err_log(t);
+        }
 
-        if let Some((cipher, mut sending_nonce_counter, sending_nonce_lsb, mut buffer)) = sending_thread_result {
+        if let Some(Some(mut state)) = sending_thread_results.into_iter().next() {
             let s = message_generating_func();
 
             trace!("Sending final mesage {:?}", s);
             // There's not much we can do with an error here, as we're closing everything down anyway
-            if let Err(e) = send(s, &mut self.tcp_connection, &cipher, &mut sending_nonce_counter,
-                sending_nonce_lsb, &mut buffer) {
+            let sequence_number = self.next_sequence_number.load(Ordering::Relaxed);
+            if let Err(e) = send(s, sequence_number, &mut self.tcp_connection, &mut state) {
                 error!("Error sending final message: {e}");
             }
         } else {
@@ -171,6 +262,403 @@ impl<S: Serialize + Send + 'static + Debug, R: for<'a> Deserialize<'a> + Seriali
     }
 }
 
+/// Gets the next message for a sending thread to send, along with its sequence number, or None once there are no more.
+type NextMessageFunc<S> = Box<dyn FnMut() -> Option<(u64, S)> + Send>;
+
+/// Shares out the messages from the main thread between the sending threads for each stream, numbering them
+/// so that the receiving side can put them back in order.
+fn dispatching_thread_main<S: Serialize + IsFinalMessage>(receiver: Receiver<S>,
+    stream_senders: Vec<crossbeam::channel::Sender<(u64, S)>>, next_sequence_number: Arc<AtomicU64>)
+{
+    while let Ok(s) = receiver.recv() {
+        let sequence_number = next_sequence_number.fetch_add(1, Ordering::Relaxed);
+        if s.is_final_message() {
+            // The final message always goes on the first stream (see AsyncEncryptedComms). Nothing can be sent after it,
+            // so the other streams are ended straight away, as the other side might be waiting for them before it replies.
+            let _ = stream_senders[0].send((sequence_number, s));
+            trace!("Dispatching thread shutting down due to sending final message");
+            return;
+        }
+
+        // Use whichever stream is ready first, so that a slow stream doesn't hold up the others
+        let mut select = crossbeam::channel::Select::new();
+        for stream_sender in &stream_senders {
+            select.send(stream_sender);
+        }
+        let operation = select.select();
+        let i = operation.index();
+        if operation.send(&stream_senders[i], (sequence_number, s)).is_err() {
+            // One of the sending threads has stopped due to an error, so stop the others too. The main
+            // thread will detect this as a closed channel.
+            trace!("Dispatching thread shutting down due to closed channel");
+            return;
+        }
+    }
+    // The sender on the main thread has been dropped, so dropping the stream senders will stop the sending threads
+    trace!("Dispatching thread shutting down due to closed channel");
+}
+
+/// Everything that a sending thread needs to send a message on its stream, which is returned when it finishes so
+/// that one more message can be sent (see shutdown_with_final_message_sent_after_threads_joined).
+struct SendingState {
+    cipher: Aes128Gcm,
+    nonce_counter: NonceCounter,
+    buffer: Vec<u8>,
+    /// Zero if messages aren't compressed (see --compress).
+    compression_level: u32,
+    compression_buffer: Vec<u8>,
+}
+
+/// Generates the nonces for one direction of one stream.
+/// Nonces for boss -> doer should always be even, and odd for vice versa. They can't be reused between them.
+/// The rest of the nonce is the index of the stream, so they can't be reused between streams either.
+struct NonceCounter {
+    counter: u64,
+    lsb: u64,
+    stream_index: u32,
+}
+impl NonceCounter {
+    fn new(lsb: u64, stream_index: u32) -> NonceCounter {
+        NonceCounter { counter: lsb, lsb, stream_index }
+    }
+
+    fn next(&mut self) -> Nonce<Aes128Gcm> {
+        assert!(self.counter % 2 == self.lsb);
+        let mut nonce_bytes = [0u8; 12];
+        nonce_bytes[0..8].copy_from_slice(&self.counter.to_le_bytes());
+        nonce_bytes[8..12].copy_from_slice(&self.stream_index.to_le_bytes());
+        // Increment by two so that it never overlaps with the nonces used by the other side
+        self.counter = self.counter.checked_add(2).expect("Nonce counter overflowed");
+        *Nonce::<Aes128Gcm>::from_slice(&nonce_bytes)
+    }
+}
+
+/// The most memory (roughly, see memory_bound_channel) that messages waiting to be put back in order can use (see Reorderer).
+const MAX_REORDERING_MEMORY: usize = BOSS_DOER_CHANNEL_MEMORY_CAPACITY;
+
+/// Puts the messages received on all the streams back in the order they were sent, before passing them on to the main thread.
+/// Messages that arrive before one that was sent earlier on another stream have to wait for it. If too many are waiting
+/// (see MAX_REORDERING_MEMORY) then the streams that they came on stop being read until the others catch up, so that
+/// a slow stream can't make us run out of memory.
+struct Reorderer<R> {
+    state: Mutex<ReordererState<R>>,
+    max_waiting_memory: usize,
+    /// Notified whenever messages are passed on (or the streams fail), which may make room for more to wait.
+    passed_on: Condvar,
+}
+struct ReordererState<R> {
+    next_sequence_number: u64,
+    /// Messages waiting for an earlier one, along with their memory usage.
+    waiting: BTreeMap<u64, (R, usize)>,
+    waiting_memory: usize,
+    /// Dropped if any of the streams fail, so that the main thread sees a closed channel rather than waiting forever
+    /// for a message that will never arrive.
+    sender: Option<Sender<R>>,
+}
+impl<R: Serialize> Reorderer<R> {
+    fn new(sender: Sender<R>, max_waiting_memory: usize) -> Reorderer<R> {
+        Reorderer {
+            state: Mutex::new(ReordererState { next_sequence_number: 0, waiting: BTreeMap::new(), waiting_memory: 0, sender: Some(sender) }),
+            max_waiting_memory,
+            passed_on: Condvar::new(),
+        }
+    }
+
+    /// Passes on the message once all the ones sent before it have been, blocking if there isn't room for it to wait.
+    fn add(&self, sequence_number: u64, r: R) -> Result<(), ()> {
+        let mut state = self.state.lock().expect("Failed to lock mutex");
+        if sequence_number != state.next_sequence_number {
+            let memory_usage = bincode::serialized_size(&r).expect("Error in serialized_size") as usize;
+            // As with memory_bound_channel, a message can always wait if there's any room left, no matter how big it is.
+            // This can't deadlock, as the message that the others are waiting for is never the one that is blocked.
+            while sequence_number != state.next_sequence_number && state.waiting_memory > self.max_waiting_memory {
+                if state.sender.is_none() {
+                    return Err(());
+                }
+                state = self.passed_on.wait(state).expect("Failed to lock mutex");
+            }
+            if sequence_number != state.next_sequence_number {
+                state.waiting.insert(sequence_number, (r, memory_usage));
+                state.waiting_memory += memory_usage;
+                return Ok(());
+            }
+        }
+
+        // Pass on this message, and then any that were waiting for it
+        let mut next = Some(r);
+        while let Some(r) = next {
+            state.next_sequence_number += 1;
+            if !matches!(&state.sender, Some(s) if s.send(r).is_ok()) {
+                // Nothing more can be passed on, so don't leave the other streams waiting
+                state.sender = None;
+                self.passed_on.notify_all();
+                return Err(());
+            }
+            let n = state.next_sequence_number;
+            next = state.waiting.remove(&n).map(|(r, memory_usage)| {
+                state.waiting_memory -= memory_usage;
+                r
+            });
+        }
+        self.passed_on.notify_all();
+        Ok(())
+    }
+
+    /// Stops passing on messages, because one of the streams has failed.
+    fn close(&self) {
+        self.state.lock().expect("Failed to lock mutex").sender = None;
+        self.passed_on.notify_all();
+    }
+}
+
+/// The first byte that the boss sends on each connection to a doer that accepts sessions, to say what it's for
+/// (see accept_connection).
+const NEW_SESSION: u8 = 0;
+const EXTRA_STREAM: u8 = 1;
+const TRANSFER_SESSION: u8 = 2;
+
+/// The first byte of the doer's reply to a new session, saying whether it will serve it (see accept_connection).
+const SESSION_BUSY: u8 = 0;
+const SESSION_ACCEPTED: u8 = 1;
+
+/// The error from session_key_handshake when the doer is busy serving a different boss, so that callers can tell this
+/// apart from the doer not being there at all.
+pub const DOER_BUSY: &str = "Doer is busy with another boss";
+
+/// Identifies the boss that a session is for, so that a doer which only serves one boss at a time (e.g. a persistent doer)
+/// can tell whether a new session is from the boss it's already serving (see session_key_handshake).
+pub type BossId = [u8; 16];
+
+/// Identifies a key made by make_transfer_key.
+pub type TransferId = [u8; 16];
+
+/// Settings for a session which are agreed between the boss and doer when it starts (see session_key_handshake).
+/// The boss asks for what it wants, the doer says the most that it allows, and the session uses the smaller of each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionOptions {
+    /// The number of TCP connections to spread messages over (see --streams).
+    pub num_streams: u32,
+    /// The deflate level to compress messages with, or zero for no compression (see --compress).
+    pub compression_level: u32,
+}
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions { num_streams: 1, compression_level: 0 }
+    }
+}
+impl SessionOptions {
+    fn to_bytes(self) -> [u8; 8] {
+        let mut bytes = [0u8; 8];
+        bytes[0..4].copy_from_slice(&self.num_streams.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.compression_level.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> SessionOptions {
+        SessionOptions {
+            num_streams: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            compression_level: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        }
+    }
+
+    fn agree(requested: SessionOptions, allowed: SessionOptions) -> SessionOptions {
+        SessionOptions {
+            num_streams: requested.num_streams.min(allowed.num_streams).max(1),
+            compression_level: requested.compression_level.min(allowed.compression_level),
+        }
+    }
+}
+
+/// What a new connection to a doer that accepts sessions is for (see accept_connection).
+pub enum IncomingConnection {
+    /// A new session, which will use these options (e.g. this many streams in total, see AsyncEncryptedComms).
+    NewSession { session_key: Key<Aes128Gcm>, options: SessionOptions, boss_id: BossId },
+    /// A new session from a different boss to the one being served, which has been turned away.
+    Busy,
+    /// A new session using a key made by make_transfer_key. The doer should only allow one session for each ID.
+    TransferSession { session_key: Key<Aes128Gcm>, options: SessionOptions, transfer_id: TransferId },
+    /// One of the other streams for a session that has already been started (see join_session).
+    ExtraStream { session_id: [u8; 16], stream_index: u32, proof: [u8; 16] },
+}
+
+/// Derives a fresh key for one session with a doer that accepts more than one (e.g. a persistent doer), from the
+/// key that the boss and doer share. Both sides contribute a random salt, so that the nonce counters (which restart
+/// for every session) are never reused with the same key, and so that a recorded session can't be replayed to the doer.
+/// The boss then proves that it knows the shared key, so that the doer doesn't tie up a thread with a connection
+/// from someone else. This needs to complete within a few seconds, so that neither side waits forever on a
+/// connection that isn't going anywhere.
+/// The options for the session (e.g. the number of streams) are agreed at the same time: the boss asks for what it wants
+/// and the doer says what it allows. Both sets of options go into the key, so they can't be tampered with.
+/// The doer might instead say straight away that it's busy with a different boss, in which case this returns DOER_BUSY.
+/// Returns the session key and the agreed options, after which the boss opens the other streams (see join_session).
+/// This is the boss side, and the doer side is accept_connection.
+pub fn session_key_handshake(tcp_connection: &mut TcpStream, shared_key: &Key<Aes128Gcm>, boss_id: &BossId, requested: SessionOptions)
+    -> Result<(Key<Aes128Gcm>, SessionOptions), String>
+{
+    new_session_handshake(tcp_connection, NEW_SESSION, boss_id, shared_key, requested)
+}
+
+/// Makes a key that a doer with the given shared key will accept for one session only, so that another doer can
+/// connect to it on our behalf (see --direct-transfer) without being given the shared key. The doer works out
+/// the same key from the ID, so it doesn't need to be told the key itself.
+pub fn make_transfer_key(shared_key: &Key<Aes128Gcm>) -> (TransferId, Key<Aes128Gcm>) {
+    let mut transfer_id = [0u8; 16];
+    OsRng.fill_bytes(&mut transfer_id);
+    (transfer_id, derive_transfer_key(shared_key, &transfer_id))
+}
+
+/// The same as session_key_handshake, but using a key made by make_transfer_key rather than the doer's shared key.
+/// The doer never says that it's busy, as it's the boss that it's serving which wants us to connect.
+pub fn transfer_session_handshake(tcp_connection: &mut TcpStream, transfer_id: &TransferId, transfer_key: &Key<Aes128Gcm>,
+    requested: SessionOptions) -> Result<(Key<Aes128Gcm>, SessionOptions), String>
+{
+    new_session_handshake(tcp_connection, TRANSFER_SESSION, transfer_id, transfer_key, requested)
+}
+
+/// The boss side of session_key_handshake and transfer_session_handshake, which differ in what the ID is for.
+fn new_session_handshake(tcp_connection: &mut TcpStream, kind: u8, id: &[u8; 16], key: &Key<Aes128Gcm>, requested: SessionOptions)
+    -> Result<(Key<Aes128Gcm>, SessionOptions), String>
+{
+    tcp_connection.set_read_timeout(Some(Duration::from_secs(5))).map_err(|e| format!("Error setting timeout: {e}"))?;
+
+    let mut boss_salt = [0u8; 16];
+    OsRng.fill_bytes(&mut boss_salt);
+    let mut request = vec![kind];
+    request.extend_from_slice(&boss_salt);
+    request.extend_from_slice(&requested.to_bytes());
+    request.extend_from_slice(id);
+    tcp_connection.write_all(&request).map_err(|e| format!("Error sending salt: {e}"))?;
+    let mut status = [0u8];
+    tcp_connection.read_exact(&mut status).map_err(|e| format!("Error receiving reply: {e}"))?;
+    if status[0] == SESSION_BUSY {
+        return Err(DOER_BUSY.to_string());
+    }
+    let mut reply = [0u8; 24];
+    tcp_connection.read_exact(&mut reply).map_err(|e| format!("Error receiving salt: {e}"))?;
+    let allowed = SessionOptions::from_bytes(&reply[16..24]);
+
+    let session_key = derive_session_key(key, &boss_salt, &reply[0..16], requested, allowed);
+    tcp_connection.write_all(&session_proof(&session_key)).map_err(|e| format!("Error sending proof: {e}"))?;
+
+    tcp_connection.set_read_timeout(None).map_err(|e| format!("Error clearing timeout: {e}"))?;
+    Ok((session_key, SessionOptions::agree(requested, allowed)))
+}
+
+/// The doer side of session_key_handshake, transfer_session_handshake and join_session, which finds out what a new
+/// connection from the boss (or another doer on its behalf) is for.
+/// New sessions may use up to the given options (e.g. at most that many streams), and are turned away if is_busy
+/// says so for the boss that they are from.
+pub fn accept_connection(tcp_connection: &mut TcpStream, shared_key: &Key<Aes128Gcm>, allowed: SessionOptions,
+    is_busy: impl FnOnce(&BossId) -> bool) -> Result<IncomingConnection, String>
+{
+    tcp_connection.set_read_timeout(Some(Duration::from_secs(5))).map_err(|e| format!("Error setting timeout: {e}"))?;
+
+    let mut kind = [0u8];
+    tcp_connection.read_exact(&mut kind).map_err(|e| format!("Error receiving connection kind: {e}"))?;
+    let result = match kind[0] {
+        k @ (NEW_SESSION | TRANSFER_SESSION) => {
+            let mut request = [0u8; 40];
+            tcp_connection.read_exact(&mut request).map_err(|e| format!("Error receiving salt: {e}"))?;
+            let requested = SessionOptions::from_bytes(&request[16..24]);
+            let id: [u8; 16] = request[24..40].try_into().unwrap();
+            let key = if k == TRANSFER_SESSION {
+                derive_transfer_key(shared_key, &id)
+            } else if is_busy(&id) {
+                tcp_connection.write_all(&[SESSION_BUSY]).map_err(|e| format!("Error sending reply: {e}"))?;
+                return Ok(IncomingConnection::Busy);
+            } else {
+                *shared_key
+            };
+
+            let mut doer_salt = [0u8; 16];
+            OsRng.fill_bytes(&mut doer_salt);
+            let mut reply = vec![SESSION_ACCEPTED];
+            reply.extend_from_slice(&doer_salt);
+            reply.extend_from_slice(&allowed.to_bytes());
+            tcp_connection.write_all(&reply).map_err(|e| format!("Error sending salt: {e}"))?;
+
+            let session_key = derive_session_key(&key, &request[0..16], &doer_salt, requested, allowed);
+            let mut received_proof = [0u8; 16];
+            tcp_connection.read_exact(&mut received_proof).map_err(|e| format!("Error receiving proof: {e}"))?;
+            if received_proof != session_proof(&session_key) {
+                return Err("Boss failed to prove it has the shared key".to_string());
+            }
+            let options = SessionOptions::agree(requested, allowed);
+            match k {
+                NEW_SESSION => IncomingConnection::NewSession { session_key, options, boss_id: id },
+                _ => IncomingConnection::TransferSession { session_key, options, transfer_id: id },
+            }
+        }
+        EXTRA_STREAM => {
+            let mut request = [0u8; 36];
+            tcp_connection.read_exact(&mut request).map_err(|e| format!("Error receiving stream details: {e}"))?;
+            IncomingConnection::ExtraStream {
+                session_id: request[0..16].try_into().unwrap(),
+                stream_index: u32::from_le_bytes(request[16..20].try_into().unwrap()),
+                proof: request[20..36].try_into().unwrap(),
+            }
+        }
+        k => return Err(format!("Unknown connection kind {k}")),
+    };
+
+    tcp_connection.set_read_timeout(None).map_err(|e| format!("Error clearing timeout: {e}"))?;
+    Ok(result)
+}
+
+/// Opens another stream for a session that was started with session_key_handshake, proving to the doer
+/// that we have the session's key. The doer acknowledges once it has added the stream to the session.
+pub fn join_session(tcp_connection: &mut TcpStream, session_key: &Key<Aes128Gcm>, stream_index: u32) -> Result<(), String> {
+    tcp_connection.set_read_timeout(Some(Duration::from_secs(5))).map_err(|e| format!("Error setting timeout: {e}"))?;
+
+    let mut request = vec![EXTRA_STREAM];
+    request.extend_from_slice(&session_id(session_key));
+    request.extend_from_slice(&stream_index.to_le_bytes());
+    request.extend_from_slice(&stream_proof(session_key, stream_index));
+    tcp_connection.write_all(&request).map_err(|e| format!("Error sending stream details: {e}"))?;
+    let mut ack = [0u8];
+    tcp_connection.read_exact(&mut ack).map_err(|e| format!("Error receiving acknowledgement for stream {stream_index}: {e}"))?;
+
+    tcp_connection.set_read_timeout(None).map_err(|e| format!("Error clearing timeout: {e}"))?;
+    Ok(())
+}
+
+/// The doer side of join_session, once the session that the stream is for has been found from its ID.
+pub fn accept_extra_stream(tcp_connection: &mut TcpStream, session_key: &Key<Aes128Gcm>, stream_index: u32, proof: &[u8; 16])
+    -> Result<(), String>
+{
+    if *proof != stream_proof(session_key, stream_index) {
+        return Err("Boss failed to prove it has the session key".to_string());
+    }
+    tcp_connection.write_all(&[1]).map_err(|e| format!("Error sending acknowledgement: {e}"))
+}
+
+/// Identifies a session so that more streams can be added to it (see join_session), without giving away its key.
+pub fn session_id(session_key: &Key<Aes128Gcm>) -> [u8; 16] {
+    Sha256::new().chain_update(session_key).chain_update(b"session id").finalize()[0..16].try_into().unwrap()
+}
+
+fn derive_session_key(shared_key: &Key<Aes128Gcm>, boss_salt: &[u8], doer_salt: &[u8], requested: SessionOptions,
+    allowed: SessionOptions) -> Key<Aes128Gcm>
+{
+    let digest = Sha256::new().chain_update(shared_key).chain_update(boss_salt).chain_update(doer_salt)
+        .chain_update(requested.to_bytes()).chain_update(allowed.to_bytes()).finalize();
+    *Key::<Aes128Gcm>::from_slice(&digest[0..16])
+}
+
+fn derive_transfer_key(shared_key: &Key<Aes128Gcm>, transfer_id: &TransferId) -> Key<Aes128Gcm> {
+    let digest = Sha256::new().chain_update(shared_key).chain_update(b"transfer").chain_update(transfer_id).finalize();
+    *Key::<Aes128Gcm>::from_slice(&digest[0..16])
+}
+
+fn session_proof(session_key: &Key<Aes128Gcm>) -> [u8; 16] {
+    Sha256::new().chain_update(session_key).chain_update(b"boss").finalize()[0..16].try_into().unwrap()
+}
+
+fn stream_proof(session_key: &Key<Aes128Gcm>, stream_index: u32) -> [u8; 16] {
+    Sha256::new().chain_update(session_key).chain_update(b"stream").chain_update(stream_index.to_le_bytes())
+        .finalize()[0..16].try_into().unwrap()
+}
+
 /// Helper func to join on a thread that returns a Result<T>, and log any errors
 fn join_with_err_log<T, E: Display>(t: JoinHandle<Result<T, E>>) -> Option<T> {
     let name = t.thread().name().expect("Failed to get thread name").to_string();
@@ -183,43 +671,64 @@ fn join_with_err_log<T, E: Display>(t: JoinHandle<Result<T, E>>) -> Option<T> {
     }
 }
 
-fn send<T>(x: T, tcp_connection: &mut TcpStream, cipher: &Aes128Gcm,
-    sending_nonce_counter: &mut u64, nonce_lsb: u64, buffer: &mut [u8]) -> Result<(), String>
-    where T : Serialize,
+/// The first byte of each (decrypted) message, saying whether the rest of it is compressed (see --compress).
+const UNCOMPRESSED: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+/// Messages smaller than this aren't compressed, as there would be little to gain.
+const MIN_COMPRESSION_SIZE: usize = 128;
+/// For larger messages, this much is compressed quickly first to see if the rest is worth compressing (see compress).
+const COMPRESSION_PROBE_SIZE: usize = 16 * 1024;
+
+fn send<T>(x: T, sequence_number: u64, tcp_connection: &mut TcpStream, state: &mut SendingState) -> Result<(), String>
+    where T : Serialize + WorthCompressing,
 {
     profile_this!();
+    let buffer = &mut state.buffer;
 
-    // Serialize the message into the re-usable buffer, leaving 8 bytes at the start for the length to
-    // be filled in later.
-    let unencrypted_len = {
+    // Serialize the message into the re-usable buffer, leaving 16 bytes at the start for the length and
+    // sequence number to be filled in later, and one more byte to say whether it is compressed.
+    let serialized_len = {
         profile_this!("Serialize");
-        let mut s = &mut buffer[8..];
+        let mut s = &mut buffer[17..];
         let l = s.len();
         bincode::serialize_into(&mut s, &x).map_err(|e| "Error serializing command: ".to_string() + &e.to_string())?;
         l - s.len()
     };
 
-    // Nonces for boss -> doer should always be even, and odd for vice versa. They can't be reused between them.
-    assert!(*sending_nonce_counter % 2 == nonce_lsb);
-    let mut nonce_bytes = [0u8; 12];
-    nonce_bytes[0..8].copy_from_slice(&sending_nonce_counter.to_le_bytes());
-    let nonce = Nonce::<Aes128Gcm>::from_slice(&nonce_bytes);
-    sending_nonce_counter.checked_add(2).unwrap(); // Increment by two so that it never overlaps with the nonce used by the doer
+    // Replace the serialized message with a compressed version, if it's any smaller
+    let compressed = state.compression_level > 0 && x.worth_compressing() && {
+        profile_this!("Compress");
+        compress(&buffer[17..17+serialized_len], state.compression_level, &mut state.compression_buffer)
+    };
+    let unencrypted_len = if compressed {
+        let compressed_len = state.compression_buffer.len();
+        buffer[16] = COMPRESSED;
+        buffer[17..17+compressed_len].copy_from_slice(&state.compression_buffer);
+        1 + compressed_len
+    } else {
+        buffer[16] = UNCOMPRESSED;
+        1 + serialized_len
+    };
+
+    let nonce = state.nonce_counter.next();
 
     // Encrypt the message in-place. This will expand it slightly, because the encrypted message is always slightly larger than the original.
+    // The sequence number is authenticated along with the message, so that messages can't be reordered.
     let encrypted_len = {
         profile_this!("Encrypt");
-        let mut s = SliceBuffer { slice : &mut buffer[8..], len: unencrypted_len };
-        cipher.encrypt_in_place(nonce, &[], &mut s).unwrap();
+        let mut s = SliceBuffer { slice : &mut buffer[16..], len: unencrypted_len };
+        state.cipher.encrypt_in_place(&nonce, &sequence_number.to_le_bytes(), &mut s).unwrap();
         s.len
     };
-    // Fill in the 8 bytes at the start of the buffer with the length of the now-encrypted message
+    // Fill in the 16 bytes at the start of the buffer with the length of the now-encrypted message and the sequence number
     buffer[0..8].copy_from_slice(&encrypted_len.to_le_bytes());
+    buffer[8..16].copy_from_slice(&sequence_number.to_le_bytes());
 
     {
         profile_this!("Tcp Write");
-        // Send the length header plus the encrypted message
-        tcp_connection.write_all(&buffer[0..8+encrypted_len]).map_err(|e| "Error sending length: ".to_string() + &e.to_string())?;
+        // Send the header plus the encrypted message
+        tcp_connection.write_all(&buffer[0..16+encrypted_len]).map_err(|e| "Error sending length: ".to_string() + &e.to_string())?;
 
         // Flush to make sure that we don't deadlock (other side waiting for data that never comes)
         tcp_connection.flush().map_err(|e| "Error flushing: ".to_string() + &e.to_string())?;
@@ -228,8 +737,40 @@ fn send<T>(x: T, tcp_connection: &mut TcpStream, cipher: &Aes128Gcm,
     Ok(())
 }
 
-fn receive<T>(tcp_connection: &mut TcpStream, cipher: &Aes128Gcm,
-    receiving_nonce_counter: &mut u64, nonce_lsb: u64, buffer: &mut [u8]) -> Result<T, String>
+/// Compresses the data into `output`, returning false if it wouldn't get any smaller (e.g. it's already compressed),
+/// in which case it should be sent as it is. To avoid spending a long time finding this out for large messages,
+/// the start of the data is compressed quickly first, and if that barely shrinks then the rest isn't tried.
+fn compress(data: &[u8], level: u32, output: &mut Vec<u8>) -> bool {
+    if data.len() < MIN_COMPRESSION_SIZE {
+        return false;
+    }
+
+    if data.len() > 2 * COMPRESSION_PROBE_SIZE {
+        let probe = &data[0..COMPRESSION_PROBE_SIZE];
+        if !deflate(probe, Compression::fast(), output) || output.len() > probe.len() * 9 / 10 {
+            return false;
+        }
+    }
+
+    deflate(data, Compression::new(level), output) && output.len() < data.len()
+}
+
+fn deflate(data: &[u8], compression: Compression, output: &mut Vec<u8>) -> bool {
+    output.clear();
+    let mut encoder = DeflateEncoder::new(output, compression);
+    encoder.write_all(data).is_ok() && encoder.finish().is_ok()
+}
+
+/// Ends one of the streams other than the first (see AsyncEncryptedComms), with a zero length
+/// which can't be a real message, as encrypted messages are never empty.
+fn send_end_of_stream(tcp_connection: &mut TcpStream) -> Result<(), String> {
+    tcp_connection.write_all(&0usize.to_le_bytes()).map_err(|e| "Error sending end of stream: ".to_string() + &e.to_string())?;
+    tcp_connection.flush().map_err(|e| "Error flushing: ".to_string() + &e.to_string())
+}
+
+/// Returns the message along with its sequence number, or None if the other side has ended the stream (see send_end_of_stream).
+fn receive<T>(tcp_connection: &mut TcpStream, cipher: &Aes128Gcm, nonce_counter: &mut NonceCounter, buffer: &mut [u8],
+    decompression_buffer: &mut Vec<u8>) -> Result<Option<(u64, T)>, String>
     where T : for<'a> Deserialize<'a>
 {
     // Note we don't profile this entire function, for the same reason as below (see profile_this!("Tcp Read"))
@@ -238,6 +779,13 @@ fn receive<T>(tcp_connection: &mut TcpStream, cipher: &Aes128Gcm,
     let mut len_buf = [0_u8; 8];
     tcp_connection.read_exact(&mut len_buf).map_err(|e| "Error reading len: ".to_string() + &e.to_string())?;
     let encrypted_len = usize::from_le_bytes(len_buf);
+    if encrypted_len == 0 {
+        return Ok(None);
+    }
+
+    // Read the 8-byte sequence number
+    let mut sequence_number_buf = [0_u8; 8];
+    tcp_connection.read_exact(&mut sequence_number_buf).map_err(|e| "Error reading sequence number: ".to_string() + &e.to_string())?;
 
     // Note we only profile this after reading the length, as most of the time will likely be spent reading the length
     // because it will be waiting for a new message. This means the profiling trace will be filled with with bar,
@@ -252,29 +800,37 @@ fn receive<T>(tcp_connection: &mut TcpStream, cipher: &Aes128Gcm,
         b
     };
 
-    // Nonces for doer -> boss should always be odd, and even for vice versa. They can't be reused between them.
-    assert!(*receiving_nonce_counter % 2 == nonce_lsb);
-    let mut nonce_bytes = [0u8; 12];
-    nonce_bytes[0..8].copy_from_slice(&receiving_nonce_counter.to_le_bytes());
-    let nonce = Nonce::<Aes128Gcm>::from_slice(&nonce_bytes);
-    receiving_nonce_counter.checked_add(2).unwrap(); // Increment by two so that it never overlaps with the nonce used by the boss
+    let nonce = nonce_counter.next();
 
     // Decrypt the data in-place. This will shorten it, as the encrypted message is always slightly longer than the plaintext
     let unencrypted_data = {
         profile_this!("Decrypt");
         let mut s = SliceBuffer { slice: &mut encrypted_data, len: encrypted_len };
-        cipher.decrypt_in_place(nonce, &[], &mut s).map_err(|e| "Error decrypting: ".to_string() + &e.to_string())?;
+        cipher.decrypt_in_place(&nonce, &sequence_number_buf, &mut s).map_err(|e| "Error decrypting: ".to_string() + &e.to_string())?;
         let unencrypted_len = s.len;
         &encrypted_data[0..unencrypted_len]
     };
 
+    // Decompress the data if needed (see send)
+    let serialized_data = match unencrypted_data.first() {
+        Some(&UNCOMPRESSED) => &unencrypted_data[1..],
+        Some(&COMPRESSED) => {
+            profile_this!("Decompress");
+            decompression_buffer.clear();
+            DeflateDecoder::new(&unencrypted_data[1..]).read_to_end(decompression_buffer)
+                .map_err(|e| "Error decompressing: ".to_string() + &e.to_string())?;
+            &decompression_buffer[..]
+        }
+        _ => return Err("Unknown message format".to_string()),
+    };
+
     // Deserialize the unencrypted data into the strongly-typed struct
     let response = {
         profile_this!("Deserialize");
-        bincode::deserialize(&unencrypted_data).map_err(|e| "Error deserializing: ".to_string() + &e.to_string())?
+        bincode::deserialize(serialized_data).map_err(|e| "Error deserializing: ".to_string() + &e.to_string())?
     };
 
-    Ok(response)
+    Ok(Some((u64::from_le_bytes(sequence_number_buf), response)))
 }
 
 /// Simple wrapper around a mutable slice of bytes, so that we can pass it to the encryption
@@ -323,3 +879,241 @@ impl<'a> AsMut<[u8]> for SliceBuffer<'a> {
         &mut self.slice[0..self.len]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::TcpListener;
+
+    use crate::boss_doer_interface::{Command, Response};
+    use crate::profiling::get_local_process_profiling;
+    use crate::root_relative_path::RootRelativePath;
+
+    use super::*;
+
+    /// Makes the given number of pairs of TcpStreams that are connected to each other.
+    fn connected_streams(n: usize) -> (Vec<TcpStream>, Vec<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        (0..n).map(|_| {
+            let a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (b, _) = listener.accept().unwrap();
+            (a, b)
+        }).unzip()
+    }
+
+    /// Messages spread over several streams arrive in the order they were sent, in both directions,
+    /// and both sides can shut down cleanly in the same way as the boss and doer do.
+    #[test]
+    fn test_multiple_streams() {
+        let (boss_streams, doer_streams) = connected_streams(3);
+        let key = Aes128Gcm::generate_key(&mut OsRng);
+        let boss = AsyncEncryptedComms::<Command, Response>::new(boss_streams, key, 0, 0, 1, ("boss", "doer"));
+        let doer = AsyncEncryptedComms::<Response, Command>::new(doer_streams, key, 0, 1, 0, ("doer", "boss"));
+
+        // Alternate large and small messages, so that later ones overtake earlier ones on the other streams
+        let size = |i: usize| if i % 2 == 1 { 1 } else { 1024 * 1024 };
+        for i in 0..100 {
+            boss.sender.send(Command::CreateOrUpdateFile { path: RootRelativePath::root(), data: vec![i as u8; size(i)],
+                set_modified_time: None, more_to_follow: false, hash: None }).unwrap();
+            doer.sender.send(Response::FileContent { data: vec![i as u8; size(i)], more_to_follow: false, hash: None }).unwrap();
+        }
+        boss.sender.send(Command::Shutdown).unwrap();
+
+        for i in 0..100 {
+            match doer.receiver.recv().unwrap() {
+                Command::CreateOrUpdateFile { data, .. } => assert_eq!(data, vec![i as u8; size(i)]),
+                x => panic!("Unexpected command {x:?}"),
+            }
+            match boss.receiver.recv().unwrap() {
+                Response::FileContent { data, .. } => assert_eq!(data, vec![i as u8; size(i)]),
+                x => panic!("Unexpected response {x:?}"),
+            }
+        }
+        assert!(matches!(doer.receiver.recv(), Ok(Command::Shutdown)));
+
+        let doer_thread = thread::spawn(move || doer.shutdown_with_final_message_sent_after_threads_joined(
+            || Response::ProfilingData(get_local_process_profiling())));
+        assert!(matches!(boss.receiver.recv(), Ok(Response::ProfilingData(_))));
+        boss.shutdown();
+        doer_thread.join().unwrap();
+    }
+
+    /// Messages which arrive out of order wait for the ones before them, but only until there isn't room for any more,
+    /// at which point the streams that they arrive on are held up.
+    #[test]
+    fn test_reorderer_memory_limit() {
+        let (sender, receiver) = memory_bound_channel::new(BOSS_DOER_CHANNEL_MEMORY_CAPACITY);
+        let reorderer = Arc::new(Reorderer::new(sender, 10));
+        // There's room for this one to wait, however big it is
+        reorderer.add(2, vec![2u8; 100]).unwrap();
+        // But not this one, until the messages before it have been passed on
+        let blocked = {
+            let reorderer = reorderer.clone();
+            thread::spawn(move || reorderer.add(1, vec![1u8; 100]))
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!blocked.is_finished());
+        assert!(receiver.try_recv().is_err());
+
+        reorderer.add(0, vec![0u8; 100]).unwrap();
+        blocked.join().unwrap().unwrap();
+        for i in 0..3 {
+            assert_eq!(receiver.recv().unwrap(), vec![i as u8; 100]);
+        }
+    }
+
+    /// Every message gets a different nonce, as reusing a nonce with the same key breaks the encryption. This includes
+    /// between the two directions and between the streams of a session.
+    #[test]
+    fn test_nonce_counter_never_repeats() {
+        let mut seen = HashSet::new();
+        for lsb in [0, 1] {
+            for stream_index in 0..3 {
+                let mut counter = NonceCounter::new(lsb, stream_index);
+                for _ in 0..100 {
+                    assert!(seen.insert(counter.next()));
+                }
+            }
+        }
+    }
+
+    /// The number of streams and compression level are the smaller of what the boss asks for and what the doer allows,
+    /// and the other streams can then join the session.
+    #[test]
+    fn test_session_handshake_streams() {
+        let (mut boss_streams, mut doer_streams) = connected_streams(2);
+        let shared_key = Aes128Gcm::generate_key(&mut OsRng);
+
+        let doer_thread = thread::spawn(move || {
+            let allowed = SessionOptions { num_streams: 2, compression_level: 9 };
+            let session_key = match accept_connection(&mut doer_streams[0], &shared_key, allowed, |_| false).unwrap() {
+                IncomingConnection::NewSession { session_key, options, boss_id: _ } => {
+                    assert_eq!(options, SessionOptions { num_streams: 2, compression_level: 6 });
+                    session_key
+                }
+                _ => panic!("Expected a new session"),
+            };
+            match accept_connection(&mut doer_streams[1], &shared_key, allowed, |_| false).unwrap() {
+                IncomingConnection::ExtraStream { session_id: id, stream_index, proof } => {
+                    assert_eq!(id, session_id(&session_key));
+                    accept_extra_stream(&mut doer_streams[1], &session_key, stream_index, &proof).unwrap();
+                }
+                _ => panic!("Expected an extra stream"),
+            }
+            session_key
+        });
+
+        let requested = SessionOptions { num_streams: 5, compression_level: 6 };
+        let (session_key, options) = session_key_handshake(&mut boss_streams[0], &shared_key, &[1; 16], requested).unwrap();
+        assert_eq!(options, SessionOptions { num_streams: 2, compression_level: 6 });
+        join_session(&mut boss_streams[1], &session_key, 1).unwrap();
+        assert_eq!(doer_thread.join().unwrap(), session_key);
+    }
+
+    /// A doer that is busy with a different boss turns a new session away straight away, rather than the boss
+    /// having to wait for a timeout.
+    #[test]
+    fn test_session_handshake_busy() {
+        let (mut boss_streams, mut doer_streams) = connected_streams(2);
+        let shared_key = Aes128Gcm::generate_key(&mut OsRng);
+        let serving: BossId = [1; 16];
+
+        let doer_thread = thread::spawn(move || {
+            let allowed = SessionOptions::default();
+            assert!(matches!(accept_connection(&mut doer_streams[0], &shared_key, allowed, |b| *b != serving).unwrap(),
+                IncomingConnection::Busy));
+            assert!(matches!(accept_connection(&mut doer_streams[1], &shared_key, allowed, |b| *b != serving).unwrap(),
+                IncomingConnection::NewSession { boss_id, .. } if boss_id == serving));
+        });
+
+        let start = std::time::Instant::now();
+        assert_eq!(session_key_handshake(&mut boss_streams[0], &shared_key, &[2; 16], SessionOptions::default()), Err(DOER_BUSY.to_string()));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(session_key_handshake(&mut boss_streams[1], &shared_key, &serving, SessionOptions::default()).is_ok());
+        doer_thread.join().unwrap();
+    }
+
+    /// A transfer key lets another doer start a session without knowing the shared key, but a made-up one doesn't.
+    #[test]
+    fn test_transfer_session_handshake() {
+        let (mut boss_streams, mut doer_streams) = connected_streams(2);
+        let shared_key = Aes128Gcm::generate_key(&mut OsRng);
+        let (transfer_id, transfer_key) = make_transfer_key(&shared_key);
+        assert_ne!(transfer_key, shared_key);
+
+        let doer_thread = thread::spawn(move || {
+            let allowed = SessionOptions::default();
+            // Transfer sessions aren't turned away when busy
+            let session_key = match accept_connection(&mut doer_streams[0], &shared_key, allowed, |_| true).unwrap() {
+                IncomingConnection::TransferSession { session_key, options: _, transfer_id: id } => {
+                    assert_eq!(id, transfer_id);
+                    session_key
+                }
+                _ => panic!("Expected a transfer session"),
+            };
+            assert!(accept_connection(&mut doer_streams[1], &shared_key, allowed, |_| true).is_err());
+            session_key
+        });
+
+        let (session_key, _) = transfer_session_handshake(&mut boss_streams[0], &transfer_id, &transfer_key, SessionOptions::default()).unwrap();
+        let wrong_key = Aes128Gcm::generate_key(&mut OsRng);
+        // The doer rejects the proof, which we only find out about when the connection is closed
+        let _ = transfer_session_handshake(&mut boss_streams[1], &transfer_id, &wrong_key, SessionOptions::default());
+        assert_eq!(doer_thread.join().unwrap(), session_key);
+    }
+
+    /// Compressed messages arrive intact, whether or not they were worth compressing.
+    #[test]
+    fn test_compression() {
+        let (boss_streams, doer_streams) = connected_streams(1);
+        let key = Aes128Gcm::generate_key(&mut OsRng);
+        let boss = AsyncEncryptedComms::<Command, Response>::new(boss_streams, key, 6, 0, 1, ("boss", "doer"));
+        let doer = AsyncEncryptedComms::<Response, Command>::new(doer_streams, key, 6, 1, 0, ("doer", "boss"));
+
+        let mut random = vec![0u8; 1024 * 1024];
+        OsRng.fill_bytes(&mut random);
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(20000);
+        let files = [("a.txt", text.clone()), ("b.bin", random.clone()), ("c.zip", text.clone()), ("d.txt", b"small".to_vec())];
+        for (name, data) in &files {
+            boss.sender.send(Command::CreateOrUpdateFile { path: RootRelativePath::try_from(std::path::Path::new(name)).unwrap(), data: data.clone(),
+                set_modified_time: None, more_to_follow: false, hash: None }).unwrap();
+            doer.sender.send(Response::FileContent { data: data.clone(), more_to_follow: false, hash: None }).unwrap();
+        }
+        boss.sender.send(Command::Shutdown).unwrap();
+
+        for (_, expected) in &files {
+            match doer.receiver.recv().unwrap() {
+                Command::CreateOrUpdateFile { data, .. } => assert_eq!(&data, expected),
+                x => panic!("Unexpected command {x:?}"),
+            }
+            match boss.receiver.recv().unwrap() {
+                Response::FileContent { data, .. } => assert_eq!(&data, expected),
+                x => panic!("Unexpected response {x:?}"),
+            }
+        }
+        assert!(matches!(doer.receiver.recv(), Ok(Command::Shutdown)));
+
+        let doer_thread = thread::spawn(move || doer.shutdown_with_final_message_sent_after_threads_joined(
+            || Response::ProfilingData(get_local_process_profiling())));
+        assert!(matches!(boss.receiver.recv(), Ok(Response::ProfilingData(_))));
+        boss.shutdown();
+        doer_thread.join().unwrap();
+    }
+
+    /// Only data that gets smaller is compressed, and large data that barely compresses isn't tried in full.
+    #[test]
+    fn test_compress() {
+        let mut output = vec![];
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(20000);
+        assert!(compress(&text, 6, &mut output));
+        assert!(output.len() < text.len() / 10);
+
+        assert!(!compress(b"too small to bother", 6, &mut output));
+
+        let mut random = vec![0u8; 1024 * 1024];
+        OsRng.fill_bytes(&mut random);
+        assert!(!compress(&random, 6, &mut output));
+        // Only the probe was compressed
+        assert!(output.len() < 2 * COMPRESSION_PROBE_SIZE);
+    }
+}
@@ -3,6 +3,7 @@ use std::process::ExitCode;
 mod boss_frontend;
 mod boss_launch;
 mod boss_deploy;
+mod boss_fan_out;
 mod embedded_binaries;
 mod exe_utils;
 mod boss_sync;
@@ -14,9 +15,11 @@ mod boss_doer_interface;
 mod root_relative_path;
 mod encrypted_comms;
 mod memory_bound_channel;
+mod bandwidth_limit;
 mod profiling;
 mod parallel_walk_dir;
 mod logger_and_progress;
+mod windows_names;
 
 use boss_frontend::*;
 use boss_launch::*;
@@ -3,6 +3,7 @@ use std::{path::{PathBuf, Path}, fmt::{Display, self}};
 use console::Style;
 use regex::{RegexSet, SetMatches};
 use serde::{Serialize, Deserialize};
+use unicode_normalization::UnicodeNormalization;
 
 
 /// Converts a platform-specific relative path (inside the source or dest root)
@@ -13,11 +14,84 @@ use serde::{Serialize, Deserialize};
 /// We instead convert to a normalized representation using forward slashes (i.e. Unix-style).
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct RootRelativePath {
+    /// For paths that aren't valid Unicode, this is a lossy version which is used
+    /// only for display and filtering. The real path is stored in `raw`.
     inner: String,
+    /// Only present if the path isn't valid Unicode (e.g. Latin-1 filenames on Linux), so that
+    /// we can still access the entry. Because this is only set when needed, each path has a single
+    /// representation and so equality/hashing works as expected.
+    raw: Option<RawPath>,
 }
-impl RootRelativePath {  
+
+/// The platform-specific representation of a path which isn't valid Unicode, using forward slashes
+/// as separators (like RootRelativePath::inner). Each platform can only make sense of its own kind.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RawPath {
+    /// Arbitrary bytes, as Unix filenames can be.
+    Unix(Vec<u8>),
+    /// UTF-16 which might be invalid (e.g. unpaired surrogates), as Windows filenames can be.
+    Windows(Vec<u16>),
+}
+impl RawPath {
+    fn split_last(&self) -> Option<(RawPath, RawPath)> {
+        match self {
+            RawPath::Unix(b) => b.iter().rposition(|c| *c == b'/')
+                .map(|i| (RawPath::Unix(b[..i].to_vec()), RawPath::Unix(b[i + 1..].to_vec()))),
+            RawPath::Windows(w) => w.iter().rposition(|c| *c == '/' as u16)
+                .map(|i| (RawPath::Windows(w[..i].to_vec()), RawPath::Windows(w[i + 1..].to_vec()))),
+        }
+    }
+
+    /// Converts a (Unicode) string to the same kind of RawPath as this one.
+    fn same_kind_from_str(&self, s: &str) -> RawPath {
+        match self {
+            RawPath::Unix(_) => RawPath::Unix(s.as_bytes().to_vec()),
+            RawPath::Windows(_) => RawPath::Windows(s.encode_utf16().collect()),
+        }
+    }
+
+    fn join(&self, other: &RawPath) -> RawPath {
+        match (self, other) {
+            (RawPath::Unix(a), RawPath::Unix(b)) => RawPath::Unix([a.as_slice(), b"/", b.as_slice()].concat()),
+            (RawPath::Windows(a), RawPath::Windows(b)) => RawPath::Windows([a.as_slice(), &['/' as u16], b.as_slice()].concat()),
+            _ => panic!("Can't mix raw paths from different platforms"),
+        }
+    }
+}
+
+impl RootRelativePath {
     pub fn root() -> RootRelativePath {
-        RootRelativePath { inner: "".to_string() }
+        RootRelativePath { inner: "".to_string(), raw: None }
+    }
+
+    fn from_str(s: &str) -> RootRelativePath {
+        RootRelativePath { inner: s.to_string(), raw: None }
+    }
+
+    /// Makes a path from its raw form, dropping the raw form if it turns out to be valid Unicode after all.
+    fn from_raw(raw: RawPath) -> RootRelativePath {
+        let valid = match &raw {
+            RawPath::Unix(b) => String::from_utf8(b.clone()).ok(),
+            RawPath::Windows(w) => String::from_utf16(w).ok(),
+        };
+        match valid {
+            Some(s) => RootRelativePath::from_str(&s),
+            None => {
+                let lossy = match &raw {
+                    RawPath::Unix(b) => String::from_utf8_lossy(b).to_string(),
+                    RawPath::Windows(w) => String::from_utf16_lossy(w),
+                };
+                RootRelativePath { inner: lossy, raw: Some(raw) }
+            }
+        }
+    }
+
+    /// Gets this path in raw form, of the same kind as the given raw path.
+    fn to_raw_like(&self, other: &RawPath) -> RawPath {
+        match &self.raw {
+            Some(r) => r.clone(),
+            None => other.same_kind_from_str(&self.inner),
+        }
     }
 
     /// Does this path refer to the root itself?
@@ -25,18 +99,124 @@ impl RootRelativePath {
         self.inner.is_empty()
     }
 
+    /// Is this path valid Unicode? If not, its string form (e.g. from Display) is lossy and
+    /// it can only be accessed on the same kind of platform that it came from.
+    pub fn is_unicode(&self) -> bool {
+        self.raw.is_none()
+    }
+
+    /// For paths which aren't valid Unicode, whether they came from a Windows platform (rather than Unix).
+    /// Returns None for Unicode paths, as these can be used on any platform.
+    pub fn is_from_windows(&self) -> Option<bool> {
+        self.raw.as_ref().map(|r| matches!(r, RawPath::Windows(_)))
+    }
+
+    /// Gets the path of the folder containing this path, or None if this is the root.
+    pub fn parent(&self) -> Option<RootRelativePath> {
+        if self.is_root() {
+            None
+        } else if let Some(raw) = &self.raw {
+            match raw.split_last() {
+                Some((p, _)) => Some(RootRelativePath::from_raw(p)),
+                None => Some(RootRelativePath::root()),
+            }
+        } else {
+            match self.inner.rsplit_once('/') {
+                Some((p, _)) => Some(RootRelativePath::from_str(p)),
+                None => Some(RootRelativePath::root()),
+            }
+        }
+    }
+
     /// Gets the full path consisting of the root and this root-relative path.
     pub fn get_full_path(&self, root: &Path) -> PathBuf {
-        if self.is_root() { root.to_path_buf() } else { root.join(&self.inner) }
+        if self.is_root() {
+            root.to_path_buf()
+        } else {
+            match &self.raw {
+                None => root.join(&self.inner),
+                // The boss shouldn't send us a path from a different kind of platform (see is_from_windows),
+                // but if it does then the lossy version is the best we can do.
+                #[cfg(unix)]
+                Some(RawPath::Unix(b)) => root.join(<std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(b)),
+                #[cfg(windows)]
+                Some(RawPath::Windows(w)) => root.join(<std::ffi::OsString as std::os::windows::ffi::OsStringExt>::from_wide(w)),
+                #[allow(unreachable_patterns)]
+                Some(_) => root.join(&self.inner),
+            }
+        }
     }
 
     /// Rather than exposing the inner string, expose just regex matching.
     /// This reduces the risk of incorrect usage of the raw string value (e.g. by using
     /// local-platform Path functions).
+    /// For paths which aren't valid Unicode, this matches against a lossy version of the path.
     pub fn regex_set_matches(&self, r: &RegexSet) -> SetMatches {
         r.matches(&self.inner)
     }
 
+    /// Gets a key which is the same for any two paths that differ only in case, for
+    /// comparing paths on case-insensitive filesystems.
+    /// Note that this is an approximation, as each filesystem has its own rules for this.
+    /// Paths which aren't valid Unicode are compared on their raw form instead, as their lossy string form
+    /// could be the same for different names. These keys start with a NUL, which real paths can't contain.
+    pub fn case_insensitive_key(&self) -> String {
+        match &self.raw {
+            Some(raw) => format!("\0{raw:?}"),
+            None => self.inner.to_lowercase(),
+        }
+    }
+
+    /// Gets the Unicode-normalized (NFC) form of this path, so that paths which look the same but
+    /// are encoded differently (e.g. macOS uses decomposed characters) can be compared.
+    /// Paths which aren't valid Unicode are left as they are.
+    pub fn normalize_unicode(&self) -> RootRelativePath {
+        if self.raw.is_some() {
+            return self.clone();
+        }
+        RootRelativePath::from_str(&self.inner.nfc().collect::<String>())
+    }
+
+    /// Gets the final component of this path (i.e. the name of the file/folder), or an empty string for the root.
+    /// For paths which aren't valid Unicode, this is lossy.
+    pub fn file_name(&self) -> &str {
+        match self.inner.rsplit_once('/') {
+            Some((_, n)) => n,
+            None => &self.inner,
+        }
+    }
+
+    /// Applies the given function to the name of each component of this path.
+    /// Paths which aren't valid Unicode are left unchanged.
+    pub fn map_names(&self, f: impl Fn(&str) -> String) -> RootRelativePath {
+        if self.raw.is_some() || self.is_root() {
+            return self.clone();
+        }
+        RootRelativePath::from_str(&self.inner.split('/').map(f).collect::<Vec<String>>().join("/"))
+    }
+
+    /// Gets the path with the same final component as this path, but inside the given folder instead.
+    pub fn with_parent(&self, parent: &RootRelativePath) -> RootRelativePath {
+        if let Some(raw) = self.raw.as_ref().or(parent.raw.as_ref()) {
+            let name = match self.to_raw_like(raw).split_last() {
+                Some((_, n)) => n,
+                None => self.to_raw_like(raw),
+            };
+            return if parent.is_root() {
+                RootRelativePath::from_raw(name)
+            } else {
+                RootRelativePath::from_raw(parent.to_raw_like(raw).join(&name))
+            };
+        }
+
+        let name = self.file_name();
+        if parent.is_root() {
+            RootRelativePath::from_str(name)
+        } else {
+            RootRelativePath::from_str(&format!("{}/{}", parent.inner, name))
+        }
+    }
+
     /// Puts the slashes back to what is requested, so that the path is appropriate for
     /// another platform.
     pub fn to_platform_path(&self, dir_separator: char) -> String {
@@ -59,13 +239,14 @@ impl TryFrom<&Path> for RootRelativePath {
         if p.is_absolute() {
             return Err("Must be relative".to_string());
         }
-    
+
         let mut result = String::new();
+        let mut is_unicode = true;
         for c in p.iter() {
-            let cs = match c.to_str() {
-                Some(x) => x,
-                None => return Err("Can't convert path component".to_string()),
-            };
+            // Components which aren't valid Unicode are checked using their lossy version, which still
+            // contains any slashes
+            let cs = c.to_string_lossy();
+            is_unicode &= c.to_str().is_some();
             if cs.contains('/') || cs.contains('\\') {
                 // Slashes in any component would mess things up, once we change which slash is significant
                 return Err("Illegal characters in path".to_string());
@@ -73,10 +254,34 @@ impl TryFrom<&Path> for RootRelativePath {
             if !result.is_empty() {
                 result += "/";
             }
-            result += cs;
+            result += &cs;
+        }
+
+        if is_unicode {
+            return Ok(RootRelativePath { inner: result, raw: None });
         }
-    
-        Ok(RootRelativePath { inner: result })
+
+        // Keep the original form of the path too, so that we can access it later
+        let mut components = p.iter();
+        #[cfg(unix)]
+        let raw = {
+            use std::os::unix::ffi::OsStrExt;
+            let mut raw = RawPath::Unix(components.next().unwrap().as_bytes().to_vec());
+            for c in components {
+                raw = raw.join(&RawPath::Unix(c.as_bytes().to_vec()));
+            }
+            raw
+        };
+        #[cfg(windows)]
+        let raw = {
+            use std::os::windows::ffi::OsStrExt;
+            let mut raw = RawPath::Windows(components.next().unwrap().encode_wide().collect());
+            for c in components {
+                raw = raw.join(&RawPath::Windows(c.encode_wide().collect()));
+            }
+            raw
+        };
+        Ok(RootRelativePath { inner: result, raw: Some(raw) })
     }
 }
 
@@ -154,6 +359,69 @@ mod tests {
 
     #[test]
     fn test_normalize_path_multiple_components() {
-        assert_eq!(RootRelativePath::try_from(Path::new("one/two/three")), Ok(RootRelativePath { inner: "one/two/three".to_string() }));
+        assert_eq!(RootRelativePath::try_from(Path::new("one/two/three")), Ok(RootRelativePath::from_str("one/two/three")));
+    }
+
+    #[test]
+    fn test_parent() {
+        assert_eq!(RootRelativePath::root().parent(), None);
+        assert_eq!(RootRelativePath::try_from(Path::new("one")).unwrap().parent(), Some(RootRelativePath::root()));
+        assert_eq!(RootRelativePath::try_from(Path::new("one/two/three")).unwrap().parent(), Some(RootRelativePath::from_str("one/two")));
+    }
+
+    /// Non-UTF-8 names (e.g. Latin-1) should be kept losslessly, so that they can be accessed again.
+    #[cfg(unix)]
+    #[test]
+    fn test_non_unicode() {
+        use std::os::unix::ffi::OsStrExt;
+        // "café" in Latin-1, which isn't valid UTF-8
+        let latin1 = std::ffi::OsStr::from_bytes(b"folder/caf\xe9/file");
+        let p = RootRelativePath::try_from(Path::new(latin1)).unwrap();
+        assert!(!p.is_unicode());
+        assert_eq!(p.is_from_windows(), Some(false));
+        assert_eq!(p.to_string(), "folder/caf\u{fffd}/file");
+        assert_eq!(p.get_full_path(Path::new("/root")), Path::new("/root").join(latin1));
+
+        // The parent is still not valid Unicode, but its parent is, so should compare equal to a regular path
+        let parent = p.parent().unwrap();
+        assert!(!parent.is_unicode());
+        assert_eq!(parent.parent(), Some(RootRelativePath::from_str("folder")));
+
+        assert_eq!(RootRelativePath::from_str("other").with_parent(&parent).get_full_path(Path::new("/root")),
+            Path::new("/root").join(std::ffi::OsStr::from_bytes(b"folder/caf\xe9/other")));
+        assert_eq!(p.with_parent(&RootRelativePath::root()), RootRelativePath::from_str("file"));
+
+        // Different names with the same lossy form don't look like they differ only in case
+        let other = RootRelativePath::try_from(Path::new(std::ffi::OsStr::from_bytes(b"folder/caf\xe8/file"))).unwrap();
+        assert_eq!(other.to_string(), p.to_string());
+        assert_ne!(other.case_insensitive_key(), p.case_insensitive_key());
+        assert_ne!(p.case_insensitive_key(), RootRelativePath::from_str("folder/caf\u{fffd}/file").case_insensitive_key());
+    }
+
+    #[test]
+    fn test_normalize_unicode() {
+        // "café" with a combining acute accent (NFD, as macOS produces) vs. a precomposed é (NFC)
+        let nfd = RootRelativePath::from_str("folder/cafe\u{0301}");
+        let nfc = RootRelativePath::from_str("folder/caf\u{00e9}");
+        assert_ne!(nfd, nfc);
+        assert_eq!(nfd.normalize_unicode(), nfc);
+        assert_eq!(nfc.normalize_unicode(), nfc);
+    }
+
+    #[test]
+    fn test_map_names() {
+        let p = RootRelativePath::from_str("one/two");
+        assert_eq!(p.map_names(|n| n.to_uppercase()), RootRelativePath::from_str("ONE/TWO"));
+        assert_eq!(p.file_name(), "two");
+        assert_eq!(RootRelativePath::root().map_names(|n| n.to_uppercase()), RootRelativePath::root());
+    }
+
+    #[test]
+    fn test_with_parent() {
+        let p = RootRelativePath::from_str("one/two/three");
+        assert_eq!(p.with_parent(&RootRelativePath::root()), RootRelativePath::from_str("three"));
+        assert_eq!(p.with_parent(&RootRelativePath::from_str("four")), RootRelativePath::from_str("four/three"));
+        assert_eq!(RootRelativePath::from_str("one").with_parent(&RootRelativePath::from_str("four")),
+            RootRelativePath::from_str("four/one"));
     }
 }
\ No newline at end of file
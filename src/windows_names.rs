@@ -0,0 +1,94 @@
+/// Characters which can't be used anywhere in a Windows file/folder name.
+/// (Slashes are also invalid, but can't be part of a name on any platform that we support).
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Names which refer to devices on Windows, and so can't be used for files or folders, even with an extension (e.g. 'aux.c').
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Escaped characters are moved into this block of the Unicode Private Use Area (i.e. 'a' becomes U+F061),
+/// which is the same approach that Cygwin uses for reserved characters.
+/// This is reversible, as long as the original names don't contain characters from this block (which is very unlikely).
+const ESCAPE_BASE: u32 = 0xF000;
+
+fn is_always_invalid_char(c: char) -> bool {
+    RESERVED_CHARS.contains(&c) || (c as u32) < 0x20
+}
+
+fn is_reserved_name(name: &str) -> bool {
+    // Windows ignores the extension and trailing spaces when checking for device names
+    let base = name.split('.').next().unwrap_or(name).trim_end_matches(' ');
+    RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(base))
+}
+
+fn escape_char(c: char) -> char {
+    char::from_u32(ESCAPE_BASE + c as u32).expect("Only ASCII characters are escaped")
+}
+
+/// Checks if the given file/folder name (not a full path) can be used on Windows.
+pub fn is_valid_windows_name(name: &str) -> bool {
+    !name.chars().any(is_always_invalid_char) && !name.ends_with(['.', ' ']) && !is_reserved_name(name)
+}
+
+/// Converts a file/folder name (not a full path) into one which can be used on Windows, in a way that can
+/// be reversed by unescape_windows_name. Valid names are unchanged.
+pub fn escape_windows_name(name: &str) -> String {
+    let mut chars: Vec<char> = name.chars().map(|c| if is_always_invalid_char(c) { escape_char(c) } else { c }).collect();
+    // Windows would silently remove a trailing dot or space. Only the final one needs escaping, as then it's no longer trailing.
+    if let Some(last) = chars.last_mut() {
+        if *last == '.' || *last == ' ' {
+            *last = escape_char(*last);
+        }
+    }
+    // Device names are all ASCII letters, so escaping the first letter means it's no longer reserved
+    if is_reserved_name(name) {
+        chars[0] = escape_char(chars[0]);
+    }
+    chars.into_iter().collect()
+}
+
+/// Reverses escape_windows_name, so that names escaped when copying to Windows are restored when copying back.
+pub fn unescape_windows_name(name: &str) -> String {
+    name.chars().map(|c| {
+        match (c as u32).checked_sub(ESCAPE_BASE) {
+            Some(x) if x > 0 && x < 0x80 => char::from_u32(x).unwrap(),
+            _ => c,
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_windows_name() {
+        assert!(is_valid_windows_name("normal.txt"));
+        assert!(is_valid_windows_name(".hidden"));
+        assert!(is_valid_windows_name("console.log"));
+        assert!(is_valid_windows_name("COM10"));
+        assert!(!is_valid_windows_name("a:b"));
+        assert!(!is_valid_windows_name("what?"));
+        assert!(!is_valid_windows_name("tab\tin name"));
+        assert!(!is_valid_windows_name("trailing."));
+        assert!(!is_valid_windows_name("trailing "));
+        assert!(!is_valid_windows_name("CON"));
+        assert!(!is_valid_windows_name("aux.c"));
+        assert!(!is_valid_windows_name("Lpt1.tar.gz"));
+    }
+
+    #[test]
+    fn test_escape_round_trip() {
+        for name in ["normal.txt", "a:b", "what?", "<>:\"|?*", "trailing..", "trailing ", "CON", "aux.c", "nul .txt"] {
+            let escaped = escape_windows_name(name);
+            assert!(is_valid_windows_name(&escaped), "{name} => {escaped}");
+            assert_eq!(unescape_windows_name(&escaped), name);
+        }
+        assert_eq!(escape_windows_name("normal.txt"), "normal.txt");
+        assert_eq!(escape_windows_name("a:b"), "a\u{F03A}b");
+        assert_eq!(escape_windows_name("aux.c"), "\u{F061}ux.c");
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::{SystemTime};
+
+use regex::Regex;
+
+use crate::{folder, test_framework::{run, TestDesc}};
+use map_macro::map;
+use crate::filesystem_node::*;
+
+// Case-insensitive filesystems aren't available on all test platforms, so these tests make the doers
+// pretend that the dest is case-insensitive. On a case-sensitive filesystem we can still check what
+// rjrssync decided to do, as the end result is visible.
+const CASE_INSENSITIVE: (&str, &str) = ("RJRSSYNC_TEST_CASE_INSENSITIVE", "1");
+
+/// Two source files which differ only in case would overwrite each other on a case-insensitive dest,
+/// so this should be an error before anything is copied.
+#[test]
+fn source_collision() {
+    let src = folder! {
+        "Readme.md" => file("one"),
+        "README.md" => file("two"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+        ],
+        env_vars: vec![CASE_INSENSITIVE],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new("source entries which differ only in case would overwrite each other: ('README.md' and 'Readme.md'|'Readme.md' and 'README.md')").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", None), // Nothing should have been done
+        ],
+        ..Default::default()
+    });
+}
+
+/// A file which has been renamed on the source to differ only in case should be deleted and copied,
+/// so that the dest ends up with the new name.
+#[test]
+fn case_only_rename() {
+    let src = folder! {
+        "README.md" => file("contents"),
+    };
+    let dest = folder! {
+        "readme.md" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dest-entry-needs-deleting=prompt".to_string(),
+        ],
+        prompt_responses: vec![
+            String::from("1:.*readme.md' needs deleting as the source entry's name differs only in case:Delete (just this occurence)"),
+        ],
+        env_vars: vec![CASE_INSENSITIVE],
+        expected_exit_code: 0,
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// If the user chooses not to delete the dest entry for a case-only rename, then the source entry
+/// must not be copied either, as it would overwrite the entry that they chose to keep.
+#[test]
+fn case_only_rename_skip() {
+    let src = folder! {
+        "Docs" => folder! {
+            "a.txt" => file("new contents"),
+        },
+    };
+    let dest = folder! {
+        "docs" => folder! {
+            "a.txt" => file_with_modified("old contents", SystemTime::UNIX_EPOCH),
+        },
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dest-entry-needs-deleting=skip".to_string(),
+        ],
+        env_vars: vec![CASE_INSENSITIVE],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Nothing to do")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&dest)), // Skipped, so unchanged
+        ],
+        ..Default::default()
+    });
+}
@@ -1,4 +1,4 @@
-use std::time::SystemTime;
+use std::time::{SystemTime, Duration};
 
 use crate::test_framework::*;
 use crate::filesystem_node::*;
@@ -191,6 +191,307 @@ fn root_always_included() {
     });
 }
 
+/// Checks that dest entries matching a protect filter are never deleted, but are still updated,
+/// and that the folders containing them are not deleted either.
+#[test]
+fn test_protect_filter() {
+    let src_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "machine.local.cfg" => file_with_modified("new config", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+    };
+    let dest_folder = folder! {
+        "c2" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+        "machine.local.cfg" => file_with_modified("old config", SystemTime::UNIX_EPOCH),
+        "other.local.cfg" => file_with_modified("other config", SystemTime::UNIX_EPOCH),
+        "sub" => folder! {
+            "deleted" => file_with_modified("contents3", SystemTime::UNIX_EPOCH),
+            "sub.local.cfg" => file_with_modified("sub config", SystemTime::UNIX_EPOCH),
+        }
+    };
+    let expected_dest_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        // Protected, but still updated as it exists on the source
+        "machine.local.cfg" => file_with_modified("new config", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        // Protected, so not deleted even though it's not on the source
+        "other.local.cfg" => file_with_modified("other config", SystemTime::UNIX_EPOCH),
+        // Not on the source, but can't be deleted because it contains a protected file
+        "sub" => folder! {
+            "sub.local.cfg" => file_with_modified("sub config", SystemTime::UNIX_EPOCH),
+        }
+    };
+
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+            ("$TEMP/dest", &dest_folder),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--filter".to_string(),
+            "P.*\\.local\\.cfg".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 2 file(s)")).unwrap()),
+            // c2 and sub/deleted are deleted, but not the sub folder itself
+            (1, Regex::new(r"Deleted 2 file\(s\) .*, 0 folder\(s\)").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest_folder)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that protecting a folder also protects everything inside it, as a protect filter only matches
+/// the folder's own path.
+#[test]
+fn test_protect_filter_folder() {
+    let src_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+    };
+    let dest_folder = folder! {
+        "c2" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+        "config" => folder! {
+            "machine.cfg" => file_with_modified("config", SystemTime::UNIX_EPOCH),
+            "sub" => folder! {
+                "more.cfg" => file_with_modified("more config", SystemTime::UNIX_EPOCH),
+            }
+        }
+    };
+    let expected_dest_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "config" => folder! {
+            "machine.cfg" => file_with_modified("config", SystemTime::UNIX_EPOCH),
+            "sub" => folder! {
+                "more.cfg" => file_with_modified("more config", SystemTime::UNIX_EPOCH),
+            }
+        }
+    };
+
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+            ("$TEMP/dest", &dest_folder),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--filter".to_string(),
+            "Pconfig".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()),
+            (1, Regex::new(r"Deleted 1 file\(s\) .*, 0 folder\(s\)").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest_folder)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that a source entry which would replace a protected dest entry of a different type (e.g. a folder
+/// over a file) isn't copied, along with anything inside it, rather than failing part way through the sync.
+#[test]
+fn test_protect_filter_incompatible() {
+    let src_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "local" => folder! {
+            "inner" => file_with_modified("inner", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let dest_folder = folder! {
+        "local" => file_with_modified("machine config", SystemTime::UNIX_EPOCH),
+    };
+    let expected_dest_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "local" => file_with_modified("machine config", SystemTime::UNIX_EPOCH),
+    };
+
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+            ("$TEMP/dest", &dest_folder),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--filter".to_string(),
+            "Plocal".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("dest file .*local.* is protected by a filter, so source entry .*local.* \\(and anything inside it\\) won't be copied").unwrap()),
+            (1, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest_folder)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --delete-excluded deletes dest entries which are excluded by the filters, including
+/// the contents of excluded folders, but not those which are also protected.
+#[test]
+fn test_delete_excluded() {
+    let src_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "c2.pyc" => file_with_modified("compiled", SystemTime::UNIX_EPOCH),
+    };
+    let dest_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "stale.pyc" => file_with_modified("compiled", SystemTime::UNIX_EPOCH),
+        "keep.pyc" => file_with_modified("compiled", SystemTime::UNIX_EPOCH),
+        "build" => folder! {
+            // Inside an excluded folder, so counts as excluded even though the filters don't match it directly
+            "output" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let expected_dest_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "keep.pyc" => file_with_modified("compiled", SystemTime::UNIX_EPOCH),
+    };
+
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+            ("$TEMP/dest", &dest_folder),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--filter".to_string(),
+            "-.*\\.pyc".to_string(),
+            "--filter".to_string(),
+            "-build".to_string(),
+            "--filter".to_string(),
+            "Pkeep\\.pyc".to_string(),
+            "--delete-excluded".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(r"Deleted 2 file\(s\) .*, 1 folder\(s\)").unwrap()),
+            (0, Regex::new("Copied").unwrap()), // c2.pyc is excluded, so isn't copied
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest_folder)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that excluded entries being deleted due to --delete-excluded are subject to --dest-entry-needs-deleting.
+#[test]
+fn test_delete_excluded_prompt() {
+    let src_folder = empty_folder();
+    let dest_folder = folder! {
+        "stale.pyc" => file_with_modified("compiled", SystemTime::UNIX_EPOCH),
+    };
+
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+            ("$TEMP/dest", &dest_folder),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--filter".to_string(),
+            "-.*\\.pyc".to_string(),
+            "--delete-excluded".to_string(),
+            "--dest-entry-needs-deleting=prompt".to_string(),
+        ],
+        prompt_responses: vec![
+            String::from("1:.*:Skip (just this occurence)"),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("dest file .*stale.pyc' needs deleting as it is excluded by the filters").unwrap()),
+            (1, Regex::new(&regex::escape("Nothing to do")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&dest_folder)), // Skipped, so unchanged
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --explain-filters reports which filters matched each path, and doesn't need a src or dest.
+#[test]
+fn test_explain_filters() {
+    run(TestDesc {
+        args: vec![
+            "--filter".to_string(),
+            "-build".to_string(),
+            "--filter".to_string(),
+            "+build/output\\.exe".to_string(),
+            "--filter".to_string(),
+            "P.*\\.exe".to_string(),
+            "--explain-filters".to_string(),
+            "build/output.exe".to_string(),
+            "src/main.c".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("'build/output.exe': excluded, as its ancestor folder 'build' is excluded")).unwrap()),
+            (1, Regex::new(&regex::escape("Matched filter #2: '+build/output\\.exe'")).unwrap()),
+            (1, Regex::new(&regex::escape("Matched filter #3: 'P.*\\.exe'")).unwrap()),
+            (1, Regex::new(&regex::escape("Protected from deletion on the dest")).unwrap()),
+            (1, Regex::new(&regex::escape("'src/main.c': included")).unwrap()),
+            (1, Regex::new(&regex::escape("No filters matched")).unwrap()),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --dry-run --verbose logs why each entry was excluded, without listing the contents of
+/// excluded folders.
+#[test]
+fn test_dry_run_verbose_explains_excluded() {
+    let src_folder = folder! {
+        "build" => folder! {
+            "output.exe" => file("contents"),
+        },
+        "log.txt" => file("contents"),
+        "main.c" => file("contents"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--filter".to_string(),
+            "-build".to_string(),
+            "--filter".to_string(),
+            "-log\\.txt".to_string(),
+            "--dry-run".to_string(),
+            "--verbose".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(r"source entry .*build' is excluded by the filters \(#1 '-build'\)").unwrap()),
+            (1, Regex::new(r"source entry .*log.txt' is excluded by the filters \(#2 '-log\\\.txt'\)").unwrap()),
+            (0, Regex::new("output.exe' is excluded").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)),
+            ("$TEMP/dest", None), // Dry run, so nothing should be created
+        ],
+        ..Default::default()
+    });
+}
+
 // "Tag" these tests as they require remote platforms (GitHub Actions differentiates these)
 mod remote {
 
@@ -17,3 +17,8 @@ mod files_same_time_tests;
 mod dest_entry_needs_deleting_tests;
 mod dest_root_needs_deleting_tests;
 mod misc_tests;
+mod case_sensitivity_tests;
+mod unicode_normalization_tests;
+mod windows_name_tests;
+mod plan_tests;
+mod watch_tests;
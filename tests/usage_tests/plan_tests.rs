@@ -0,0 +1,196 @@
+use std::time::{SystemTime, Duration};
+
+use regex::Regex;
+
+use crate::{folder, test_framework::{run, TestDesc}};
+use map_macro::map;
+use crate::filesystem_node::*;
+
+// Each run() uses its own $TEMP folder, so these tests use their own temporary folder which is shared
+// between making the plan and applying it.
+
+/// Making a plan shouldn't change anything, and applying it later should do what was planned.
+#[test]
+fn write_then_apply() {
+    let temp = tempfile::tempdir().expect("Failed to create temp dir");
+    let src_path = temp.path().join("src").to_string_lossy().to_string();
+    let dest_path = temp.path().join("dest").to_string_lossy().to_string();
+    let plan_path = temp.path().join("plan").to_string_lossy().to_string();
+
+    let src = folder! {
+        "file" => file_with_modified("contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "folder" => folder! {
+            "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let dest = folder! {
+        "file" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+        "file2" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            (&src_path, &src),
+            (&dest_path, &dest),
+        ],
+        args: vec![
+            src_path.clone(),
+            dest_path.clone(),
+            format!("--write-plan={plan_path}"),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Would delete 1 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Would copy 2 file(s)")).unwrap()),
+            (1, Regex::new("Plan saved to .*plan'").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            (&src_path, Some(&src)), // Source should always be unchanged
+            (&dest_path, Some(&dest)), // Nothing done yet
+        ],
+        ..Default::default()
+    });
+
+    run(TestDesc {
+        args: vec![
+            format!("--apply-plan={plan_path}"),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Deleted 1 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Copied 2 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            (&src_path, Some(&src)), // Source should always be unchanged
+            (&dest_path, Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Actions which the user declined when making the plan shouldn't be done when applying it, and they
+/// shouldn't be prompted again.
+#[test]
+fn apply_skipped_action() {
+    let temp = tempfile::tempdir().expect("Failed to create temp dir");
+    let src_path = temp.path().join("src").to_string_lossy().to_string();
+    let dest_path = temp.path().join("dest").to_string_lossy().to_string();
+    let plan_path = temp.path().join("plan").to_string_lossy().to_string();
+
+    let src = folder! {
+        "new" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    let dest = folder! {
+        "keep" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    let expected_dest = folder! {
+        "new" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "keep" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            (&src_path, &src),
+            (&dest_path, &dest),
+        ],
+        args: vec![
+            src_path.clone(),
+            dest_path.clone(),
+            "--dest-entry-needs-deleting=prompt".to_string(),
+            format!("--write-plan={plan_path}"),
+        ],
+        prompt_responses: vec![
+            String::from("1:.*keep' needs deleting.*:Skip (just this occurence)"),
+        ],
+        expected_exit_code: 0,
+        ..Default::default()
+    });
+
+    run(TestDesc {
+        args: vec![
+            format!("--apply-plan={plan_path}"),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (0, Regex::new("needs deleting").unwrap()),
+            (1, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            (&src_path, Some(&src)), // Source should always be unchanged
+            (&dest_path, Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// If any of the entries in the plan have changed since the plan was made, nothing should be done.
+#[test]
+fn apply_after_changes() {
+    let temp = tempfile::tempdir().expect("Failed to create temp dir");
+    let src_path = temp.path().join("src").to_string_lossy().to_string();
+    let dest_path = temp.path().join("dest").to_string_lossy().to_string();
+    let plan_path = temp.path().join("plan").to_string_lossy().to_string();
+
+    let src = folder! {
+        "file" => file_with_modified("contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "new" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    let dest = folder! {
+        "file" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+        "file2" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            (&src_path, &src),
+            (&dest_path, &dest),
+        ],
+        args: vec![
+            src_path.clone(),
+            dest_path.clone(),
+            format!("--write-plan={plan_path}"),
+        ],
+        expected_exit_code: 0,
+        ..Default::default()
+    });
+
+    // Change the dest file that was going to be overwritten, and one that was going to be deleted
+    let changed_dest = folder! {
+        "file" => file_with_modified("changed since", SystemTime::UNIX_EPOCH),
+        "file2" => file_with_modified("contents", SystemTime::UNIX_EPOCH + Duration::from_secs(10)),
+    };
+    std::fs::remove_dir_all(&dest_path).unwrap();
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            (&dest_path, &changed_dest),
+        ],
+        args: vec![
+            format!("--apply-plan={plan_path}"),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new("have changed since the plan was made, so the plan will not be applied: dest entry .*file2', dest entry .*file'").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            (&src_path, Some(&src)), // Source should always be unchanged
+            (&dest_path, Some(&changed_dest)), // Nothing should have been done
+        ],
+        ..Default::default()
+    });
+}
+
+/// A plan file from elsewhere should be rejected without connecting to anything.
+#[test]
+fn apply_invalid_plan() {
+    let plan = file("not a plan");
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/plan", &plan),
+        ],
+        args: vec![
+            "--apply-plan=$TEMP/plan".to_string(),
+        ],
+        expected_exit_code: 18,
+        expected_output_messages: vec![
+            (1, Regex::new("Failed to load plan from .*plan'").unwrap()),
+        ],
+        ..Default::default()
+    });
+}
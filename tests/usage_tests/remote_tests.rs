@@ -3,7 +3,7 @@ use std::time::SystemTime;
 use regex::Regex;
 
 use map_macro::map;
-use crate::{test_framework::{run, TestDesc, NumActions, copied_files}, folder, test_utils::{RemotePlatforms, run_process_with_live_output, RemotePlatform, self}};
+use crate::{test_framework::{run, TestDesc, NumActions, copied_files, copied_files_and_folders}, folder, test_utils::{RemotePlatforms, run_process_with_live_output, RemotePlatform, self}};
 use crate::filesystem_node::*;
 
 /// Tests that rjrssync can be launched on a remote platform, and communication is estabilished.
@@ -339,3 +339,206 @@ fn remote_port() {
         ..Default::default()
     });
 }
+
+/// Tests that the --persist-doers option leaves the remote doer running, and that the next run reconnects to it
+/// rather than launching a new one over ssh.
+#[test]
+fn persist_doers() {
+    let src = file_with_modified("something to sync", SystemTime::UNIX_EPOCH);
+    let args = vec![
+        "$TEMP/src".to_string(),
+        "$REMOTE_LINUX_TEMP/dest".to_string(),
+        "--deploy=ok".to_string(),
+        "--verbose".to_string(), // So that we can check how we connected in the logs
+        "--persist-doers=60".to_string(),
+    ];
+    // The first run may or may not find a doer left over from a previous test run, so we don't check how it connected
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: args.clone(),
+        expected_exit_code: 0,
+        expected_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/dest", Some(&src)), // Src copied to dest
+        ],
+        ..Default::default()
+    });
+
+    // The second run should reuse the doer
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args,
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("Connecting to persistent doer at").unwrap()),
+            (0, Regex::new("Running remote command").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/dest", Some(&src)), // Still there
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that when the source and dest are on the same remote host, one doer is launched and serves both.
+#[test]
+fn shared_doer() {
+    let src = folder! {
+        "file" => file("contents"),
+        "folder" => folder! {
+            "c1" => file("contents1"),
+        },
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/src", &src),
+        ],
+        args: vec![
+            "$REMOTE_LINUX_TEMP/src".to_string(),
+            "$REMOTE_LINUX_TEMP/dest".to_string(),
+            "--deploy=ok".to_string(),
+            "--verbose".to_string(), // So that we can check how we connected in the logs
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: [&[
+            (1, Regex::new("Running remote command").unwrap()),
+            (1, Regex::new("Connecting to shared doer").unwrap()),
+        ], &<NumActions as Into<Vec<(usize, Regex)>>>::into(copied_files_and_folders(2, 2))[..]].concat(),
+        expected_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/src", Some(&src)), // Unchanged
+            ("$REMOTE_LINUX_TEMP/dest", Some(&src)), // Src copied to dest
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that --direct-transfer copies files between two remote targets, with the dest fetching them from the source.
+#[test]
+fn direct_transfer() {
+    let src = folder! {
+        "file" => file("contents"),
+        "folder" => folder! {
+            "c1" => file("contents1"),
+        },
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$REMOTE_WINDOWS_TEMP/src", &src),
+        ],
+        args: vec![
+            "$REMOTE_WINDOWS_TEMP/src".to_string(),
+            "$REMOTE_LINUX_TEMP/dest".to_string(),
+            "--deploy=ok".to_string(),
+            "--direct-transfer".to_string(),
+            "--verbose".to_string(), // So that we can check how the dest connected in the logs
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: [&[
+            (1, Regex::new("Connecting to source doer").unwrap()),
+        ], &<NumActions as Into<Vec<(usize, Regex)>>>::into(copied_files_and_folders(2, 2))[..]].concat(),
+        expected_filesystem_nodes: vec![
+            ("$REMOTE_WINDOWS_TEMP/src", Some(&src)), // Unchanged
+            ("$REMOTE_LINUX_TEMP/dest", Some(&src)), // Src copied to dest
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that --streams spreads the data over several connections to the remote doer, and that it all arrives in order.
+/// The large file is split into several chunks, which will be sent on different streams.
+#[test]
+fn multiple_streams() {
+    let src = folder! {
+        "large" => file(&"so much big!".repeat(1000*1000*2)), // Roughly 24MB
+        "folder" => folder! {
+            "c1" => file("contents1"),
+            "c2" => file("contents2"),
+        },
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$REMOTE_LINUX_TEMP/dest".to_string(),
+            "--deploy=ok".to_string(),
+            "--streams=4".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files_and_folders(3, 2).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$REMOTE_LINUX_TEMP/dest", Some(&src)), // Src copied to dest
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that --compress sends compressed data to and from the remote doer, and that files which are already
+/// compressed (judging by their extension) or which don't compress still arrive intact.
+#[test]
+fn compress() {
+    let src = folder! {
+        "text.txt" => file(&"compress me please. ".repeat(1000*100)),
+        "archive.zip" => file(&"not really a zip. ".repeat(1000*100)),
+        "folder" => folder! {
+            "c1" => file("contents1"),
+        },
+    };
+    let args = |src: &str, dest: &str| vec![
+        src.to_string(),
+        dest.to_string(),
+        "--deploy=ok".to_string(),
+        "--compress=9".to_string(),
+    ];
+    // Local -> remote, where the boss compresses
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: args("$TEMP/src", "$REMOTE_LINUX_TEMP/dest"),
+        expected_exit_code: 0,
+        expected_output_messages: copied_files_and_folders(3, 2).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$REMOTE_LINUX_TEMP/dest", Some(&src)), // Src copied to dest
+        ],
+        ..Default::default()
+    });
+    // Remote -> local, where the doer compresses
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/src", &src),
+        ],
+        args: args("$REMOTE_LINUX_TEMP/src", "$TEMP/dest"),
+        expected_exit_code: 0,
+        expected_output_messages: copied_files_and_folders(3, 2).into(),
+        expected_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/src", Some(&src)), // Unchanged
+            ("$TEMP/dest", Some(&src)), // Src copied to dest
+        ],
+        ..Default::default()
+    });
+}
+
+/// --direct-transfer can't be used when either side is local, as there's no doer for the other one to connect to.
+/// This is checked before connecting to anything.
+#[test]
+fn direct_transfer_local() {
+    run(TestDesc {
+        args: vec![
+            "$TEMP/src".to_string(),
+            "nobody@nowhere:dest".to_string(),
+            "--direct-transfer".to_string(),
+        ],
+        expected_exit_code: 18,
+        expected_output_messages: vec![
+            (1, Regex::new("--direct-transfer can only be used when both the source and destination are remote").unwrap()),
+        ],
+        ..Default::default()
+    });
+}
@@ -164,6 +164,224 @@ fn test_spec_file() {
     });
 }
 
+/// Tests that the syncs in a spec file can be run in parallel, with the result of each one reported.
+#[test]
+fn test_spec_file_parallel() {
+    let spec_file = file(r#"
+        parallel: true
+        syncs:
+        - src: src1/
+          dest: dest1/
+        - src: src2/
+          dest: dest2/
+    "#);
+    let src1 = folder! {
+        "c1" => file("contents1"),
+    };
+    let src2 = folder! {
+        "c2" => file("contents2"),
+        "c3" => file("contents3"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/spec.yaml", &spec_file),
+            ("$TEMP/src1", &src1),
+            ("$TEMP/src2", &src2),
+        ],
+        args: vec![
+            "--spec".to_string(),
+            "$TEMP/spec.yaml".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            // Each sync's messages say which one they're from
+            (1, Regex::new(&regex::escape("[src1/ => dest1/] Copied 1 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("[src2/ => dest2/] Copied 2 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("src1/ => dest1/: Done")).unwrap()),
+            (1, Regex::new(&regex::escape("src2/ => dest2/: Done")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest1", Some(&src1)),
+            ("$TEMP/dest2", Some(&src2)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that syncs which would interfere with each other can't be run in parallel.
+#[test]
+fn test_parallel_overlapping_roots() {
+    let spec_file = file(r#"
+        syncs:
+        - src: src1/
+          dest: dest1/
+        - src: dest1/src2
+          dest: dest2/
+    "#);
+    let src1 = folder! {
+        "c1" => file("contents1"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/spec.yaml", &spec_file),
+            ("$TEMP/src1", &src1),
+        ],
+        args: vec![
+            "--spec".to_string(),
+            "$TEMP/spec.yaml".to_string(),
+            "--parallel".to_string(),
+        ],
+        expected_exit_code: 18,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Syncs can't be run in parallel as the dest 'dest1/' overlaps the src 'dest1/src2' of another sync")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest1", None),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests syncing one source to several dests, each of which needs different things doing.
+#[test]
+fn test_multiple_dests() {
+    let src = folder! {
+        "c1" => file("contents1"),
+        "c2" => file("contents2"),
+    };
+    let dest2 = folder! {
+        "c1" => file_with_modified("old contents", SystemTime::UNIX_EPOCH),
+        "extra" => file("extra"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest2", &dest2),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest1".to_string(),
+            "$TEMP/dest2".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("dest1] Copied 2 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("dest2] Copied 2 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("dest2] Deleted 1 file(s)")).unwrap()),
+            (1, Regex::new("dest1: Done").unwrap()),
+            (1, Regex::new("dest2: Done").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest1", Some(&src)),
+            ("$TEMP/dest2", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that when syncing one source to several dests, each file is only read from the source once, however
+/// many of the dests need it.
+#[test]
+fn test_multiple_dests_reads_source_once() {
+    let src = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "c2" => file("contents2"),
+        "big" => file(&"big".repeat(10000)), // Big enough to be fetched on its own, rather than with the small files
+    };
+    let dest2 = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH), // Doesn't need copying to this dest
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest2", &dest2),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest1".to_string(),
+            "$TEMP/dest2".to_string(),
+            "$TEMP/dest3".to_string(),
+        ],
+        // So that we can count what the source doer reads in the logs
+        env_vars: vec![("RUST_LOG", "info,rjrssync::doer=trace")],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("Getting content of '.*c1'").unwrap()),
+            (1, Regex::new("Getting content of '.*c2'").unwrap()),
+            (1, Regex::new("Getting content of '.*big'").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest1", Some(&src)),
+            ("$TEMP/dest2", Some(&src)),
+            ("$TEMP/dest3", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that when syncing lots of small files to several dests, which each need a different set of them (and so
+/// fetch them in different batches), each file is still only read from the source once.
+#[test]
+fn test_multiple_dests_small_files_read_once() {
+    let src = FilesystemNode::Folder {
+        children: (0..600).map(|i| (format!("file{i}"), file_with_modified(&format!("contents{i}"), SystemTime::UNIX_EPOCH))).collect(),
+    };
+    // Already has every third file, so doesn't need those copying
+    let dest2 = FilesystemNode::Folder {
+        children: (0..600).step_by(3).map(|i| (format!("file{i}"), file_with_modified(&format!("contents{i}"), SystemTime::UNIX_EPOCH))).collect(),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest2", &dest2),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest1".to_string(),
+            "$TEMP/dest2".to_string(),
+        ],
+        // So that we can count what the source doer reads in the logs
+        env_vars: vec![("RUST_LOG", "info,rjrssync::doer=trace")],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (600, Regex::new("Getting content of").unwrap()),
+            (1, Regex::new(&regex::escape("dest1] Copied 600 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("dest2] Copied 400 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest1", Some(&src)),
+            ("$TEMP/dest2", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that a source can't be synced to the same place twice.
+#[test]
+fn test_multiple_dests_overlapping() {
+    let src = folder! {
+        "c1" => file("contents1"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest1".to_string(),
+            "$TEMP/dest1/inner".to_string(),
+        ],
+        expected_exit_code: 18,
+        expected_output_messages: vec![
+            (1, Regex::new("The dests of a sync can't overlap").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest1", None),
+        ],
+        ..Default::default()
+    });
+}
+
 /// Syncing a large file that therefore needs splitting into chunks
 #[test]
 fn test_large_file() {
@@ -173,6 +391,18 @@ fn test_large_file() {
     run_expect_success(&src_folder, &empty_folder(), copied_files(1));
 }
 
+/// Syncing lots of small files, which are fetched and written several at a time (see GetFileContents),
+/// more than fit in a single batch.
+#[test]
+fn test_many_small_files() {
+    let mut children: std::collections::HashMap<String, FilesystemNode> = (0..600)
+        .map(|i| (format!("file{i}"), file(&format!("contents{i}")))).collect();
+    // A big file in the middle, which is fetched on its own
+    children.insert("big".to_string(), file(&"big".repeat(10000)));
+    let src_folder = FilesystemNode::Folder { children };
+    run_expect_success(&src_folder, &empty_folder(), copied_files(601));
+}
+
 /// Checks that the --dry-run flag means that no changes are made, and that information about
 /// what _would_ happen is printed.
 #[test]
@@ -264,6 +494,194 @@ fn dry_run_root_ancestors() {
     });
 }
 
+/// Checks that --itemize shows a line for each action, with the reasons and what changed.
+#[test]
+fn itemize() {
+    let src = folder! {
+        "older" => file_with_modified("new contents", SystemTime::UNIX_EPOCH + Duration::from_secs(2)),
+        "same_size" => file_with_modified("bbb", SystemTime::UNIX_EPOCH + Duration::from_secs(2)),
+        "incompatible" => folder! {},
+        "folder" => folder! {
+            "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let dest = folder! {
+        "older" => file_with_modified("old", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "same_size" => file_with_modified("aaa", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "incompatible" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "extra" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--itemize".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("-f not-src   -- extra")).unwrap()),
+            (1, Regex::new(&regex::escape("-f incompat  -- incompatible")).unwrap()),
+            (1, Regex::new(&regex::escape(">f older     st older")).unwrap()),
+            (1, Regex::new(&regex::escape(">f older     .t same_size")).unwrap()),
+            (1, Regex::new(&regex::escape("+d not-dest  ++ incompatible")).unwrap()),
+            (1, Regex::new(&regex::escape("+d not-dest  ++ folder")).unwrap()),
+            (1, Regex::new(&regex::escape("+f not-dest  ++ folder/c1")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --itemize with --dry-run shows the itemized lines instead of the usual "Would ..." lines,
+/// rather than both.
+#[test]
+fn itemize_dry_run() {
+    let src = folder! {
+        "c1" => file("contents1"),
+        "folder" => folder! {},
+    };
+    let dest = folder! {
+        "extra" => file("contents"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--itemize".to_string(),
+            "--dry-run".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("-f not-src   -- extra")).unwrap()),
+            (1, Regex::new(&regex::escape("+f not-dest  ++ c1")).unwrap()),
+            (1, Regex::new(&regex::escape("+d not-dest  ++ folder")).unwrap()),
+            (0, Regex::new("Would delete .*extra|Would create .*folder|Would copy .*c1").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&dest)), // Dest should be unchanged for a dry run
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --verify checks the copied files, and doesn't get in the way of the sync.
+#[test]
+fn verify() {
+    let src = folder! {
+        "c1" => file("contents1"),
+        "folder" => folder! {
+            "c2" => file("contents2"),
+        },
+    };
+    let dest = folder! {
+        "c1" => file_with_modified("old contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--verify".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 2 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Verified 2 file(s) totalling 18B")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --verify works for more files than it hashes at once, so that the requests are pipelined.
+#[test]
+fn verify_many_files() {
+    let names: Vec<String> = (0..250).map(|i| format!("file{i}")).collect();
+    let src = folder(names.iter().map(|n| (n.as_str(), file(n))).collect());
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--verify".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 250 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Verified 250 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --plan-json outputs details of each action, including the reasons, in a form that
+/// scripts can rely on.
+#[test]
+fn dry_run_plan_json() {
+    let src = folder! {
+        "file" => file_with_modified("contents", SystemTime::UNIX_EPOCH + Duration::from_secs(2)),
+        "folder" => folder! {
+            "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        },
+        "symlink" => symlink_file("bob")
+    };
+    let dest = folder! {
+        "file" => file_with_modified("old", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "file2" => file("contents"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dry-run".to_string(),
+            "--plan-json=-".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(r#""dry_run": true"#).unwrap()),
+            (1, Regex::new(r#""skipped": false"#).unwrap()),
+            (1, Regex::new(r#""path": "file2",\s*"reason": "not_on_source",\s*"kind": "file",\s*"size": 8,"#).unwrap()),
+            (1, Regex::new(r#""path": "file",\s*"dest_path": "file",\s*"reason": "dest_older",\s*"kind": "file",\s*"size": 8,\s*"modified_time": 2\s"#).unwrap()),
+            (1, Regex::new(r#""path": "folder",\s*"dest_path": "folder",\s*"reason": "not_on_dest",\s*"kind": "folder""#).unwrap()),
+            (1, Regex::new(r#""path": "folder/c1",\s*"dest_path": "folder/c1",\s*"reason": "not_on_dest",\s*"kind": "file",\s*"size": 9,\s*"modified_time": 0\s"#).unwrap()),
+            (1, Regex::new(r#""path": "symlink",\s*"dest_path": "symlink",\s*"reason": "not_on_dest",\s*"kind": "symlink",\s*"symlink_kind": "(file|unknown)",\s*"target": "bob""#).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&dest)), // Dest should be unchanged too
+        ],
+        ..Default::default()
+    });
+}
+
 /// Checks what happens when a file's size changes between the querying phase and the actual sync.
 #[test]
 fn file_size_change_during_sync() {
@@ -337,6 +755,38 @@ fn stats() {
     });
 }
 
+/// Checks that --bwlimit still copies everything, and that --stats shows the effective rate.
+/// The file is large enough that it can't be sent within the limit straight away.
+#[test]
+fn bwlimit() {
+    let src = folder! {
+        "large" => file(&"x".repeat(200*1024)),
+        "folder" => folder! {
+            "c1" => file("contents1"),
+        },
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--bwlimit=100K".to_string(),
+            "--stats".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(r"Sent 200.01 KiB of file contents at an effective .*/s \(limited to 100.00 KiB/s\), waiting [1-9].* seconds").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)),
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
 /// Checks that --quiet doesn't print anything, but does show errors
 #[test]
 fn quiet() {
@@ -437,3 +887,213 @@ fn read_only_dest_file() {
     });
 }
 
+/// Checks that with --keep-going, a file on the dest that can't be overwritten doesn't stop the
+/// rest of the sync, and is reported at the end.
+#[test]
+fn keep_going() {
+    let dest_folder = tempfile::tempdir().expect("Failed to create temp dir");
+    let read_only_file = dest_folder.path().join("read_only");
+    std::fs::write(&read_only_file, "").expect("Failed to create file");
+    let mut perm = std::fs::metadata(&read_only_file).expect("Failed to query metadata").permissions();
+    perm.set_readonly(true);
+    std::fs::set_permissions(&read_only_file, perm).expect("Failed to make read-only");
+    let read_only_modified = std::fs::metadata(&read_only_file).unwrap().modified().unwrap();
+
+    let src = folder! {
+        "read_only" => file_with_modified("contents", read_only_modified + Duration::from_secs(1)),
+        "other" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+    };
+    let expected_dest = folder! {
+        "read_only" => file_with_modified("", read_only_modified), // Unchanged, as it couldn't be written
+        "other" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            dest_folder.path().to_string_lossy().to_string(),
+            "--keep-going".to_string(),
+        ],
+        expected_exit_code: 15,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Failed to sync 1 entries:")).unwrap()),
+            (1, Regex::new("Error writing file contents to .*read_only").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source unchanged
+            (&dest_folder.path().to_string_lossy(), Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that with --keep-going, a source file that can't be read doesn't stop the rest of the sync, even though
+/// the files after it will have already been requested from the source (see request_file_contents).
+#[cfg(unix)]
+#[test]
+fn keep_going_unreadable_src_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = tempfile::tempdir().expect("Failed to create temp dir");
+    let src = temp.path().join("src");
+    let dest = temp.path().join("dest");
+    // Lots of small files, which are fetched in batches, and some big ones which are fetched on their own
+    let mut children: std::collections::HashMap<String, FilesystemNode> = (0..20)
+        .map(|i| (format!("small{i}"), file_with_modified(&format!("contents{i}"), SystemTime::UNIX_EPOCH))).collect();
+    children.insert("big1".to_string(), file_with_modified(&"big".repeat(10000), SystemTime::UNIX_EPOCH));
+    children.insert("big2".to_string(), file_with_modified(&"big".repeat(10000), SystemTime::UNIX_EPOCH));
+    let expected_dest = FilesystemNode::Folder { children };
+    save_filesystem_node_to_disk_local(&expected_dest, &src);
+    let unreadable = src.join("unreadable");
+    std::fs::write(&unreadable, "big".repeat(10000)).expect("Failed to create file");
+    std::fs::set_permissions(&unreadable, std::fs::Permissions::from_mode(0o000)).expect("Failed to make unreadable");
+
+    run(TestDesc {
+        args: vec![
+            src.to_string_lossy().to_string(),
+            dest.to_string_lossy().to_string(),
+            "--keep-going".to_string(),
+        ],
+        expected_exit_code: 15,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Failed to sync 1 entries:")).unwrap()),
+            (1, Regex::new("unreadable.*[Pp]ermission denied").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            (&dest.to_string_lossy(), Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that with --keep-going, a small source file that can't be read doesn't stop the rest of the sync,
+/// including the other files that were fetched in the same batch as it.
+#[cfg(unix)]
+#[test]
+fn keep_going_unreadable_small_src_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = tempfile::tempdir().expect("Failed to create temp dir");
+    let src = temp.path().join("src");
+    let dest = temp.path().join("dest");
+    let expected_dest = FilesystemNode::Folder {
+        children: (0..500).map(|i| (format!("file{i}"), file_with_modified(&format!("contents{i}"), SystemTime::UNIX_EPOCH))).collect(),
+    };
+    save_filesystem_node_to_disk_local(&expected_dest, &src);
+    let unreadable = src.join("unreadable");
+    std::fs::write(&unreadable, "contents").expect("Failed to create file");
+    std::fs::set_permissions(&unreadable, std::fs::Permissions::from_mode(0o000)).expect("Failed to make unreadable");
+
+    run(TestDesc {
+        args: vec![
+            src.to_string_lossy().to_string(),
+            dest.to_string_lossy().to_string(),
+            "--keep-going".to_string(),
+        ],
+        expected_exit_code: 15,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Failed to sync 1 entries:")).unwrap()),
+            (1, Regex::new("unreadable.*[Pp]ermission denied").unwrap()),
+            (1, Regex::new(&regex::escape("Copied 500 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            (&dest.to_string_lossy(), Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --report and --prometheus-textfile write a summary of the sync, for monitoring.
+#[test]
+fn report() {
+    let src = folder! {
+        "file" => file("contents"),
+        "folder" => folder! {
+            "c1" => file("contents1"),
+        },
+    };
+    let dest = folder! {
+        "old" => file("old"),
+    };
+    let report_folder = tempfile::tempdir().expect("Failed to create temp dir");
+    let report_file = report_folder.path().join("report.json");
+    let prometheus_file = report_folder.path().join("rjrssync.prom");
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            format!("--report={}", report_file.display()),
+            format!("--prometheus-textfile={}", prometheus_file.display()),
+        ],
+        expected_exit_code: 0,
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source unchanged
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+
+    let report = std::fs::read_to_string(&report_file).expect("Failed to read report");
+    assert!(Regex::new(r#""exit_code": 0"#).unwrap().is_match(&report));
+    assert!(Regex::new(r#""success": true,\s*"error": null"#).unwrap().is_match(&report));
+    assert!(Regex::new(r#""deleted": \{\s*"files": 1,\s*"folders": 0,\s*"symlinks": 0,\s*"bytes": 3\s*\}"#).unwrap().is_match(&report));
+    assert!(Regex::new(r#""copied": \{\s*"files": 2,\s*"folders": 1,\s*"symlinks": 0,\s*"bytes": 17\s*\}"#).unwrap().is_match(&report));
+    assert!(Regex::new(r#""copy": \d"#).unwrap().is_match(&report));
+
+    let metrics = std::fs::read_to_string(&prometheus_file).expect("Failed to read Prometheus metrics");
+    assert!(metrics.contains("rjrssync_exit_code 0\n"));
+    assert!(Regex::new(r#"rjrssync_sync_success\{src=".*src",dest=".*dest"\} 1\n"#).unwrap().is_match(&metrics));
+    assert!(Regex::new(r#"rjrssync_copied_entries\{src=".*",dest=".*",kind="files"\} 2\n"#).unwrap().is_match(&metrics));
+    assert!(Regex::new(r#"rjrssync_duration_seconds\{src=".*",dest=".*",phase="copy"\} \d"#).unwrap().is_match(&metrics));
+}
+
+/// Checks that entries whose names aren't valid UTF-8 (e.g. Latin-1 names from old archives) can be synced,
+/// and that their names are preserved exactly.
+/// The filesystem node helpers can only describe Unicode names, so we set this up manually.
+#[cfg(unix)]
+#[test]
+fn non_unicode_names() {
+    use std::os::unix::ffi::OsStrExt;
+    use std::ffi::OsStr;
+
+    let temp = tempfile::tempdir().expect("Failed to create temp dir");
+    let src = temp.path().join("src");
+    let dest = temp.path().join("dest");
+    // "café" and "naïve" in Latin-1, which aren't valid UTF-8
+    let folder_name = OsStr::from_bytes(b"caf\xe9");
+    let file_name = OsStr::from_bytes(b"na\xefve.txt");
+    std::fs::create_dir_all(src.join(folder_name)).unwrap();
+    std::fs::write(src.join(folder_name).join(file_name), "contents").unwrap();
+
+    run(TestDesc {
+        args: vec![
+            src.to_string_lossy().to_string(),
+            dest.to_string_lossy().to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()),
+        ],
+        ..Default::default()
+    });
+    assert_eq!(std::fs::read_to_string(dest.join(folder_name).join(file_name)).unwrap(), "contents");
+
+    // Running again should find that there's nothing to do, i.e. the names match up exactly
+    run(TestDesc {
+        args: vec![
+            src.to_string_lossy().to_string(),
+            dest.to_string_lossy().to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Nothing to do")).unwrap()),
+        ],
+        ..Default::default()
+    });
+}
@@ -39,6 +39,9 @@ pub struct TestDesc<'a> {
     /// held by the calling code. This allows the calling code to lock the remote platforms, do some setup,
     /// then call this test, all within the same lock.
     pub remote_platforms: Option<&'a RemotePlatforms>,
+    /// Additional environment variables to set for rjrssync (e.g. to simulate behaviour that isn't available
+    /// on all test platforms).
+    pub env_vars: Vec<(&'a str, &'a str)>,
 }
 
 /// Checks that running rjrssync with the setup described by the TestDesc behaves as described by the TestDesc.
@@ -125,6 +128,7 @@ fn run_impl(desc: TestDesc) {
         std::process::Command::new(rjrssync_path)
         .current_dir(&temp_folder) // So that any relative paths are inside the test folder
         .env("RJRSSYNC_TEST_PROMPT_RESPONSE", desc.prompt_responses.join(","))
+        .envs(desc.env_vars.iter().cloned())
         .args(desc.args.iter().map(|a| substitute_vars(a).0)));
 
     // Check exit code
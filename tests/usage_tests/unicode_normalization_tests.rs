@@ -0,0 +1,143 @@
+use std::time::{SystemTime, Duration};
+
+use regex::Regex;
+
+use crate::{folder, test_framework::{run, TestDesc}};
+use map_macro::map;
+use crate::filesystem_node::*;
+
+// "café" with a precomposed é (NFC, as usually used on Linux and Windows)
+const NFC: &str = "caf\u{00e9}";
+// "café" with a combining acute accent (NFD, as used by macOS)
+const NFD: &str = "cafe\u{0301}";
+
+/// Without --normalize-unicode, names which are encoded differently are different entries, so the dest
+/// entry is deleted and the source entry copied.
+#[test]
+fn different_encoding_without_normalize() {
+    let src = folder! {
+        NFC => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    let dest = folder! {
+        NFD => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Deleted 1 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// With --normalize-unicode, names which are encoded differently are the same entry, so there is nothing to do.
+#[test]
+fn different_encoding_unchanged() {
+    let src = folder! {
+        NFC => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    let dest = folder! {
+        NFD => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--normalize-unicode".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Nothing to do")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&dest)), // Existing name should be kept
+        ],
+        ..Default::default()
+    });
+}
+
+/// With --normalize-unicode, updating a file and creating a new file inside a folder whose name is
+/// encoded differently on the dest should use the dest's existing folder name.
+#[test]
+fn different_encoding_updated() {
+    let src = folder! {
+        NFD => folder! {
+            "existing" => file_with_modified("new contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+            "new" => file_with_modified("new file", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let dest = folder! {
+        NFC => folder! {
+            "existing" => file_with_modified("old contents", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let expected_dest = folder! {
+        NFC => folder! {
+            "existing" => file_with_modified("new contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+            "new" => file_with_modified("new file", SystemTime::UNIX_EPOCH),
+        },
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--normalize-unicode".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 2 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Two source entries whose names are the same once normalized can't both be synced, so a warning is shown.
+#[test]
+fn normalized_collision() {
+    let src = folder! {
+        NFC => file("one"),
+        NFD => file("two"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--normalize-unicode".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("has the same name as .* after Unicode normalization, so will be ignored").unwrap()),
+            (1, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()),
+        ],
+        ..Default::default()
+    });
+}
@@ -0,0 +1,57 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::folder;
+use crate::filesystem_node::*;
+use map_macro::map;
+
+// --watch never finishes on its own, so these tests launch rjrssync themselves (rather than using run())
+// and kill it once they've seen what they're looking for.
+
+/// Waits until the given function returns true, panicking if this takes too long.
+fn wait_until(description: &str, f: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !f() {
+        assert!(start.elapsed() < Duration::from_secs(10), "Timed out waiting for {description}");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Changes to the source (new, modified and deleted entries) are synced as they happen, after the initial sync.
+#[test]
+fn changes_are_synced() {
+    let temp = tempfile::tempdir().expect("Failed to create temp dir");
+    let src = temp.path().join("src");
+    let dest = temp.path().join("dest");
+    save_filesystem_node_to_disk_local(&folder! {
+        "file" => file("contents"),
+        "folder" => folder! {
+            "c1" => file("contents1"),
+        },
+    }, &src);
+
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rjrssync"))
+        .arg(&src).arg(&dest).arg("--watch").arg("--no-progress")
+        .spawn().expect("Failed to launch rjrssync");
+
+    let read = |p: &Path| std::fs::read_to_string(p).ok();
+    wait_until("initial sync", || read(&dest.join("folder").join("c1")).as_deref() == Some("contents1"));
+
+    std::fs::write(src.join("file"), "changed").expect("Failed to write file");
+    std::fs::create_dir_all(src.join("new").join("deep")).expect("Failed to create folders");
+    std::fs::write(src.join("new").join("deep").join("c2"), "contents2").expect("Failed to write file");
+    std::fs::remove_file(src.join("folder").join("c1")).expect("Failed to delete file");
+
+    wait_until("changes to be synced", || {
+        read(&dest.join("file")).as_deref() == Some("changed") &&
+        read(&dest.join("new").join("deep").join("c2")).as_deref() == Some("contents2") &&
+        !dest.join("folder").join("c1").exists()
+    });
+
+    // Should still be running, waiting for more changes
+    assert!(child.try_wait().expect("Failed to check process").is_none());
+    child.kill().expect("Failed to kill rjrssync");
+    let _ = child.wait();
+
+    assert_eq!(load_filesystem_node_from_disk_local(&dest), load_filesystem_node_from_disk_local(&src));
+}
@@ -0,0 +1,182 @@
+use std::time::{SystemTime};
+
+use regex::Regex;
+
+use crate::{folder, test_framework::{run, TestDesc}};
+use map_macro::map;
+use crate::filesystem_node::*;
+
+/// Names which can't be used on Windows are fine when the dest isn't Windows, so should be synced as normal.
+#[test]
+fn non_windows_dest_unaffected() {
+    let src = folder! {
+        "a:b" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "aux.c" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    if cfg!(windows) {
+        return; // Can't create these files on Windows in the first place
+    }
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--windows-incompatible-name=escape".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 2 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&src)), // Names unchanged
+        ],
+        ..Default::default()
+    });
+}
+
+// "Tag" these tests as they require remote platforms (GitHub Actions differentiates these)
+mod remote {
+
+use super::*;
+
+/// By default, names which can't be used on a Windows dest are an error, reported before anything is changed.
+#[test]
+fn error() {
+    let src = folder! {
+        "a:b" => folder! {
+            "inside" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        },
+        "aux.c" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "fine" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/src", &src),
+        ],
+        args: vec![
+            "--deploy=ok".to_string(),  // Skip the confirmation prompt for deploying
+            "$REMOTE_LINUX_TEMP/src".to_string(),
+            "$REMOTE_WINDOWS_TEMP/dest".to_string(),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new("source folder .*a:b' has a name which can't be used on Windows").unwrap()),
+            (1, Regex::new("source file .*aux.c' has a name which can't be used on Windows").unwrap()),
+            (0, Regex::new("inside' has a name").unwrap()), // Only the folder itself is reported
+        ],
+        expected_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$REMOTE_WINDOWS_TEMP/dest", None), // Nothing should have been done
+        ],
+        ..Default::default()
+    });
+}
+
+/// Names which can't be used on a Windows dest can be skipped, with a warning.
+#[test]
+fn skip() {
+    let src = folder! {
+        "a:b" => folder! {
+            "inside" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        },
+        "fine" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    let expected_dest = folder! {
+        "fine" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/src", &src),
+        ],
+        args: vec![
+            "--deploy=ok".to_string(),  // Skip the confirmation prompt for deploying
+            "$REMOTE_LINUX_TEMP/src".to_string(),
+            "$REMOTE_WINDOWS_TEMP/dest".to_string(),
+            "--windows-incompatible-name=skip".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("source folder .*a:b' has a name which can't be used on Windows. It will be skipped").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$REMOTE_WINDOWS_TEMP/dest", Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Names which can't be used on a Windows dest can be escaped.
+#[test]
+fn escape() {
+    let src = folder! {
+        "a:b" => folder! {
+            "what?" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        },
+        "aux.c" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "trailing." => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    let expected_dest = folder! {
+        "a\u{F03A}b" => folder! {
+            "what\u{F03F}" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        },
+        "\u{F061}ux.c" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "trailing\u{F02E}" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/src", &src),
+        ],
+        args: vec![
+            "--deploy=ok".to_string(),  // Skip the confirmation prompt for deploying
+            "$REMOTE_LINUX_TEMP/src".to_string(),
+            "$REMOTE_WINDOWS_TEMP/dest".to_string(),
+            "--windows-incompatible-name=escape".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_filesystem_nodes: vec![
+            ("$REMOTE_LINUX_TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$REMOTE_WINDOWS_TEMP/dest", Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Names which were escaped when copying to Windows are restored when copying back.
+#[test]
+fn unescape() {
+    let src = folder! {
+        "a\u{F03A}b" => folder! {
+            "what\u{F03F}" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        },
+        "\u{F061}ux.c" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    let expected_dest = folder! {
+        "a:b" => folder! {
+            "what?" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        },
+        "aux.c" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$REMOTE_WINDOWS_TEMP/src", &src),
+        ],
+        args: vec![
+            "--deploy=ok".to_string(),  // Skip the confirmation prompt for deploying
+            "$REMOTE_WINDOWS_TEMP/src".to_string(),
+            "$REMOTE_LINUX_TEMP/dest".to_string(),
+            "--windows-incompatible-name=escape".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_filesystem_nodes: vec![
+            ("$REMOTE_WINDOWS_TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$REMOTE_LINUX_TEMP/dest", Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+}